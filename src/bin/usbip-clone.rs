@@ -0,0 +1,146 @@
+//! Exports an emulated clone of a device over USBIP.
+//!
+//! Usage: `usbip-clone <capture.pcap | descriptors> [busnum:devnum]`
+//!
+//! The descriptors are taken either from a usbmon pcap capture of the enumeration of
+//! the device or from the binary dump in `/sys/bus/usb/devices/*/descriptors`.
+//! The clone answers the enumeration like the original, but stalls all other requests.
+
+use std::{env, fs, process, thread, time::Duration};
+use usbip_device::{
+    descriptor::{ConfigurationDescriptor, DeviceDescriptor},
+    emulated::{DescriptorSet, EmulatedDevice},
+    replay::Recording,
+    UsbIpError,
+};
+
+/// The magic numbers of pcap files in both byte orders and with both timestamp resolutions
+const PCAP_MAGIC: [[u8; 4]; 4] = [
+    [0xd4, 0xc3, 0xb2, 0xa1],
+    [0xa1, 0xb2, 0xc3, 0xd4],
+    [0x4d, 0x3c, 0xb2, 0xa1],
+    [0xa1, 0xb2, 0x3c, 0x4d],
+];
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!(
+            "usage: {} <capture.pcap | descriptors> [busnum:devnum]",
+            args[0]
+        );
+        process::exit(2);
+    }
+
+    let devid = args.get(2).map(|arg| match parse_devid(arg) {
+        Some(devid) => devid,
+        None => {
+            eprintln!("invalid device {}, expected busnum:devnum", arg);
+            process::exit(2);
+        }
+    });
+
+    let descriptors = match load(&args[1], devid) {
+        Ok(descriptors) => descriptors,
+        Err(err) => {
+            eprintln!("failed to load {}: {}", args[1], err);
+            process::exit(1);
+        }
+    };
+
+    let device = match descriptors.device().map(DeviceDescriptor::from_slice) {
+        Some(Ok(device)) => device,
+        _ => {
+            eprintln!("no device descriptor found in {}", args[1]);
+            process::exit(1);
+        }
+    };
+    print_summary(&descriptors, &device);
+
+    let server = EmulatedDevice::new(descriptors, ());
+    println!("exporting the clone as 1-1");
+    loop {
+        server.poll();
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Parses a device as `busnum:devnum` into `busnum << 16 | devnum`.
+fn parse_devid(arg: &str) -> Option<u32> {
+    let mut parts = arg.splitn(2, ':');
+    let busnum: u32 = parts.next()?.parse().ok()?;
+    let devnum: u32 = parts.next()?.parse().ok()?;
+    Some(busnum << 16 | devnum)
+}
+
+/// Loads the descriptors from a pcap or a sysfs dump.
+///
+/// If the capture contains multiple devices and no `devid` is given,
+/// the first device, that is asked for its device descriptor, is cloned.
+fn load(path: &str, devid: Option<u32>) -> Result<DescriptorSet, UsbIpError> {
+    let data = fs::read(path)?;
+    if data.len() < 4 || !PCAP_MAGIC.iter().any(|magic| data[..4] == magic[..]) {
+        return DescriptorSet::from_sysfs_descriptors(&data);
+    }
+
+    let mut recording = Recording::from_pcap(&data[..])?;
+    let devid = devid.or_else(|| {
+        recording
+            .urbs
+            .iter()
+            .find(|urb| matches!(urb.setup, Some([0x80, 0x06, 0x00, 0x01, ..])))
+            .map(|urb| urb.devid)
+    });
+    if let Some(devid) = devid {
+        recording.retain_device(devid);
+    }
+
+    Ok(DescriptorSet::from_recording(&recording))
+}
+
+fn print_summary(descriptors: &DescriptorSet, device: &DeviceDescriptor) {
+    println!(
+        "device {:04x}:{:04x}, class {:02x}/{:02x}/{:02x}, {} configuration(s)",
+        device.vendor_id,
+        device.product_id,
+        device.device_class,
+        device.device_subclass,
+        device.device_protocol,
+        device.num_configurations,
+    );
+
+    for index in 0..device.num_configurations {
+        let config = match descriptors
+            .configuration(index)
+            .map(ConfigurationDescriptor::from_slice)
+        {
+            Some(Ok(config)) => config,
+            _ => {
+                println!("  configuration {} is missing", index);
+                continue;
+            }
+        };
+
+        println!("  configuration {}", config.configuration_value);
+        for interface in &config.interfaces {
+            println!(
+                "    interface {}.{}, class {:02x}/{:02x}/{:02x}",
+                interface.interface_number,
+                interface.alternate_setting,
+                interface.interface_class,
+                interface.interface_subclass,
+                interface.interface_protocol,
+            );
+            for endpoint in &interface.endpoints {
+                println!(
+                    "      endpoint {:#04x} {:?}, max packet size {}",
+                    endpoint.address,
+                    endpoint.transfer_type(),
+                    endpoint.max_packet_size,
+                );
+            }
+        }
+    }
+
+    println!("  {} descriptor(s) in total", descriptors.descriptors.len());
+}
@@ -0,0 +1,480 @@
+//! Capturing of urb traffic.
+//!
+//! A [`Capture`] records every submitted urb, its completion and unlinked urbs.
+//! There are two implementations:
+//!
+//! - The [`PcapWriter`] writes pcap files in the format of the Linux usbmon interface
+//!   ([`LINKTYPE_USB_LINUX_MMAPPED`]). Wireshark decodes such captures with its USB
+//!   dissectors, including the ones of the HID, CDC and mass storage classes.
+//! - The [`JsonLinesWriter`] writes one JSON object per event, which is easy to
+//!   process by test infrastructure.
+//!
+//! ```no_run
+//! use usbip_device::{
+//!     capture::{JsonLinesWriter, PcapWriter},
+//!     UsbIpBus,
+//! };
+//!
+//! let bus = UsbIpBus::new();
+//! bus.add_capture(PcapWriter::create("usb.pcap").unwrap());
+//! bus.add_capture(JsonLinesWriter::create("usb.jsonl").unwrap());
+//! ```
+
+use crate::{
+    cmd::{Direction, UsbIpHeader},
+    request::{UsbIpRequest, UsbIpRequestCmd},
+    response::{UsbIpResponse, UsbIpResponseCmd, ECONNRESET},
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    fs::File,
+    io::{BufWriter, Result as IoResult, Write},
+    path::Path,
+    time::Duration,
+};
+use usb_device::endpoint::EndpointType;
+
+/// The pcap link type of usbmon captures with the 64 byte header
+pub const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// The maximum number of bytes captured per record
+const SNAPLEN: u32 = 0x0004_0000;
+/// Size of the usbmon header, that precedes the data of every record
+const USBMON_HEADER_SIZE: usize = 64;
+
+const EVENT_SUBMIT: u8 = b'S';
+const EVENT_COMPLETE: u8 = b'C';
+
+const XFER_TYPE_ISOCHRONOUS: u8 = 0;
+const XFER_TYPE_INTERRUPT: u8 = 1;
+const XFER_TYPE_CONTROL: u8 = 2;
+const XFER_TYPE_BULK: u8 = 3;
+
+/// The properties of a submitted urb, that its completion is recorded with
+#[derive(Debug, Clone, Copy)]
+struct UrbInfo {
+    xfer_type: u8,
+    epnum: u8,
+    devnum: u8,
+    busnum: u16,
+}
+
+/// A single usbmon event
+struct Event<'a> {
+    id: u32,
+    ty: u8,
+    info: UrbInfo,
+    setup: Option<[u8; 8]>,
+    status: i32,
+    length: u32,
+    data: &'a [u8],
+    interval: i32,
+    start_frame: i32,
+    xfer_flags: u32,
+}
+
+/// A sink, that records the urb traffic of a bus.
+pub trait Capture: Debug + Send {
+    /// Records a request of the host.
+    ///
+    /// `ty` is the transfer type of the endpoint, that the request is addressed to.
+    /// `time` is the time since the unix epoch, as read from the [`Clock`](crate::clock::Clock) of the bus.
+    fn request(&mut self, request: &UsbIpRequest, ty: EndpointType, time: Duration)
+        -> IoResult<()>;
+
+    /// Records a response of the device at `time` since the unix epoch.
+    fn response(&mut self, response: &UsbIpResponse, time: Duration) -> IoResult<()>;
+}
+
+/// Writes urbs as pcap records in the usbmon format.
+pub struct PcapWriter {
+    writer: Box<dyn Write + Send>,
+    /// Urbs, that have been submitted but not completed yet
+    urbs: HashMap<u32, UrbInfo>,
+    /// The urbs targeted by pending unlink requests, by the sequence number of the unlink
+    unlinks: HashMap<u32, u32>,
+}
+
+impl Debug for PcapWriter {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("PcapWriter")
+            .field("urbs", &self.urbs.len())
+            .finish()
+    }
+}
+
+impl PcapWriter {
+    /// Starts a capture into `writer` by writing the pcap file header.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> IoResult<Self> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+
+        let mut header = [0; 24];
+        header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            urbs: HashMap::new(),
+            unlinks: HashMap::new(),
+        })
+    }
+
+    /// Starts a capture into a newly created file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    fn record(&mut self, event: Event<'_>, timestamp: Duration) -> IoResult<()> {
+        let data = &event.data[..usize::min(event.data.len(), SNAPLEN as usize)];
+        let record_len = (USBMON_HEADER_SIZE + data.len()) as u32;
+
+        let mut record = Vec::with_capacity(16 + record_len as usize);
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&record_len.to_le_bytes());
+        record.extend_from_slice(&record_len.to_le_bytes());
+
+        // The usbmon header
+        record.extend_from_slice(&(event.id as u64).to_le_bytes());
+        record.push(event.ty);
+        record.push(event.info.xfer_type);
+        record.push(event.info.epnum);
+        record.push(event.info.devnum);
+        record.extend_from_slice(&event.info.busnum.to_le_bytes());
+        record.push(if event.setup.is_some() { 0 } else { b'-' });
+        record.push(match (data.is_empty(), event.info.epnum & 0x80 != 0) {
+            (false, _) => 0,
+            (true, true) => b'<',
+            (true, false) => b'>',
+        });
+        record.extend_from_slice(&(timestamp.as_secs() as i64).to_le_bytes());
+        record.extend_from_slice(&(timestamp.subsec_micros() as i32).to_le_bytes());
+        record.extend_from_slice(&event.status.to_le_bytes());
+        record.extend_from_slice(&event.length.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&event.setup.unwrap_or_default());
+        record.extend_from_slice(&event.interval.to_le_bytes());
+        record.extend_from_slice(&event.start_frame.to_le_bytes());
+        record.extend_from_slice(&event.xfer_flags.to_le_bytes());
+        record.extend_from_slice(&0u32.to_le_bytes());
+
+        record.extend_from_slice(data);
+
+        self.writer.write_all(&record)?;
+        self.writer.flush()
+    }
+}
+
+impl Capture for PcapWriter {
+    fn request(
+        &mut self,
+        request: &UsbIpRequest,
+        ty: EndpointType,
+        time: Duration,
+    ) -> IoResult<()> {
+        let cmd = match request.cmd {
+            UsbIpRequestCmd::Cmd(ref cmd) => cmd,
+            UsbIpRequestCmd::Unlink(ref unlink) => {
+                // Unlinks show up as completion of the urb with -ECONNRESET
+                self.unlinks.insert(request.header.seqnum, unlink.seqnum);
+                return Ok(());
+            }
+        };
+
+        let info = urb_info(&request.header, ty);
+        self.urbs.insert(request.header.seqnum, info);
+
+        let setup = match info.xfer_type {
+            XFER_TYPE_CONTROL => Some(cmd.setup),
+            _ => None,
+        };
+
+        self.record(
+            Event {
+                id: request.header.seqnum,
+                ty: EVENT_SUBMIT,
+                info,
+                setup,
+                status: 0,
+                length: cmd.transfer_buffer_length.max(0) as u32,
+                data: &request.data,
+                interval: cmd.interval,
+                start_frame: cmd.start_frame,
+                xfer_flags: cmd.transfer_flags.bits(),
+            },
+            time,
+        )
+    }
+
+    fn response(&mut self, response: &UsbIpResponse, time: Duration) -> IoResult<()> {
+        let (seqnum, status, length, start_frame) = match response.cmd {
+            UsbIpResponseCmd::Cmd(ref ret) => (
+                response.header.seqnum,
+                ret.status,
+                ret.actual_length.max(0) as u32,
+                ret.start_frame,
+            ),
+            UsbIpResponseCmd::Unlink(ref ret) => {
+                let seqnum = match self.unlinks.remove(&response.header.seqnum) {
+                    Some(seqnum) if ret.status == -ECONNRESET => seqnum,
+                    // The urb was completed before it could be unlinked
+                    _ => return Ok(()),
+                };
+                (seqnum, ret.status, 0, 0)
+            }
+        };
+
+        let info = match self.urbs.remove(&seqnum) {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+
+        self.record(
+            Event {
+                id: seqnum,
+                ty: EVENT_COMPLETE,
+                info,
+                setup: None,
+                status,
+                length,
+                data: &response.data,
+                interval: 0,
+                start_frame,
+                xfer_flags: 0,
+            },
+            time,
+        )
+    }
+}
+
+/// The encoding of payloads in a [`JsonLinesWriter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadEncoding {
+    Hex,
+    Base64,
+}
+
+/// Writes urbs as JSON objects, one per line.
+///
+/// Every object has a `time` in seconds since the unix epoch, an `event` and the
+/// `seqnum` of the urb. Depending on the event, it contains further fields:
+///
+/// | `event`           | Fields                                                                                           |
+/// |-------------------|--------------------------------------------------------------------------------------------------|
+/// | `submit`          | `devid`, `ep`, `direction`, `type`, `transfer_flags`, `transfer_buffer_length`, `setup`, `data` |
+/// | `complete`        | `devid`, `ep`, `direction`, `type`, `status`, `actual_length`, `data`                           |
+/// | `unlink`          | `devid`, `unlink_seqnum`                                                                         |
+/// | `unlink_complete` | `unlink_seqnum`, `status`                                                                        |
+///
+/// `setup` is `null` for urbs, that are not control transfers.
+pub struct JsonLinesWriter {
+    writer: Box<dyn Write + Send>,
+    encoding: PayloadEncoding,
+    /// Urbs, that have been submitted but not completed yet
+    urbs: HashMap<u32, (u32, Direction, EndpointType)>,
+    /// The urbs targeted by pending unlink requests, by the sequence number of the unlink
+    unlinks: HashMap<u32, u32>,
+}
+
+impl Debug for JsonLinesWriter {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("JsonLinesWriter")
+            .field("encoding", &self.encoding)
+            .field("urbs", &self.urbs.len())
+            .finish()
+    }
+}
+
+impl JsonLinesWriter {
+    /// Starts a log into `writer`, with payloads encoded in hex.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Box::new(writer),
+            encoding: PayloadEncoding::Hex,
+            urbs: HashMap::new(),
+            unlinks: HashMap::new(),
+        }
+    }
+
+    /// Starts a log into a newly created file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Sets the encoding of the payloads.
+    pub fn set_payload_encoding(&mut self, encoding: PayloadEncoding) {
+        self.encoding = encoding;
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        match self.encoding {
+            PayloadEncoding::Hex => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            PayloadEncoding::Base64 => base64(data),
+        }
+    }
+
+    fn write_line(&mut self, line: String) -> IoResult<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+impl Capture for JsonLinesWriter {
+    fn request(
+        &mut self,
+        request: &UsbIpRequest,
+        ty: EndpointType,
+        time: Duration,
+    ) -> IoResult<()> {
+        let header = &request.header;
+        let time = time.as_secs_f64();
+
+        let line = match request.cmd {
+            UsbIpRequestCmd::Cmd(ref cmd) => {
+                self.urbs
+                    .insert(header.seqnum, (header.ep, header.direction, ty));
+
+                let setup = match ty {
+                    EndpointType::Control => format!("\"{}\"", self.encode(&cmd.setup)),
+                    _ => "null".to_string(),
+                };
+
+                format!(
+                    "{{\"time\":{:.6},\"event\":\"submit\",\"seqnum\":{},\"devid\":{},\"ep\":{},\"direction\":\"{}\",\"type\":\"{}\",\"transfer_flags\":{},\"transfer_buffer_length\":{},\"setup\":{},\"data\":\"{}\"}}",
+                    time,
+                    header.seqnum,
+                    header.devid,
+                    header.ep,
+                    direction_name(header.direction),
+                    type_name(ty),
+                    cmd.transfer_flags.bits(),
+                    cmd.transfer_buffer_length,
+                    setup,
+                    self.encode(&request.data),
+                )
+            }
+            UsbIpRequestCmd::Unlink(ref unlink) => {
+                self.unlinks.insert(header.seqnum, unlink.seqnum);
+
+                format!(
+                    "{{\"time\":{:.6},\"event\":\"unlink\",\"seqnum\":{},\"devid\":{},\"unlink_seqnum\":{}}}",
+                    time, header.seqnum, header.devid, unlink.seqnum,
+                )
+            }
+        };
+
+        self.write_line(line)
+    }
+
+    fn response(&mut self, response: &UsbIpResponse, time: Duration) -> IoResult<()> {
+        let header = &response.header;
+        let time = time.as_secs_f64();
+
+        let line = match response.cmd {
+            UsbIpResponseCmd::Cmd(ref ret) => {
+                // The header of a response does not need to name the endpoint
+                let (ep, direction, ty) = self.urbs.remove(&header.seqnum).unwrap_or((
+                    header.ep,
+                    header.direction,
+                    EndpointType::Bulk,
+                ));
+
+                format!(
+                    "{{\"time\":{:.6},\"event\":\"complete\",\"seqnum\":{},\"devid\":{},\"ep\":{},\"direction\":\"{}\",\"type\":\"{}\",\"status\":{},\"actual_length\":{},\"data\":\"{}\"}}",
+                    time,
+                    header.seqnum,
+                    header.devid,
+                    ep,
+                    direction_name(direction),
+                    type_name(ty),
+                    ret.status,
+                    ret.actual_length,
+                    self.encode(&response.data),
+                )
+            }
+            UsbIpResponseCmd::Unlink(ref ret) => {
+                let unlink_seqnum = self.unlinks.remove(&header.seqnum).unwrap_or(0);
+                if ret.status == -ECONNRESET {
+                    self.urbs.remove(&unlink_seqnum);
+                }
+
+                format!(
+                    "{{\"time\":{:.6},\"event\":\"unlink_complete\",\"seqnum\":{},\"unlink_seqnum\":{},\"status\":{}}}",
+                    time, header.seqnum, unlink_seqnum, ret.status,
+                )
+            }
+        };
+
+        self.write_line(line)
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::IN => "in",
+        _ => "out",
+    }
+}
+
+fn type_name(ty: EndpointType) -> &'static str {
+    match ty {
+        EndpointType::Control => "control",
+        EndpointType::Isochronous { .. } => "isochronous",
+        EndpointType::Bulk => "bulk",
+        EndpointType::Interrupt => "interrupt",
+    }
+}
+
+/// Encodes `data` in base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(data.len() / 3 * 4 + 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+
+    result
+}
+
+fn urb_info(header: &UsbIpHeader, ty: EndpointType) -> UrbInfo {
+    let xfer_type = match ty {
+        EndpointType::Control => XFER_TYPE_CONTROL,
+        EndpointType::Isochronous { .. } => XFER_TYPE_ISOCHRONOUS,
+        EndpointType::Bulk => XFER_TYPE_BULK,
+        EndpointType::Interrupt => XFER_TYPE_INTERRUPT,
+    };
+
+    let direction = match header.direction {
+        Direction::IN => 0x80,
+        _ => 0x00,
+    };
+
+    UrbInfo {
+        xfer_type,
+        epnum: (header.ep as u8 & 0x0f) | direction,
+        devnum: header.devid as u8,
+        busnum: (header.devid >> 16) as u16,
+    }
+}
@@ -0,0 +1,396 @@
+//! A host-side driver for the CDC Abstract Control Model, as used by serial ports.
+
+use crate::{
+    class::{find_endpoint, find_interface},
+    client::{SetupPacket, UsbHost},
+    descriptor::EnumeratedDevice,
+    UsbIpError,
+};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+use usb_device::{endpoint::EndpointType, UsbDirection};
+
+/// The class code of communication interfaces
+pub const USB_CLASS_CDC: u8 = 0x02;
+/// The class code of the data interface of a communication device
+pub const USB_CLASS_CDC_DATA: u8 = 0x0a;
+/// The subclass code of the Abstract Control Model
+pub const CDC_SUBCLASS_ACM: u8 = 0x02;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQ_SEND_BREAK: u8 = 0x23;
+
+/// The descriptor type of class specific interface descriptors
+const CS_INTERFACE: u8 = 0x24;
+/// The descriptor subtype of the union functional descriptor
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const NOTIFICATION_NETWORK_CONNECTION: u8 = 0x00;
+const NOTIFICATION_RESPONSE_AVAILABLE: u8 = 0x01;
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+/// Size of the header of a notification in bytes
+const NOTIFICATION_HEADER_SIZE: usize = 8;
+
+/// The class specific request type of requests to the communication interface
+const TO_INTERFACE: u8 = 0x21;
+const FROM_INTERFACE: u8 = 0xa1;
+
+/// The line coding of a serial port, as exchanged by SET_LINE_CODING and GET_LINE_CODING.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCoding {
+    /// The baud rate in bits per second
+    pub data_rate: u32,
+    /// 0 = 1 stop bit, 1 = 1.5 stop bits, 2 = 2 stop bits
+    pub stop_bits: u8,
+    /// 0 = none, 1 = odd, 2 = even, 3 = mark, 4 = space
+    pub parity: u8,
+    /// The number of data bits: 5, 6, 7, 8 or 16
+    pub data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            data_rate: 115_200,
+            stop_bits: 0,
+            parity: 0,
+            data_bits: 8,
+        }
+    }
+}
+
+impl LineCoding {
+    pub fn to_array(&self) -> [u8; 7] {
+        let mut result = [0; 7];
+
+        result[0..4].copy_from_slice(&self.data_rate.to_le_bytes());
+        result[4] = self.stop_bits;
+        result[5] = self.parity;
+        result[6] = self.data_bits;
+
+        result
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 7 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            data_rate: u32::from_le_bytes(data[0..4].try_into().unwrap()),
+            stop_bits: data[4],
+            parity: data[5],
+            data_bits: data[6],
+        })
+    }
+}
+
+/// Prints the line coding in the usual short form, e.g. `115200 8N1`.
+impl Display for LineCoding {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let parity = match self.parity {
+            0 => 'N',
+            1 => 'O',
+            2 => 'E',
+            3 => 'M',
+            4 => 'S',
+            _ => '?',
+        };
+        let stop_bits = match self.stop_bits {
+            0 => "1",
+            1 => "1.5",
+            2 => "2",
+            _ => "?",
+        };
+        write!(
+            f,
+            "{} {}{}{}",
+            self.data_rate, self.data_bits, parity, stop_bits
+        )
+    }
+}
+
+bitflags::bitflags! {
+   /// The state of the serial port, as sent in SERIAL_STATE notifications
+   pub struct SerialState: u16 {
+      const DCD = 0x0001;
+      const DSR = 0x0002;
+      const BREAK = 0x0004;
+      const RING = 0x0008;
+      const FRAMING = 0x0010;
+      const PARITY = 0x0020;
+      const OVERRUN = 0x0040;
+   }
+}
+
+/// A notification, sent by the device on the interrupt endpoint of the communication interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// NETWORK_CONNECTION with the connection state
+    NetworkConnection(bool),
+    /// RESPONSE_AVAILABLE
+    ResponseAvailable,
+    /// SERIAL_STATE
+    SerialState(SerialState),
+    /// Any other notification, with its code, value and data
+    Other {
+        notification: u8,
+        value: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl Notification {
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < NOTIFICATION_HEADER_SIZE {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        let value = u16::from_le_bytes([data[2], data[3]]);
+        let length = u16::from_le_bytes([data[6], data[7]]) as usize;
+        let payload = &data[NOTIFICATION_HEADER_SIZE..];
+        if payload.len() < length {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+        let payload = &payload[..length];
+
+        Ok(match data[1] {
+            NOTIFICATION_NETWORK_CONNECTION => Self::NetworkConnection(value != 0),
+            NOTIFICATION_RESPONSE_AVAILABLE => Self::ResponseAvailable,
+            NOTIFICATION_SERIAL_STATE if payload.len() >= 2 => {
+                Self::SerialState(SerialState::from_bits_truncate(u16::from_le_bytes([
+                    payload[0], payload[1],
+                ])))
+            }
+            notification => Self::Other {
+                notification,
+                value,
+                data: payload.to_vec(),
+            },
+        })
+    }
+}
+
+/// A host-side driver for a CDC-ACM serial port.
+///
+/// The driver does not own the host, instead it is passed to every call,
+/// such that a [`LoopbackSession`](crate::loopback::LoopbackSession) can be
+/// started and ended around each of them.
+#[derive(Debug, Clone)]
+pub struct CdcAcm {
+    comm_interface: u8,
+    notification_ep: Option<(u8, u16)>,
+    data_in_ep: (u8, u16),
+    data_out_ep: u8,
+    /// Received bytes, that have not been read yet
+    buf: VecDeque<u8>,
+}
+
+impl CdcAcm {
+    /// Sets up the driver for the first CDC-ACM function of an enumerated device.
+    pub fn new(device: &EnumeratedDevice) -> Result<Self, UsbIpError> {
+        let comm = find_interface(device, USB_CLASS_CDC, CDC_SUBCLASS_ACM)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_CDC))?;
+
+        // The union functional descriptor names the data interface of the function
+        let union = comm
+            .extra
+            .iter()
+            .find(|desc| desc.len() >= 5 && desc[1] == CS_INTERFACE && desc[2] == CDC_TYPE_UNION);
+        let data = match union {
+            Some(union) => device.configurations[0]
+                .interfaces
+                .iter()
+                .find(|iface| iface.interface_number == union[4]),
+            None => find_interface(device, USB_CLASS_CDC_DATA, 0),
+        }
+        .ok_or(UsbIpError::ClassNotFound(USB_CLASS_CDC_DATA))?;
+
+        let notification_ep = find_endpoint(comm, EndpointType::Interrupt, UsbDirection::In)
+            .map(|ep| (ep.number(), ep.max_packet_size));
+        let data_in_ep = find_endpoint(data, EndpointType::Bulk, UsbDirection::In)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_CDC_DATA))?;
+        let data_out_ep = find_endpoint(data, EndpointType::Bulk, UsbDirection::Out)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_CDC_DATA))?;
+
+        Ok(Self {
+            comm_interface: comm.interface_number,
+            notification_ep,
+            data_in_ep: (data_in_ep.number(), data_in_ep.max_packet_size),
+            data_out_ep: data_out_ep.number(),
+            buf: VecDeque::new(),
+        })
+    }
+
+    /// Sets the line coding of the serial port.
+    pub fn set_line_coding<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        line_coding: &LineCoding,
+    ) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_SET_LINE_CODING, 0, 7);
+        host.control_transfer(setup, &line_coding.to_array())?;
+        Ok(())
+    }
+
+    /// Reads back the line coding of the serial port.
+    pub fn get_line_coding<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+    ) -> Result<LineCoding, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_GET_LINE_CODING, 0, 7);
+        LineCoding::from_slice(&host.control_transfer(setup, &[])?)
+    }
+
+    /// Sets the DTR and RTS control lines.
+    pub fn set_control_line_state<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        dtr: bool,
+        rts: bool,
+    ) -> Result<(), UsbIpError> {
+        let value = (dtr as u16) | (rts as u16) << 1;
+        let setup = self.request(TO_INTERFACE, REQ_SET_CONTROL_LINE_STATE, value, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    /// Sends a break of `duration_ms` milliseconds, `0xffff` starts an indefinite break.
+    pub fn send_break<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        duration_ms: u16,
+    ) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_SEND_BREAK, duration_ms, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    /// Writes all of `data` to the serial port.
+    pub fn write<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        data: &[u8],
+    ) -> Result<usize, UsbIpError> {
+        host.bulk_out(self.data_out_ep, data)
+    }
+
+    /// Reads the available bytes from the serial port into `buf`.
+    ///
+    /// Fails with [`UsbIpError::Timeout`], if no bytes arrive in time.
+    pub fn read<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbIpError> {
+        if self.buf.is_empty() {
+            self.receive(host)?;
+        }
+
+        let len = usize::min(buf.len(), self.buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.buf.drain(..len)) {
+            *dst = src;
+        }
+
+        Ok(len)
+    }
+
+    /// Reads exactly `len` bytes from the serial port.
+    ///
+    /// Fails with [`UsbIpError::Timeout`], if the bytes do not arrive in time.
+    /// Bytes received so far are kept for the next read.
+    pub fn read_exact<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        len: usize,
+    ) -> Result<Vec<u8>, UsbIpError> {
+        while self.buf.len() < len {
+            self.receive(host)?;
+        }
+
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Waits for the next notification of the device.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if no notification arrived in time or the function has no notification endpoint
+    pub fn poll_notification<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+    ) -> Result<Option<Notification>, UsbIpError> {
+        let (ep, max_packet_size) = match self.notification_ep {
+            Some(ep) => ep,
+            None => return Ok(None),
+        };
+
+        match host.interrupt_in(ep, max_packet_size as usize) {
+            Ok(data) => Notification::from_slice(&data).map(Some),
+            Err(UsbIpError::Timeout) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Receives the next packet from the bulk in endpoint into the buffer.
+    fn receive<H: UsbHost + ?Sized>(&mut self, host: &mut H) -> Result<(), UsbIpError> {
+        let (ep, max_packet_size) = self.data_in_ep;
+        let data = host.bulk_in(ep, max_packet_size as usize)?;
+        self.buf.extend(data);
+        Ok(())
+    }
+
+    fn request(&self, request_type: u8, request: u8, value: u16, length: u16) -> SetupPacket {
+        SetupPacket::new(
+            request_type,
+            request,
+            value,
+            self.comm_interface as u16,
+            length,
+        )
+    }
+}
+
+/// Describes a CDC class request for the debug output.
+pub(crate) fn describe_request(setup: &SetupPacket) -> Option<String> {
+    Some(match setup.request {
+        REQ_SEND_ENCAPSULATED_COMMAND => "SEND_ENCAPSULATED_COMMAND".to_string(),
+        REQ_GET_ENCAPSULATED_RESPONSE => {
+            format!("GET_ENCAPSULATED_RESPONSE(wLength={})", setup.length)
+        }
+        REQ_SET_LINE_CODING => "SET_LINE_CODING".to_string(),
+        REQ_GET_LINE_CODING => format!("GET_LINE_CODING(wLength={})", setup.length),
+        REQ_SET_CONTROL_LINE_STATE => format!(
+            "SET_CONTROL_LINE_STATE(DTR={}, RTS={})",
+            setup.value & 0x01 != 0,
+            setup.value & 0x02 != 0
+        ),
+        REQ_SEND_BREAK if setup.value == 0xffff => "SEND_BREAK(until cleared)".to_string(),
+        REQ_SEND_BREAK => format!("SEND_BREAK({}ms)", setup.value),
+        _ => return None,
+    })
+}
+
+/// Describes the data stage of a CDC class request for the debug output.
+pub(crate) fn describe_control_data(setup: &SetupPacket, data: &[u8]) -> Option<String> {
+    match setup.request {
+        REQ_SET_LINE_CODING | REQ_GET_LINE_CODING => LineCoding::from_slice(data)
+            .ok()
+            .map(|coding| coding.to_string()),
+        _ => None,
+    }
+}
+
+/// Describes a notification of the communication interface for the debug output.
+pub(crate) fn describe_notification(data: &[u8]) -> Option<String> {
+    Notification::from_slice(data)
+        .ok()
+        .map(|notification| format!("{:?}", notification))
+}
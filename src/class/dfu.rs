@@ -0,0 +1,407 @@
+//! A host-side driver for the Device Firmware Upgrade class 1.1.
+//!
+//! A device announces DFU in its runtime configuration with a DFU interface using
+//! [`DFU_PROTOCOL_RUNTIME`]. After DFU_DETACH and a reset, it enumerates again in
+//! DFU mode using [`DFU_PROTOCOL_DFU_MODE`], in which the firmware can be
+//! downloaded and uploaded. Another reset returns the device to its runtime mode.
+
+use crate::{
+    class::find_interface,
+    client::{SetupPacket, UsbHost},
+    descriptor::EnumeratedDevice,
+    UsbIpError,
+};
+use std::time::{Duration, Instant};
+
+/// The class code of application specific interfaces
+pub const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xfe;
+/// The subclass code of the Device Firmware Upgrade
+pub const DFU_SUBCLASS: u8 = 0x01;
+/// The protocol code of a DFU interface in runtime mode
+pub const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+/// The protocol code of a DFU interface in DFU mode
+pub const DFU_PROTOCOL_DFU_MODE: u8 = 0x02;
+
+/// The descriptor type of the DFU functional descriptor
+const DESCRIPTOR_TYPE_DFU_FUNCTIONAL: u8 = 0x21;
+
+const REQ_DETACH: u8 = 0x00;
+const REQ_DNLOAD: u8 = 0x01;
+const REQ_UPLOAD: u8 = 0x02;
+const REQ_GETSTATUS: u8 = 0x03;
+const REQ_CLRSTATUS: u8 = 0x04;
+const REQ_GETSTATE: u8 = 0x05;
+const REQ_ABORT: u8 = 0x06;
+
+const TO_INTERFACE: u8 = 0x21;
+const FROM_INTERFACE: u8 = 0xa1;
+
+/// The status code, that signals no error
+const STATUS_OK: u8 = 0x00;
+
+bitflags::bitflags! {
+   /// The capabilities of a DFU interface, as announced in its functional descriptor
+   pub struct DfuAttributes: u8 {
+      const CAN_DNLOAD = 0x01;
+      const CAN_UPLOAD = 0x02;
+      const MANIFESTATION_TOLERANT = 0x04;
+      const WILL_DETACH = 0x08;
+   }
+}
+
+/// The DFU functional descriptor of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionalDescriptor {
+    pub attributes: DfuAttributes,
+    /// The time in milliseconds, the device waits for a reset after DFU_DETACH
+    pub detach_timeout: u16,
+    /// The maximum number of bytes of a single DFU_DNLOAD or DFU_UPLOAD
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+}
+
+impl FunctionalDescriptor {
+    /// Size of the functional descriptor in bytes
+    pub const SIZE: usize = 9;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < Self::SIZE || data[1] != DESCRIPTOR_TYPE_DFU_FUNCTIONAL {
+            return Err(UsbIpError::InvalidDescriptor(
+                DESCRIPTOR_TYPE_DFU_FUNCTIONAL,
+            ));
+        }
+
+        Ok(Self {
+            attributes: DfuAttributes::from_bits_truncate(data[2]),
+            detach_timeout: u16::from_le_bytes([data[3], data[4]]),
+            transfer_size: u16::from_le_bytes([data[5], data[6]]),
+            dfu_version: u16::from_le_bytes([data[7], data[8]]),
+        })
+    }
+}
+
+/// The state of a DFU interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuState {
+    AppIdle,
+    AppDetach,
+    DfuIdle,
+    DnloadSync,
+    DnBusy,
+    DnloadIdle,
+    ManifestSync,
+    Manifest,
+    ManifestWaitReset,
+    UploadIdle,
+    Error,
+    /// A state, that is not defined by the specification
+    Other(u8),
+}
+
+impl DfuState {
+    pub fn from_u8(state: u8) -> Self {
+        match state {
+            0 => Self::AppIdle,
+            1 => Self::AppDetach,
+            2 => Self::DfuIdle,
+            3 => Self::DnloadSync,
+            4 => Self::DnBusy,
+            5 => Self::DnloadIdle,
+            6 => Self::ManifestSync,
+            7 => Self::Manifest,
+            8 => Self::ManifestWaitReset,
+            9 => Self::UploadIdle,
+            10 => Self::Error,
+            state => Self::Other(state),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::AppIdle => 0,
+            Self::AppDetach => 1,
+            Self::DfuIdle => 2,
+            Self::DnloadSync => 3,
+            Self::DnBusy => 4,
+            Self::DnloadIdle => 5,
+            Self::ManifestSync => 6,
+            Self::Manifest => 7,
+            Self::ManifestWaitReset => 8,
+            Self::UploadIdle => 9,
+            Self::Error => 10,
+            Self::Other(state) => state,
+        }
+    }
+}
+
+/// The answer to DFU_GETSTATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuStatus {
+    /// The status code of the last request, `0` if it succeeded
+    pub status: u8,
+    /// The time in milliseconds, the host should wait before the next DFU_GETSTATUS
+    pub poll_timeout: u32,
+    pub state: DfuState,
+    /// The index of a string descriptor, that describes the status
+    pub string_index: u8,
+}
+
+impl DfuStatus {
+    /// Size of the status in bytes
+    pub const SIZE: usize = 6;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < Self::SIZE {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            status: data[0],
+            poll_timeout: u32::from_le_bytes([data[1], data[2], data[3], 0]),
+            state: DfuState::from_u8(data[4]),
+            string_index: data[5],
+        })
+    }
+
+    /// Turns the status into [`UsbIpError::DfuFailed`], if it does not signal success.
+    fn into_result(self) -> Result<Self, UsbIpError> {
+        if self.status != STATUS_OK || self.state == DfuState::Error {
+            return Err(UsbIpError::DfuFailed(self.state.to_u8(), self.status));
+        }
+
+        Ok(self)
+    }
+}
+
+/// A driver for a DFU interface, either in runtime or in DFU mode.
+#[derive(Debug)]
+pub struct Dfu {
+    interface: u8,
+    protocol: u8,
+    functional: FunctionalDescriptor,
+    /// How long the device may stay busy with a single block or the manifestation
+    busy_timeout: Duration,
+}
+
+impl Dfu {
+    /// Sets up the driver for the first DFU interface of an enumerated device.
+    pub fn new(device: &EnumeratedDevice) -> Result<Self, UsbIpError> {
+        let iface = find_interface(device, USB_CLASS_APPLICATION_SPECIFIC, DFU_SUBCLASS)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_APPLICATION_SPECIFIC))?;
+
+        let functional = iface
+            .extra
+            .iter()
+            .find(|desc| desc.len() >= 2 && desc[1] == DESCRIPTOR_TYPE_DFU_FUNCTIONAL)
+            .ok_or(UsbIpError::InvalidDescriptor(
+                DESCRIPTOR_TYPE_DFU_FUNCTIONAL,
+            ))?;
+
+        Ok(Self {
+            interface: iface.interface_number,
+            protocol: iface.interface_protocol,
+            functional: FunctionalDescriptor::from_slice(functional)?,
+            busy_timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// Sets how long the device may stay busy with a single block or the manifestation,
+    /// before the transfer fails with [`UsbIpError::Timeout`].
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.busy_timeout = timeout;
+    }
+
+    /// Returns `true`, if the device is in DFU mode, rather than in runtime mode.
+    pub fn is_dfu_mode(&self) -> bool {
+        self.protocol == DFU_PROTOCOL_DFU_MODE
+    }
+
+    pub fn functional_descriptor(&self) -> &FunctionalDescriptor {
+        &self.functional
+    }
+
+    /// Requests the device in runtime mode to detach, such that it enters DFU mode on the next reset.
+    pub fn detach<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        timeout_ms: u16,
+    ) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_DETACH, timeout_ms, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    /// Switches a device from runtime mode into DFU mode.
+    ///
+    /// The device is detached, reset unless it detaches on its own, and enumerated again.
+    ///
+    /// # Returns
+    /// The enumerated device in DFU mode, to which a new [`Dfu`] driver can be bound.
+    pub fn enter_dfu_mode<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+    ) -> Result<EnumeratedDevice, UsbIpError> {
+        self.detach(host, self.functional.detach_timeout)?;
+        if !self
+            .functional
+            .attributes
+            .contains(DfuAttributes::WILL_DETACH)
+        {
+            host.reset_device()?;
+        }
+
+        host.enumerate()
+    }
+
+    /// Sends a single block of firmware.
+    pub fn dnload<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        block: u16,
+        data: &[u8],
+    ) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_DNLOAD, block, data.len() as u16);
+        host.control_transfer(setup, data)?;
+        Ok(())
+    }
+
+    /// Receives a single block of firmware of up to `length` bytes.
+    pub fn upload<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        block: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_UPLOAD, block, length);
+        host.control_transfer(setup, &[])
+    }
+
+    pub fn get_status<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<DfuStatus, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_GETSTATUS, 0, DfuStatus::SIZE as u16);
+        DfuStatus::from_slice(&host.control_transfer(setup, &[])?)
+    }
+
+    /// Leaves the error state.
+    pub fn clear_status<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_CLRSTATUS, 0, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    pub fn get_state<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<DfuState, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_GETSTATE, 0, 1);
+        let data = host.control_transfer(setup, &[])?;
+        match data.first() {
+            Some(&state) => Ok(DfuState::from_u8(state)),
+            None => Err(UsbIpError::PkgTooShort(0)),
+        }
+    }
+
+    /// Aborts a download or upload and returns to the idle state.
+    pub fn abort<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_ABORT, 0, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    /// Downloads a complete firmware image in blocks of the transfer size
+    /// and waits for the manifestation.
+    ///
+    /// # Returns
+    /// - `true` if the device waits for a reset to leave DFU mode
+    /// - `false` if it is manifestation tolerant and returned to the idle state
+    pub fn download<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        firmware: &[u8],
+    ) -> Result<bool, UsbIpError> {
+        let transfer_size = self.functional.transfer_size.max(1) as usize;
+
+        let mut block: u16 = 0;
+        for chunk in firmware.chunks(transfer_size) {
+            self.dnload(host, block, chunk)?;
+            let status = self.wait_while(host, &[DfuState::DnloadSync, DfuState::DnBusy])?;
+            if status.state != DfuState::DnloadIdle {
+                return Err(UsbIpError::DfuFailed(status.state.to_u8(), status.status));
+            }
+
+            block = block.wrapping_add(1);
+        }
+
+        self.manifest(host, block)
+    }
+
+    /// Ends a download with a zero length DFU_DNLOAD, which starts the manifestation
+    /// of the firmware, and waits for it to finish.
+    ///
+    /// # Returns
+    /// - `true` if the device waits for a reset to leave DFU mode
+    /// - `false` if it is manifestation tolerant and returned to the idle state
+    pub fn manifest<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        block: u16,
+    ) -> Result<bool, UsbIpError> {
+        self.dnload(host, block, &[])?;
+
+        let status = self.wait_while(host, &[DfuState::ManifestSync, DfuState::Manifest])?;
+        match status.state {
+            DfuState::ManifestWaitReset => Ok(true),
+            DfuState::DfuIdle => Ok(false),
+            state => Err(UsbIpError::DfuFailed(state.to_u8(), status.status)),
+        }
+    }
+
+    /// Uploads the complete firmware image, until the device sends a short block.
+    pub fn upload_all<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<Vec<u8>, UsbIpError> {
+        let transfer_size = self.functional.transfer_size.max(1);
+
+        let mut firmware = vec![];
+        let mut block: u16 = 0;
+        loop {
+            let data = self.upload(host, block, transfer_size)?;
+            let done = data.len() < transfer_size as usize;
+            firmware.extend(data);
+
+            if done {
+                return Ok(firmware);
+            }
+
+            block = block.wrapping_add(1);
+        }
+    }
+
+    /// Polls the status, while the device is in one of the `busy` states.
+    ///
+    /// Fails with [`UsbIpError::DfuFailed`], if the device reports an error,
+    /// and with [`UsbIpError::Timeout`], if it is still busy after the busy timeout.
+    fn wait_while<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        busy: &[DfuState],
+    ) -> Result<DfuStatus, UsbIpError> {
+        let deadline = Instant::now() + self.busy_timeout;
+
+        loop {
+            let status = self.get_status(host)?.into_result()?;
+            if !busy.contains(&status.state) {
+                return Ok(status);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(UsbIpError::Timeout);
+            }
+
+            // The poll timeout may be up to four hours long
+            let poll_timeout = Duration::from_millis(status.poll_timeout as u64);
+            if !poll_timeout.is_zero() {
+                std::thread::sleep(poll_timeout.min(deadline - now));
+            }
+        }
+    }
+
+    fn request(&self, request_type: u8, request: u8, value: u16, length: u16) -> SetupPacket {
+        SetupPacket::new(request_type, request, value, self.interface as u16, length)
+    }
+}
@@ -0,0 +1,544 @@
+//! A host-side driver for human interface devices, including a report descriptor parser.
+
+use crate::{
+    class::{find_endpoint, find_interface},
+    client::{SetupPacket, UsbHost},
+    descriptor::EnumeratedDevice,
+    UsbIpError,
+};
+use usb_device::{control::Request, endpoint::EndpointType, UsbDirection};
+
+/// The class code of human interface devices
+pub const USB_CLASS_HID: u8 = 0x03;
+
+/// The descriptor type of the HID descriptor
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+/// The descriptor type of the report descriptor
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+const REQ_GET_REPORT: u8 = 0x01;
+const REQ_GET_IDLE: u8 = 0x02;
+const REQ_GET_PROTOCOL: u8 = 0x03;
+const REQ_SET_REPORT: u8 = 0x09;
+const REQ_SET_IDLE: u8 = 0x0a;
+const REQ_SET_PROTOCOL: u8 = 0x0b;
+
+/// The class specific request type of requests to the interface
+const TO_INTERFACE: u8 = 0x21;
+const FROM_INTERFACE: u8 = 0xa1;
+/// The request type of standard requests to the interface
+const FROM_INTERFACE_STANDARD: u8 = 0x81;
+
+/// The type of a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+/// A data field of a report, as described by a main item of the report descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportField {
+    pub report_type: ReportType,
+    /// The report id, `0` if the descriptor does not use report ids
+    pub report_id: u8,
+    /// The offset of the field in bits, not counting the report id
+    pub bit_offset: usize,
+    /// The size of a single element in bits
+    pub report_size: usize,
+    /// The number of elements
+    pub report_count: usize,
+    /// The flags of the main item, e.g. constant, variable and relative
+    pub flags: u32,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    /// The usages of the elements, including the usage page in the upper 16 bits.
+    ///
+    /// For variable fields, there is one usage per element, for array fields,
+    /// the element values index into the usages.
+    pub usages: Vec<u32>,
+}
+
+impl ReportField {
+    /// Returns `true` for padding, that carries no data.
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Returns `true` if every element is a value for a usage, instead of an array index.
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+
+    /// Reads element `index` of this field from a report without the report id.
+    fn element(&self, data: &[u8], index: usize) -> Option<i32> {
+        let offset = self.bit_offset + index * self.report_size;
+        if self.report_size == 0
+            || self.report_size > 32
+            || offset + self.report_size > data.len() * 8
+        {
+            return None;
+        }
+
+        let mut value: u32 = 0;
+        for bit in 0..self.report_size {
+            let pos = offset + bit;
+            if data[pos / 8] & (1 << (pos % 8)) != 0 {
+                value |= 1 << bit;
+            }
+        }
+
+        // Sign extend, if the field can hold negative values
+        if self.logical_minimum < 0
+            && self.report_size < 32
+            && value & (1 << (self.report_size - 1)) != 0
+        {
+            value |= !0 << self.report_size;
+        }
+
+        Some(value as i32)
+    }
+}
+
+/// The global items of the report descriptor parser state.
+#[derive(Debug, Clone, Copy, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    report_size: usize,
+    report_count: usize,
+    report_id: u8,
+}
+
+/// A parsed report descriptor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportDescriptor {
+    pub fields: Vec<ReportField>,
+    /// Whether the reports are prefixed with a report id
+    pub uses_report_ids: bool,
+}
+
+impl ReportDescriptor {
+    /// Parses the short items of a report descriptor.
+    pub fn parse(data: &[u8]) -> Result<Self, UsbIpError> {
+        let mut descriptor = Self::default();
+        let mut global = GlobalState::default();
+        let mut stack = vec![];
+        let mut usages: Vec<u32> = vec![];
+        let mut usage_minimum: Option<u32> = None;
+        let mut offsets: Vec<(ReportType, u8, usize)> = vec![];
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let prefix = data[pos];
+
+            // Long items only carry vendor data
+            if prefix == 0xfe {
+                let size = *data.get(pos + 1).ok_or(invalid())? as usize;
+                pos += 3 + size;
+                continue;
+            }
+
+            let size = match prefix & 0x03 {
+                3 => 4,
+                size => size as usize,
+            };
+            let item = data.get(pos + 1..pos + 1 + size).ok_or(invalid())?;
+            pos += 1 + size;
+
+            let mut bytes = [0; 4];
+            bytes[..size].copy_from_slice(item);
+            let unsigned = u32::from_le_bytes(bytes);
+            let signed = match size {
+                1 => item[0] as i8 as i32,
+                2 => i16::from_le_bytes([item[0], item[1]]) as i32,
+                _ => unsigned as i32,
+            };
+
+            // Usages without a page use the current global usage page
+            let usage = |global: &GlobalState| match size {
+                4 => unsigned,
+                _ => (global.usage_page as u32) << 16 | unsigned,
+            };
+
+            match prefix & 0xfc {
+                // Main items
+                0x80 | 0x90 | 0xb0 => {
+                    let report_type = match prefix & 0xfc {
+                        0x80 => ReportType::Input,
+                        0x90 => ReportType::Output,
+                        _ => ReportType::Feature,
+                    };
+
+                    let key = (report_type, global.report_id);
+                    let bit_offset = match offsets.iter_mut().find(|(ty, id, _)| (*ty, *id) == key)
+                    {
+                        Some((_, _, offset)) => offset,
+                        None => {
+                            offsets.push((report_type, global.report_id, 0));
+                            &mut offsets.last_mut().unwrap().2
+                        }
+                    };
+
+                    descriptor.fields.push(ReportField {
+                        report_type,
+                        report_id: global.report_id,
+                        bit_offset: *bit_offset,
+                        report_size: global.report_size,
+                        report_count: global.report_count,
+                        flags: unsigned,
+                        logical_minimum: global.logical_minimum,
+                        logical_maximum: global.logical_maximum,
+                        usages: std::mem::take(&mut usages),
+                    });
+                    *bit_offset = global
+                        .report_size
+                        .checked_mul(global.report_count)
+                        .and_then(|bits| bit_offset.checked_add(bits))
+                        .ok_or(invalid())?;
+                    usage_minimum = None;
+                }
+                // Collection and End Collection
+                0xa0 | 0xc0 => {
+                    usages.clear();
+                    usage_minimum = None;
+                }
+                // Global items
+                0x04 => global.usage_page = unsigned as u16,
+                0x14 => global.logical_minimum = signed,
+                0x24 => {
+                    // The maximum is only signed, if the minimum is
+                    global.logical_maximum = if global.logical_minimum < 0 {
+                        signed
+                    } else {
+                        unsigned as i32
+                    }
+                }
+                0x74 => global.report_size = unsigned as usize,
+                0x84 => {
+                    global.report_id = unsigned as u8;
+                    descriptor.uses_report_ids = true;
+                }
+                0x94 => global.report_count = unsigned as usize,
+                0xa4 => stack.push(global),
+                0xb4 => global = stack.pop().ok_or(invalid())?,
+                // Local items
+                0x08 => usages.push(usage(&global)),
+                0x18 => usage_minimum = Some(usage(&global)),
+                0x28 => {
+                    let minimum = usage_minimum.take().ok_or(invalid())?;
+                    let maximum = usage(&global);
+                    if maximum < minimum || maximum - minimum > 0xffff {
+                        return Err(invalid());
+                    }
+                    usages.extend(minimum..=maximum);
+                }
+                // Physical extents, units, designators and strings are not needed to decode reports
+                _ => (),
+            }
+        }
+
+        Ok(descriptor)
+    }
+
+    /// Decodes a report into the values of its usages.
+    ///
+    /// `data` starts with the report id, if the descriptor uses report ids.
+    pub fn decode(&self, report_type: ReportType, data: &[u8]) -> Result<Report, UsbIpError> {
+        let (report_id, payload) = match self.uses_report_ids {
+            true => match data.split_first() {
+                Some((&id, payload)) => (id, payload),
+                None => return Err(UsbIpError::PkgTooShort(0)),
+            },
+            false => (0, data),
+        };
+
+        let mut values = vec![];
+        let fields = self
+            .fields
+            .iter()
+            .filter(|field| field.report_type == report_type && field.report_id == report_id)
+            .filter(|field| !field.is_constant());
+        for field in fields {
+            for index in 0..field.report_count {
+                let value = field
+                    .element(payload, index)
+                    .ok_or(UsbIpError::PkgTooShort(data.len()))?;
+
+                if field.is_variable() {
+                    // Elements without own usage share the last one
+                    let usage = field.usages.get(index).or_else(|| field.usages.last());
+                    if let Some(&usage) = usage {
+                        values.push((usage, value));
+                    }
+                } else {
+                    // Array elements select a usage, values outside of the range select none
+                    let selected = value
+                        .checked_sub(field.logical_minimum)
+                        .filter(|_| {
+                            value >= field.logical_minimum && value <= field.logical_maximum
+                        })
+                        .and_then(|index| field.usages.get(index as usize));
+                    if let Some(&usage) = selected {
+                        values.push((usage, 1));
+                    }
+                }
+            }
+        }
+
+        Ok(Report {
+            report_id,
+            data: data.to_vec(),
+            values,
+        })
+    }
+}
+
+/// A decoded report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub report_id: u8,
+    /// The raw report, including the report id
+    pub data: Vec<u8>,
+    /// The values of all usages in the report, with the usage page in the upper 16 bits
+    pub values: Vec<(u32, i32)>,
+}
+
+impl Report {
+    /// Returns the value of a usage, or `None` if the report does not contain it.
+    pub fn get(&self, usage_page: u16, usage: u16) -> Option<i32> {
+        let usage = (usage_page as u32) << 16 | usage as u32;
+        self.values
+            .iter()
+            .find(|(other, _)| *other == usage)
+            .map(|(_, value)| *value)
+    }
+}
+
+fn invalid() -> UsbIpError {
+    UsbIpError::InvalidDescriptor(DESCRIPTOR_TYPE_REPORT)
+}
+
+/// A host-side driver for a HID interface.
+///
+/// Like [`CdcAcm`](crate::class::cdc::CdcAcm), the driver does not own the host,
+/// instead it is passed to every call.
+#[derive(Debug, Clone)]
+pub struct Hid {
+    interface: u8,
+    in_ep: (u8, u16),
+    out_ep: Option<u8>,
+    report_descriptor: ReportDescriptor,
+}
+
+impl Hid {
+    /// Sets up the driver for the first HID interface of an enumerated device
+    /// and fetches its report descriptor.
+    pub fn new<H: UsbHost + ?Sized>(
+        host: &mut H,
+        device: &EnumeratedDevice,
+    ) -> Result<Self, UsbIpError> {
+        let iface = find_interface(device, USB_CLASS_HID, 0)
+            .or_else(|| find_interface(device, USB_CLASS_HID, 1))
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_HID))?;
+
+        let in_ep = find_endpoint(iface, EndpointType::Interrupt, UsbDirection::In)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_HID))?;
+        let out_ep = find_endpoint(iface, EndpointType::Interrupt, UsbDirection::Out);
+
+        // The HID descriptor announces the length of the report descriptor
+        let hid_descriptor = iface
+            .extra
+            .iter()
+            .find(|desc| desc.len() >= 9 && desc[1] == DESCRIPTOR_TYPE_HID)
+            .ok_or(UsbIpError::InvalidDescriptor(DESCRIPTOR_TYPE_HID))?;
+        let length = u16::from_le_bytes([hid_descriptor[7], hid_descriptor[8]]);
+
+        let setup = SetupPacket::new(
+            FROM_INTERFACE_STANDARD,
+            Request::GET_DESCRIPTOR,
+            (DESCRIPTOR_TYPE_REPORT as u16) << 8,
+            iface.interface_number as u16,
+            length,
+        );
+        let report_descriptor = ReportDescriptor::parse(&host.control_transfer(setup, &[])?)?;
+        log::debug!("parsed {:?}", report_descriptor);
+
+        Ok(Self {
+            interface: iface.interface_number,
+            in_ep: (in_ep.number(), in_ep.max_packet_size),
+            out_ep: out_ep.map(|ep| ep.number()),
+            report_descriptor,
+        })
+    }
+
+    pub fn report_descriptor(&self) -> &ReportDescriptor {
+        &self.report_descriptor
+    }
+
+    /// Waits for the next input report on the interrupt endpoint.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if no report arrived in time
+    pub fn poll_report<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+    ) -> Result<Option<Report>, UsbIpError> {
+        let (ep, max_packet_size) = self.in_ep;
+        match host.interrupt_in(ep, max_packet_size as usize) {
+            Ok(data) => self
+                .report_descriptor
+                .decode(ReportType::Input, &data)
+                .map(Some),
+            Err(UsbIpError::Timeout) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Sends an output report over the interrupt out endpoint,
+    /// or with SET_REPORT if the interface has none.
+    ///
+    /// `data` starts with the report id, if the descriptor uses report ids.
+    pub fn send_output_report<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        data: &[u8],
+    ) -> Result<(), UsbIpError> {
+        match self.out_ep {
+            Some(ep) => host.interrupt_out(ep, data).map(|_| ()),
+            None => {
+                let report_id = match self.report_descriptor.uses_report_ids {
+                    true => data.first().copied().unwrap_or(0),
+                    false => 0,
+                };
+                self.set_report(host, ReportType::Output, report_id, data)
+            }
+        }
+    }
+
+    /// Sends a feature report with SET_REPORT.
+    pub fn send_feature_report<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<(), UsbIpError> {
+        self.set_report(host, ReportType::Feature, report_id, data)
+    }
+
+    /// Issues a GET_REPORT request and decodes the returned report.
+    pub fn get_report<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        report_type: ReportType,
+        report_id: u8,
+        length: u16,
+    ) -> Result<Report, UsbIpError> {
+        let value = (report_type as u16) << 8 | report_id as u16;
+        let setup = self.request(FROM_INTERFACE, REQ_GET_REPORT, value, length);
+        let data = host.control_transfer(setup, &[])?;
+        self.report_descriptor.decode(report_type, &data)
+    }
+
+    /// Issues a SET_REPORT request.
+    pub fn set_report<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        report_type: ReportType,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<(), UsbIpError> {
+        let value = (report_type as u16) << 8 | report_id as u16;
+        let setup = self.request(TO_INTERFACE, REQ_SET_REPORT, value, data.len() as u16);
+        host.control_transfer(setup, data)?;
+        Ok(())
+    }
+
+    /// Sets the idle rate of a report in units of 4 ms, `0` only reports changes.
+    pub fn set_idle<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        duration: u8,
+        report_id: u8,
+    ) -> Result<(), UsbIpError> {
+        let value = (duration as u16) << 8 | report_id as u16;
+        let setup = self.request(TO_INTERFACE, REQ_SET_IDLE, value, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    /// Reads the idle rate of a report in units of 4 ms.
+    pub fn get_idle<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        report_id: u8,
+    ) -> Result<u8, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_GET_IDLE, report_id as u16, 1);
+        let data = host.control_transfer(setup, &[])?;
+        data.first().copied().ok_or(UsbIpError::PkgTooShort(0))
+    }
+
+    /// Selects the boot protocol with `0` or the report protocol with `1`.
+    pub fn set_protocol<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        protocol: u8,
+    ) -> Result<(), UsbIpError> {
+        let setup = self.request(TO_INTERFACE, REQ_SET_PROTOCOL, protocol as u16, 0);
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    /// Reads the protocol, `0` for the boot protocol and `1` for the report protocol.
+    pub fn get_protocol<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<u8, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_GET_PROTOCOL, 0, 1);
+        let data = host.control_transfer(setup, &[])?;
+        data.first().copied().ok_or(UsbIpError::PkgTooShort(0))
+    }
+
+    fn request(&self, request_type: u8, request: u8, value: u16, length: u16) -> SetupPacket {
+        SetupPacket::new(request_type, request, value, self.interface as u16, length)
+    }
+}
+
+/// Describes a HID class request for the debug output.
+pub(crate) fn describe_request(setup: &SetupPacket) -> Option<String> {
+    let report = |value: u16| {
+        let report_type = match value >> 8 {
+            1 => "INPUT",
+            2 => "OUTPUT",
+            3 => "FEATURE",
+            _ => "RESERVED",
+        };
+        format!("{}, id={}", report_type, value & 0xff)
+    };
+
+    Some(match setup.request {
+        REQ_GET_REPORT => format!(
+            "GET_REPORT({}, wLength={})",
+            report(setup.value),
+            setup.length
+        ),
+        REQ_SET_REPORT => format!("SET_REPORT({})", report(setup.value)),
+        REQ_GET_IDLE => format!("GET_IDLE(id={})", setup.value & 0xff),
+        // A duration of 0 only reports on changes
+        REQ_SET_IDLE if setup.value >> 8 == 0 => {
+            format!("SET_IDLE(indefinite, id={})", setup.value & 0xff)
+        }
+        REQ_SET_IDLE => format!(
+            "SET_IDLE({}ms, id={})",
+            (setup.value >> 8) * 4,
+            setup.value & 0xff
+        ),
+        REQ_GET_PROTOCOL => "GET_PROTOCOL".to_string(),
+        REQ_SET_PROTOCOL if setup.value == 0 => "SET_PROTOCOL(BOOT)".to_string(),
+        REQ_SET_PROTOCOL => "SET_PROTOCOL(REPORT)".to_string(),
+        _ => return None,
+    })
+}
@@ -0,0 +1,39 @@
+//! Host-side drivers for common USB device classes.
+//!
+//! The drivers run on top of any [`UsbHost`](crate::client::UsbHost) and are set up
+//! from the descriptors of an [`EnumeratedDevice`], such that firmware built on
+//! the usual usb-device class crates can be tested from `cargo test`.
+
+pub mod cdc;
+pub mod dfu;
+pub mod hid;
+pub mod msc;
+
+use crate::descriptor::{EndpointDescriptor, EnumeratedDevice, InterfaceDescriptor};
+use usb_device::{endpoint::EndpointType, UsbDirection};
+
+/// Returns the first interface of the active configuration with the given class and subclass.
+pub(crate) fn find_interface(
+    device: &EnumeratedDevice,
+    class: u8,
+    subclass: u8,
+) -> Option<&InterfaceDescriptor> {
+    device
+        .configurations
+        .first()?
+        .interfaces
+        .iter()
+        .find(|iface| iface.interface_class == class && iface.interface_subclass == subclass)
+}
+
+/// Returns the first endpoint of an interface with the given transfer type and direction.
+pub(crate) fn find_endpoint(
+    iface: &InterfaceDescriptor,
+    ty: EndpointType,
+    direction: UsbDirection,
+) -> Option<&EndpointDescriptor> {
+    iface
+        .endpoints
+        .iter()
+        .find(|ep| ep.transfer_type() == ty && ep.direction() == direction)
+}
@@ -0,0 +1,541 @@
+//! A host-side driver for mass storage devices using the Bulk-Only Transport and SCSI commands.
+
+use crate::{
+    class::{find_endpoint, find_interface},
+    client::{SetupPacket, UsbHost},
+    descriptor::EnumeratedDevice,
+    response::EPIPE,
+    UsbIpError,
+};
+use std::convert::TryInto;
+use usb_device::{control::Request, endpoint::EndpointType, UsbDirection};
+
+/// The class code of mass storage interfaces
+pub const USB_CLASS_MSC: u8 = 0x08;
+/// The subclass code of the SCSI transparent command set
+pub const MSC_SUBCLASS_SCSI: u8 = 0x06;
+/// The protocol code of the Bulk-Only Transport
+pub const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const REQ_BULK_ONLY_RESET: u8 = 0xff;
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+
+const TO_INTERFACE: u8 = 0x21;
+const FROM_INTERFACE: u8 = 0xa1;
+/// The request type of standard requests to an endpoint
+const TO_ENDPOINT_STANDARD: u8 = 0x02;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+/// Size of a command block wrapper in bytes
+const CBW_SIZE: usize = 31;
+/// Size of a command status wrapper in bytes
+const CSW_SIZE: usize = 13;
+
+const CSW_STATUS_PASSED: u8 = 0x00;
+const CSW_STATUS_FAILED: u8 = 0x01;
+const CSW_STATUS_PHASE_ERROR: u8 = 0x02;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+
+/// Size of the standard inquiry data, that is requested
+const INQUIRY_LENGTH: u8 = 36;
+/// Size of the fixed format sense data, that is requested
+const SENSE_LENGTH: u8 = 18;
+
+/// The data phase of a Bulk-Only command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataPhase<'a> {
+    /// The command transfers no data
+    None,
+    /// The command receives up to the contained number of bytes from the device
+    In(usize),
+    /// The command sends the contained bytes to the device
+    Out(&'a [u8]),
+}
+
+/// The outcome of a Bulk-Only command, as reported by the command status wrapper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandStatus {
+    /// `false` if the device reported the command as failed
+    pub passed: bool,
+    /// The difference between the announced and the processed amount of data
+    pub residue: u32,
+    /// The data received in the data phase
+    pub data: Vec<u8>,
+}
+
+/// The sense data, that describes why the last command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sense {
+    pub key: u8,
+    /// The additional sense code
+    pub asc: u8,
+    /// The additional sense code qualifier
+    pub ascq: u8,
+}
+
+impl Sense {
+    /// Parses fixed format sense data.
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 14 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            key: data[2] & 0x0f,
+            asc: data[12],
+            ascq: data[13],
+        })
+    }
+}
+
+/// The standard inquiry data of a logical unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inquiry {
+    pub peripheral_device_type: u8,
+    pub removable: bool,
+    pub vendor: String,
+    pub product: String,
+    pub revision: String,
+}
+
+impl Inquiry {
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < INQUIRY_LENGTH as usize {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        let text = |range: std::ops::Range<usize>| {
+            String::from_utf8_lossy(&data[range]).trim_end().to_string()
+        };
+
+        Ok(Self {
+            peripheral_device_type: data[0] & 0x1f,
+            removable: data[1] & 0x80 != 0,
+            vendor: text(8..16),
+            product: text(16..32),
+            revision: text(32..36),
+        })
+    }
+}
+
+/// The capacity of a logical unit, as reported by READ CAPACITY(10).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    /// The address of the last block
+    pub last_lba: u32,
+    /// The size of a block in bytes
+    pub block_size: u32,
+}
+
+impl Capacity {
+    /// Returns the number of blocks of the logical unit.
+    pub fn block_count(&self) -> u64 {
+        self.last_lba as u64 + 1
+    }
+}
+
+/// A driver for a mass storage interface, that uses the Bulk-Only Transport.
+///
+/// Failed transfers are recovered from as the specification requires:
+/// A stalled bulk endpoint gets cleared by CLEAR_FEATURE(HALT),
+/// an invalid status wrapper or a phase error triggers the reset recovery.
+#[derive(Debug)]
+pub struct MassStorage {
+    interface: u8,
+    bulk_in: (u8, u16),
+    bulk_out: u8,
+    next_tag: u32,
+}
+
+impl MassStorage {
+    /// Sets up the driver for the first Bulk-Only SCSI interface of an enumerated device.
+    pub fn new(device: &EnumeratedDevice) -> Result<Self, UsbIpError> {
+        let iface = find_interface(device, USB_CLASS_MSC, MSC_SUBCLASS_SCSI)
+            .filter(|iface| iface.interface_protocol == MSC_PROTOCOL_BOT)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_MSC))?;
+
+        let bulk_in = find_endpoint(iface, EndpointType::Bulk, UsbDirection::In)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_MSC))?;
+        let bulk_out = find_endpoint(iface, EndpointType::Bulk, UsbDirection::Out)
+            .ok_or(UsbIpError::ClassNotFound(USB_CLASS_MSC))?;
+
+        Ok(Self {
+            interface: iface.interface_number,
+            bulk_in: (bulk_in.address, bulk_in.max_packet_size),
+            bulk_out: bulk_out.address,
+            next_tag: 1,
+        })
+    }
+
+    /// Returns the highest logical unit number of the device.
+    ///
+    /// Devices, that stall the request, have a single logical unit.
+    pub fn get_max_lun<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<u8, UsbIpError> {
+        let setup = self.request(FROM_INTERFACE, REQ_GET_MAX_LUN, 1);
+        match host.control_transfer(setup, &[]) {
+            Ok(data) => Ok(data.first().copied().unwrap_or(0)),
+            Err(UsbIpError::UrbFailed(status)) if status == -EPIPE => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Performs the reset recovery: Bulk-Only Mass Storage Reset,
+    /// followed by clearing the halt of both bulk endpoints.
+    pub fn reset_recovery<H: UsbHost + ?Sized>(&self, host: &mut H) -> Result<(), UsbIpError> {
+        log::info!("mass storage reset recovery");
+        let setup = self.request(TO_INTERFACE, REQ_BULK_ONLY_RESET, 0);
+        host.control_transfer(setup, &[])?;
+        self.clear_halt(host, self.bulk_in.0)?;
+        self.clear_halt(host, self.bulk_out)
+    }
+
+    /// Executes a command block on a logical unit.
+    ///
+    /// # Returns
+    /// - `Ok(CommandStatus)` if the device processed the command, which includes failed commands
+    /// - `Err(UsbIpError::InvalidCsw)` or `Err(UsbIpError::PhaseError)` after the reset recovery
+    pub fn command<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+        cb: &[u8],
+        data: DataPhase<'_>,
+    ) -> Result<CommandStatus, UsbIpError> {
+        if cb.is_empty() || cb.len() > 16 {
+            return Err(UsbIpError::InvalidTransferLength(cb.len() as i32));
+        }
+
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+
+        let (length, flags) = match data {
+            DataPhase::None => (0, 0x00),
+            DataPhase::In(length) => (length, 0x80),
+            DataPhase::Out(data) => (data.len(), 0x00),
+        };
+
+        let mut cbw = [0; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[13] = lun;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+
+        if let Err(err) = host.bulk_out(self.bulk_out, &cbw) {
+            if is_stall(&err) {
+                self.reset_recovery(host)?;
+            }
+            return Err(err);
+        }
+
+        // A stall in the data phase ends it, the status is still read afterwards
+        let received = match data {
+            DataPhase::None => vec![],
+            DataPhase::In(length) => match self.receive(host, length) {
+                Ok(data) => data,
+                Err(err) if is_stall(&err) => {
+                    self.clear_halt(host, self.bulk_in.0)?;
+                    vec![]
+                }
+                Err(err) => return Err(err),
+            },
+            DataPhase::Out(data) => {
+                match host.bulk_out(self.bulk_out, data) {
+                    Ok(_) => (),
+                    Err(err) if is_stall(&err) => self.clear_halt(host, self.bulk_out)?,
+                    Err(err) => return Err(err),
+                }
+                vec![]
+            }
+        };
+
+        // The status may be stalled once, before it can be read
+        let csw = match host.bulk_in(self.bulk_in.0, CSW_SIZE) {
+            Err(err) if is_stall(&err) => {
+                self.clear_halt(host, self.bulk_in.0)?;
+                host.bulk_in(self.bulk_in.0, CSW_SIZE)
+            }
+            result => result,
+        };
+        let csw = match csw {
+            Ok(csw) => csw,
+            Err(err) => {
+                if is_stall(&err) {
+                    self.reset_recovery(host)?;
+                }
+                return Err(err);
+            }
+        };
+
+        if csw.len() != CSW_SIZE
+            || u32::from_le_bytes(csw[0..4].try_into().unwrap()) != CSW_SIGNATURE
+            || u32::from_le_bytes(csw[4..8].try_into().unwrap()) != tag
+        {
+            log::warn!("invalid csw for tag {}: {:?}", tag, csw);
+            self.reset_recovery(host)?;
+            return Err(UsbIpError::InvalidCsw);
+        }
+
+        match csw[12] {
+            CSW_STATUS_PASSED | CSW_STATUS_FAILED => Ok(CommandStatus {
+                passed: csw[12] == CSW_STATUS_PASSED,
+                residue: u32::from_le_bytes(csw[8..12].try_into().unwrap()),
+                data: received,
+            }),
+            _ => {
+                self.reset_recovery(host)?;
+                Err(UsbIpError::PhaseError)
+            }
+        }
+    }
+
+    /// Checks, whether a logical unit is ready.
+    pub fn test_unit_ready<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+    ) -> Result<(), UsbIpError> {
+        self.scsi(
+            host,
+            lun,
+            &[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0],
+            DataPhase::None,
+        )?;
+        Ok(())
+    }
+
+    /// Reads the standard inquiry data of a logical unit.
+    pub fn inquiry<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+    ) -> Result<Inquiry, UsbIpError> {
+        let cb = [SCSI_INQUIRY, 0, 0, 0, INQUIRY_LENGTH, 0];
+        let data = self.scsi(host, lun, &cb, DataPhase::In(INQUIRY_LENGTH as usize))?;
+        Inquiry::from_slice(&data)
+    }
+
+    /// Reads the sense data of the last failed command of a logical unit.
+    pub fn request_sense<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+    ) -> Result<Sense, UsbIpError> {
+        let cb = [SCSI_REQUEST_SENSE, 0, 0, 0, SENSE_LENGTH, 0];
+        let status = self.command(host, lun, &cb, DataPhase::In(SENSE_LENGTH as usize))?;
+        Sense::from_slice(&status.data)
+    }
+
+    /// Reads the number and size of the blocks of a logical unit.
+    pub fn read_capacity<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+    ) -> Result<Capacity, UsbIpError> {
+        let cb = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let data = self.scsi(host, lun, &cb, DataPhase::In(8))?;
+        if data.len() < 8 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Capacity {
+            last_lba: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            block_size: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+        })
+    }
+
+    /// Reads `blocks` blocks of `block_size` bytes starting at `lba`.
+    pub fn read_10<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+        lba: u32,
+        blocks: u16,
+        block_size: u32,
+    ) -> Result<Vec<u8>, UsbIpError> {
+        let cb = rw_10(SCSI_READ_10, lba, blocks);
+        let length = blocks as usize * block_size as usize;
+        self.scsi(host, lun, &cb, DataPhase::In(length))
+    }
+
+    /// Writes `data`, which must consist of whole blocks of `block_size` bytes, starting at `lba`.
+    pub fn write_10<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+        lba: u32,
+        block_size: u32,
+        data: &[u8],
+    ) -> Result<(), UsbIpError> {
+        let blocks = data.len() / block_size.max(1) as usize;
+        if blocks * block_size as usize != data.len() || blocks > u16::MAX as usize {
+            return Err(UsbIpError::InvalidTransferLength(data.len() as i32));
+        }
+
+        let cb = rw_10(SCSI_WRITE_10, lba, blocks as u16);
+        self.scsi(host, lun, &cb, DataPhase::Out(data))?;
+        Ok(())
+    }
+
+    /// Executes a SCSI command and fetches the sense data, if it fails.
+    fn scsi<H: UsbHost + ?Sized>(
+        &mut self,
+        host: &mut H,
+        lun: u8,
+        cb: &[u8],
+        data: DataPhase<'_>,
+    ) -> Result<Vec<u8>, UsbIpError> {
+        let status = self.command(host, lun, cb, data)?;
+        if status.passed {
+            return Ok(status.data);
+        }
+
+        let sense = self.request_sense(host, lun)?;
+        Err(UsbIpError::CommandFailed(sense.key, sense.asc, sense.ascq))
+    }
+
+    /// Receives up to `length` bytes from the bulk in endpoint, until a short packet arrives.
+    fn receive<H: UsbHost + ?Sized>(
+        &self,
+        host: &mut H,
+        length: usize,
+    ) -> Result<Vec<u8>, UsbIpError> {
+        let (ep, max_packet_size) = self.bulk_in;
+        let mut data = vec![];
+        while data.len() < length {
+            let chunk = host.bulk_in(ep, length - data.len())?;
+            let short = chunk.is_empty() || chunk.len() % max_packet_size.max(1) as usize != 0;
+            data.extend(chunk);
+
+            if short {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn clear_halt<H: UsbHost + ?Sized>(&self, host: &mut H, ep: u8) -> Result<(), UsbIpError> {
+        log::debug!("clearing halt of endpoint {:#04x}", ep);
+        let setup = SetupPacket::new(
+            TO_ENDPOINT_STANDARD,
+            Request::CLEAR_FEATURE,
+            Request::FEATURE_ENDPOINT_HALT,
+            ep as u16,
+            0,
+        );
+        host.control_transfer(setup, &[])?;
+        Ok(())
+    }
+
+    fn request(&self, request_type: u8, request: u8, length: u16) -> SetupPacket {
+        SetupPacket::new(request_type, request, 0, self.interface as u16, length)
+    }
+}
+
+/// Builds the command block of READ(10) and WRITE(10).
+fn rw_10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cb = [0; 10];
+    cb[0] = opcode;
+    cb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cb
+}
+
+fn is_stall(err: &UsbIpError) -> bool {
+    matches!(err, UsbIpError::UrbFailed(status) if *status == -EPIPE)
+}
+
+/// Describes a mass storage class request for the debug output.
+pub(crate) fn describe_request(setup: &SetupPacket) -> Option<String> {
+    match setup.request {
+        REQ_BULK_ONLY_RESET => Some("BULK_ONLY_RESET".to_string()),
+        REQ_GET_MAX_LUN => Some("GET_MAX_LUN".to_string()),
+        _ => None,
+    }
+}
+
+/// Describes a command or status wrapper for the debug output.
+///
+/// Returns `None` for the data of the data phase.
+pub(crate) fn describe_data(data: &[u8]) -> Option<String> {
+    let signature = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap());
+    let tag = u32::from_le_bytes(data.get(4..8)?.try_into().unwrap());
+
+    match (data.len(), signature) {
+        (CBW_SIZE, CBW_SIGNATURE) => {
+            let length = u32::from_le_bytes(data[8..12].try_into().unwrap());
+            let direction = if data[12] & 0x80 != 0 { "IN" } else { "OUT" };
+            let cb_length = usize::min(data[14] as usize & 0x1f, 16);
+            Some(format!(
+                "CBW(tag={}, length={}, {}, lun={}, {})",
+                tag,
+                length,
+                direction,
+                data[13] & 0x0f,
+                describe_command(&data[15..15 + cb_length])
+            ))
+        }
+        (CSW_SIZE, CSW_SIGNATURE) => {
+            let residue = u32::from_le_bytes(data[8..12].try_into().unwrap());
+            let status = match data[12] {
+                CSW_STATUS_PASSED => "PASSED".to_string(),
+                CSW_STATUS_FAILED => "FAILED".to_string(),
+                CSW_STATUS_PHASE_ERROR => "PHASE_ERROR".to_string(),
+                status => format!("status={:#04x}", status),
+            };
+            Some(format!("CSW(tag={}, residue={}, {})", tag, residue, status))
+        }
+        _ => None,
+    }
+}
+
+/// Describes a SCSI command block.
+fn describe_command(cb: &[u8]) -> String {
+    let u16_at = |i: usize| {
+        cb.get(i..i + 2)
+            .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let u32_at = |i: usize| {
+        cb.get(i..i + 4)
+            .map_or(0, |b| u32::from_be_bytes(b.try_into().unwrap()))
+    };
+
+    let opcode = match cb.first() {
+        Some(&opcode) => opcode,
+        None => return "EMPTY".to_string(),
+    };
+    match opcode {
+        SCSI_TEST_UNIT_READY => "TEST_UNIT_READY".to_string(),
+        SCSI_REQUEST_SENSE => format!("REQUEST_SENSE(length={})", cb.get(4).unwrap_or(&0)),
+        SCSI_INQUIRY => format!("INQUIRY(length={})", u16_at(3)),
+        0x1a => format!("MODE_SENSE_6(page={:#04x})", cb.get(2).unwrap_or(&0) & 0x3f),
+        0x1b => format!("START_STOP_UNIT({:#04x})", cb.get(4).unwrap_or(&0)),
+        0x1e => format!(
+            "PREVENT_ALLOW_MEDIUM_REMOVAL(prevent={})",
+            cb.get(4).unwrap_or(&0) & 0x03
+        ),
+        0x23 => format!("READ_FORMAT_CAPACITIES(length={})", u16_at(7)),
+        SCSI_READ_CAPACITY_10 => "READ_CAPACITY_10".to_string(),
+        SCSI_READ_10 => format!("READ_10(lba={}, blocks={})", u32_at(2), u16_at(7)),
+        SCSI_WRITE_10 => format!("WRITE_10(lba={}, blocks={})", u32_at(2), u16_at(7)),
+        0x2f => format!("VERIFY_10(lba={}, blocks={})", u32_at(2), u16_at(7)),
+        0x35 => "SYNCHRONIZE_CACHE_10".to_string(),
+        0x5a => format!(
+            "MODE_SENSE_10(page={:#04x})",
+            cb.get(2).unwrap_or(&0) & 0x3f
+        ),
+        0x9e => "SERVICE_ACTION_IN_16".to_string(),
+        0xa0 => "REPORT_LUNS".to_string(),
+        opcode => format!("SCSI(opcode={:#04x})", opcode),
+    }
+}
@@ -0,0 +1,532 @@
+//! A client for the host side of the USBIP protocol.
+//!
+//! The [`UsbIpClient`] takes the role of the `vhci-hcd` kernel driver.
+//! It connects to a USBIP server, such as a [`UsbIpBus`](crate::UsbIpBus),
+//! imports a device and then drives it with urbs.
+//! This allows testing devices end-to-end without any kernel involvement.
+//!
+//! ```no_run
+//! use usbip_device::client::{SetupPacket, UsbHost, UsbIpClient};
+//!
+//! let mut client = UsbIpClient::import("127.0.0.1:3240", "1-1").unwrap();
+//!
+//! // GET_DESCRIPTOR(DEVICE)
+//! let descriptor = client
+//!     .control_transfer(SetupPacket::new(0x80, 0x06, 0x0100, 0, 18), &[])
+//!     .unwrap();
+//! ```
+
+use crate::{
+    cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
+    debug::ClassDecoder,
+    descriptor::{self, EnumeratedDevice},
+    op::{
+        OpExportedDevice, OpHeader, OpRequest, OpResponse, OpResponseCommand, OP_REQ_DEVLIST,
+        OP_REQ_IMPORT, ST_OK, USBIP_VERSION,
+    },
+    request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd},
+    response::{UsbIpResponse, UsbIpResponseCmd, ECONNRESET},
+    UsbIpError, UsbIpLimits,
+};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+/// The setup packet of a control transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Creates a new setup packet
+    pub fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Self {
+        Self {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    /// Returns the direction of the data stage of the control transfer.
+    pub fn direction(&self) -> Direction {
+        if self.request_type & 0x80 != 0 {
+            Direction::IN
+        } else {
+            Direction::OUT
+        }
+    }
+
+    pub fn to_array(&self) -> [u8; 8] {
+        let mut result = [0; 8];
+
+        result[0] = self.request_type;
+        result[1] = self.request;
+        result[2..4].copy_from_slice(&self.value.to_le_bytes());
+        result[4..6].copy_from_slice(&self.index.to_le_bytes());
+        result[6..8].copy_from_slice(&self.length.to_le_bytes());
+
+        result
+    }
+
+    pub fn from_array(data: &[u8; 8]) -> Self {
+        Self {
+            request_type: data[0],
+            request: data[1],
+            value: u16::from_le_bytes([data[2], data[3]]),
+            index: u16::from_le_bytes([data[4], data[5]]),
+            length: u16::from_le_bytes([data[6], data[7]]),
+        }
+    }
+}
+
+/// The result of a completed urb.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrbCompletion {
+    /// The status of the urb, `0` on success and a negative error number otherwise
+    pub status: i32,
+    /// The number of bytes transferred
+    pub actual_length: usize,
+    /// The received data, empty for out urbs
+    pub data: Vec<u8>,
+}
+
+impl UrbCompletion {
+    /// Turns the completion into an error, if the status signals a failure.
+    fn into_result(self) -> Result<Self, UsbIpError> {
+        match self.status {
+            0 => Ok(self),
+            status => Err(UsbIpError::UrbFailed(status)),
+        }
+    }
+}
+
+/// A host, that drives an imported device with urbs.
+///
+/// This is implemented by the [`UsbIpClient`], as well as the
+/// [`LoopbackSession`](crate::loopback::LoopbackSession), such that the
+/// same code can drive devices over both transports.
+pub trait UsbHost {
+    /// Submits an urb without waiting for its completion.
+    ///
+    /// For out urbs, `data` is sent to the device, for in urbs,
+    /// `length` bytes are requested.
+    ///
+    /// # Returns
+    /// The sequence number of the urb, which can be passed to
+    /// [`wait`](Self::wait) or [`unlink`](Self::unlink).
+    fn submit(
+        &mut self,
+        ep: u8,
+        direction: Direction,
+        setup: Option<SetupPacket>,
+        data: &[u8],
+        length: usize,
+    ) -> Result<u32, UsbIpError>;
+
+    /// Waits for the completion of the urb with `seqnum`.
+    ///
+    /// Fails with [`UsbIpError::Timeout`], if the urb does not complete in time.
+    fn wait(&mut self, seqnum: u32) -> Result<UrbCompletion, UsbIpError>;
+
+    /// Cancels the urb with `seqnum`.
+    ///
+    /// # Returns
+    /// - `true` if the urb was cancelled
+    /// - `false` if it had already been completed, in which case the completion
+    ///   can still be retrieved by [`wait`](Self::wait)
+    fn unlink(&mut self, seqnum: u32) -> Result<bool, UsbIpError>;
+
+    /// Performs a control transfer on endpoint 0.
+    ///
+    /// For out transfers, `data` is sent in the data stage, for in transfers,
+    /// up to `setup.length` bytes are received and returned.
+    fn control_transfer(&mut self, setup: SetupPacket, data: &[u8]) -> Result<Vec<u8>, UsbIpError> {
+        let direction = setup.direction();
+        let seqnum = self.submit(0, direction, Some(setup), data, setup.length as usize)?;
+        Ok(complete(self, seqnum)?.data)
+    }
+
+    /// Receives up to `length` bytes from a bulk in endpoint.
+    fn bulk_in(&mut self, ep: u8, length: usize) -> Result<Vec<u8>, UsbIpError> {
+        transfer_in(self, ep, length)
+    }
+
+    /// Sends `data` to a bulk out endpoint.
+    fn bulk_out(&mut self, ep: u8, data: &[u8]) -> Result<usize, UsbIpError> {
+        transfer_out(self, ep, data)
+    }
+
+    /// Receives up to `length` bytes from an interrupt in endpoint.
+    fn interrupt_in(&mut self, ep: u8, length: usize) -> Result<Vec<u8>, UsbIpError> {
+        transfer_in(self, ep, length)
+    }
+
+    /// Sends `data` to an interrupt out endpoint.
+    fn interrupt_out(&mut self, ep: u8, data: &[u8]) -> Result<usize, UsbIpError> {
+        transfer_out(self, ep, data)
+    }
+
+    /// Enumerates the device and selects its first configuration.
+    ///
+    /// See [`descriptor::enumerate`] for details.
+    fn enumerate(&mut self) -> Result<EnumeratedDevice, UsbIpError> {
+        descriptor::enumerate(self)
+    }
+
+    /// Resets the device, which returns it to its default state.
+    ///
+    /// USBIP forwards the reset as SET_FEATURE(PORT_RESET) request, that the server
+    /// turns into a reset of the device. The device needs to be enumerated again afterwards.
+    fn reset_device(&mut self) -> Result<(), UsbIpError> {
+        self.control_transfer(SetupPacket::new(0x23, 0x03, 0x0004, 0, 0), &[])?;
+        Ok(())
+    }
+}
+
+fn transfer_in<H: UsbHost + ?Sized>(
+    host: &mut H,
+    ep: u8,
+    length: usize,
+) -> Result<Vec<u8>, UsbIpError> {
+    let seqnum = host.submit(ep & 0x0f, Direction::IN, None, &[], length)?;
+    Ok(complete(host, seqnum)?.data)
+}
+
+fn transfer_out<H: UsbHost + ?Sized>(
+    host: &mut H,
+    ep: u8,
+    data: &[u8],
+) -> Result<usize, UsbIpError> {
+    let seqnum = host.submit(ep & 0x0f, Direction::OUT, None, data, 0)?;
+    Ok(complete(host, seqnum)?.actual_length)
+}
+
+/// Waits for an urb to complete and unlinks it, if it does not complete in time.
+fn complete<H: UsbHost + ?Sized>(host: &mut H, seqnum: u32) -> Result<UrbCompletion, UsbIpError> {
+    match host.wait(seqnum) {
+        Err(UsbIpError::Timeout) => {
+            if host.unlink(seqnum)? {
+                return Err(UsbIpError::Timeout);
+            }
+
+            // The urb completed, before it could be unlinked
+            host.wait(seqnum)?.into_result()
+        }
+        result => result?.into_result(),
+    }
+}
+
+/// The timeout of op requests and the default timeout of transfers
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client, that has imported a device from a USBIP server.
+#[derive(Debug)]
+pub struct UsbIpClient {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    device: OpExportedDevice,
+    next_seqnum: u32,
+    /// The directions of all submitted urbs, that have not been completed yet
+    pending: HashMap<u32, Direction>,
+    /// Responses, that were received while waiting for a different urb
+    completed: HashMap<u32, UsbIpResponse>,
+    limits: UsbIpLimits,
+    timeout: Duration,
+    /// The decoder of the logged urbs
+    decoder: ClassDecoder,
+}
+
+impl UsbIpClient {
+    /// Lists the devices exported by the server at `addr`.
+    ///
+    /// # Returns
+    /// - `Err(UsbIpError::Timeout)` if the server did not respond within the default timeout
+    pub fn list_devices(addr: impl ToSocketAddrs) -> Result<Vec<OpExportedDevice>, UsbIpError> {
+        let mut stream = TcpStream::connect(addr)?;
+        let request = OpRequest::ListDevices(OpHeader {
+            version: USBIP_VERSION,
+            command: OP_REQ_DEVLIST,
+            status: ST_OK,
+        });
+
+        match request_op(&mut stream, &mut vec![], &request, DEFAULT_TIMEOUT)? {
+            OpResponse {
+                status: ST_OK,
+                cmd: OpResponseCommand::ListDevices(devices),
+                ..
+            } => Ok(devices),
+            OpResponse { status: ST_OK, .. } => Err(UsbIpError::InvalidCommand(OP_REQ_DEVLIST)),
+            OpResponse { status, .. } => Err(UsbIpError::StatusNotOk(status)),
+        }
+    }
+
+    /// Imports the device with `bus_id` from the server at `addr`.
+    ///
+    /// # Returns
+    /// - `Err(UsbIpError::Timeout)` if the server did not respond within the default timeout
+    pub fn import(addr: impl ToSocketAddrs, bus_id: &str) -> Result<Self, UsbIpError> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let request = OpRequest::ConnectDevice(
+            OpHeader {
+                version: USBIP_VERSION,
+                command: OP_REQ_IMPORT,
+                status: ST_OK,
+            },
+            bus_id.to_string(),
+        );
+
+        // Anything received after the response already belongs to the urb traffic
+        let mut buf = vec![];
+        let device = match request_op(&mut stream, &mut buf, &request, DEFAULT_TIMEOUT)? {
+            OpResponse {
+                status: ST_OK,
+                cmd: OpResponseCommand::ConnectDevice(Some(device)),
+                ..
+            } => device,
+            OpResponse { status: ST_OK, .. } => {
+                return Err(UsbIpError::InvalidCommand(OP_REQ_IMPORT))
+            }
+            OpResponse { status, .. } => return Err(UsbIpError::StatusNotOk(status)),
+        };
+        log::info!("imported device {}", device.bus_id);
+
+        Ok(Self {
+            stream,
+            buf,
+            device,
+            next_seqnum: 1,
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+            limits: UsbIpLimits::default(),
+            timeout: DEFAULT_TIMEOUT,
+            decoder: ClassDecoder::new(),
+        })
+    }
+
+    /// Returns the description of the imported device, as sent by the server.
+    pub fn device(&self) -> &OpExportedDevice {
+        &self.device
+    }
+
+    /// Sets the timeout of [`wait`](UsbHost::wait) and the synchronous transfer functions.
+    ///
+    /// The synchronous transfer functions unlink urbs, that do not complete in time.
+    /// A timed out [`wait`](UsbHost::wait) leaves the urb pending.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the limits, that protect the client from misbehaving servers.
+    pub fn set_limits(&mut self, limits: UsbIpLimits) {
+        self.limits = limits;
+    }
+
+    /// Receives responses, until the one with `seqnum` arrives.
+    fn wait_response(
+        &mut self,
+        seqnum: u32,
+        timeout: Duration,
+    ) -> Result<UsbIpResponse, UsbIpError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(response) = self.completed.remove(&seqnum) {
+                return Ok(response);
+            }
+
+            if !self.pending.contains_key(&seqnum) {
+                return Err(UsbIpError::UnknownSeqnum(seqnum));
+            }
+
+            let response = self.receive(deadline)?;
+            log::debug!("{:?}", self.decoder.response(&response));
+
+            // Nobody waits for duplicated or unsolicited responses
+            match self.pending.remove(&response.header.seqnum) {
+                Some(_) => {
+                    self.completed.insert(response.header.seqnum, response);
+                }
+                None => log::warn!(
+                    "dropping response for unknown urb {}",
+                    response.header.seqnum
+                ),
+            }
+        }
+    }
+
+    /// Receives the next response from the server.
+    fn receive(&mut self, deadline: Instant) -> Result<UsbIpResponse, UsbIpError> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            let pending = &self.pending;
+            // The direction of an unknown urb can only be taken from the header
+            let decoded = UsbIpResponse::decode(&self.buf, &self.limits, |header| {
+                pending
+                    .get(&header.seqnum)
+                    .copied()
+                    .unwrap_or(header.direction)
+            })?;
+            if let Some((response, len)) = decoded {
+                self.buf.drain(..len);
+                return Ok(response);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(UsbIpError::Timeout);
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(UsbIpError::ConnectionClosed),
+                Ok(bytes_read) => self.buf.extend_from_slice(&chunk[..bytes_read]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn header(&self, command: UsbCmd, seqnum: u32, direction: Direction, ep: u8) -> UsbIpHeader {
+        UsbIpHeader {
+            command,
+            seqnum,
+            devid: self.device.descriptor.busnum << 16 | self.device.descriptor.devnum,
+            direction,
+            ep: ep as u32,
+        }
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        let seqnum = self.next_seqnum;
+        self.next_seqnum = self.next_seqnum.wrapping_add(1).max(1);
+        seqnum
+    }
+}
+
+impl UsbHost for UsbIpClient {
+    fn submit(
+        &mut self,
+        ep: u8,
+        direction: Direction,
+        setup: Option<SetupPacket>,
+        data: &[u8],
+        length: usize,
+    ) -> Result<u32, UsbIpError> {
+        let length = if direction == Direction::OUT {
+            data.len()
+        } else {
+            length
+        };
+
+        let seqnum = self.next_seqnum();
+        let request = UsbIpRequest {
+            header: self.header(UsbCmd::Request, seqnum, direction, ep),
+            cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+                transfer_flags: TransferFlags::empty(),
+                transfer_buffer_length: length as i32,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup: setup.map(|setup| setup.to_array()).unwrap_or_default(),
+            }),
+            data: data.to_vec(),
+        };
+        log::debug!("{:?}", self.decoder.request(&request));
+
+        self.stream.write_all(&request.encode())?;
+        self.pending.insert(seqnum, direction);
+
+        Ok(seqnum)
+    }
+
+    fn wait(&mut self, seqnum: u32) -> Result<UrbCompletion, UsbIpError> {
+        let timeout = self.timeout;
+        let response = self.wait_response(seqnum, timeout)?;
+        match response.cmd {
+            UsbIpResponseCmd::Cmd(ret) => Ok(UrbCompletion {
+                status: ret.status,
+                actual_length: ret.actual_length.max(0) as usize,
+                data: response.data,
+            }),
+            UsbIpResponseCmd::Unlink(_) => Err(UsbIpError::InvalidUrbCommand(
+                UsbCmd::UnlinkResponse.to_u32(),
+            )),
+        }
+    }
+
+    fn unlink(&mut self, seqnum: u32) -> Result<bool, UsbIpError> {
+        let unlink_seqnum = self.next_seqnum();
+        let request = UsbIpRequest {
+            header: self.header(UsbCmd::UnlinkRequest, unlink_seqnum, Direction::OUT, 0),
+            cmd: UsbIpRequestCmd::Unlink(UsbIpCmdUnlink { seqnum }),
+            data: vec![],
+        };
+        log::debug!("{:?}", self.decoder.request(&request));
+
+        self.stream.write_all(&request.encode())?;
+        self.pending.insert(unlink_seqnum, Direction::OUT);
+
+        let timeout = self.timeout;
+        let response = self.wait_response(unlink_seqnum, timeout)?;
+        match response.cmd {
+            UsbIpResponseCmd::Unlink(ret) if ret.status == -ECONNRESET => {
+                self.pending.remove(&seqnum);
+                self.completed.remove(&seqnum);
+                Ok(true)
+            }
+            UsbIpResponseCmd::Unlink(_) => Ok(false),
+            UsbIpResponseCmd::Cmd(_) => {
+                Err(UsbIpError::InvalidUrbCommand(UsbCmd::Response.to_u32()))
+            }
+        }
+    }
+}
+
+/// Sends an op request and receives the response.
+///
+/// Bytes, that were received after the response, are left in `buf`.
+///
+/// # Returns
+/// - `Err(UsbIpError::Timeout)` if the server did not respond within `timeout`
+fn request_op(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    request: &OpRequest,
+    timeout: Duration,
+) -> Result<OpResponse, UsbIpError> {
+    stream.write_all(&request.encode()?)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some((response, len)) = OpResponse::decode(buf)? {
+            buf.drain(..len);
+            return Ok(response);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(UsbIpError::Timeout);
+        }
+        stream.set_read_timeout(Some(deadline - now))?;
+
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(UsbIpError::ConnectionClosed),
+            Ok(bytes_read) => buf.extend_from_slice(&chunk[..bytes_read]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
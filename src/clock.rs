@@ -0,0 +1,99 @@
+//! The clock, that a [`UsbIpBus`](crate::UsbIpBus) measures time with.
+//!
+//! Latencies in the statistics, the timing emulation and the timestamps of captures
+//! all read the clock of the bus. By default, this is the [`WallClock`].
+//! A [`VirtualClock`] only advances when told to, such that a test, which polls the
+//! device and a [`LoopbackHost`](crate::loopback::LoopbackHost) in lockstep,
+//! produces identical captures on every run.
+//!
+//! ```
+//! use std::time::Duration;
+//! use usbip_device::{clock::VirtualClock, UsbIpBus};
+//!
+//! let (bus, mut host) = UsbIpBus::loopback();
+//! let clock = VirtualClock::new();
+//! bus.set_clock(clock.clone());
+//!
+//! let _session = host.session(|| {
+//!     // dev.poll(&mut [&mut class]);
+//!     clock.advance(Duration::from_micros(125));
+//! });
+//! ```
+
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A source of the current time.
+pub trait Clock: Debug + Send {
+    /// Returns the time since the unix epoch.
+    ///
+    /// The time must never decrease.
+    fn now(&self) -> Duration;
+}
+
+/// The real time, which never jumps backwards, even if the system time is changed.
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    /// The system time and the monotonic time at creation
+    start: (Duration, Instant),
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        let unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            start: (unix, Instant::now()),
+        }
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for WallClock {
+    fn now(&self) -> Duration {
+        self.start.0 + self.start.1.elapsed()
+    }
+}
+
+/// A clock, that only advances when told to.
+///
+/// All clones share the same time, such that a test can keep a clone to drive the clock,
+/// that it has handed to the bus.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock(Arc<AtomicU64>);
+
+impl VirtualClock {
+    /// Creates a clock at the unix epoch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.0
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Sets the clock to `time` since the unix epoch, unless that would turn it back.
+    pub fn set(&self, time: Duration) {
+        self.0.fetch_max(time.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::SeqCst))
+    }
+}
@@ -1,11 +1,16 @@
+use crate::UsbIpError;
 use std::{convert::TryInto, fmt::Debug};
 
 /// The command type of the Urb
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UsbCmd {
+    /// USBIP_CMD_SUBMIT
     Request,
+    /// USBIP_CMD_UNLINK
     UnlinkRequest,
+    /// USBIP_RET_SUBMIT
     Response,
+    /// USBIP_RET_UNLINK
     UnlinkResponse,
 }
 
@@ -30,7 +35,8 @@ impl UsbCmd {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The header, that is shared by all urb packets.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UsbIpHeader {
     pub command: UsbCmd,
     pub seqnum: u32,
@@ -52,21 +58,31 @@ impl UsbIpHeader {
         result
     }
 
-    pub fn from_slice(data: &[u8]) -> Self {
-        Self {
-            command: UsbCmd::try_from_u32(u32::from_be_bytes(data[0..4].try_into().unwrap()))
-                .unwrap(),
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 20 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        let command = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let command =
+            UsbCmd::try_from_u32(command).ok_or(UsbIpError::InvalidUrbCommand(command))?;
+
+        let direction = u32::from_be_bytes(data[12..16].try_into().unwrap());
+        let direction =
+            Direction::from_bits(direction).ok_or(UsbIpError::InvalidDirection(direction))?;
+
+        Ok(Self {
+            command,
             seqnum: u32::from_be_bytes(data[4..8].try_into().unwrap()),
             devid: u32::from_be_bytes(data[8..12].try_into().unwrap()),
-            direction: Direction::from_bits_truncate(u32::from_be_bytes(
-                data[12..16].try_into().unwrap(),
-            )),
+            direction,
             ep: u32::from_be_bytes(data[16..20].try_into().unwrap()),
-        }
+        })
     }
 }
 
 bitflags::bitflags! {
+   /// The transfer flags of a [`UsbIpCmdSubmit`](crate::protocol::UsbIpCmdSubmit)
    pub struct TransferFlags: u32 {
       const SHORT_NOT_OK = 0x00000001;
       const ISO_ASAP = 0x00000002;
@@ -79,6 +95,7 @@ bitflags::bitflags! {
 }
 
 bitflags::bitflags! {
+   /// The direction of an urb
    pub struct Direction: u32 {
       const OUT = 0x0000000;
       const IN = 0x0000001;
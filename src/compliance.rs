@@ -0,0 +1,660 @@
+//! A runner for the standard request checks of the USB 2.0 spec, chapter 9.
+//!
+//! [`run`] enumerates a device through a [`UsbHost`] and then checks, that the
+//! device handles the standard requests as required: GET_STATUS, SET/CLEAR_FEATURE,
+//! GET/SET_CONFIGURATION, GET/SET_INTERFACE, requests that must stall, the length and
+//! consistency of the descriptors, as well as halting and clearing the halt of every
+//! endpoint. The outcome of every check is collected in a [`ComplianceReport`].
+//!
+//! ```
+//! use usb_device::{bus::UsbBusAllocator, prelude::*};
+//! use usbd_serial::SerialPort;
+//! use usbip_device::{compliance, UsbIpBus};
+//!
+//! let (bus, mut host) = UsbIpBus::loopback();
+//! let bus_allocator = UsbBusAllocator::new(bus);
+//! let mut usb_serial = SerialPort::new(&bus_allocator);
+//! let mut usb_bus = UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(0x16c0, 0x27dd)).build();
+//!
+//! let report = compliance::run(&mut host.session(|| {
+//!     usb_bus.poll(&mut [&mut usb_serial]);
+//! }))
+//! .unwrap();
+//! assert!(report.passed(), "{}", report);
+//! ```
+
+use crate::{
+    client::{SetupPacket, UsbHost},
+    cmd::Direction,
+    descriptor::{self, get_descriptor, ConfigurationDescriptor, EnumeratedDevice},
+    response::EPIPE,
+    UsbIpError,
+};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use usb_device::{
+    control::Request, descriptor::descriptor_type, endpoint::EndpointType, UsbDirection,
+};
+
+/// The request type of standard requests to the device
+const TO_DEVICE: u8 = 0x00;
+const FROM_DEVICE: u8 = 0x80;
+const TO_INTERFACE: u8 = 0x01;
+const FROM_INTERFACE: u8 = 0x81;
+const TO_ENDPOINT: u8 = 0x02;
+const FROM_ENDPOINT: u8 = 0x82;
+
+/// The bit of the configuration attributes, that signals support for remote wakeup
+const ATTRIBUTE_REMOTE_WAKEUP: u8 = 0x20;
+
+/// The outcome of a single compliance check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComplianceCheck {
+    pub name: String,
+    /// `Err` with the reason, if the check failed
+    pub result: Result<(), String>,
+    /// A remark on a check, that passed although the device behaved unusually
+    pub warning: Option<String>,
+}
+
+/// The outcomes of all compliance checks, that were run against a device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComplianceReport {
+    pub checks: Vec<ComplianceCheck>,
+}
+
+impl ComplianceReport {
+    /// Returns `true`, if all checks passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+
+    /// Returns all checks, that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &ComplianceCheck> {
+        self.checks.iter().filter(|check| check.result.is_err())
+    }
+
+    /// Returns all checks, that passed with a warning.
+    pub fn warnings(&self) -> impl Iterator<Item = &ComplianceCheck> {
+        self.checks.iter().filter(|check| check.warning.is_some())
+    }
+}
+
+impl Display for ComplianceReport {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        for check in self.checks.iter() {
+            match (&check.result, &check.warning) {
+                (Ok(()), None) => writeln!(f, "PASS {}", check.name)?,
+                (Ok(()), Some(warning)) => writeln!(f, "WARN {}: {}", check.name, warning)?,
+                (Err(reason), _) => writeln!(f, "FAIL {}: {}", check.name, reason)?,
+            }
+        }
+
+        let failed = self.failures().count();
+        write!(
+            f,
+            "{} of {} checks passed",
+            self.checks.len() - failed,
+            self.checks.len()
+        )
+    }
+}
+
+/// Why a check did not pass.
+enum CheckError {
+    /// The device did not behave as required
+    Failed(String),
+    /// The device behaved unusually, but not against the spec
+    Warning(String),
+    /// The host can no longer talk to the device, which aborts the run
+    Abort(UsbIpError),
+}
+
+impl From<UsbIpError> for CheckError {
+    fn from(err: UsbIpError) -> Self {
+        match err {
+            UsbIpError::UrbFailed(_)
+            | UsbIpError::Timeout
+            | UsbIpError::InvalidDescriptor(_)
+            | UsbIpError::InvalidString => Self::Failed(err.to_string()),
+            err => Self::Abort(err),
+        }
+    }
+}
+
+/// Fails the check with `reason`, unless `condition` holds.
+fn ensure(condition: bool, reason: impl FnOnce() -> String) -> Result<(), CheckError> {
+    match condition {
+        true => Ok(()),
+        false => Err(CheckError::Failed(reason())),
+    }
+}
+
+struct Runner<'a, H: ?Sized> {
+    host: &'a mut H,
+    report: ComplianceReport,
+}
+
+impl<'a, H: UsbHost + ?Sized> Runner<'a, H> {
+    /// Runs a check and records its outcome.
+    ///
+    /// # Returns
+    /// - `Ok(true)` if the check passed
+    fn check(
+        &mut self,
+        name: impl Into<String>,
+        check: impl FnOnce(&mut H) -> Result<(), CheckError>,
+    ) -> Result<bool, UsbIpError> {
+        let name = name.into();
+        let (result, warning) = match check(self.host) {
+            Ok(()) => (Ok(()), None),
+            Err(CheckError::Failed(reason)) => (Err(reason), None),
+            Err(CheckError::Warning(warning)) => (Ok(()), Some(warning)),
+            Err(CheckError::Abort(err)) => return Err(err),
+        };
+        log::info!("compliance check {}: {:?} {:?}", name, result, warning);
+
+        let passed = result.is_ok();
+        self.report.checks.push(ComplianceCheck {
+            name,
+            result,
+            warning,
+        });
+        Ok(passed)
+    }
+}
+
+/// Performs a standard request, that is expected to return exactly `length` bytes.
+fn request_in<H: UsbHost + ?Sized>(
+    host: &mut H,
+    setup: SetupPacket,
+) -> Result<Vec<u8>, CheckError> {
+    let data = host.control_transfer(setup, &[])?;
+    ensure(data.len() == setup.length as usize, || {
+        format!("expected {} bytes, got {}", setup.length, data.len())
+    })?;
+    Ok(data)
+}
+
+/// Performs a request, that the device is required to reject with a stall.
+fn expect_stall<H: UsbHost + ?Sized>(
+    host: &mut H,
+    setup: SetupPacket,
+    data: &[u8],
+) -> Result<(), CheckError> {
+    match host.control_transfer(setup, data) {
+        Err(UsbIpError::UrbFailed(status)) if status == -EPIPE => Ok(()),
+        Err(err) => Err(err.into()),
+        Ok(_) => Err(CheckError::Failed("request was not stalled".to_string())),
+    }
+}
+
+fn get_status<H: UsbHost + ?Sized>(
+    host: &mut H,
+    request_type: u8,
+    index: u16,
+) -> Result<u16, CheckError> {
+    let data = request_in(
+        host,
+        SetupPacket::new(request_type, Request::GET_STATUS, 0, index, 2),
+    )?;
+    Ok(u16::from_le_bytes([data[0], data[1]]))
+}
+
+fn get_configuration<H: UsbHost + ?Sized>(host: &mut H) -> Result<u8, CheckError> {
+    let data = request_in(
+        host,
+        SetupPacket::new(FROM_DEVICE, Request::GET_CONFIGURATION, 0, 0, 1),
+    )?;
+    Ok(data[0])
+}
+
+fn set_configuration<H: UsbHost + ?Sized>(host: &mut H, value: u8) -> Result<(), CheckError> {
+    let setup = SetupPacket::new(TO_DEVICE, Request::SET_CONFIGURATION, value as u16, 0, 0);
+    host.control_transfer(setup, &[])?;
+    Ok(())
+}
+
+/// Runs all compliance checks against the device behind `host`.
+///
+/// Devices, that fail a check, are reported in the [`ComplianceReport`].
+/// An `Err` is only returned, if the host can no longer talk to the device.
+pub fn run<H: UsbHost + ?Sized>(host: &mut H) -> Result<ComplianceReport, UsbIpError> {
+    let mut runner = Runner {
+        host,
+        report: ComplianceReport::default(),
+    };
+
+    let mut enumerated = None;
+    runner.check("enumeration", |host| {
+        enumerated = Some(descriptor::enumerate(host)?);
+        Ok(())
+    })?;
+    let enumerated = match enumerated {
+        Some(enumerated) => enumerated,
+        None => return Ok(runner.report),
+    };
+
+    check_descriptors(&mut runner, &enumerated)?;
+    check_standard_requests(&mut runner, &enumerated)?;
+    check_stalls(&mut runner, &enumerated)?;
+    check_halts(&mut runner, &enumerated)?;
+
+    Ok(runner.report)
+}
+
+fn check_descriptors<H: UsbHost + ?Sized>(
+    runner: &mut Runner<'_, H>,
+    enumerated: &EnumeratedDevice,
+) -> Result<(), UsbIpError> {
+    let device = &enumerated.device;
+
+    runner.check("device descriptor length", |host| {
+        let setup = get_descriptor(descriptor_type::DEVICE, 0, 0, 255);
+        let data = host.control_transfer(setup, &[])?;
+        ensure(data.len() == 18 && data.first() == Some(&18), || {
+            format!(
+                "descriptor has {} bytes and bLength {:?}",
+                data.len(),
+                data.first()
+            )
+        })
+    })?;
+
+    runner.check("device descriptor short read", |host| {
+        request_in(host, get_descriptor(descriptor_type::DEVICE, 0, 0, 8)).map(|_| ())
+    })?;
+
+    runner.check("device descriptor max packet size", |_| {
+        ensure([8, 16, 32, 64].contains(&device.max_packet_size_0), || {
+            format!("invalid bMaxPacketSize0 {}", device.max_packet_size_0)
+        })
+    })?;
+
+    runner.check("device descriptor configurations", |_| {
+        ensure(
+            device.num_configurations as usize == enumerated.configurations.len()
+                && device.num_configurations > 0,
+            || format!("invalid bNumConfigurations {}", device.num_configurations),
+        )
+    })?;
+
+    for (index, config) in enumerated.configurations.iter().enumerate() {
+        runner.check(format!("configuration {} total length", index), |host| {
+            let setup = get_descriptor(descriptor_type::CONFIGURATION, index as u8, 0, 0xffff);
+            let data = host.control_transfer(setup, &[])?;
+            ensure(data.len() == config.total_length as usize, || {
+                format!(
+                    "wTotalLength is {}, but {} bytes were returned",
+                    config.total_length,
+                    data.len()
+                )
+            })
+        })?;
+
+        runner.check(format!("configuration {} interfaces", index), |_| {
+            let num_interfaces = count_interfaces(config);
+            ensure(num_interfaces == config.num_interfaces as usize, || {
+                format!(
+                    "bNumInterfaces is {}, but {} interfaces are described",
+                    config.num_interfaces, num_interfaces
+                )
+            })
+        })?;
+
+        for iface in config.interfaces.iter() {
+            let name = format!(
+                "configuration {} interface {} alternate setting {} endpoints",
+                index, iface.interface_number, iface.alternate_setting
+            );
+            runner.check(name, |_| {
+                ensure(
+                    iface.num_endpoints as usize == iface.endpoints.len(),
+                    || {
+                        format!(
+                            "bNumEndpoints is {}, but {} endpoints are described",
+                            iface.num_endpoints,
+                            iface.endpoints.len()
+                        )
+                    },
+                )?;
+
+                for (i, ep) in iface.endpoints.iter().enumerate() {
+                    ensure(ep.number() != 0, || "endpoint 0 is described".to_string())?;
+                    ensure(
+                        ep.max_packet_size > 0 && ep.max_packet_size & 0x7ff <= 1024,
+                        || format!("invalid wMaxPacketSize {}", ep.max_packet_size),
+                    )?;
+                    ensure(
+                        iface.endpoints[..i]
+                            .iter()
+                            .all(|other| other.address != ep.address),
+                        || format!("endpoint {:#04x} is described twice", ep.address),
+                    )?;
+                }
+
+                Ok(())
+            })?;
+        }
+    }
+
+    runner.check("string descriptors", |_| {
+        let mut indices = vec![
+            device.manufacturer_index,
+            device.product_index,
+            device.serial_number_index,
+        ];
+        for config in enumerated.configurations.iter() {
+            indices.push(config.configuration_index);
+            indices.extend(config.interfaces.iter().map(|iface| iface.interface_index));
+        }
+
+        for index in indices.into_iter().filter(|&index| index != 0) {
+            ensure(!enumerated.languages.is_empty(), || {
+                format!(
+                    "string {} is referenced, but no languages are supported",
+                    index
+                )
+            })?;
+            ensure(enumerated.strings.contains_key(&index), || {
+                format!("string {} is referenced, but could not be read", index)
+            })?;
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+fn check_standard_requests<H: UsbHost + ?Sized>(
+    runner: &mut Runner<'_, H>,
+    enumerated: &EnumeratedDevice,
+) -> Result<(), UsbIpError> {
+    let config = match enumerated.configurations.first() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+
+    runner.check("GET_STATUS device", |host| {
+        let status = get_status(host, FROM_DEVICE, 0)?;
+        ensure(status & !0x0003 == 0, || {
+            format!("reserved status bits are set: {:#06x}", status)
+        })
+    })?;
+
+    runner.check("GET_CONFIGURATION", |host| {
+        let value = get_configuration(host)?;
+        ensure(value == config.configuration_value, || {
+            format!(
+                "expected configuration {}, got {}",
+                config.configuration_value, value
+            )
+        })
+    })?;
+
+    runner.check("SET_CONFIGURATION 0", |host| {
+        set_configuration(host, 0)?;
+        let value = get_configuration(host)?;
+        ensure(value == 0, || {
+            format!("expected configuration 0, got {}", value)
+        })
+    })?;
+
+    runner.check("SET_CONFIGURATION", |host| {
+        set_configuration(host, config.configuration_value)?;
+        let value = get_configuration(host)?;
+        ensure(value == config.configuration_value, || {
+            format!(
+                "expected configuration {}, got {}",
+                config.configuration_value, value
+            )
+        })
+    })?;
+
+    if config.attributes & ATTRIBUTE_REMOTE_WAKEUP != 0 {
+        runner.check("SET_FEATURE DEVICE_REMOTE_WAKEUP", |host| {
+            let setup = SetupPacket::new(
+                TO_DEVICE,
+                Request::SET_FEATURE,
+                Request::FEATURE_DEVICE_REMOTE_WAKEUP,
+                0,
+                0,
+            );
+            host.control_transfer(setup, &[])?;
+            let status = get_status(host, FROM_DEVICE, 0)?;
+            ensure(status & 0x0002 != 0, || {
+                "remote wakeup is not enabled".to_string()
+            })
+        })?;
+
+        runner.check("CLEAR_FEATURE DEVICE_REMOTE_WAKEUP", |host| {
+            let setup = SetupPacket::new(
+                TO_DEVICE,
+                Request::CLEAR_FEATURE,
+                Request::FEATURE_DEVICE_REMOTE_WAKEUP,
+                0,
+                0,
+            );
+            host.control_transfer(setup, &[])?;
+            let status = get_status(host, FROM_DEVICE, 0)?;
+            ensure(status & 0x0002 == 0, || {
+                "remote wakeup is still enabled".to_string()
+            })
+        })?;
+    }
+
+    for iface in config.interfaces.iter() {
+        // Alternate settings are checked once per interface
+        if iface.alternate_setting != 0 {
+            continue;
+        }
+        let number = iface.interface_number as u16;
+
+        runner.check(format!("GET_STATUS interface {}", number), |host| {
+            let status = get_status(host, FROM_INTERFACE, number)?;
+            ensure(status == 0, || {
+                format!("expected status 0, got {:#06x}", status)
+            })
+        })?;
+
+        runner.check(format!("SET_INTERFACE interface {}", number), |host| {
+            let setup = SetupPacket::new(TO_INTERFACE, Request::SET_INTERFACE, 0, number, 0);
+            host.control_transfer(setup, &[])?;
+
+            let setup = SetupPacket::new(FROM_INTERFACE, Request::GET_INTERFACE, 0, number, 1);
+            let data = request_in(host, setup)?;
+            ensure(data[0] == 0, || {
+                format!("expected alternate setting 0, got {}", data[0])
+            })
+        })?;
+    }
+
+    Ok(())
+}
+
+fn check_stalls<H: UsbHost + ?Sized>(
+    runner: &mut Runner<'_, H>,
+    enumerated: &EnumeratedDevice,
+) -> Result<(), UsbIpError> {
+    runner.check("unknown standard request stalls", |host| {
+        expect_stall(host, SetupPacket::new(FROM_DEVICE, 0xff, 0, 0, 2), &[])
+    })?;
+
+    runner.check("GET_DESCRIPTOR of invalid type stalls", |host| {
+        expect_stall(host, get_descriptor(0xff, 0, 0, 255), &[])
+    })?;
+
+    // A device may have strings, that no descriptor references,
+    // so answering the request is suspicious, but not a failure
+    let index = enumerated
+        .strings
+        .keys()
+        .max()
+        .copied()
+        .unwrap_or(0)
+        .checked_add(1);
+    if let (Some(&language_id), Some(index)) = (enumerated.languages.first(), index) {
+        runner.check("GET_DESCRIPTOR of unreferenced string stalls", |host| {
+            let setup = get_descriptor(descriptor_type::STRING, index, language_id, 255);
+            match expect_stall(host, setup, &[]) {
+                Err(CheckError::Failed(_)) => Err(CheckError::Warning(format!(
+                    "string {} is not referenced by any descriptor, but was returned",
+                    index
+                ))),
+                result => result,
+            }
+        })?;
+    }
+
+    let config = match enumerated.configurations.first() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+
+    let value = enumerated
+        .configurations
+        .iter()
+        .map(|config| config.configuration_value)
+        .max()
+        .unwrap_or(0)
+        .checked_add(1);
+    if let Some(value) = value {
+        runner.check("SET_CONFIGURATION of invalid value stalls", |host| {
+            let setup = SetupPacket::new(TO_DEVICE, Request::SET_CONFIGURATION, value as u16, 0, 0);
+            expect_stall(host, setup, &[])
+        })?;
+    }
+
+    for iface in config.interfaces.iter() {
+        if iface.alternate_setting != 0 {
+            continue;
+        }
+        let number = iface.interface_number;
+
+        let alternate_setting = config
+            .interfaces
+            .iter()
+            .filter(|other| other.interface_number == number)
+            .map(|other| other.alternate_setting)
+            .max()
+            .unwrap_or(0)
+            .checked_add(1);
+        let alternate_setting = match alternate_setting {
+            Some(alternate_setting) => alternate_setting,
+            None => continue,
+        };
+
+        let name = format!(
+            "SET_INTERFACE of invalid alternate setting of interface {} stalls",
+            number
+        );
+        runner.check(name, |host| {
+            let setup = SetupPacket::new(
+                TO_INTERFACE,
+                Request::SET_INTERFACE,
+                alternate_setting as u16,
+                number as u16,
+                0,
+            );
+            expect_stall(host, setup, &[])
+        })?;
+    }
+
+    Ok(())
+}
+
+fn check_halts<H: UsbHost + ?Sized>(
+    runner: &mut Runner<'_, H>,
+    enumerated: &EnumeratedDevice,
+) -> Result<(), UsbIpError> {
+    let config = match enumerated.configurations.first() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+
+    let endpoints = config
+        .interfaces
+        .iter()
+        .filter(|iface| iface.alternate_setting == 0)
+        .flat_map(|iface| iface.endpoints.iter())
+        .filter(|ep| ep.transfer_type() != EndpointType::Control);
+
+    for ep in endpoints {
+        let address = ep.address as u16;
+        let halt = |host: &mut H, request: u8| {
+            let setup = SetupPacket::new(
+                TO_ENDPOINT,
+                request,
+                Request::FEATURE_ENDPOINT_HALT,
+                address,
+                0,
+            );
+            host.control_transfer(setup, &[]).map(|_| ())
+        };
+
+        runner.check(format!("GET_STATUS endpoint {:#04x}", address), |host| {
+            let status = get_status(host, FROM_ENDPOINT, address)?;
+            ensure(status == 0, || {
+                format!("expected status 0, got {:#06x}", status)
+            })
+        })?;
+
+        let halted = runner.check(
+            format!("SET_FEATURE ENDPOINT_HALT {:#04x}", address),
+            |host| {
+                halt(host, Request::SET_FEATURE)?;
+                let status = get_status(host, FROM_ENDPOINT, address)?;
+                ensure(status == 1, || {
+                    format!("expected status 1, got {:#06x}", status)
+                })
+            },
+        )?;
+
+        if halted {
+            runner.check(format!("halted endpoint {:#04x} stalls", address), |host| {
+                let seqnum = match ep.direction() {
+                    UsbDirection::In => host.submit(ep.number(), Direction::IN, None, &[], 1)?,
+                    UsbDirection::Out => host.submit(ep.number(), Direction::OUT, None, &[0], 0)?,
+                };
+
+                match host.wait(seqnum) {
+                    Ok(completion) if completion.status == -EPIPE => Ok(()),
+                    Ok(completion) => Err(CheckError::Failed(format!(
+                        "urb completed with status {}",
+                        completion.status
+                    ))),
+                    Err(UsbIpError::Timeout) => {
+                        host.unlink(seqnum)?;
+                        Err(CheckError::Failed("urb was not completed".to_string()))
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            })?;
+        }
+
+        runner.check(
+            format!("CLEAR_FEATURE ENDPOINT_HALT {:#04x}", address),
+            |host| {
+                halt(host, Request::CLEAR_FEATURE)?;
+                let status = get_status(host, FROM_ENDPOINT, address)?;
+                ensure(status == 0, || {
+                    format!("expected status 0, got {:#06x}", status)
+                })
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Counts the distinct interface numbers of a configuration.
+fn count_interfaces(config: &ConfigurationDescriptor) -> usize {
+    let mut numbers: Vec<u8> = config
+        .interfaces
+        .iter()
+        .map(|iface| iface.interface_number)
+        .collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+    numbers.len()
+}
@@ -1,34 +1,91 @@
 //! A collection of functions which allow for better debug output.
+//!
+//! The `Debug` output of urbs renders setup packets as the request they encode,
+//! e.g. `GET_DESCRIPTOR(CONFIGURATION, idx=0, wLength=255)`, and decodes the
+//! standard descriptors returned by control transfers.
+//! Long payloads are truncated to the length set by [`set_payload_limit`].
+//!
+//! A [`ClassDecoder`] additionally decodes the requests and payloads of the
+//! CDC, HID and mass storage classes, based on the interface descriptors it
+//! snooped from the enumeration.
 
-use std::fmt::{Debug, Formatter, LowerHex, Result as FmtResult};
+use crate::{
+    class::{
+        cdc::{self, USB_CLASS_CDC, USB_CLASS_CDC_DATA},
+        hid::{self, USB_CLASS_HID},
+        msc::{self, USB_CLASS_MSC},
+    },
+    client::SetupPacket,
+    cmd::Direction,
+    descriptor::{
+        ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor,
+    },
+    request::{UsbIpCmdSubmit, UsbIpRequest, UsbIpRequestCmd},
+    response::{UsbIpResponse, UsbIpResponseCmd},
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display, Formatter, LowerHex, Result as FmtResult, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use usb_device::descriptor::descriptor_type;
+
+/// The number of payload bytes, that are printed by default
+pub const DEFAULT_PAYLOAD_LIMIT: usize = 64;
+
+static PAYLOAD_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_PAYLOAD_LIMIT);
+
+/// Sets the number of bytes, after which payloads are truncated in the debug output.
+///
+/// `None` prints payloads completely.
+pub fn set_payload_limit(limit: Option<usize>) {
+    PAYLOAD_LIMIT.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// Returns the number of bytes, after which payloads are truncated in the debug output.
+pub fn payload_limit() -> Option<usize> {
+    match PAYLOAD_LIMIT.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        limit => Some(limit),
+    }
+}
 
 /// Just a thin wrapper to allow for printing in hexadecimal
 #[derive(Clone)]
-pub struct DbgBuf<'a>(pub &'a [u8]);
+pub(crate) struct DbgBuf<'a>(pub &'a [u8]);
 
 impl Debug for DbgBuf<'_> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        if self.0.len() == 0 {
+        if self.0.is_empty() {
             return f.write_str("[]");
         }
 
         f.write_str("[ ")?;
         f.write_fmt(format_args!("{:02x}", self))?;
-        f.write_str("]")
+        f.write_str("]")?;
+
+        if self.0.len() > PAYLOAD_LIMIT.load(Ordering::Relaxed) {
+            f.write_fmt(format_args!(" ({} bytes)", self.0.len()))?;
+        }
+        Ok(())
     }
 }
 
 impl LowerHex for DbgBuf<'_> {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        for byte in self.0 {
+        let limit = PAYLOAD_LIMIT.load(Ordering::Relaxed);
+        for byte in self.0.iter().take(limit) {
             (fmt.write_fmt(format_args!("{:02x} ", byte)))?;
         }
+        if self.0.len() > limit {
+            fmt.write_str(".. ")?;
+        }
         Ok(())
     }
 }
 
 #[derive(Clone)]
-pub struct DbgEmpty;
+pub(crate) struct DbgEmpty;
 
 impl Debug for DbgEmpty {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
@@ -36,4 +93,933 @@ impl Debug for DbgEmpty {
     }
 }
 
-// TODO: Better output of Setup packets
+/// Prints a setup packet as the request it encodes.
+#[derive(Clone)]
+pub(crate) struct DbgSetup<'a>(pub &'a [u8; 8]);
+
+impl Debug for DbgSetup<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let setup = self.0;
+        let request_type = setup[0];
+        let request = setup[1];
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+        let length = u16::from_le_bytes([setup[6], setup[7]]);
+
+        // Class and vendor requests are only known to the class drivers
+        match request_type & 0x60 {
+            0x00 => (),
+            // USBIP forwards a reset of the port as SET_FEATURE(PORT_RESET) to the device
+            0x20 if request_type == 0x23 && request == 0x03 && value == 0x0004 => {
+                return f.write_str("SET_PORT_FEATURE(PORT_RESET)")
+            }
+            ty => {
+                let kind = match ty {
+                    0x20 => "CLASS",
+                    0x40 => "VENDOR",
+                    _ => "RESERVED",
+                };
+                return write!(
+                    f,
+                    "{}_{}(type={:#04x}, request={:#04x}, wValue={:#06x}, wIndex={:#06x}, wLength={})",
+                    kind,
+                    direction_name(request_type),
+                    request_type,
+                    request,
+                    value,
+                    index,
+                    length
+                );
+            }
+        }
+
+        match request {
+            0x00 => write!(f, "GET_STATUS({}, wLength={})", Recipient(request_type, index), length),
+            0x01 | 0x03 => {
+                let name = if request == 0x01 { "CLEAR_FEATURE" } else { "SET_FEATURE" };
+                let feature = match (request_type & 0x1f, value) {
+                    (0, 1) => "DEVICE_REMOTE_WAKEUP".to_string(),
+                    (0, 2) => "TEST_MODE".to_string(),
+                    (2, 0) => "ENDPOINT_HALT".to_string(),
+                    (_, feature) => format!("{:#06x}", feature),
+                };
+                write!(f, "{}({}, {})", name, feature, Recipient(request_type, index))
+            }
+            0x05 => write!(f, "SET_ADDRESS({})", value),
+            0x06 | 0x07 => {
+                let name = if request == 0x06 { "GET_DESCRIPTOR" } else { "SET_DESCRIPTOR" };
+                let ty = (value >> 8) as u8;
+                write!(f, "{}({}, idx={}", name, DescriptorType(ty), value & 0xff)?;
+                match (ty, request_type & 0x1f) {
+                    (descriptor_type::STRING, _) => write!(f, ", lang={:#06x}", index)?,
+                    (_, 0) => (),
+                    _ => write!(f, ", {}", Recipient(request_type, index))?,
+                }
+                write!(f, ", wLength={})", length)
+            }
+            0x08 => write!(f, "GET_CONFIGURATION(wLength={})", length),
+            0x09 => write!(f, "SET_CONFIGURATION({})", value),
+            0x0a => write!(f, "GET_INTERFACE(interface={}, wLength={})", index, length),
+            0x0b => write!(f, "SET_INTERFACE(interface={}, alt={})", index, value),
+            0x0c => write!(f, "SYNCH_FRAME(endpoint={:#04x})", index),
+            _ => write!(
+                f,
+                "STANDARD_{}(type={:#04x}, request={:#04x}, wValue={:#06x}, wIndex={:#06x}, wLength={})",
+                direction_name(request_type),
+                request_type,
+                request,
+                value,
+                index,
+                length
+            ),
+        }
+    }
+}
+
+fn direction_name(request_type: u8) -> &'static str {
+    if request_type & 0x80 != 0 {
+        "IN"
+    } else {
+        "OUT"
+    }
+}
+
+/// The recipient of a request, as encoded in the request type and `wIndex`.
+struct Recipient(u8, u16);
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self.0 & 0x1f {
+            0 => f.write_str("device"),
+            1 => write!(f, "interface={}", self.1),
+            2 => write!(f, "endpoint={:#04x}", self.1),
+            3 => write!(f, "other={}", self.1),
+            recipient => write!(f, "recipient={}, wIndex={:#06x}", recipient, self.1),
+        }
+    }
+}
+
+/// The name of a descriptor type.
+struct DescriptorType(u8);
+
+impl Display for DescriptorType {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let name = match self.0 {
+            descriptor_type::DEVICE => "DEVICE",
+            descriptor_type::CONFIGURATION => "CONFIGURATION",
+            descriptor_type::STRING => "STRING",
+            descriptor_type::INTERFACE => "INTERFACE",
+            descriptor_type::ENDPOINT => "ENDPOINT",
+            0x06 => "DEVICE_QUALIFIER",
+            0x07 => "OTHER_SPEED_CONFIGURATION",
+            0x08 => "INTERFACE_POWER",
+            0x09 => "OTG",
+            0x0a => "DEBUG",
+            descriptor_type::IAD => "INTERFACE_ASSOCIATION",
+            descriptor_type::BOS => "BOS",
+            descriptor_type::CAPABILITY => "DEVICE_CAPABILITY",
+            0x21 => "HID",
+            0x22 => "REPORT",
+            0x23 => "PHYSICAL",
+            0x24 => "CS_INTERFACE",
+            0x25 => "CS_ENDPOINT",
+            ty => return write!(f, "{:#04x}", ty),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Prints the payload of a control transfer, decoding the standard descriptors in it.
+///
+/// Payloads, that do not consist of descriptors, are printed in hexadecimal.
+#[derive(Clone)]
+pub(crate) struct DbgControlData<'a>(pub &'a [u8]);
+
+impl Debug for DbgControlData<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if !is_descriptor_list(self.0) {
+            return Debug::fmt(&DbgBuf(self.0), f);
+        }
+
+        let mut list = f.debug_list();
+        let mut rest = self.0;
+        while !rest.is_empty() {
+            let len = usize::min(rest[0] as usize, rest.len());
+            list.entry(&DbgDescriptor(&rest[..len]));
+            rest = &rest[len..];
+        }
+        list.finish()
+    }
+}
+
+/// Returns `true` if `data` starts with a standard descriptor and
+/// consists of complete descriptors, except for a possibly truncated last one.
+fn is_descriptor_list(data: &[u8]) -> bool {
+    let expected_len = match data {
+        [_, descriptor_type::DEVICE, ..] => DeviceDescriptor::SIZE,
+        [_, descriptor_type::CONFIGURATION, ..] | [_, 0x07, ..] => ConfigurationDescriptor::SIZE,
+        [_, descriptor_type::STRING, ..] => return data[0] as usize >= 2 && data[0] & 1 == 0,
+        [_, descriptor_type::BOS, ..] => 5,
+        [_, 0x06, ..] => 10,
+        _ => return false,
+    };
+    if data[0] as usize != expected_len {
+        return false;
+    }
+
+    let mut rest = data;
+    while rest.len() >= 2 {
+        let len = rest[0] as usize;
+        if len < 2 {
+            return false;
+        }
+        rest = &rest[usize::min(len, rest.len())..];
+    }
+    rest.is_empty()
+}
+
+/// Prints a single descriptor.
+struct DbgDescriptor<'a>(&'a [u8]);
+
+impl Debug for DbgDescriptor<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let data = self.0;
+        let ty = data.get(1).copied().unwrap_or(0);
+
+        match ty {
+            descriptor_type::DEVICE => {
+                if let Ok(dev) = DeviceDescriptor::from_slice(data) {
+                    return write!(
+                        f,
+                        "DEVICE(usb={}, class={:02x}/{:02x}/{:02x}, mps0={}, id={:04x}:{:04x}, release={}, strings={}/{}/{}, configurations={})",
+                        Bcd(dev.usb_version),
+                        dev.device_class,
+                        dev.device_subclass,
+                        dev.device_protocol,
+                        dev.max_packet_size_0,
+                        dev.vendor_id,
+                        dev.product_id,
+                        Bcd(dev.device_version),
+                        dev.manufacturer_index,
+                        dev.product_index,
+                        dev.serial_number_index,
+                        dev.num_configurations
+                    );
+                }
+            }
+            descriptor_type::CONFIGURATION | 0x07
+                if data.len() >= ConfigurationDescriptor::SIZE =>
+            {
+                return write!(
+                    f,
+                    "{}(total={}, interfaces={}, value={}, idx={}, attributes={:#04x}, power={}mA)",
+                    DescriptorType(ty),
+                    u16::from_le_bytes([data[2], data[3]]),
+                    data[4],
+                    data[5],
+                    data[6],
+                    data[7],
+                    data[8] as u32 * 2
+                );
+            }
+            descriptor_type::STRING if data.len() == data[0] as usize => {
+                let chars: Vec<u16> = data[2..]
+                    .chunks_exact(2)
+                    .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+                    .collect();
+                let string = String::from_utf16_lossy(&chars);
+
+                // The response does not tell, whether it is the list of languages
+                // of string 0, which mostly decode as cyrillic characters
+                if string
+                    .chars()
+                    .all(|c| c.is_ascii() && !c.is_ascii_control())
+                {
+                    return write!(f, "STRING({:?})", string);
+                }
+                let langs: Vec<_> = chars.iter().map(|lang| format!("{:#06x}", lang)).collect();
+                return write!(f, "STRING({:?}, langs=[{}])", string, langs.join(", "));
+            }
+            descriptor_type::INTERFACE => {
+                if let Ok(interface) = InterfaceDescriptor::from_slice(data) {
+                    return write!(
+                        f,
+                        "INTERFACE({}.{}, class={:02x}/{:02x}/{:02x}, endpoints={}, idx={})",
+                        interface.interface_number,
+                        interface.alternate_setting,
+                        interface.interface_class,
+                        interface.interface_subclass,
+                        interface.interface_protocol,
+                        interface.num_endpoints,
+                        interface.interface_index
+                    );
+                }
+            }
+            descriptor_type::ENDPOINT => {
+                if let Ok(endpoint) = EndpointDescriptor::from_slice(data) {
+                    return write!(
+                        f,
+                        "ENDPOINT({:#04x}, {:?}, mps={}, interval={})",
+                        endpoint.address,
+                        endpoint.transfer_type(),
+                        endpoint.max_packet_size,
+                        endpoint.interval
+                    );
+                }
+            }
+            descriptor_type::IAD if data.len() >= 8 => {
+                return write!(
+                    f,
+                    "INTERFACE_ASSOCIATION(first={}, count={}, class={:02x}/{:02x}/{:02x})",
+                    data[2], data[3], data[4], data[5], data[6]
+                );
+            }
+            _ => (),
+        }
+
+        // Class specific and truncated descriptors are printed raw
+        write!(f, "{}{:?}", DescriptorType(ty), DbgBuf(data))
+    }
+}
+
+/// A version number in binary coded decimal.
+struct Bcd(u16);
+
+impl Display for Bcd {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{:x}.{:02x}", self.0 >> 8, self.0 & 0xff)
+    }
+}
+
+/// Prints a byte stream as escaped text.
+#[derive(Clone)]
+pub(crate) struct DbgText<'a>(pub &'a [u8]);
+
+impl Debug for DbgText<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let limit = PAYLOAD_LIMIT.load(Ordering::Relaxed);
+        f.write_str("b\"")?;
+        for byte in self.0.iter().take(limit) {
+            for c in std::ascii::escape_default(*byte) {
+                f.write_char(c as char)?;
+            }
+        }
+        f.write_str("\"")?;
+
+        if self.0.len() > limit {
+            write!(f, ".. ({} bytes)", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// The classes, whose traffic the [`ClassDecoder`] decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodedClass {
+    Cdc,
+    CdcData,
+    Hid,
+    MassStorage,
+}
+
+impl DecodedClass {
+    fn from_code(class: u8) -> Option<Self> {
+        match class {
+            USB_CLASS_CDC => Some(Self::Cdc),
+            USB_CLASS_CDC_DATA => Some(Self::CdcData),
+            USB_CLASS_HID => Some(Self::Hid),
+            USB_CLASS_MSC => Some(Self::MassStorage),
+            _ => None,
+        }
+    }
+
+    fn describe_request(self, setup: &SetupPacket) -> Option<String> {
+        match self {
+            Self::Cdc | Self::CdcData => cdc::describe_request(setup),
+            Self::Hid => hid::describe_request(setup),
+            Self::MassStorage => msc::describe_request(setup),
+        }
+    }
+
+    fn describe_control_data(self, setup: &SetupPacket, data: &[u8]) -> Option<String> {
+        match self {
+            Self::Cdc | Self::CdcData => cdc::describe_control_data(setup, data),
+            _ => None,
+        }
+    }
+}
+
+/// A submitted urb, as far as the decoder needs to remember it.
+#[derive(Debug, Clone, Copy)]
+struct SubmittedUrb {
+    /// The endpoint address including the direction bit
+    address: u8,
+    setup: [u8; 8],
+}
+
+/// A decoder, that prints urbs with the payloads of known classes decoded.
+///
+/// The decoder learns the classes of the interfaces and endpoints from the configuration
+/// descriptors passing through it, so it has to see the urbs of the enumeration.
+/// It also remembers the submitted urbs, such that completions can be decoded according
+/// to the urb they complete, even if the server does not repeat the endpoint.
+///
+/// ```
+/// use usbip_device::{client::SetupPacket, debug::ClassDecoder, protocol::*};
+///
+/// // GET_DESCRIPTOR(CONFIGURATION, idx=0, wLength=255)
+/// let setup = SetupPacket::new(0x80, 0x06, 0x0200, 0, 255);
+/// let request = UsbIpRequest {
+///     header: UsbIpHeader {
+///         command: UsbCmd::Request,
+///         seqnum: 1,
+///         devid: 1 << 16 | 2,
+///         direction: Direction::IN,
+///         ep: 0,
+///     },
+///     cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+///         transfer_flags: TransferFlags::empty(),
+///         transfer_buffer_length: 255,
+///         start_frame: 0,
+///         number_of_packets: 0,
+///         interval: 0,
+///         setup: setup.to_array(),
+///     }),
+///     data: Vec::new(),
+/// };
+///
+/// let mut decoder = ClassDecoder::new();
+/// log::debug!("{:?}", decoder.request(&request));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClassDecoder {
+    /// The classes of the interfaces by interface number
+    interfaces: HashMap<u8, DecodedClass>,
+    /// The classes of the endpoints by address
+    endpoints: HashMap<u8, DecodedClass>,
+    /// The urbs, that have been submitted but not completed, by sequence number
+    submitted: HashMap<u32, SubmittedUrb>,
+}
+
+impl ClassDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers the request and returns it for printing.
+    pub fn request<'a>(&'a mut self, request: &'a UsbIpRequest) -> DbgRequest<'a> {
+        match request.cmd {
+            UsbIpRequestCmd::Cmd(ref cmd) => {
+                let address = match request.header.direction {
+                    Direction::IN => request.header.ep as u8 | 0x80,
+                    _ => request.header.ep as u8,
+                };
+                let urb = SubmittedUrb {
+                    address,
+                    setup: cmd.setup,
+                };
+                self.submitted.insert(request.header.seqnum, urb);
+            }
+            // Whether the urb completes or is unlinked, there is nothing left to decode
+            UsbIpRequestCmd::Unlink(ref unlink) => {
+                self.submitted.remove(&unlink.seqnum);
+            }
+        }
+
+        DbgRequest {
+            decoder: self,
+            request,
+        }
+    }
+
+    /// Learns the classes from the response and returns it for printing.
+    pub fn response<'a>(&'a mut self, response: &'a UsbIpResponse) -> DbgResponse<'a> {
+        let urb = match response.cmd {
+            UsbIpResponseCmd::Cmd(_) => self.submitted.remove(&response.header.seqnum),
+            UsbIpResponseCmd::Unlink(_) => None,
+        };
+
+        if let Some(urb) = urb {
+            let setup = SetupPacket::from_array(&urb.setup);
+            let is_get_configuration = setup.request_type == 0x80
+                && setup.request == 0x06
+                && setup.value >> 8 == descriptor_type::CONFIGURATION as u16;
+            if urb.address == 0x80 && is_get_configuration {
+                self.learn(&response.data);
+            }
+        }
+
+        DbgResponse {
+            decoder: self,
+            response,
+            urb,
+        }
+    }
+
+    /// Learns the classes of the interfaces and endpoints of a configuration descriptor.
+    fn learn(&mut self, data: &[u8]) {
+        let config = match ConfigurationDescriptor::from_slice(data) {
+            Ok(config) => config,
+            Err(_) => return,
+        };
+
+        for interface in &config.interfaces {
+            let class = match DecodedClass::from_code(interface.interface_class) {
+                Some(class) => class,
+                None => continue,
+            };
+
+            self.interfaces.insert(interface.interface_number, class);
+            for endpoint in &interface.endpoints {
+                self.endpoints.insert(endpoint.address, class);
+            }
+        }
+    }
+
+    /// Returns the class, that a class request is directed to.
+    fn request_class(&self, setup: &SetupPacket) -> Option<DecodedClass> {
+        if setup.request_type & 0x60 != 0x20 {
+            return None;
+        }
+
+        match setup.request_type & 0x1f {
+            1 => self.interfaces.get(&(setup.index as u8)).copied(),
+            2 => self.endpoints.get(&(setup.index as u8)).copied(),
+            _ => None,
+        }
+    }
+
+    /// Prints the setup packet of a control transfer.
+    fn fmt_setup(&self, f: &mut Formatter, setup: &[u8; 8]) -> FmtResult {
+        let packet = SetupPacket::from_array(setup);
+        let described = self
+            .request_class(&packet)
+            .and_then(|class| class.describe_request(&packet));
+
+        match described {
+            Some(described) => f.write_str(&described),
+            None => DbgSetup(setup).fmt(f),
+        }
+    }
+
+    /// Prints the payload of an urb.
+    fn fmt_data(&self, f: &mut Formatter, urb: Option<&SubmittedUrb>, data: &[u8]) -> FmtResult {
+        let urb = match urb {
+            Some(urb) => urb,
+            None => return Debug::fmt(&DbgBuf(data), f),
+        };
+
+        if urb.address & 0x7f == 0 {
+            let setup = SetupPacket::from_array(&urb.setup);
+            let described = self
+                .request_class(&setup)
+                .and_then(|class| class.describe_control_data(&setup, data));
+            return match described {
+                Some(described) => f.write_str(&described),
+                None => DbgControlData(data).fmt(f),
+            };
+        }
+
+        let described = match self.endpoints.get(&urb.address) {
+            Some(DecodedClass::Cdc) => cdc::describe_notification(data),
+            Some(DecodedClass::CdcData) => return DbgText(data).fmt(f),
+            Some(DecodedClass::MassStorage) => msc::describe_data(data),
+            _ => None,
+        };
+        match described {
+            Some(described) => f.write_str(&described),
+            None => Debug::fmt(&DbgBuf(data), f),
+        }
+    }
+}
+
+/// A request, printed by a [`ClassDecoder`].
+pub struct DbgRequest<'a> {
+    decoder: &'a ClassDecoder,
+    request: &'a UsbIpRequest,
+}
+
+impl Debug for DbgRequest<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let cmd = match self.request.cmd {
+            UsbIpRequestCmd::Cmd(ref cmd) => cmd,
+            UsbIpRequestCmd::Unlink(_) => return self.request.fmt(f),
+        };
+        let urb = self.decoder.submitted.get(&self.request.header.seqnum);
+
+        let cmd = DbgCmd {
+            decoder: self.decoder,
+            cmd,
+            ep: self.request.header.ep,
+        };
+        let data = DbgData {
+            decoder: self.decoder,
+            urb,
+            data: &self.request.data,
+        };
+
+        f.debug_struct("UsbIpRequest")
+            .field("header", &self.request.header)
+            .field("cmd", &cmd)
+            .field("data", &data)
+            .finish()
+    }
+}
+
+/// A response, printed by a [`ClassDecoder`].
+pub struct DbgResponse<'a> {
+    decoder: &'a ClassDecoder,
+    response: &'a UsbIpResponse,
+    urb: Option<SubmittedUrb>,
+}
+
+impl Debug for DbgResponse<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.urb.is_none() {
+            return self.response.fmt(f);
+        }
+
+        let data = DbgData {
+            decoder: self.decoder,
+            urb: self.urb.as_ref(),
+            data: &self.response.data,
+        };
+
+        f.debug_struct("UsbIpResponse")
+            .field("header", &self.response.header)
+            .field("cmd", &self.response.cmd)
+            .field("data", &data)
+            .finish()
+    }
+}
+
+/// Prints a submit command with the setup packet decoded by the [`ClassDecoder`].
+struct DbgCmd<'a> {
+    decoder: &'a ClassDecoder,
+    cmd: &'a UsbIpCmdSubmit,
+    ep: u32,
+}
+
+impl Debug for DbgCmd<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str("Cmd(")?;
+        f.debug_struct("UsbIpCmdSubmit")
+            .field("transfer_flags", &self.cmd.transfer_flags)
+            .field("transfer_buffer_length", &self.cmd.transfer_buffer_length)
+            .field("setup", &DbgClassSetup(self))
+            .finish()?;
+        f.write_str(")")
+    }
+}
+
+/// Prints the setup packet of a [`DbgCmd`].
+struct DbgClassSetup<'a>(&'a DbgCmd<'a>);
+
+impl Debug for DbgClassSetup<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self.0.ep {
+            0 => self.0.decoder.fmt_setup(f, &self.0.cmd.setup),
+            _ => DbgEmpty.fmt(f),
+        }
+    }
+}
+
+/// Prints the payload of an urb decoded by the [`ClassDecoder`].
+struct DbgData<'a> {
+    decoder: &'a ClassDecoder,
+    urb: Option<&'a SubmittedUrb>,
+    data: &'a [u8],
+}
+
+impl Debug for DbgData<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.decoder.fmt_data(f, self.urb, self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{TransferFlags, UsbCmd, UsbIpHeader};
+
+    /// The device descriptor of a CDC device with id 1209:0001
+    const DEVICE: [u8; 18] = [
+        18, 1, 0x00, 0x02, 0x02, 0x00, 0x00, 8, 0x09, 0x12, 0x01, 0x00, 0x10, 0x00, 1, 2, 3, 1,
+    ];
+
+    /// A configuration with one interface and one bulk in endpoint
+    const CONFIGURATION: [u8; 25] = [
+        9, 2, 25, 0, 1, 1, 0, 0x80, 50, //
+        9, 4, 0, 0, 1, 0xff, 0, 0, 0, //
+        7, 5, 0x81, 2, 64, 0, 0,
+    ];
+
+    #[test]
+    fn setup_packets() {
+        let cases: [([u8; 8], &str); 6] = [
+            (
+                [0x80, 0x06, 0x00, 0x02, 0, 0, 0xff, 0],
+                "GET_DESCRIPTOR(CONFIGURATION, idx=0, wLength=255)",
+            ),
+            (
+                [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0xff, 0],
+                "GET_DESCRIPTOR(STRING, idx=2, lang=0x0409, wLength=255)",
+            ),
+            ([0x00, 0x09, 1, 0, 0, 0, 0, 0], "SET_CONFIGURATION(1)"),
+            (
+                [0x02, 0x01, 0, 0, 0x81, 0, 0, 0],
+                "CLEAR_FEATURE(ENDPOINT_HALT, endpoint=0x81)",
+            ),
+            (
+                [0x23, 0x03, 0x04, 0, 1, 0, 0, 0],
+                "SET_PORT_FEATURE(PORT_RESET)",
+            ),
+            (
+                [0xc0, 0x01, 0x34, 0x12, 0, 0, 4, 0],
+                "VENDOR_IN(type=0xc0, request=0x01, wValue=0x1234, wIndex=0x0000, wLength=4)",
+            ),
+        ];
+
+        for (setup, expected) in &cases {
+            assert_eq!(format!("{:?}", DbgSetup(setup)), *expected);
+        }
+    }
+
+    #[test]
+    fn descriptors() {
+        assert_eq!(
+            format!("{:?}", DbgControlData(&DEVICE)),
+            "[DEVICE(usb=2.00, class=02/00/00, mps0=8, id=1209:0001, release=0.10, strings=1/2/3, configurations=1)]"
+        );
+        assert_eq!(
+            format!("{:?}", DbgControlData(&CONFIGURATION)),
+            "[CONFIGURATION(total=25, interfaces=1, value=1, idx=0, attributes=0x80, power=100mA), \
+             INTERFACE(0.0, class=ff/00/00, endpoints=1, idx=0), \
+             ENDPOINT(0x81, Bulk, mps=64, interval=0)]"
+        );
+
+        let string = [8, 3, b'a', 0, b'b', 0, b'c', 0];
+        assert_eq!(
+            format!("{:?}", DbgControlData(&string)),
+            "[STRING(\"abc\")]"
+        );
+        let languages = [4, 3, 0x09, 0x04];
+        assert_eq!(
+            format!("{:?}", DbgControlData(&languages)),
+            "[STRING(\"\u{409}\", langs=[0x0409])]"
+        );
+    }
+
+    #[test]
+    fn truncated_descriptors() {
+        // The host often only reads the first bytes of a descriptor
+        assert_eq!(
+            format!("{:?}", DbgControlData(&DEVICE[..8])),
+            format!("[DEVICE{:?}]", DbgBuf(&DEVICE[..8]))
+        );
+        assert_eq!(
+            format!("{:?}", DbgControlData(&CONFIGURATION[..20])),
+            format!(
+                "[CONFIGURATION(total=25, interfaces=1, value=1, idx=0, attributes=0x80, power=100mA), \
+                 INTERFACE(0.0, class=ff/00/00, endpoints=1, idx=0), \
+                 ENDPOINT{:?}]",
+                DbgBuf(&CONFIGURATION[18..20])
+            )
+        );
+
+        // Anything, that does not start with a complete header, is printed raw
+        for len in 0..2 {
+            let data = &CONFIGURATION[..len];
+            assert!(!is_descriptor_list(data));
+            assert_eq!(
+                format!("{:?}", DbgControlData(data)),
+                format!("{:?}", DbgBuf(data))
+            );
+        }
+        assert!(!is_descriptor_list(&[9, 2, 25, 0, 1, 1, 0, 0x80, 50, 0, 4]));
+        assert!(!is_descriptor_list(&[3, 3, b'a']));
+    }
+
+    /// A configuration with a serial port, a mass storage and a HID interface
+    const COMPOSITE: [u8; 87] = [
+        9, 2, 87, 0, 4, 1, 0, 0x80, 50, //
+        9, 4, 0, 0, 1, 0x02, 0x02, 0x01, 0, //
+        7, 5, 0x83, 3, 8, 0, 16, //
+        9, 4, 1, 0, 2, 0x0a, 0, 0, 0, //
+        7, 5, 0x82, 2, 64, 0, 0, //
+        7, 5, 0x01, 2, 64, 0, 0, //
+        9, 4, 2, 0, 2, 0x08, 0x06, 0x50, 0, //
+        7, 5, 0x84, 2, 64, 0, 0, //
+        7, 5, 0x04, 2, 64, 0, 0, //
+        9, 4, 3, 0, 1, 0x03, 0, 0, 0, //
+        7, 5, 0x85, 3, 8, 0, 10,
+    ];
+
+    fn submit(seqnum: u32, address: u8, setup: [u8; 8], data: &[u8]) -> UsbIpRequest {
+        let direction = match address & 0x80 {
+            0 => Direction::OUT,
+            _ => Direction::IN,
+        };
+        UsbIpRequest {
+            header: UsbIpHeader {
+                command: UsbCmd::Request,
+                seqnum,
+                devid: 1 << 16 | 2,
+                direction,
+                ep: (address & 0x7f) as u32,
+            },
+            cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+                transfer_flags: TransferFlags::empty(),
+                transfer_buffer_length: 64,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup,
+            }),
+            data: data.to_vec(),
+        }
+    }
+
+    /// Submits an urb and returns the printed request and the printed completion with `data`.
+    fn transfer(
+        decoder: &mut ClassDecoder,
+        address: u8,
+        setup: [u8; 8],
+        data: &[u8],
+    ) -> (String, String) {
+        let seqnum = decoder.submitted.len() as u32 + 1;
+        let (out_data, in_data) = match address & 0x80 {
+            0 => (data, &[][..]),
+            _ => (&[][..], data),
+        };
+
+        let request = submit(seqnum, address, setup, out_data);
+        let request = format!("{:?}", decoder.request(&request));
+        let response = UsbIpResponse::ret_submit(
+            &submit(seqnum, address, setup, &[]).header,
+            0,
+            in_data.len(),
+            in_data.to_vec(),
+        );
+        let response = format!("{:?}", decoder.response(&response));
+        (request, response)
+    }
+
+    /// Returns a decoder, that has seen the enumeration of [`COMPOSITE`].
+    fn composite_decoder() -> ClassDecoder {
+        let mut decoder = ClassDecoder::new();
+        let get_configuration = [0x80, 0x06, 0x00, 0x02, 0, 0, 0xff, 0];
+        transfer(&mut decoder, 0x80, get_configuration, &COMPOSITE);
+        decoder
+    }
+
+    #[test]
+    fn class_requests() {
+        let mut decoder = ClassDecoder::new();
+        let set_line_coding = [0x21, 0x20, 0, 0, 0, 0, 7, 0];
+        let line_coding = [0x80, 0x25, 0, 0, 2, 2, 7];
+
+        // Without the enumeration, the interface is unknown
+        let (request, _) = transfer(&mut decoder, 0x00, set_line_coding, &line_coding);
+        assert!(request.contains("CLASS_OUT(type=0x21, request=0x20"));
+
+        let mut decoder = composite_decoder();
+        let (request, _) = transfer(&mut decoder, 0x00, set_line_coding, &line_coding);
+        assert!(request.contains("setup: SET_LINE_CODING"));
+        assert!(request.contains("data: 9600 7E2"));
+        let (request, response) = transfer(
+            &mut decoder,
+            0x80,
+            [0xa1, 0x21, 0, 0, 0, 0, 7, 0],
+            &line_coding,
+        );
+        assert!(request.contains("setup: GET_LINE_CODING(wLength=7)"));
+        assert!(response.contains("data: 9600 7E2"));
+        let (request, _) = transfer(&mut decoder, 0x00, [0x21, 0x22, 3, 0, 0, 0, 0, 0], &[]);
+        assert!(request.contains("SET_CONTROL_LINE_STATE(DTR=true, RTS=true)"));
+
+        let (request, _) = transfer(&mut decoder, 0x80, [0xa1, 0xfe, 0, 0, 2, 0, 1, 0], &[0]);
+        assert!(request.contains("setup: GET_MAX_LUN"));
+
+        let (request, _) = transfer(&mut decoder, 0x80, [0xa1, 0x01, 0, 1, 3, 0, 8, 0], &[0; 8]);
+        assert!(request.contains("setup: GET_REPORT(INPUT, id=0, wLength=8)"));
+        let (request, _) = transfer(&mut decoder, 0x00, [0x21, 0x0a, 0, 125, 3, 0, 0, 0], &[]);
+        assert!(request.contains("setup: SET_IDLE(500ms, id=0)"));
+    }
+
+    #[test]
+    fn truncated_class_requests() {
+        let mut decoder = composite_decoder();
+
+        // A line coding of less than 7 bytes is printed raw
+        let truncated = [0x80, 0x25, 0, 0, 2];
+        let (_, response) = transfer(
+            &mut decoder,
+            0x80,
+            [0xa1, 0x21, 0, 0, 0, 0, 7, 0],
+            &truncated,
+        );
+        assert!(response.contains(&format!("data: {:?}", DbgBuf(&truncated))));
+
+        // Unknown class requests fall back to the standard output
+        let (request, _) = transfer(&mut decoder, 0x80, [0xa1, 0x42, 0, 0, 2, 0, 1, 0], &[0]);
+        assert!(request.contains("CLASS_IN(type=0xa1, request=0x42"));
+    }
+
+    #[test]
+    fn class_payloads() {
+        let mut decoder = composite_decoder();
+
+        let serial_state = [0xa1, 0x20, 0, 0, 0, 0, 2, 0, 0x03, 0];
+        let (_, response) = transfer(&mut decoder, 0x83, [0; 8], &serial_state);
+        assert!(response.contains("data: SerialState(DCD | DSR)"));
+
+        let (request, _) = transfer(&mut decoder, 0x01, [0; 8], b"hello\r\n");
+        assert!(request.contains(r#"data: b"hello\r\n""#));
+        let (_, response) = transfer(&mut decoder, 0x82, [0; 8], b"world");
+        assert!(response.contains(r#"data: b"world""#));
+
+        let mut cbw = vec![0x55, 0x53, 0x42, 0x43, 1, 0, 0, 0, 0, 2, 0, 0, 0x80, 0, 10];
+        cbw.extend_from_slice(&[0x28, 0, 0, 0, 0, 2, 0, 0, 1, 0]);
+        cbw.resize(31, 0);
+        let (request, _) = transfer(&mut decoder, 0x04, [0; 8], &cbw);
+        assert!(
+            request.contains("data: CBW(tag=1, length=512, IN, lun=0, READ_10(lba=2, blocks=1))")
+        );
+        let csw = [0x55, 0x53, 0x42, 0x53, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        let (_, response) = transfer(&mut decoder, 0x84, [0; 8], &csw);
+        assert!(response.contains("data: CSW(tag=1, residue=0, FAILED)"));
+    }
+
+    #[test]
+    fn truncated_class_payloads() {
+        let mut decoder = composite_decoder();
+
+        // The notification announces two bytes of data, but only carries one
+        let serial_state = [0xa1, 0x20, 0, 0, 0, 0, 2, 0, 0x03];
+        let (_, response) = transfer(&mut decoder, 0x83, [0; 8], &serial_state);
+        assert!(response.contains(&format!("data: {:?}", DbgBuf(&serial_state))));
+
+        // Wrappers are only decoded at their exact size
+        let mut cbw = vec![0x55, 0x53, 0x42, 0x43, 1, 0, 0, 0, 0, 2, 0, 0, 0x80, 0, 10];
+        cbw.extend_from_slice(&[0x28, 0, 0, 0, 0, 2, 0, 0, 1, 0]);
+        for len in [0, 3, 7, 25, 30] {
+            let (request, _) = transfer(
+                &mut decoder,
+                0x04,
+                [0; 8],
+                &cbw[..usize::min(len, cbw.len())],
+            );
+            assert!(!request.contains("CBW"));
+        }
+        let csw = [0x55, 0x53, 0x42, 0x53, 1, 0, 0, 0, 0, 0, 0, 0];
+        let (_, response) = transfer(&mut decoder, 0x84, [0; 8], &csw);
+        assert!(response.contains(&format!("data: {:?}", DbgBuf(&csw))));
+
+        // A command block length beyond the wrapper is clamped
+        cbw.resize(31, 0);
+        cbw[14] = 0x1f;
+        let (request, _) = transfer(&mut decoder, 0x04, [0; 8], &cbw);
+        assert!(request.contains("READ_10(lba=2, blocks=1)"));
+    }
+}
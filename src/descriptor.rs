@@ -0,0 +1,396 @@
+//! Typed standard USB descriptors and the enumeration of devices.
+//!
+//! [`enumerate`] performs the same requests on a device, that the Linux USB core
+//! issues when a device is attached, and returns the parsed descriptors.
+//! It is usually called through [`UsbHost::enumerate`].
+
+use crate::{
+    client::{SetupPacket, UsbHost},
+    response::EPIPE,
+    UsbIpError,
+};
+use std::collections::BTreeMap;
+use usb_device::{
+    control::Request,
+    descriptor::descriptor_type,
+    endpoint::{EndpointType, IsochronousSynchronizationType, IsochronousUsageType},
+    UsbDirection,
+};
+
+/// The standard device descriptor (USB 2.0 spec section 9.6.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    /// Size of the device descriptor in bytes
+    pub const SIZE: usize = 18;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        check_header(data, descriptor_type::DEVICE, Self::SIZE)?;
+
+        Ok(Self {
+            usb_version: u16::from_le_bytes([data[2], data[3]]),
+            device_class: data[4],
+            device_subclass: data[5],
+            device_protocol: data[6],
+            max_packet_size_0: data[7],
+            vendor_id: u16::from_le_bytes([data[8], data[9]]),
+            product_id: u16::from_le_bytes([data[10], data[11]]),
+            device_version: u16::from_le_bytes([data[12], data[13]]),
+            manufacturer_index: data[14],
+            product_index: data[15],
+            serial_number_index: data[16],
+            num_configurations: data[17],
+        })
+    }
+}
+
+/// The standard configuration descriptor (USB 2.0 spec section 9.6.3),
+/// together with all descriptors, that follow it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub configuration_value: u8,
+    pub configuration_index: u8,
+    pub attributes: u8,
+    /// The maximum power consumption in units of 2 mA
+    pub max_power: u8,
+    /// All interfaces, including their alternate settings
+    pub interfaces: Vec<InterfaceDescriptor>,
+    /// Descriptors, that precede the first interface, e.g. interface associations
+    pub extra: Vec<Vec<u8>>,
+}
+
+impl ConfigurationDescriptor {
+    /// Size of the configuration descriptor without the following descriptors in bytes
+    pub const SIZE: usize = 9;
+
+    /// Parses the configuration descriptor and all descriptors, that follow it.
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        check_header(data, descriptor_type::CONFIGURATION, Self::SIZE)?;
+
+        let mut config = Self {
+            total_length: u16::from_le_bytes([data[2], data[3]]),
+            num_interfaces: data[4],
+            configuration_value: data[5],
+            configuration_index: data[6],
+            attributes: data[7],
+            max_power: data[8],
+            interfaces: vec![],
+            extra: vec![],
+        };
+
+        let total_length = usize::min(config.total_length as usize, data.len());
+        let mut offset = data[0] as usize;
+        while offset < total_length {
+            let descriptor = &data[offset..total_length];
+            let len = descriptor[0] as usize;
+            if len < 2 || len > descriptor.len() {
+                return Err(UsbIpError::InvalidDescriptor(
+                    descriptor_type::CONFIGURATION,
+                ));
+            }
+            let descriptor = &descriptor[..len];
+
+            match descriptor[1] {
+                descriptor_type::INTERFACE => config
+                    .interfaces
+                    .push(InterfaceDescriptor::from_slice(descriptor)?),
+                descriptor_type::ENDPOINT => match config.interfaces.last_mut() {
+                    Some(interface) => interface
+                        .endpoints
+                        .push(EndpointDescriptor::from_slice(descriptor)?),
+                    None => return Err(UsbIpError::InvalidDescriptor(descriptor_type::ENDPOINT)),
+                },
+                // Class specific descriptors belong to the preceding endpoint or interface
+                _ => match config.interfaces.last_mut() {
+                    Some(interface) => match interface.endpoints.last_mut() {
+                        Some(endpoint) => endpoint.extra.push(descriptor.to_vec()),
+                        None => interface.extra.push(descriptor.to_vec()),
+                    },
+                    None => config.extra.push(descriptor.to_vec()),
+                },
+            }
+
+            offset += len;
+        }
+
+        Ok(config)
+    }
+}
+
+/// The standard interface descriptor (USB 2.0 spec section 9.6.5),
+/// together with its endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub num_endpoints: u8,
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub interface_index: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+    /// Class specific descriptors, that follow the interface descriptor
+    pub extra: Vec<Vec<u8>>,
+}
+
+impl InterfaceDescriptor {
+    /// Size of the interface descriptor in bytes
+    pub const SIZE: usize = 9;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        check_header(data, descriptor_type::INTERFACE, Self::SIZE)?;
+
+        Ok(Self {
+            interface_number: data[2],
+            alternate_setting: data[3],
+            num_endpoints: data[4],
+            interface_class: data[5],
+            interface_subclass: data[6],
+            interface_protocol: data[7],
+            interface_index: data[8],
+            endpoints: vec![],
+            extra: vec![],
+        })
+    }
+}
+
+/// The standard endpoint descriptor (USB 2.0 spec section 9.6.6).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    /// Class specific descriptors, that follow the endpoint descriptor
+    pub extra: Vec<Vec<u8>>,
+}
+
+impl EndpointDescriptor {
+    /// Size of the endpoint descriptor in bytes
+    pub const SIZE: usize = 7;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        check_header(data, descriptor_type::ENDPOINT, Self::SIZE)?;
+
+        Ok(Self {
+            address: data[2],
+            attributes: data[3],
+            max_packet_size: u16::from_le_bytes([data[4], data[5]]),
+            interval: data[6],
+            extra: vec![],
+        })
+    }
+
+    /// Returns the endpoint number without the direction bit.
+    pub fn number(&self) -> u8 {
+        self.address & 0x0f
+    }
+
+    pub fn direction(&self) -> UsbDirection {
+        UsbDirection::from(self.address)
+    }
+
+    pub fn transfer_type(&self) -> EndpointType {
+        match self.attributes & 0b11 {
+            0b00 => EndpointType::Control,
+            0b01 => EndpointType::Isochronous {
+                synchronization: match (self.attributes >> 2) & 0b11 {
+                    0b00 => IsochronousSynchronizationType::NoSynchronization,
+                    0b01 => IsochronousSynchronizationType::Asynchronous,
+                    0b10 => IsochronousSynchronizationType::Adaptive,
+                    _ => IsochronousSynchronizationType::Synchronous,
+                },
+                usage: match (self.attributes >> 4) & 0b11 {
+                    0b01 => IsochronousUsageType::Feedback,
+                    0b10 => IsochronousUsageType::ImplicitFeedbackData,
+                    _ => IsochronousUsageType::Data,
+                },
+            },
+            0b10 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        }
+    }
+}
+
+/// Parses the string descriptor with index 0, which lists the supported languages.
+pub fn parse_languages(data: &[u8]) -> Result<Vec<u16>, UsbIpError> {
+    check_header(data, descriptor_type::STRING, 2)?;
+
+    Ok(data[2..data[0] as usize]
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .collect())
+}
+
+/// Parses a string descriptor.
+pub fn parse_string(data: &[u8]) -> Result<String, UsbIpError> {
+    check_header(data, descriptor_type::STRING, 2)?;
+
+    let chars: Vec<u16> = data[2..data[0] as usize]
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .collect();
+    String::from_utf16(&chars).map_err(|_| UsbIpError::InvalidString)
+}
+
+/// Checks, that `data` holds a complete descriptor of type `ty` with at least `size` bytes.
+fn check_header(data: &[u8], ty: u8, size: usize) -> Result<(), UsbIpError> {
+    if data.len() < 2 || data[1] != ty {
+        return Err(UsbIpError::InvalidDescriptor(ty));
+    }
+
+    let len = data[0] as usize;
+    if len < size || len > data.len() {
+        return Err(UsbIpError::InvalidDescriptor(ty));
+    }
+
+    Ok(())
+}
+
+/// The descriptors of an enumerated device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnumeratedDevice {
+    pub device: DeviceDescriptor,
+    pub configurations: Vec<ConfigurationDescriptor>,
+    /// The languages supported by the string descriptors
+    pub languages: Vec<u16>,
+    /// All strings referenced by the other descriptors in the first language, by index
+    pub strings: BTreeMap<u8, String>,
+}
+
+impl EnumeratedDevice {
+    /// Returns the string with `index` or `None`, if the index is 0 or unknown.
+    pub fn string(&self, index: u8) -> Option<&str> {
+        self.strings.get(&index).map(String::as_str)
+    }
+
+    pub fn manufacturer(&self) -> Option<&str> {
+        self.string(self.device.manufacturer_index)
+    }
+
+    pub fn product(&self) -> Option<&str> {
+        self.string(self.device.product_index)
+    }
+
+    pub fn serial_number(&self) -> Option<&str> {
+        self.string(self.device.serial_number_index)
+    }
+}
+
+/// Builds the setup packet of a GET_DESCRIPTOR request.
+pub fn get_descriptor(ty: u8, index: u8, language_id: u16, length: u16) -> SetupPacket {
+    SetupPacket::new(
+        0x80,
+        Request::GET_DESCRIPTOR,
+        (ty as u16) << 8 | index as u16,
+        language_id,
+        length,
+    )
+}
+
+/// Enumerates the device like the Linux USB core does on attach.
+///
+/// The device, configuration and string descriptors are requested,
+/// after which the first configuration is selected by SET_CONFIGURATION.
+pub fn enumerate<H: UsbHost + ?Sized>(host: &mut H) -> Result<EnumeratedDevice, UsbIpError> {
+    let data = host.control_transfer(
+        get_descriptor(descriptor_type::DEVICE, 0, 0, DeviceDescriptor::SIZE as u16),
+        &[],
+    )?;
+    let device = DeviceDescriptor::from_slice(&data)?;
+    log::debug!("enumerated {:?}", device);
+
+    let mut configurations = vec![];
+    for index in 0..device.num_configurations {
+        // Read the header first, to learn the total length of the configuration
+        let setup = get_descriptor(
+            descriptor_type::CONFIGURATION,
+            index,
+            0,
+            ConfigurationDescriptor::SIZE as u16,
+        );
+        let header = ConfigurationDescriptor::from_slice(&host.control_transfer(setup, &[])?)?;
+
+        let setup = get_descriptor(
+            descriptor_type::CONFIGURATION,
+            index,
+            0,
+            header.total_length,
+        );
+        let config = ConfigurationDescriptor::from_slice(&host.control_transfer(setup, &[])?)?;
+        log::debug!("enumerated {:?}", config);
+        configurations.push(config);
+    }
+
+    // A device without strings stalls the request for the supported languages
+    let setup = get_descriptor(descriptor_type::STRING, 0, 0, 255);
+    let languages = match host.control_transfer(setup, &[]) {
+        Ok(data) => parse_languages(&data)?,
+        Err(UsbIpError::UrbFailed(status)) if status == -EPIPE => vec![],
+        Err(err) => return Err(err),
+    };
+
+    let mut strings = BTreeMap::new();
+    if let Some(&language_id) = languages.first() {
+        let mut indices = vec![
+            device.manufacturer_index,
+            device.product_index,
+            device.serial_number_index,
+        ];
+        for config in configurations.iter() {
+            indices.push(config.configuration_index);
+            indices.extend(config.interfaces.iter().map(|iface| iface.interface_index));
+        }
+
+        for index in indices {
+            if index == 0 || strings.contains_key(&index) {
+                continue;
+            }
+
+            let setup = get_descriptor(descriptor_type::STRING, index, language_id, 255);
+            match host.control_transfer(setup, &[]) {
+                Ok(data) => {
+                    strings.insert(index, parse_string(&data)?);
+                }
+                Err(UsbIpError::UrbFailed(status)) if status == -EPIPE => {
+                    log::warn!("device stalled request for string {}", index);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    if let Some(config) = configurations.first() {
+        let setup = SetupPacket::new(
+            0x00,
+            Request::SET_CONFIGURATION,
+            config.configuration_value as u16,
+            0,
+            0,
+        );
+        host.control_transfer(setup, &[])?;
+    }
+
+    Ok(EnumeratedDevice {
+        device,
+        configurations,
+        languages,
+        strings,
+    })
+}
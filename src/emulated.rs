@@ -0,0 +1,632 @@
+//! Emulation of a device from cloned descriptors.
+//!
+//! A [`DescriptorSet`] holds all descriptors of a device, as answered to GET_DESCRIPTOR.
+//! It is extracted from a [`Recording`] of the enumeration of a physical device or
+//! from the binary descriptor dump, that Linux exports in sysfs.
+//! The [`EmulatedDevice`] answers the enumeration from these descriptors and handles
+//! the other standard requests itself. Class and vendor requests, as well as the data
+//! endpoints, are left to [`DeviceHooks`].
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use usbip_device::{
+//!     emulated::{DescriptorSet, EmulatedDevice},
+//!     replay::Recording,
+//! };
+//!
+//! let mut recording = Recording::from_pcap(File::open("usb.pcap").unwrap()).unwrap();
+//! recording.retain_device(1 << 16 | 4);
+//!
+//! let device = EmulatedDevice::new(DescriptorSet::from_recording(&recording), ());
+//! loop {
+//!     device.poll();
+//! #   break;
+//! }
+//! ```
+
+use crate::{
+    client::SetupPacket,
+    cmd::{Direction, UsbIpHeader},
+    descriptor::{ConfigurationDescriptor, DeviceDescriptor},
+    handler::{Responder, SocketHandler},
+    loopback::LoopbackHost,
+    replay::Recording,
+    request::UsbIpCmdSubmit,
+    response::{UsbIpResponse, EPIPE},
+    UsbIpBus, UsbIpBusInner, UsbIpError,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{Debug, Formatter, Result as FmtResult},
+    sync::{Arc, Mutex},
+};
+use usb_device::descriptor::descriptor_type;
+
+const REQ_GET_STATUS: u8 = 0x00;
+const REQ_CLEAR_FEATURE: u8 = 0x01;
+const REQ_SET_FEATURE: u8 = 0x03;
+const REQ_SET_ADDRESS: u8 = 0x05;
+const REQ_GET_DESCRIPTOR: u8 = 0x06;
+const REQ_GET_CONFIGURATION: u8 = 0x08;
+const REQ_SET_CONFIGURATION: u8 = 0x09;
+const REQ_GET_INTERFACE: u8 = 0x0a;
+const REQ_SET_INTERFACE: u8 = 0x0b;
+
+/// The request type of requests to a port of a hub
+const TO_PORT: u8 = 0x23;
+const FEATURE_PORT_RESET: u16 = 0x0004;
+const FEATURE_ENDPOINT_HALT: u16 = 0x0000;
+
+/// The descriptors of a device, by the request type, value and index of
+/// the GET_DESCRIPTOR request, that returns them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DescriptorSet {
+    pub descriptors: BTreeMap<(u8, u16, u16), Vec<u8>>,
+}
+
+impl DescriptorSet {
+    /// Collects the answers to all successful GET_DESCRIPTOR requests of a recording.
+    ///
+    /// If a descriptor was requested multiple times, the longest answer is kept.
+    pub fn from_recording(recording: &Recording) -> Self {
+        let mut set = Self::default();
+
+        for urb in &recording.urbs {
+            let setup = match urb.setup {
+                Some(ref setup) => SetupPacket::from_array(setup),
+                None => continue,
+            };
+            let completion = match urb.completion {
+                Some(ref completion) if completion.status == 0 => completion,
+                _ => continue,
+            };
+
+            if urb.ep == 0 && setup.request == REQ_GET_DESCRIPTOR && setup.request_type & 0x80 != 0
+            {
+                let key = (setup.request_type, setup.value, setup.index);
+                let known = set.descriptors.get(&key).map(Vec::len).unwrap_or(0);
+                if completion.data.len() > known {
+                    set.descriptors.insert(key, completion.data.clone());
+                }
+            }
+        }
+
+        set
+    }
+
+    /// Parses the binary dump of a device descriptor, followed by all
+    /// configuration descriptors, as found in `/sys/bus/usb/devices/*/descriptors`.
+    pub fn from_sysfs_descriptors(data: &[u8]) -> Result<Self, UsbIpError> {
+        let device = data
+            .get(..DeviceDescriptor::SIZE)
+            .ok_or(UsbIpError::InvalidDescriptor(descriptor_type::DEVICE))?;
+        DeviceDescriptor::from_slice(device)?;
+
+        let mut set = Self::default();
+        set.insert(
+            0x80,
+            (descriptor_type::DEVICE as u16) << 8,
+            0,
+            device.to_vec(),
+        );
+
+        let mut rest = &data[DeviceDescriptor::SIZE..];
+        let mut index = 0;
+        while !rest.is_empty() {
+            let config = ConfigurationDescriptor::from_slice(rest)?;
+            let len = usize::min(config.total_length as usize, rest.len());
+
+            let value = (descriptor_type::CONFIGURATION as u16) << 8 | index;
+            set.insert(0x80, value, 0, rest[..len].to_vec());
+
+            rest = &rest[len..];
+            index += 1;
+        }
+
+        Ok(set)
+    }
+
+    pub fn insert(&mut self, request_type: u8, value: u16, index: u16, descriptor: Vec<u8>) {
+        self.descriptors
+            .insert((request_type, value, index), descriptor);
+    }
+
+    /// Returns the answer to a GET_DESCRIPTOR request.
+    ///
+    /// Strings, that are not known in the requested language, are returned in any known language.
+    pub fn get(&self, request_type: u8, value: u16, index: u16) -> Option<&[u8]> {
+        if let Some(descriptor) = self.descriptors.get(&(request_type, value, index)) {
+            return Some(descriptor);
+        }
+
+        if value >> 8 != descriptor_type::STRING as u16 {
+            return None;
+        }
+
+        self.descriptors
+            .iter()
+            .find(|((ty, v, _), _)| *ty == request_type && *v == value)
+            .map(|(_, descriptor)| descriptor.as_slice())
+    }
+
+    /// Returns the raw device descriptor.
+    pub fn device(&self) -> Option<&[u8]> {
+        self.get(0x80, (descriptor_type::DEVICE as u16) << 8, 0)
+    }
+
+    /// Returns the raw configuration descriptor with `index`, including all descriptors following it.
+    pub fn configuration(&self, index: u8) -> Option<&[u8]> {
+        let value = (descriptor_type::CONFIGURATION as u16) << 8 | index as u16;
+        self.get(0x80, value, 0)
+    }
+}
+
+/// Handlers for the parts of an [`EmulatedDevice`], that the descriptors do not describe.
+///
+/// Failures are returned as negative error number, which becomes the status of the urb.
+/// All handlers stall by default, except for [`data_in`](Self::data_in), which never completes.
+pub trait DeviceHooks: Send {
+    /// Handles a control transfer, that is not a standard request.
+    ///
+    /// # Returns
+    /// The data of the data stage of an in transfer
+    fn control(&mut self, setup: SetupPacket, data: &[u8]) -> Result<Vec<u8>, i32> {
+        let _ = (setup, data);
+        Err(-EPIPE)
+    }
+
+    /// Handles data sent to an out endpoint.
+    fn data_out(&mut self, ep: u8, data: &[u8]) -> Result<(), i32> {
+        let _ = (ep, data);
+        Err(-EPIPE)
+    }
+
+    /// Handles a request for up to `length` bytes from an in endpoint.
+    ///
+    /// # Returns
+    /// - `None` if there is no data yet, in which case the urb is retried on the next poll
+    fn data_in(&mut self, ep: u8, length: usize) -> Option<Result<Vec<u8>, i32>> {
+        let _ = (ep, length);
+        None
+    }
+}
+
+/// Hooks, that stall all control transfers and out endpoints and never send data.
+impl DeviceHooks for () {}
+
+/// The state of an emulated device, that the bus answers urbs from.
+pub(crate) struct EmulatedState {
+    pub descriptors: DescriptorSet,
+    hooks: Box<dyn DeviceHooks>,
+    configuration: u8,
+    /// The selected alternate settings by interface
+    alternate_settings: HashMap<u16, u8>,
+    /// The addresses of the halted endpoints
+    halted: HashSet<u8>,
+    /// In urbs, that the hooks had no data for yet
+    pending: VecDeque<(UsbIpHeader, usize)>,
+}
+
+impl Debug for EmulatedState {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("EmulatedState")
+            .field("configuration", &self.configuration)
+            .field("alternate_settings", &self.alternate_settings)
+            .field("halted", &self.halted)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl EmulatedState {
+    fn new(descriptors: DescriptorSet, hooks: Box<dyn DeviceHooks>) -> Self {
+        Self {
+            descriptors,
+            hooks,
+            configuration: 0,
+            alternate_settings: HashMap::new(),
+            halted: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Answers a submitted urb.
+    ///
+    /// # Returns
+    /// - `None` if the urb stays pending
+    pub fn submit(
+        &mut self,
+        header: &UsbIpHeader,
+        cmd: &UsbIpCmdSubmit,
+        data: &[u8],
+    ) -> Option<UsbIpResponse> {
+        if header.ep == 0 {
+            let setup = SetupPacket::from_array(&cmd.setup);
+            return Some(match self.control(setup, data) {
+                Ok(_) if header.direction == Direction::OUT => {
+                    UsbIpResponse::ret_submit(header, 0, data.len(), vec![])
+                }
+                Ok(mut result) => {
+                    result.truncate(setup.length as usize);
+                    UsbIpResponse::ret_submit(header, 0, result.len(), result)
+                }
+                Err(status) => UsbIpResponse::ret_submit(header, status, 0, vec![]),
+            });
+        }
+
+        if self.halted.contains(&endpoint_address(header)) {
+            return Some(UsbIpResponse::ret_submit(header, -EPIPE, 0, vec![]));
+        }
+
+        match header.direction {
+            Direction::IN => {
+                // Urbs to the same endpoint complete in order
+                let length = cmd.transfer_buffer_length.max(0) as usize;
+                if self
+                    .pending
+                    .iter()
+                    .any(|(pending, _)| pending.ep == header.ep)
+                {
+                    self.pending.push_back((header.clone(), length));
+                    return None;
+                }
+
+                let response = self.data_in(header, length);
+                if response.is_none() {
+                    self.pending.push_back((header.clone(), length));
+                }
+                response
+            }
+            _ => Some(match self.hooks.data_out(header.ep as u8, data) {
+                Ok(()) => UsbIpResponse::ret_submit(header, 0, data.len(), vec![]),
+                Err(status) => UsbIpResponse::ret_submit(header, status, 0, vec![]),
+            }),
+        }
+    }
+
+    /// Retries the pending in urbs.
+    pub fn poll(&mut self) -> Vec<UsbIpResponse> {
+        let mut responses = vec![];
+        let mut blocked = HashSet::new();
+
+        for (header, length) in std::mem::take(&mut self.pending) {
+            if !blocked.contains(&header.ep) {
+                if let Some(response) = self.data_in(&header, length) {
+                    responses.push(response);
+                    continue;
+                }
+            }
+
+            blocked.insert(header.ep);
+            self.pending.push_back((header, length));
+        }
+
+        responses
+    }
+
+    /// Removes a pending urb.
+    ///
+    /// # Returns
+    /// - `true` if the urb was pending
+    pub fn unlink(&mut self, seqnum: u32) -> bool {
+        let old_len = self.pending.len();
+        self.pending.retain(|(header, _)| header.seqnum != seqnum);
+        old_len != self.pending.len()
+    }
+
+    fn data_in(&mut self, header: &UsbIpHeader, length: usize) -> Option<UsbIpResponse> {
+        match self.hooks.data_in(header.ep as u8, length)? {
+            Ok(mut data) => {
+                data.truncate(length);
+                Some(UsbIpResponse::ret_submit(header, 0, data.len(), data))
+            }
+            Err(status) => Some(UsbIpResponse::ret_submit(header, status, 0, vec![])),
+        }
+    }
+
+    /// Handles the standard requests and passes all others to the hooks.
+    fn control(&mut self, setup: SetupPacket, data: &[u8]) -> Result<Vec<u8>, i32> {
+        match (setup.request_type, setup.request) {
+            (0x80..=0x82, REQ_GET_DESCRIPTOR) => self
+                .descriptors
+                .get(setup.request_type, setup.value, setup.index)
+                .map(<[u8]>::to_vec)
+                .ok_or(-EPIPE),
+            (0x00, REQ_SET_ADDRESS) => Ok(vec![]),
+            (0x80, REQ_GET_CONFIGURATION) => Ok(vec![self.configuration]),
+            (0x00, REQ_SET_CONFIGURATION) => {
+                self.configuration = setup.value as u8;
+                self.alternate_settings.clear();
+                self.halted.clear();
+                Ok(vec![])
+            }
+            (0x81, REQ_GET_INTERFACE) => Ok(vec![*self
+                .alternate_settings
+                .get(&setup.index)
+                .unwrap_or(&0)]),
+            (0x01, REQ_SET_INTERFACE) => {
+                self.alternate_settings
+                    .insert(setup.index, setup.value as u8);
+                Ok(vec![])
+            }
+            (0x80, REQ_GET_STATUS) | (0x81, REQ_GET_STATUS) => Ok(vec![0, 0]),
+            (0x82, REQ_GET_STATUS) => {
+                let halted = self.halted.contains(&(setup.index as u8));
+                Ok(vec![halted as u8, 0])
+            }
+            (0x02, REQ_SET_FEATURE) if setup.value == FEATURE_ENDPOINT_HALT => {
+                self.halted.insert(setup.index as u8);
+                Ok(vec![])
+            }
+            (0x02, REQ_CLEAR_FEATURE) if setup.value == FEATURE_ENDPOINT_HALT => {
+                self.halted.remove(&(setup.index as u8));
+                Ok(vec![])
+            }
+            (TO_PORT, REQ_SET_FEATURE) if setup.value == FEATURE_PORT_RESET => {
+                self.configuration = 0;
+                self.alternate_settings.clear();
+                self.halted.clear();
+                Ok(vec![])
+            }
+            _ => self.hooks.control(setup, data),
+        }
+    }
+}
+
+/// Returns the endpoint address including the direction bit.
+fn endpoint_address(header: &UsbIpHeader) -> u8 {
+    match header.direction {
+        Direction::IN => header.ep as u8 | 0x80,
+        _ => header.ep as u8,
+    }
+}
+
+/// A server, that exports a device emulated from a [`DescriptorSet`].
+#[derive(Debug, Clone)]
+pub struct EmulatedDevice {
+    bus: UsbIpBus,
+}
+
+impl EmulatedDevice {
+    /// Creates a server, that exports the emulated device on port 3240.
+    ///
+    /// # Panics
+    /// If port 3240 is already in use.
+    pub fn new<H: DeviceHooks + 'static>(descriptors: DescriptorSet, hooks: H) -> Self {
+        Self::with_handler(SocketHandler::new(), descriptors, Box::new(hooks))
+    }
+
+    /// Creates a server, that is driven by the returned in-process [`LoopbackHost`].
+    ///
+    /// Pending in urbs are only retried by [`poll`](Self::poll).
+    pub fn loopback<H: DeviceHooks + 'static>(
+        descriptors: DescriptorSet,
+        hooks: H,
+    ) -> (Self, LoopbackHost) {
+        let device = Self::with_handler(SocketHandler::loopback(), descriptors, Box::new(hooks));
+        device.bus.lock().reset = false;
+
+        let host = LoopbackHost::new(device.bus.clone());
+        (device, host)
+    }
+
+    fn with_handler(
+        handler: SocketHandler,
+        descriptors: DescriptorSet,
+        hooks: Box<dyn DeviceHooks>,
+    ) -> Self {
+        let mut inner = UsbIpBusInner::new(handler);
+        inner.responder = Some(Responder::Emulated(EmulatedState::new(descriptors, hooks)));
+
+        Self {
+            bus: UsbIpBus(Arc::new(Mutex::new(inner))),
+        }
+    }
+
+    /// Accepts connections, answers the received packets and retries pending in urbs.
+    pub fn poll(&self) {
+        self.bus.lock().poll_responder();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{TransferFlags, UsbCmd},
+        replay::{RecordedCompletion, RecordedUrb},
+        response::UsbIpResponseCmd,
+    };
+
+    const DEVICE: [u8; 18] = [
+        18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x09, 0x12, 0x01, 0x00, 0x10, 0x00, 0, 0, 0, 2,
+    ];
+
+    const CONFIGURATION: [u8; 25] = [
+        9, 2, 25, 0, 1, 1, 0, 0x80, 50, //
+        9, 4, 0, 0, 1, 0xff, 0, 0, 0, //
+        7, 5, 0x81, 2, 64, 0, 0,
+    ];
+
+    fn get_descriptor(value: u16, index: u16, status: i32, data: &[u8]) -> RecordedUrb {
+        let setup = SetupPacket::new(0x80, REQ_GET_DESCRIPTOR, value, index, 255);
+        RecordedUrb {
+            devid: 1 << 16 | 2,
+            ep: 0,
+            direction: Direction::IN,
+            setup: Some(setup.to_array()),
+            transfer_buffer_length: 255,
+            data: vec![],
+            completion: Some(RecordedCompletion {
+                status,
+                actual_length: data.len(),
+                data: data.to_vec(),
+            }),
+        }
+    }
+
+    /// Answers in urbs with a counting byte, while it has data available.
+    struct Counter {
+        next: u8,
+        available: usize,
+    }
+
+    impl DeviceHooks for Counter {
+        fn data_in(&mut self, _ep: u8, _length: usize) -> Option<Result<Vec<u8>, i32>> {
+            if self.available == 0 {
+                return None;
+            }
+            self.available -= 1;
+            self.next += 1;
+            Some(Ok(vec![self.next]))
+        }
+    }
+
+    fn header(seqnum: u32, ep: u32, direction: Direction) -> UsbIpHeader {
+        UsbIpHeader {
+            command: UsbCmd::Request,
+            seqnum,
+            devid: 1 << 16 | 2,
+            direction,
+            ep,
+        }
+    }
+
+    fn cmd(setup: [u8; 8], length: i32) -> UsbIpCmdSubmit {
+        UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: length,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+        }
+    }
+
+    /// Returns the seqnum, status and data of a response.
+    fn completion(response: &UsbIpResponse) -> (u32, i32, Vec<u8>) {
+        match response.cmd {
+            UsbIpResponseCmd::Cmd(ref ret) => {
+                (response.header.seqnum, ret.status, response.data.clone())
+            }
+            UsbIpResponseCmd::Unlink(_) => panic!("submit answered with an unlink"),
+        }
+    }
+
+    /// Submits a control transfer and returns its status and data.
+    fn control(state: &mut EmulatedState, setup: SetupPacket) -> (i32, Vec<u8>) {
+        let direction = setup.direction();
+        let response = state
+            .submit(&header(1, 0, direction), &cmd(setup.to_array(), 255), &[])
+            .unwrap();
+        let (_, status, data) = completion(&response);
+        (status, data)
+    }
+
+    #[test]
+    fn descriptors_from_recording() {
+        let recording = Recording {
+            urbs: vec![
+                get_descriptor(0x0100, 0, 0, &DEVICE[..8]),
+                get_descriptor(0x0100, 0, 0, &DEVICE),
+                get_descriptor(0x0200, 0, 0, &CONFIGURATION[..9]),
+                get_descriptor(0x0200, 0, 0, &CONFIGURATION),
+                // A shorter answer does not replace the longer one
+                get_descriptor(0x0200, 0, 0, &CONFIGURATION[..9]),
+                get_descriptor(0x0302, 0x0409, 0, &[6, 3, b'a', 0, b'b', 0]),
+                // Failed requests are not descriptors
+                get_descriptor(0x0600, 0, -EPIPE, &[]),
+            ],
+        };
+        let set = DescriptorSet::from_recording(&recording);
+
+        assert_eq!(set.descriptors.len(), 3);
+        assert_eq!(set.device(), Some(&DEVICE[..]));
+        assert_eq!(set.configuration(0), Some(&CONFIGURATION[..]));
+        assert_eq!(set.configuration(1), None);
+
+        // Strings are returned in any language, if the requested one is not known
+        let string = Some(&[6, 3, b'a', 0, b'b', 0][..]);
+        assert_eq!(set.get(0x80, 0x0302, 0x0407), string);
+        assert_eq!(set.get(0x80, 0x0303, 0x0409), None);
+    }
+
+    #[test]
+    fn descriptors_from_sysfs() {
+        let mut dump = DEVICE.to_vec();
+        dump.extend_from_slice(&CONFIGURATION);
+        dump.extend_from_slice(&CONFIGURATION);
+        let set = DescriptorSet::from_sysfs_descriptors(&dump).unwrap();
+
+        assert_eq!(set.device(), Some(&DEVICE[..]));
+        assert_eq!(set.configuration(0), Some(&CONFIGURATION[..]));
+        assert_eq!(set.configuration(1), Some(&CONFIGURATION[..]));
+
+        // Truncated dumps
+        assert!(DescriptorSet::from_sysfs_descriptors(&DEVICE[..17]).is_err());
+        assert!(DescriptorSet::from_sysfs_descriptors(&dump[..DEVICE.len() + 5]).is_err());
+    }
+
+    #[test]
+    fn standard_requests() {
+        let mut set = DescriptorSet::default();
+        set.insert(0x80, 0x0100, 0, DEVICE.to_vec());
+        let mut state = EmulatedState::new(set, Box::new(()));
+
+        let get_device = SetupPacket::new(0x80, REQ_GET_DESCRIPTOR, 0x0100, 0, 8);
+        assert_eq!(control(&mut state, get_device), (0, DEVICE[..8].to_vec()));
+        let get_string = SetupPacket::new(0x80, REQ_GET_DESCRIPTOR, 0x0301, 0x0409, 255);
+        assert_eq!(control(&mut state, get_string), (-EPIPE, vec![]));
+
+        let set_configuration = SetupPacket::new(0x00, REQ_SET_CONFIGURATION, 1, 0, 0);
+        assert_eq!(control(&mut state, set_configuration), (0, vec![]));
+        let get_configuration = SetupPacket::new(0x80, REQ_GET_CONFIGURATION, 0, 0, 1);
+        assert_eq!(control(&mut state, get_configuration), (0, vec![1]));
+
+        // A halted endpoint stalls until the halt is cleared
+        let halt = SetupPacket::new(0x02, REQ_SET_FEATURE, FEATURE_ENDPOINT_HALT, 0x81, 0);
+        let get_status = SetupPacket::new(0x82, REQ_GET_STATUS, 0, 0x81, 2);
+        let clear_halt = SetupPacket::new(0x02, REQ_CLEAR_FEATURE, FEATURE_ENDPOINT_HALT, 0x81, 0);
+        assert_eq!(control(&mut state, halt), (0, vec![]));
+        assert_eq!(control(&mut state, get_status), (0, vec![1, 0]));
+        let bulk_in = state.submit(&header(2, 1, Direction::IN), &cmd([0; 8], 64), &[]);
+        assert_eq!(completion(&bulk_in.unwrap()), (2, -EPIPE, vec![]));
+        assert_eq!(control(&mut state, clear_halt), (0, vec![]));
+        assert_eq!(control(&mut state, get_status), (0, vec![0, 0]));
+
+        // A port reset deconfigures the device
+        let reset = SetupPacket::new(TO_PORT, REQ_SET_FEATURE, FEATURE_PORT_RESET, 1, 0);
+        assert_eq!(control(&mut state, reset), (0, vec![]));
+        assert_eq!(control(&mut state, get_configuration), (0, vec![0]));
+
+        // Everything else is left to the hooks, which stall by default
+        let class = SetupPacket::new(0x21, 0x20, 0, 0, 0);
+        assert_eq!(control(&mut state, class), (-EPIPE, vec![]));
+    }
+
+    #[test]
+    fn pending_in_urbs_complete_in_order() {
+        let hooks = Counter {
+            next: 0,
+            available: 0,
+        };
+        let mut state = EmulatedState::new(DescriptorSet::default(), Box::new(hooks));
+
+        for seqnum in 1..=3 {
+            let header = header(seqnum, 1, Direction::IN);
+            assert!(state.submit(&header, &cmd([0; 8], 64), &[]).is_none());
+        }
+        assert!(state.poll().is_empty());
+        assert!(state.unlink(2));
+        assert!(!state.unlink(2));
+
+        // The device has data for both remaining urbs now
+        state.hooks = Box::new(Counter {
+            next: 0,
+            available: 2,
+        });
+        let responses: Vec<_> = state.poll().iter().map(completion).collect();
+        assert_eq!(responses, vec![(1, 0, vec![1]), (3, 0, vec![2])]);
+        assert!(state.poll().is_empty());
+    }
+}
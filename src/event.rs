@@ -0,0 +1,183 @@
+//! Notifications about the lifecycle of a [`UsbIpBus`](crate::UsbIpBus) and its urbs.
+//!
+//! [`UsbIpBus::subscribe`](crate::UsbIpBus::subscribe) returns a receiver,
+//! that gets a [`BusEvent`] for everything, that happens on the bus from then on.
+//! The events are sent, while the bus is polled.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usbip_device::{event::BusEvent, UsbIpBus};
+//!
+//! let bus = UsbIpBus::new();
+//! let events = bus.subscribe();
+//! bus.wait_for_attach(Duration::from_secs(10)).unwrap();
+//!
+//! // ... run the device ...
+//!
+//! for event in events.try_iter() {
+//!     if let BusEvent::ProtocolError(err) = event {
+//!         println!("host misbehaved: {}", err);
+//!     }
+//! }
+//! ```
+
+use crate::UsbIpError;
+use std::net::SocketAddr;
+use usb_device::endpoint::EndpointAddress;
+
+/// Something, that happened on the bus.
+#[derive(Debug, Clone)]
+pub enum BusEvent {
+    /// A remote host has opened a connection
+    ClientConnected(SocketAddr),
+
+    /// The list of exported devices was sent to a remote host
+    DevlistServed(Option<SocketAddr>),
+
+    /// A host has imported the device, the address is `None` for the loopback host
+    Imported(Option<SocketAddr>),
+
+    /// The host has detached the device or the connection was dropped
+    Detached,
+
+    /// The host has reset the port of the device
+    Reset,
+
+    /// The device was suspended
+    Suspended,
+
+    /// The device was resumed
+    Resumed,
+
+    /// The device has halted an endpoint
+    EndpointStalled(EndpointAddress),
+
+    /// The halt of an endpoint was cleared
+    EndpointCleared(EndpointAddress),
+
+    /// The host has submitted an urb
+    UrbSubmitted {
+        seqnum: u32,
+        ep: EndpointAddress,
+        /// The transfer buffer length of the urb
+        length: usize,
+    },
+
+    /// An urb was completed with the contained status
+    UrbCompleted {
+        seqnum: u32,
+        ep: EndpointAddress,
+        status: i32,
+        actual_length: usize,
+    },
+
+    /// The host has unlinked an urb, before it completed
+    UrbUnlinked { seqnum: u32 },
+
+    /// A host has sent something invalid and was disconnected
+    ProtocolError(UsbIpError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{SetupPacket, UsbHost},
+        cmd::Direction,
+        UsbIpBus,
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+    use usbd_serial::SerialPort;
+
+    #[test]
+    fn urb_lifecycle() {
+        let (bus, mut host) = UsbIpBus::loopback();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut serial = SerialPort::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+        let events = bus.subscribe();
+        host.detach();
+        host.attach();
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut serial]);
+            while serial.read(&mut [0; 64]).is_ok() {}
+        });
+        session.enumerate().unwrap();
+
+        let lifecycle: Vec<_> = events.try_iter().take(2).collect();
+        assert!(matches!(
+            lifecycle[..],
+            [BusEvent::Detached, BusEvent::Imported(None)]
+        ));
+        // Drop the events of the enumeration
+        events.try_iter().for_each(drop);
+
+        let seqnum = session
+            .submit(1, Direction::OUT, None, b"hello", 5)
+            .unwrap();
+        session.wait(seqnum).unwrap();
+        let ep = EndpointAddress::from(0x01);
+        let urb: Vec<_> = events.try_iter().collect();
+        assert!(matches!(
+            urb[..],
+            [
+                BusEvent::UrbSubmitted { seqnum: s, ep: e, length: 5 },
+                BusEvent::UrbCompleted { seqnum: c, ep: f, status: 0, actual_length: 5 },
+            ] if s == seqnum && c == seqnum && e == ep && f == ep
+        ));
+
+        // The serial port has no data, so the in urb stays pending
+        let seqnum = session.submit(2, Direction::IN, None, &[], 64).unwrap();
+        assert!(session.unlink(seqnum).unwrap());
+        let unlinked: Vec<_> = events.try_iter().collect();
+        assert!(matches!(
+            unlinked[..],
+            [BusEvent::UrbSubmitted { .. }, BusEvent::UrbUnlinked { seqnum: s }] if s == seqnum
+        ));
+    }
+
+    #[test]
+    fn endpoint_halt() {
+        let (bus, mut host) = UsbIpBus::loopback();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut serial = SerialPort::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut serial]);
+        });
+        session.enumerate().unwrap();
+
+        let events = bus.subscribe();
+        let ep = EndpointAddress::from(0x82);
+        let set_halt = SetupPacket::new(0x02, 0x03, 0, 0x82, 0);
+        let clear_halt = SetupPacket::new(0x02, 0x01, 0, 0x82, 0);
+        session.control_transfer(set_halt, &[]).unwrap();
+        session.control_transfer(clear_halt, &[]).unwrap();
+
+        let halts: Vec<_> = events
+            .try_iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    BusEvent::EndpointStalled(_) | BusEvent::EndpointCleared(_)
+                )
+            })
+            .collect();
+        assert!(matches!(
+            halts[..],
+            [BusEvent::EndpointStalled(s), BusEvent::EndpointCleared(c)] if s == ep && c == ep
+        ));
+    }
+
+    #[test]
+    fn dropped_subscribers_are_removed() {
+        let (bus, mut host) = UsbIpBus::loopback();
+        let events = bus.subscribe();
+        drop(bus.subscribe());
+
+        host.detach();
+        assert!(matches!(events.try_recv(), Ok(BusEvent::Detached)));
+        assert_eq!(bus.lock().subscribers.len(), 1);
+    }
+}
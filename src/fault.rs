@@ -0,0 +1,183 @@
+//! Injection of faults into the completion of urbs.
+//!
+//! A [`FaultInjector`] installed with [`UsbIpBus::set_fault_injector`](crate::UsbIpBus::set_fault_injector)
+//! sees every urb, that the device completes, and may replace its status, drop, duplicate
+//! or truncate it, or unplug the device in the middle of the transfer.
+//! Which urbs are hit is decided by [`FaultRule`]s, either deterministically
+//! or randomly from a seeded PRNG, such that a failing run can be reproduced.
+//!
+//! ```no_run
+//! use usb_device::{endpoint::EndpointType, UsbDirection};
+//! use usbip_device::{
+//!     fault::{Fault, FaultInjector, FaultRule},
+//!     protocol::{EPROTO, ETIME},
+//!     UsbIpBus,
+//! };
+//!
+//! let bus = UsbIpBus::new();
+//! let faults = FaultInjector::new(42)
+//!     // Fail the 3rd bulk out urb
+//!     .rule(
+//!         FaultRule::new(Fault::Status(-EPROTO))
+//!             .transfer_type(EndpointType::Bulk)
+//!             .direction(UsbDirection::Out)
+//!             .nth(3),
+//!     )
+//!     // Time out one in a hundred urbs on endpoint 1
+//!     .rule(FaultRule::new(Fault::Status(-ETIME)).endpoint(1).probability(0.01));
+//! bus.set_fault_injector(Some(faults));
+//! ```
+
+use crate::prng::Prng;
+use usb_device::{endpoint::EndpointType, UsbDirection};
+
+/// What happens to an urb, that a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The urb completes with the contained status and without data
+    Status(i32),
+    /// The urb never completes, until the host unlinks it
+    Drop,
+    /// The urb completes twice
+    Duplicate,
+    /// The data of the urb is cut down to the contained number of bytes
+    Truncate(usize),
+    /// The device is detached before the urb completes, as if it was unplugged
+    Unplug,
+}
+
+/// A fault together with the urbs, that it is injected into.
+///
+/// A rule without any filter matches every urb.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub fault: Fault,
+    /// Only urbs to this endpoint number
+    pub ep: Option<u8>,
+    /// Only urbs in this direction
+    pub direction: Option<UsbDirection>,
+    /// Only urbs to endpoints of this type
+    pub ty: Option<EndpointType>,
+    /// Only the urb with this sequence number
+    pub seqnum: Option<u32>,
+    /// Only the n-th urb, that passes the other filters, counting from 1
+    pub nth: Option<u64>,
+    /// Only this fraction of the urbs, that pass the other filters
+    pub probability: Option<f64>,
+    /// The number of urbs, that passed the filters so far
+    matched: u64,
+}
+
+impl FaultRule {
+    /// Creates a rule, that injects `fault` into every urb.
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            ep: None,
+            direction: None,
+            ty: None,
+            seqnum: None,
+            nth: None,
+            probability: None,
+            matched: 0,
+        }
+    }
+
+    /// Restricts the rule to urbs to the endpoint with the given number.
+    pub fn endpoint(mut self, ep: u8) -> Self {
+        self.ep = Some(ep);
+        self
+    }
+
+    /// Restricts the rule to urbs in the given direction.
+    pub fn direction(mut self, direction: UsbDirection) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Restricts the rule to urbs to endpoints of the given type.
+    pub fn transfer_type(mut self, ty: EndpointType) -> Self {
+        self.ty = Some(ty);
+        self
+    }
+
+    /// Restricts the rule to the urb with the given sequence number.
+    pub fn seqnum(mut self, seqnum: u32) -> Self {
+        self.seqnum = Some(seqnum);
+        self
+    }
+
+    /// Restricts the rule to the n-th matching urb, counting from 1.
+    pub fn nth(mut self, nth: u64) -> Self {
+        self.nth = Some(nth);
+        self
+    }
+
+    /// Restricts the rule to a random fraction of the matching urbs.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = Some(probability);
+        self
+    }
+
+    fn matches(&self, ep: u8, direction: UsbDirection, ty: EndpointType, seqnum: u32) -> bool {
+        self.ep.filter(|&val| val != ep).is_none()
+            && self.direction.filter(|&val| val != direction).is_none()
+            && self.ty.filter(|&val| val != ty).is_none()
+            && self.seqnum.filter(|&val| val != seqnum).is_none()
+    }
+}
+
+/// A set of rules, that decides which faults are injected into which urbs.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+    /// The PRNG used for the probabilities
+    rng: Prng,
+}
+
+impl FaultInjector {
+    /// Creates an injector without rules, whose random decisions are derived from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rules: vec![],
+            rng: Prng::new(seed),
+        }
+    }
+
+    /// Adds a rule. If multiple rules match an urb, the first one added wins.
+    pub fn rule(mut self, rule: FaultRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Returns the fault to inject into an urb or `None`, if it completes normally.
+    pub(crate) fn inject(
+        &mut self,
+        ep: u8,
+        direction: UsbDirection,
+        ty: EndpointType,
+        seqnum: u32,
+    ) -> Option<Fault> {
+        for i in 0..self.rules.len() {
+            let rule = &mut self.rules[i];
+            if !rule.matches(ep, direction, ty, seqnum) {
+                continue;
+            }
+
+            rule.matched += 1;
+            if matches!(rule.nth, Some(nth) if nth != rule.matched) {
+                continue;
+            }
+
+            if let Some(probability) = rule.probability {
+                if self.rng.next_f64() >= probability {
+                    continue;
+                }
+            }
+
+            return Some(self.rules[i].fault);
+        }
+
+        None
+    }
+}
@@ -1,86 +1,429 @@
 use crate::{
+    capture::Capture,
     cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
-    op::{OpDeviceDescriptor, OpInterfaceDescriptor, OpRequest, OpResponse, OpResponseCommand},
-    request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd},
-    response::{UsbIpResponse, UsbIpResponseCmd, UsbIpRetSubmit, UsbIpRetUnlink},
-    UsbIpBusInner,
+    debug::ClassDecoder,
+    descriptor::{ConfigurationDescriptor, DeviceDescriptor},
+    emulated::{DescriptorSet, EmulatedState},
+    event::BusEvent,
+    fault::Fault,
+    op::{
+        OpDeviceDescriptor, OpExportedDevice, OpInterfaceDescriptor, OpRequest, OpResponse,
+        OpResponseCommand, ST_DEV_BUSY, ST_ERROR, ST_NODEV, ST_OK, USBIP_VERSION,
+    },
+    replay::ReplayState,
+    request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd, URB_HEADER_SIZE},
+    response::{UsbIpResponse, UsbIpResponseCmd, UsbIpRetUnlink, ECONNRESET, ENOENT, EPIPE},
+    stats::{ConnectionState, SubmittedUrb},
+    UsbIpBusInner, UsbIpError,
 };
 use std::{
-    io::{ErrorKind, Write},
-    net::{TcpListener, TcpStream},
+    collections::VecDeque,
+    io::{Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+use usb_device::{
+    endpoint::{EndpointAddress, EndpointType},
+    UsbDirection, UsbError,
 };
-use usb_device::{endpoint::EndpointType, UsbError};
 
 #[derive(Debug)]
 pub struct SocketHandler {
-    listener: TcpListener,
-    connection: Option<TcpStream>,
+    /// The listener for new connections, `None` in loopback mode
+    listener: Option<TcpListener>,
+    /// The transport over which the device is currently imported
+    connection: Option<Transport>,
+    /// Connections that have not imported the device and only exchange op packets
+    clients: Vec<Connection>,
+    /// The protocol version, that the device was imported with over TCP
+    version: Option<u16>,
 }
 
 // TODO: Allow settable device speed
 const DEVICE_SPEED: u32 = 3;
 
+/// The bus id, under which the device is exported
+const BUS_ID: &str = "1-1";
+
+/// The number of bytes buffered for connections, that only exchange op packets
+const OP_BUFFER_SIZE: usize = 1024;
+
+/// The size of the setup packet of a control transfer
+const SETUP_SIZE: usize = 8;
+
+/// The start of the setup packet of SET_FEATURE(PORT_RESET) to a hub port
+const PORT_RESET_SETUP: [u8; 4] = [0x23, 0x03, 0x04, 0x00];
+
 impl SocketHandler {
     /// Create a new handler
     pub fn new() -> Self {
         let listener = TcpListener::bind(("127.0.0.1", 3240)).unwrap();
         listener.set_nonblocking(true).unwrap();
         Self {
-            listener,
+            listener: Some(listener),
             connection: None,
+            clients: vec![],
+            version: None,
+        }
+    }
+
+    /// Create a new handler, that does not listen for connections
+    /// and has the device imported by an in-process host
+    pub fn loopback() -> Self {
+        Self {
+            listener: None,
+            connection: Some(Transport::Loopback(VecDeque::new())),
+            clients: vec![],
+            version: None,
         }
     }
 
     pub fn is_connected(&self) -> bool {
         self.connection.is_some()
     }
+
+    /// Returns how the device is currently imported.
+    pub fn connection_state(&self) -> ConnectionState {
+        match self.connection {
+            None => ConnectionState::Detached,
+            Some(Transport::Tcp(_)) => ConnectionState::Imported,
+            Some(Transport::Loopback(_)) => ConnectionState::Loopback,
+        }
+    }
+
+    /// Returns the address of the remote host, that has imported the device.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.connection {
+            Some(Transport::Tcp(ref connection)) => connection.stream.peer_addr().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the protocol version, that the device was imported with over TCP.
+    pub fn version(&self) -> Option<u16> {
+        self.version
+    }
+
+    /// Imports the device over the loopback, unless it is already imported.
+    pub fn attach_loopback(&mut self) {
+        if self.connection.is_none() {
+            self.connection = Some(Transport::Loopback(VecDeque::new()));
+        }
+    }
+
+    /// Takes the oldest response, that has not yet been received by the loopback host.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if there is no response waiting
+    /// - `Err(UsbIpError::ConnectionClosed)` if the device is not imported over the loopback
+    pub fn pop_loopback_response(&mut self) -> Result<Option<UsbIpResponse>, UsbIpError> {
+        match self.connection {
+            Some(Transport::Loopback(ref mut responses)) => Ok(responses.pop_front()),
+            _ => Err(UsbIpError::ConnectionClosed),
+        }
+    }
+}
+
+/// The transport, over which the device is imported.
+#[derive(Debug)]
+pub enum Transport {
+    /// A connection of a remote host
+    Tcp(Connection),
+    /// The responses for the in-process host, that have not been received yet
+    Loopback(VecDeque<UsbIpResponse>),
+}
+
+/// The source of the responses, if urbs are not answered by the device on the bus.
+#[derive(Debug)]
+pub(crate) enum Responder {
+    /// The urbs are answered from a recording
+    Replay(ReplayState),
+    /// The urbs are answered from cloned descriptors and user hooks
+    Emulated(EmulatedState),
+}
+
+impl Responder {
+    /// Answers a submitted urb.
+    ///
+    /// # Returns
+    /// - `None` if the urb stays pending
+    fn submit(
+        &mut self,
+        header: &UsbIpHeader,
+        cmd: &UsbIpCmdSubmit,
+        data: &[u8],
+    ) -> Option<UsbIpResponse> {
+        match self {
+            Self::Replay(replay) => replay.submit(header, cmd, data),
+            Self::Emulated(emulated) => emulated.submit(header, cmd, data),
+        }
+    }
+
+    /// Removes a pending urb.
+    ///
+    /// # Returns
+    /// - `true` if the urb was pending
+    pub fn unlink(&mut self, seqnum: u32) -> bool {
+        match self {
+            Self::Replay(replay) => replay.unlink(seqnum),
+            Self::Emulated(emulated) => emulated.unlink(seqnum),
+        }
+    }
+
+    /// Returns the urbs, that completed since they were submitted.
+    fn poll(&mut self) -> Vec<UsbIpResponse> {
+        match self {
+            Self::Replay(_) => vec![],
+            Self::Emulated(emulated) => emulated.poll(),
+        }
+    }
+
+    /// Returns the descriptors of the impersonated device, if they are known.
+    fn descriptors(&self) -> Option<&DescriptorSet> {
+        match self {
+            Self::Replay(_) => None,
+            Self::Emulated(emulated) => Some(&emulated.descriptors),
+        }
+    }
+}
+
+/// A connection together with the received bytes, that do not form a complete packet yet,
+/// and the bytes, that the socket has not accepted yet.
+#[derive(Debug)]
+pub struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    out: VecDeque<u8>,
+    closed: bool,
+    /// The connection is closed, once all buffered bytes are sent
+    closing: bool,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Result<Self, Error> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            buf: vec![],
+            out: VecDeque::new(),
+            closed: false,
+            closing: false,
+        })
+    }
+
+    /// Decodes the next packet from the connection without blocking.
+    ///
+    /// At most `capacity` bytes are buffered, while waiting for a packet to complete.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if no complete packet has been received yet
+    /// - `Err(err)` with kind [`ErrorKind::NotConnected`] if the connection was closed
+    pub fn receive<T>(
+        &mut self,
+        capacity: usize,
+        decode: impl FnOnce(&[u8]) -> Result<Option<(T, usize)>, UsbIpError>,
+    ) -> Result<Option<T>, Error> {
+        let mut chunk = [0; 4096];
+        while !self.closed && self.buf.len() < capacity {
+            let to_read = usize::min(chunk.len(), capacity - self.buf.len());
+            match self.stream.read(&mut chunk[..to_read]) {
+                Ok(0) => self.closed = true,
+                Ok(bytes_read) => self.buf.extend_from_slice(&chunk[..bytes_read]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        match decode(&self.buf) {
+            Ok(Some((pkg, len))) => {
+                self.buf.drain(..len);
+                Ok(Some(pkg))
+            }
+            Ok(None) if self.closed => Err(Error::new(
+                ErrorKind::NotConnected,
+                Box::new(UsbIpError::ConnectionClosed),
+            )),
+            Ok(None) => Ok(None),
+            Err(err) => Err(Error::new(ErrorKind::InvalidInput, Box::new(err))),
+        }
+    }
+
+    /// Queues a complete packet and sends as much as the socket accepts without blocking.
+    ///
+    /// The rest is sent by [`Connection::flush`].
+    /// Fails with [`ErrorKind::WouldBlock`], if more than `capacity` bytes would be buffered.
+    pub fn send(&mut self, data: &[u8], capacity: usize) -> Result<(), Error> {
+        if self.out.len() + data.len() > capacity {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "peer does not receive the sent packets",
+            ));
+        }
+
+        self.out.extend(data);
+        self.flush()
+    }
+
+    /// Sends the buffered bytes, until the socket would block.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        while !self.out.is_empty() {
+            match self.stream.write(self.out.as_slices().0) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(bytes_written) => drop(self.out.drain(..bytes_written)),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl UsbIpBusInner {
+    /// Handles the socket and sends the responses, that completed in the meantime.
+    pub fn poll_responder(&mut self) {
+        self.handle_socket();
+
+        let responses = match self.responder {
+            Some(ref mut responder) => responder.poll(),
+            None => return,
+        };
+        for response in responses {
+            self.send_response(response);
+        }
+    }
+
     pub fn handle_socket(&mut self) {
-        match self.handler.connection {
-            // If not connected, listen for new connections
-            None => match self.handler.listener.accept() {
-                Ok((connection, addr)) => {
+        self.send_delayed();
+
+        // Accept all new connections, even if the device is already imported,
+        // such that they can still list the devices
+        while let Some(ref listener) = self.handler.listener {
+            match listener.accept() {
+                Ok((stream, addr)) => {
                     log::info!("new connection from: {}", addr);
-                    self.handler.connection = Some(connection)
+                    match Connection::new(stream) {
+                        Ok(connection) => {
+                            self.handler.clients.push(connection);
+                            self.emit(BusEvent::ClientConnected(addr));
+                        }
+                        Err(err) => log::error!("failed to set up connection: {}", err),
+                    }
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => (),
-                Err(err) => panic!("unexpected error: {}", err),
-            },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("failed to accept connection: {}", err);
+                    break;
+                }
+            }
+        }
 
-            // If connected, receive the data
-            Some(ref mut stream) => {
-                match self.reset {
-                    // If in reset state, answer op msgs
-                    true => {
-                        // in case of Op, we directly send a response here
-                        let op = match OpRequest::read(stream) {
-                            Ok(op) => op,
-                            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
-                            Err(err) if err.kind() == ErrorKind::NotConnected => {
-                                self.handler.connection = None;
-                                return;
-                            }
-                            Err(err) => panic!("unexpected error {}", err),
-                        };
-                        self.handle_op(op);
-                    }
-                    // If not in reset state, expect commands
-                    false => {
-                        let cmd = match UsbIpRequest::read(stream) {
-                            Ok(cmd) => cmd,
-                            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
-                            Err(err) if err.kind() == ErrorKind::NotConnected => {
-                                // If the connection is no longer connected, return to initial state
-                                self.reset = true;
-                                self.handler.connection = None;
-                                return;
-                            }
-                            Err(err) => panic!("unexpected error {}", err),
-                        };
-                        self.handle_usbip_pkg(cmd);
-                    }
+        self.handle_clients();
+
+        // If the device is imported, receive the commands
+        // NOTE: The loopback host submits its commands directly
+        if let Some(Transport::Tcp(ref mut connection)) = self.handler.connection {
+            if let Err(err) = connection.flush() {
+                log::warn!("disconnecting after failing to send responses: {}", err);
+                self.disconnect();
+                return;
+            }
+
+            let limits = self.limits;
+            let capacity = URB_HEADER_SIZE + limits.max_urb_size;
+            let cmd = match connection.receive(capacity, |buf| UsbIpRequest::decode(buf, &limits)) {
+                Ok(Some(cmd)) => cmd,
+                Ok(None) => return,
+                Err(err) if err.kind() == ErrorKind::NotConnected => {
+                    log::info!("device was detached");
+                    self.disconnect();
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("disconnecting after receiving invalid urb: {}", err);
+                    self.emit(BusEvent::ProtocolError(protocol_error(&err)));
+                    self.disconnect();
+                    return;
+                }
+            };
+
+            if let Err(err) = self.handle_usbip_pkg(cmd) {
+                log::warn!("disconnecting after invalid urb: {}", err);
+                self.emit(BusEvent::ProtocolError(err));
+                self.disconnect();
+            }
+        }
+    }
+
+    /// Drops the connection, over which the device is imported,
+    /// and returns to the initial state.
+    pub fn disconnect(&mut self) {
+        if self.handler.connection.is_some() {
+            self.emit(BusEvent::Detached);
+        }
+
+        self.handler.connection = None;
+        self.handler.version = None;
+
+        // The urbs of the lost connection must not be completed to the next importer
+        self.reset();
+
+        self.reset = true;
+        self.port_reset = false;
+        self.decoder = ClassDecoder::new();
+        self.in_flight.clear();
+        if let Some(ref mut timing) = self.timing {
+            timing.clear();
+        }
+    }
+
+    /// Answers the op packets of all connections, that have not imported the device.
+    fn handle_clients(&mut self) {
+        let clients = std::mem::take(&mut self.handler.clients);
+
+        for mut client in clients {
+            if let Err(err) = client.flush() {
+                log::debug!("dropping client after failing to send op reply: {}", err);
+                continue;
+            }
+
+            // A refused client is only dropped, once it has got the reply
+            if client.closing {
+                if !client.out.is_empty() {
+                    self.handler.clients.push(client);
+                }
+                continue;
+            }
+
+            // in case of Op, we directly send a response here
+            let op = match client.receive(OP_BUFFER_SIZE, OpRequest::decode) {
+                Ok(Some(op)) => op,
+                Ok(None) => {
+                    self.handler.clients.push(client);
+                    continue;
+                }
+                Err(err) if err.kind() == ErrorKind::NotConnected => {
+                    log::debug!("client closed the connection");
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!("dropping client after receiving invalid op: {}", err);
+                    self.emit(BusEvent::ProtocolError(protocol_error(&err)));
+                    continue;
+                }
+            };
+
+            match self.handle_op(&mut client, op) {
+                // The client has imported the device, from now on we expect commands
+                Ok(true) => {
+                    self.emit(BusEvent::Imported(client.stream.peer_addr().ok()));
+                    self.handler.connection = Some(Transport::Tcp(client));
+                }
+                Ok(false) => self.handler.clients.push(client),
+                Err(err) => {
+                    log::warn!("closing client after refused op: {}", err);
+                    self.emit(BusEvent::ProtocolError(protocol_error(&err)));
+                    client.closing = true;
+                    self.handler.clients.push(client);
                 }
             }
         }
@@ -96,267 +439,641 @@ impl UsbIpBusInner {
             return;
         }
 
-        let (header, _cmd, _) = match ep.pending_ins.pop_front() {
+        let (header, cmd, _) = match ep.pending_ins.pop_front() {
             Some(urb) => urb,
             None => return,
         };
-        //let bytes_requested = cmd.transfer_buffer_length;
+        let bytes_requested = cmd.transfer_buffer_length.max(0) as usize;
 
         let ep_in = match ep.get_in() {
             Ok(ep_in) => ep_in,
             Err(UsbError::InvalidEndpoint) => return,
             Err(e) => panic!("unexpected error {:?} while processing in packet", e),
         };
+        let max_packet_size = ep_in.max_packet_size as usize;
 
         // Read data from the packet buffer into the output buffer
-        // We must be careful to not send more bytes than requested
-        // FIXME: Fix this up so it supports transfer_buffers smaller than waiting data
+        // We must be careful to not send more bytes than requested,
+        // the rest stays queued for the next urb
         let mut out_buf = vec![];
         while let Some(data) = ep_in.data.pop_front() {
-            //let bytes_left = bytes_requested as usize - out_buf.len();
-            //let bytes_to_read = usize::min(data.len(), bytes_left);
+            let bytes_left = bytes_requested - out_buf.len();
+            if data.len() > bytes_left {
+                out_buf.extend_from_slice(&data[..bytes_left]);
+                ep_in.data.push_front(data[bytes_left..].to_vec());
+                break;
+            }
 
-            //out_buf.extend_from_slice(&data[..bytes_to_read]);
             out_buf.extend_from_slice(&data);
 
-            // if bytes_to_read != data.len() {
-            //    assert_eq!(out_buf.len(), bytes_requested as usize);
-            //    ep_in.data.push_front(data[bytes_to_read..].to_vec());
-            //    break;
-            // }
+            // A short packet terminates the transfer
+            if data.len() < max_packet_size || out_buf.len() == bytes_requested {
+                break;
+            }
         }
 
         // After sending, the in_complete can be set
         ep.in_complete_flag = true;
 
-        // TODO: Error if exact read was requested and out_buf.len() smaller than bytes_requested
+        let response = UsbIpResponse::ret_submit(&header, 0, out_buf.len(), out_buf);
 
-        let response = UsbIpResponse {
-            header: UsbIpHeader {
-                command: UsbCmd::Response,
-                seqnum: header.seqnum,
-                devid: 2,
-                direction: Direction::IN,
-                ep: ep_addr as u32,
+        self.complete_urb(response);
+    }
+
+    /// Describes the exported device in op responses.
+    fn exported_device(&self) -> OpExportedDevice {
+        let mut device = OpExportedDevice {
+            path: "/sys/devices/pci0000:00/0000:00:01.2/usb1/1-1".to_string(),
+            bus_id: BUS_ID.to_string(),
+            descriptor: OpDeviceDescriptor {
+                busnum: 1,
+                devnum: 2,
+                speed: DEVICE_SPEED,
+
+                // These values should be settable via configuration
+                vendor: 0x1111,
+                product: 0x1010,
+                bcd_device: 0,
+                device_class: 0,
+                device_subclass: 0,
+                device_protocol: 0,
+                configuration_value: 0,
+
+                // These are fixed for this implementation
+                num_configurations: 1,
+                num_interfaces: 1,
             },
-            cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
-                status: 0,
-                actual_length: out_buf.len() as i32,
-                start_frame: 0,
-                number_of_packets: 0,
-                error_count: 0,
-            }),
-            data: out_buf,
+            interfaces: vec![OpInterfaceDescriptor {
+                // TODO: Make these setable
+                interface_class: 0,
+                interface_subclass: 0,
+                interface_protocol: 0,
+                padding: 0,
+            }],
         };
-        log::debug!("{:?}", response);
 
-        self.handler
-            .connection
-            .as_mut()
-            .unwrap()
-            .write_all(&response.to_vec().unwrap())
-            .unwrap();
+        // An emulated device is exported as the device it was cloned from
+        let descriptors = match self.responder {
+            Some(ref responder) => responder.descriptors(),
+            None => None,
+        };
+        if let Some(descriptors) = descriptors {
+            export_descriptors(&mut device, descriptors);
+        }
+
+        device
     }
 
     /// Handles an incomming op packet, sends out the corresponding response
-    fn handle_op(&mut self, op: OpRequest) {
+    ///
+    /// # Returns
+    /// - `true` if the client has imported the device
+    /// - `false` otherwise
+    fn handle_op(&mut self, client: &mut Connection, op: OpRequest) -> Result<bool, Error> {
+        // Refuse to talk to clients, that speak a different version of the protocol
+        let version = op.header().version;
+        if version != USBIP_VERSION {
+            log::warn!("refusing op of unsupported version {:#06x}", version);
+
+            let cmd = match op {
+                OpRequest::ListDevices(_) => OpResponseCommand::ListDevices(vec![]),
+                OpRequest::ConnectDevice(_, _) => OpResponseCommand::ConnectDevice(None),
+            };
+            send_op(client, USBIP_VERSION, ST_ERROR, cmd)?;
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                Box::new(UsbIpError::UnsupportedVersion(version)),
+            ));
+        }
+
         match op {
-            OpRequest::ListDevices(header) => {
-                let list_response = OpResponse {
-                    version: header.version,
-                    path: "/sys/devices/pci0000:00/0000:00:01.2/usb1/1-1".to_string(),
-                    bus_id: "1-1".to_string(),
-                    descriptor: OpDeviceDescriptor {
-                        busnum: 1,
-                        devnum: 2,
-                        speed: DEVICE_SPEED,
-
-                        // These values should be settable via configuration
-                        vendor: 0x1111,
-                        product: 0x1010,
-                        bcd_device: 0,
-                        device_class: 0,
-                        device_subclass: 0,
-                        device_protocol: 0,
-                        configuration_value: 0,
-
-                        // These are fixed for this implementation
-                        num_configurations: 1,
-                        num_interfaces: 1,
-                    },
-                    cmd: OpResponseCommand::ListDevices(OpInterfaceDescriptor {
-                        // TODO: Make these setable
-                        interface_class: 0,
-                        interface_subclass: 0,
-                        interface_protocol: 0,
-                        padding: 0,
-                    }),
-                };
-
-                self.handler
-                    .connection
-                    .as_mut()
-                    .unwrap()
-                    .write_all(&list_response.to_vec().unwrap())
-                    .unwrap();
-            }
-            OpRequest::ConnectDevice(header) => {
-                let list_response = OpResponse {
-                    version: header.version,
-                    path: "/sys/devices/pci0000:00/0000:00:01.2/usb1/1-1".to_string(),
-                    bus_id: "1-1".to_string(),
-                    descriptor: OpDeviceDescriptor {
-                        busnum: 1,
-                        devnum: 2,
-                        speed: DEVICE_SPEED,
-
-                        // These values should be settable via configuration
-                        vendor: 0x1111,
-                        product: 0x1010,
-                        bcd_device: 0,
-                        device_class: 0,
-                        device_subclass: 0,
-                        device_protocol: 0,
-                        configuration_value: 0,
-
-                        // These are fixed for this implementation
-                        num_configurations: 1,
-                        num_interfaces: 1,
-                    },
-                    cmd: OpResponseCommand::ConnectDevice,
-                };
+            OpRequest::ListDevices(_) => {
+                let devices = vec![self.exported_device()];
+                send_op(
+                    client,
+                    version,
+                    ST_OK,
+                    OpResponseCommand::ListDevices(devices),
+                )?;
+                self.emit(BusEvent::DevlistServed(client.stream.peer_addr().ok()));
+                Ok(false)
+            }
+            OpRequest::ConnectDevice(_, bus_id) => {
+                // Only the device we export can be imported
+                if bus_id != BUS_ID {
+                    log::warn!("refusing import of unknown bus id {}", bus_id);
+
+                    send_op(
+                        client,
+                        version,
+                        ST_NODEV,
+                        OpResponseCommand::ConnectDevice(None),
+                    )?;
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        Box::new(UsbIpError::UnknownBusId(bus_id)),
+                    ));
+                }
+
+                // Only one client can import the device at a time
+                if self.handler.connection.is_some() {
+                    log::warn!("refusing import, device is already imported");
+
+                    let cmd = OpResponseCommand::ConnectDevice(None);
+                    send_op(client, version, ST_DEV_BUSY, cmd)?;
+                    return Ok(false);
+                }
+
+                let cmd = OpResponseCommand::ConnectDevice(Some(self.exported_device()));
+                send_op(client, version, ST_OK, cmd)?;
 
                 // Set the inner value to not reset, because we have connected the device
                 log::info!("device is leaving reset state");
                 self.reset = false;
+                self.handler.version = Some(version);
 
-                self.handler
-                    .connection
-                    .as_mut()
-                    .unwrap()
-                    .write_all(&list_response.to_vec().unwrap())
-                    .unwrap();
+                Ok(true)
             }
         }
     }
 
-    fn handle_usbip_pkg(&mut self, request: UsbIpRequest) {
-        log::debug!("{:?}", request);
+    pub fn handle_usbip_pkg(&mut self, request: UsbIpRequest) -> Result<(), UsbIpError> {
+        log::debug!("{:?}", self.decoder.request(&request));
+        self.capture_request(&request);
 
         match request.cmd {
             UsbIpRequestCmd::Unlink(unlink) => self.handle_unlink(request.header, unlink),
-            UsbIpRequestCmd::Cmd(cmd) => self.handle_cmd(request.header, cmd, request.data),
+            UsbIpRequestCmd::Cmd(cmd) => self.handle_cmd(request.header, cmd, request.data)?,
         }
+
+        Ok(())
     }
 
     /// Handle a [`UsbIpCmdSubmit`] package
-    fn handle_cmd(&mut self, header: UsbIpHeader, cmd: UsbIpCmdSubmit, data: Vec<u8>) {
+    fn handle_cmd(
+        &mut self,
+        header: UsbIpHeader,
+        cmd: UsbIpCmdSubmit,
+        data: Vec<u8>,
+    ) -> Result<(), UsbIpError> {
+        // Do not let the host queue up an unbounded number of in urbs
+        let num_pending = self.num_pending_urbs();
+        if header.direction == Direction::IN && num_pending >= self.limits.max_pending_urbs {
+            return Err(UsbIpError::TooManyPendingUrbs(num_pending + 1));
+        }
+
+        let urb = SubmittedUrb {
+            ep: header.ep as usize,
+            direction: header.direction,
+            time: self.clock.now(),
+        };
+        self.in_flight.insert(header.seqnum, urb);
+        self.emit(BusEvent::UrbSubmitted {
+            seqnum: header.seqnum,
+            ep: endpoint_address(header.ep, header.direction),
+            length: cmd.transfer_buffer_length.max(0) as usize,
+        });
+
+        // A replay or emulation answers instead of the device
+        if let Some(ref mut responder) = self.responder {
+            if let Some(response) = responder.submit(&header, &cmd, &data) {
+                self.send_response(response);
+            }
+            return Ok(());
+        }
+
+        // USBIP forwards a reset of the port as SET_FEATURE(PORT_RESET) to the device
+        if header.ep == 0 && cmd.setup[..4] == PORT_RESET_SETUP {
+            log::info!("host is resetting the device");
+            self.reset();
+            self.port_reset = true;
+            self.emit(BusEvent::Reset);
+            self.ack_cmd_out(&header, 0);
+            return Ok(());
+        }
+
         // Get the endpoint
         let ep = match self.get_endpoint(header.ep as usize) {
-            Ok(ep) => ep,
-            Err(err) => {
-                log::warn!("reveiced message for unimplemented endpoint {:?}", err);
-                return;
+            Ok(ep) if ep.pipe_in.is_some() || ep.pipe_out.is_some() => ep,
+            _ => {
+                log::warn!("received message for unimplemented endpoint {}", header.ep);
+                self.fail_cmd(&header, -ENOENT);
+                return Ok(());
             }
         };
 
         // check wether we have a setup packet
         // NOTE: This assumes the control endpoints have no URBs pending
-        if cmd.setup != [0, 0, 0, 0, 0, 0, 0, 0] {
-            ep.get_out().unwrap().data.push_back(cmd.setup.to_vec());
-            ep.setup_flag = true;
+        let is_setup = cmd.setup != [0, 0, 0, 0, 0, 0, 0, 0];
+        let is_in = header.direction == Direction::IN;
+
+        // Validate the urb, before a setup packet changes the state of the endpoint
+        if is_setup && ep.pipe_out.is_none() {
+            log::warn!(
+                "received setup packet for endpoint {} without out pipe",
+                header.ep
+            );
+            self.fail_cmd(&header, -EPIPE);
+            return Ok(());
         }
 
-        match header.direction {
-            Direction::OUT => {
-                let ep_out = ep.get_out().unwrap();
+        let (pipe, name) = match is_in {
+            true => (&ep.pipe_in, "in"),
+            false => (&ep.pipe_out, "out"),
+        };
+        let stalled = match pipe {
+            // A setup packet clears the halt of a control endpoint
+            Some(pipe) => pipe.stalled && !is_setup,
+            None => {
+                log::warn!(
+                    "received {} packet for endpoint {} without {} pipe",
+                    name,
+                    header.ep,
+                    name
+                );
+                self.fail_cmd(&header, -EPIPE);
+                return Ok(());
+            }
+        };
+        if stalled {
+            log::debug!("failing {} packet for halted endpoint {}", name, header.ep);
+            self.fail_cmd(&header, -EPIPE);
+            return Ok(());
+        }
 
-                // pass the data into the correct buffers
-                for chunk in data.chunks(ep_out.max_packet_size as usize) {
-                    ep_out.data.push_back(chunk.to_vec());
-                }
+        if is_setup {
+            // A setup packet starts a new transfer, which clears the state of the previous one
+            if let Some(ref mut ep_in) = ep.pipe_in {
+                ep_in.data.clear();
+                ep_in.stalled = false;
+            }
+            if let Some(ref mut ep_out) = ep.pipe_out {
+                ep_out.stalled = false;
+                ep_out.data.push_back(cmd.setup.to_vec());
+            }
+            ep.setup_flag = true;
+        }
 
-                if cmd.transfer_flags.contains(TransferFlags::ZERO_PACKET)
-                    && ep_out.ty == EndpointType::Bulk
-                {
-                    ep_out.data.push_back(vec![]);
-                }
+        if is_in {
+            let ep_addr = header.ep;
+            ep.pending_ins.push_back((header, cmd, data));
+            self.try_send_pending(ep_addr as usize);
+        } else if let Some(ref mut ep_out) = ep.pipe_out {
+            // pass the data into the correct buffers
+            for chunk in data.chunks(ep_out.max_packet_size as usize) {
+                ep_out.data.push_back(chunk.to_vec());
+            }
 
-                self.ack_cmd_out(header.ep, header.seqnum, data.len());
+            if cmd.transfer_flags.contains(TransferFlags::ZERO_PACKET)
+                && ep_out.ty == EndpointType::Bulk
+            {
+                ep_out.data.push_back(vec![]);
             }
-            Direction::IN => {
-                let ep_addr = header.ep;
-                ep.pending_ins.push_back((header, cmd, data));
-                self.try_send_pending(ep_addr as usize);
+
+            // Control transfers are only acknowledged, once the device has accepted them
+            if is_setup {
+                ep.pending_control_out = Some((header, data.len()));
+            } else {
+                self.ack_cmd_out(&header, data.len());
             }
-            _ => panic!(),
+        }
+
+        Ok(())
+    }
+
+    /// Completes all urbs pending on an endpoint with an error status.
+    pub fn fail_pending(&mut self, ep_addr: usize, status: i32) {
+        let ep = match self.get_endpoint(ep_addr) {
+            Ok(ep) => ep,
+            Err(_) => return,
+        };
+
+        let mut headers: Vec<UsbIpHeader> = ep
+            .pending_ins
+            .drain(..)
+            .map(|(header, _, _)| header)
+            .collect();
+        headers.extend(ep.pending_control_out.take().map(|(header, _)| header));
+
+        for header in headers {
+            log::debug!("failing urb {} with status {}", header.seqnum, status);
+            self.fail_cmd(&header, status);
         }
     }
 
     /// Send an acknowledgement after recieving a cmd out package.
-    fn ack_cmd_out(&mut self, ep: u32, seqnum: u32, len: usize) {
-        let response = UsbIpResponse {
-            header: UsbIpHeader {
-                command: UsbCmd::Response,
-                seqnum,
-                devid: 2,
-                direction: Direction::OUT,
-                ep,
-            },
-            cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
-                status: 0,
-                actual_length: len as i32,
-                start_frame: 0,
-                number_of_packets: 0,
-                error_count: 0,
-            }),
-            data: vec![],
+    pub fn ack_cmd_out(&mut self, header: &UsbIpHeader, len: usize) {
+        let response = UsbIpResponse::ret_submit(header, 0, len, vec![]);
+        self.complete_urb(response);
+    }
+
+    /// Sends the completion of an urb by the device, unless a fault is injected instead.
+    fn complete_urb(&mut self, mut response: UsbIpResponse) {
+        let header = response.header.clone();
+        let ep = endpoint_address(header.ep, header.direction);
+        let ty = self.transfer_type(header.ep as usize, header.direction);
+        let fault = match self.faults {
+            Some(ref mut faults) => {
+                faults.inject(ep.index() as u8, ep.direction(), ty, header.seqnum)
+            }
+            None => None,
+        };
+
+        let (fault, ret) = match (fault, &mut response.cmd) {
+            (Some(fault), UsbIpResponseCmd::Cmd(ret)) => (fault, ret),
+            _ => return self.send_response(response),
         };
-        log::debug!("{:?}", response);
+        log::info!("injecting {:?} into urb {}", fault, header.seqnum);
+
+        match fault {
+            Fault::Status(status) => {
+                ret.status = status;
+                ret.actual_length = 0;
+                response.data.clear();
+                self.send_response(response);
+            }
+            Fault::Truncate(len) => {
+                ret.actual_length = ret.actual_length.min(len as i32);
+                response.data.truncate(len);
+                self.send_response(response);
+            }
+            Fault::Duplicate => {
+                self.send_response(response.clone());
+                self.send_response(response);
+            }
+            Fault::Drop => {
+                self.in_flight.remove(&header.seqnum);
+            }
+            Fault::Unplug => self.disconnect(),
+        }
+    }
+
+    /// Returns the type of the endpoint, that an urb is sent to.
+    fn transfer_type(&mut self, ep: usize, direction: Direction) -> EndpointType {
+        let pipe = match self.get_endpoint(ep) {
+            Ok(ep) if direction == Direction::IN => ep.pipe_in.as_ref(),
+            Ok(ep) => ep.pipe_out.as_ref(),
+            Err(_) => None,
+        };
+        match pipe {
+            Some(pipe) => pipe.ty,
+            None if ep == 0 => EndpointType::Control,
+            None => EndpointType::Bulk,
+        }
+    }
 
-        self.handler
-            .connection
-            .as_mut()
-            .unwrap()
-            .write_all(&response.to_vec().unwrap())
-            .unwrap();
+    /// Complete a cmd package, that could not be processed, with an error status.
+    fn fail_cmd(&mut self, header: &UsbIpHeader, status: i32) {
+        let response = UsbIpResponse::ret_submit(header, status, 0, vec![]);
+        self.send_response(response);
     }
 
     /// Handle a received unlink package
     fn handle_unlink(&mut self, header: UsbIpHeader, unlink: UsbIpCmdUnlink) {
-        match self.unlink(unlink.seqnum) {
-            true => (),
+        let status = match self.unlink(unlink.seqnum) {
+            true => {
+                self.emit(BusEvent::UrbUnlinked {
+                    seqnum: unlink.seqnum,
+                });
+                if let Some(urb) = self.in_flight.remove(&unlink.seqnum) {
+                    if let Some(stats) = self.pipe_stats(urb.ep, urb.direction) {
+                        stats.urbs_unlinked += 1;
+                    }
+                }
+                -ECONNRESET
+            }
             false => {
                 log::warn!(
                     "received request to remove urb {} that does not exists",
                     unlink.seqnum
                 );
+                0
             }
-        }
+        };
 
-        self.ack_unlink(header.ep, header.seqnum);
+        self.ack_unlink(&header, status);
     }
 
     /// Send an acknowledgement after recieving an unlink package.
-    fn ack_unlink(&mut self, ep: u32, seqnum: u32) {
+    ///
+    /// The status is `-ECONNRESET` if the urb was removed and `0` if it was already completed.
+    fn ack_unlink(&mut self, header: &UsbIpHeader, status: i32) {
         let response = UsbIpResponse {
             header: UsbIpHeader {
                 command: UsbCmd::UnlinkResponse,
-                seqnum,
-                devid: 2,
+                seqnum: header.seqnum,
+                devid: header.devid,
                 direction: Direction::OUT,
-                ep,
+                ep: header.ep,
             },
-            cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink { status: 0 }),
+            cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink { status }),
             data: vec![],
         };
-        log::debug!("{:?}", response);
 
-        self.handler
-            .connection
-            .as_mut()
-            .unwrap()
-            .write_all(&response.to_vec().unwrap())
-            .unwrap();
+        self.send_response(response);
+    }
+
+    /// Writes a request into all captures.
+    fn capture_request(&mut self, request: &UsbIpRequest) {
+        if self.captures.is_empty() {
+            return;
+        }
+
+        let ty = self.transfer_type(request.header.ep as usize, request.header.direction);
+        let time = self.clock.now();
+        self.update_captures(|capture| capture.request(request, ty, time));
+    }
+
+    /// Writes a response into all captures.
+    fn capture_response(&mut self, response: &UsbIpResponse) {
+        if !self.captures.is_empty() {
+            let time = self.clock.now();
+            self.update_captures(|capture| capture.response(response, time));
+        }
+    }
+
+    /// Applies `f` to all captures and stops the ones, that fail.
+    fn update_captures<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut dyn Capture) -> Result<(), Error>,
+    {
+        let captures = std::mem::take(&mut self.captures);
+        self.captures = captures
+            .into_iter()
+            .filter_map(|mut capture| match f(capture.as_mut()) {
+                Ok(()) => Some(capture),
+                Err(err) => {
+                    log::warn!("stopping capture after error: {}", err);
+                    None
+                }
+            })
+            .collect();
+    }
+
+    /// Reports a completed urb and counts it on the pipe, that it was submitted to.
+    fn record_completion(&mut self, response: &UsbIpResponse) {
+        let status = match response.cmd {
+            UsbIpResponseCmd::Cmd(ref ret) => {
+                self.emit(BusEvent::UrbCompleted {
+                    seqnum: response.header.seqnum,
+                    ep: endpoint_address(response.header.ep, response.header.direction),
+                    status: ret.status,
+                    actual_length: ret.actual_length.max(0) as usize,
+                });
+                ret.status
+            }
+            UsbIpResponseCmd::Unlink(_) => return,
+        };
+        let urb = match self.in_flight.remove(&response.header.seqnum) {
+            Some(urb) => urb,
+            None => return,
+        };
+        let latency = self.clock.now().saturating_sub(urb.time);
+        let stats = match self.pipe_stats(urb.ep, urb.direction) {
+            Some(stats) => stats,
+            None => return,
+        };
+
+        match status {
+            0 => {
+                stats.urbs_completed += 1;
+                stats.latency.record(latency);
+            }
+            status if status == -EPIPE => stats.urbs_stalled += 1,
+            _ => stats.urbs_failed += 1,
+        }
+    }
+
+    /// Sends an event to all subscribers and drops the ones, that hung up.
+    pub fn emit(&mut self, event: BusEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Send a response over the connection, over which the device is imported.
+    ///
+    /// If the bus timing is emulated, completions are held back until they are due.
+    fn send_response(&mut self, response: UsbIpResponse) {
+        let len = match (&self.timing, &response.cmd) {
+            (Some(_), UsbIpResponseCmd::Cmd(ret)) => ret.actual_length.max(0) as usize,
+            _ => return self.transmit_response(response),
+        };
+
+        // The setup packet is transferred as well
+        let len = match response.header.ep {
+            0 => len + SETUP_SIZE,
+            _ => len,
+        };
+        let interval = match self.pipe_mut(response.header.ep as usize, response.header.direction) {
+            Some(pipe) if pipe.ty == EndpointType::Interrupt => Some(pipe.interval),
+            _ => None,
+        };
+        let now = self.clock.now();
+        if let Some(ref mut timing) = self.timing {
+            timing.delay(response, len, interval, now);
+        }
+    }
+
+    /// Sends the completions, that the timing emulation has held back until now.
+    fn send_delayed(&mut self) {
+        let now = self.clock.now();
+        while let Some(response) = self.timing.as_mut().and_then(|timing| timing.pop_due(now)) {
+            self.transmit_response(response);
+        }
     }
+
+    /// Sends a response right away.
+    pub fn transmit_response(&mut self, response: UsbIpResponse) {
+        log::debug!("{:?}", self.decoder.response(&response));
+        self.capture_response(&response);
+        self.record_completion(&response);
+
+        let connection = match self.handler.connection {
+            Some(Transport::Tcp(ref mut connection)) => connection,
+            Some(Transport::Loopback(ref mut responses)) => {
+                responses.push_back(response);
+                return;
+            }
+            None => {
+                log::warn!("dropping response, device is not imported");
+                return;
+            }
+        };
+
+        if let Err(err) = connection.send(&response.encode(), self.limits.max_send_buffer) {
+            log::warn!("disconnecting after failing to send response: {}", err);
+            self.disconnect();
+        }
+    }
+}
+
+/// Returns the address of the endpoint, that an urb is sent to.
+fn endpoint_address(ep: u32, direction: Direction) -> EndpointAddress {
+    let direction = match direction {
+        Direction::IN => UsbDirection::In,
+        _ => UsbDirection::Out,
+    };
+    EndpointAddress::from_parts(ep as usize, direction)
+}
+
+/// Extracts the cause of a failure to receive or answer a packet.
+fn protocol_error(err: &Error) -> UsbIpError {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<UsbIpError>())
+        .cloned()
+        .unwrap_or_else(|| UsbIpError::Io(err.kind()))
+}
+
+/// Fills in the values of the exported device from its descriptors.
+fn export_descriptors(device: &mut OpExportedDevice, descriptors: &DescriptorSet) {
+    if let Some(Ok(dev)) = descriptors.device().map(DeviceDescriptor::from_slice) {
+        let op = &mut device.descriptor;
+        op.vendor = dev.vendor_id;
+        op.product = dev.product_id;
+        op.bcd_device = dev.device_version;
+        op.device_class = dev.device_class;
+        op.device_subclass = dev.device_subclass;
+        op.device_protocol = dev.device_protocol;
+        op.num_configurations = dev.num_configurations;
+    }
+
+    let config = match descriptors
+        .configuration(0)
+        .map(ConfigurationDescriptor::from_slice)
+    {
+        Some(Ok(config)) => config,
+        _ => return,
+    };
+    device.descriptor.num_interfaces = config.num_interfaces;
+
+    // Only the first alternate setting of each interface is exported
+    device.interfaces = config
+        .interfaces
+        .iter()
+        .filter(|interface| interface.alternate_setting == 0)
+        .map(|interface| OpInterfaceDescriptor {
+            interface_class: interface.interface_class,
+            interface_subclass: interface.interface_subclass,
+            interface_protocol: interface.interface_protocol,
+            padding: 0,
+        })
+        .collect();
+}
+
+/// Sends an op response over a connection
+fn send_op(
+    client: &mut Connection,
+    version: u16,
+    status: u32,
+    cmd: OpResponseCommand,
+) -> Result<(), Error> {
+    let response = OpResponse {
+        version,
+        status,
+        cmd,
+    };
+    let data = response
+        .encode()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, Box::new(err)))?;
+    client.send(&data, OP_BUFFER_SIZE)
 }
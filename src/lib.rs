@@ -1,14 +1,48 @@
+pub mod capture;
+pub mod class;
+pub mod client;
+pub mod clock;
 pub(crate) mod cmd;
-pub(crate) mod debug;
+pub mod compliance;
+pub mod debug;
+pub mod descriptor;
+pub mod emulated;
+pub mod event;
+pub mod fault;
 pub(crate) mod handler;
+pub mod loopback;
 pub(crate) mod op;
+pub(crate) mod prng;
+pub mod protocol;
+pub mod proxy;
+pub mod replay;
 pub(crate) mod request;
 pub(crate) mod response;
-
-use crate::{cmd::UsbIpHeader, handler::SocketHandler, request::UsbIpCmdSubmit};
+pub mod stats;
+pub mod timing;
+
+use crate::{
+    capture::{Capture, PcapWriter},
+    clock::{Clock, WallClock},
+    cmd::{Direction, UsbIpHeader},
+    debug::ClassDecoder,
+    event::BusEvent,
+    fault::FaultInjector,
+    handler::{Responder, SocketHandler},
+    loopback::LoopbackHost,
+    request::UsbIpCmdSubmit,
+    response::{EPIPE, ESHUTDOWN},
+    stats::{BusSnapshot, EndpointSnapshot, PipeSnapshot, PipeStats, SubmittedUrb},
+    timing::{BusTiming, TimingState},
+};
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex, MutexGuard},
+    collections::{HashMap, VecDeque},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
 };
 use usb_device::{
     Result as UsbResult, UsbDirection, UsbError,
@@ -32,6 +66,69 @@ pub enum UsbIpError {
 
     /// A received packet had a status field set to an unknown status value.
     StatusNotOk(u32),
+
+    /// A received op packet uses a version of the protocol, that is not supported.
+    UnsupportedVersion(u16),
+
+    /// A device with the requested bus id is not exported by this server.
+    UnknownBusId(String),
+
+    /// A received urb contained a command, that is unknown to the USBIP specification.
+    InvalidUrbCommand(u32),
+
+    /// A received urb had a direction, that is neither in nor out.
+    InvalidDirection(u32),
+
+    /// A received urb had a negative transfer buffer length.
+    InvalidTransferLength(i32),
+
+    /// A received urb exceeded the maximum urb size set in [`UsbIpLimits`].
+    UrbTooLarge(usize),
+
+    /// The host exceeded the maximum number of pending urbs set in [`UsbIpLimits`].
+    TooManyPendingUrbs(usize),
+
+    /// A string is too long to fit into its field of a packet.
+    StringTooLong(usize),
+
+    /// A received packet or descriptor contained a string, that is not valid utf-8 or utf-16.
+    InvalidString,
+
+    /// A received urb is part of an isochronous transfer, which is not supported.
+    IsochronousUnsupported,
+
+    /// An I/O error occured on the underlying connection.
+    Io(std::io::ErrorKind),
+
+    /// An operation did not complete in time.
+    Timeout,
+
+    /// An urb was completed with the contained negative error number as status.
+    UrbFailed(i32),
+
+    /// There is no pending urb with the requested sequence number.
+    UnknownSeqnum(u32),
+
+    /// A descriptor of the contained type, received from the device, is malformed.
+    InvalidDescriptor(u8),
+
+    /// The device has no interface of the contained class, that a driver could bind to.
+    ClassNotFound(u8),
+
+    /// A mass storage device answered with a malformed or mismatching command status wrapper.
+    InvalidCsw,
+
+    /// A mass storage device reported a phase error.
+    PhaseError,
+
+    /// A SCSI command failed with the contained sense key, additional sense code and qualifier.
+    CommandFailed(u8, u8, u8),
+
+    /// A recording could not be loaded, because the contained record or line is malformed.
+    InvalidRecording(usize),
+
+    /// A DFU device ended up in the contained state with the contained status instead of the expected one.
+    DfuFailed(u8, u8),
 }
 
 impl std::fmt::Display for UsbIpError {
@@ -41,12 +138,81 @@ impl std::fmt::Display for UsbIpError {
             Self::PkgTooShort(len) => write!(f, "packet of length {} is to short to parse", len),
             Self::InvalidCommand(cmd) => write!(f, "unknown command: {}", cmd),
             Self::StatusNotOk(status) => write!(f, "received invalid status: {}", status),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version: {:#06x}", version)
+            }
+            Self::UnknownBusId(bus_id) => write!(f, "unknown bus id: {}", bus_id),
+            Self::InvalidUrbCommand(cmd) => write!(f, "unknown urb command: {}", cmd),
+            Self::InvalidDirection(direction) => write!(f, "invalid urb direction: {}", direction),
+            Self::InvalidTransferLength(len) => write!(f, "invalid transfer length: {}", len),
+            Self::UrbTooLarge(len) => write!(f, "urb of length {} exceeds the limit", len),
+            Self::TooManyPendingUrbs(num) => write!(f, "{} pending urbs exceed the limit", num),
+            Self::StringTooLong(len) => write!(f, "string of length {} is too long", len),
+            Self::InvalidString => write!(f, "received string is not valid unicode"),
+            Self::IsochronousUnsupported => write!(f, "isochronous transfers are not supported"),
+            Self::Io(kind) => write!(f, "i/o error: {:?}", kind),
+            Self::Timeout => write!(f, "operation timed out"),
+            Self::UrbFailed(status) => write!(f, "urb failed with status {}", status),
+            Self::UnknownSeqnum(seqnum) => write!(f, "no pending urb with seqnum {}", seqnum),
+            Self::InvalidDescriptor(ty) => write!(f, "malformed descriptor of type {}", ty),
+            Self::ClassNotFound(class) => write!(f, "no interface of class {:#04x}", class),
+            Self::InvalidCsw => write!(f, "invalid command status wrapper"),
+            Self::PhaseError => write!(f, "mass storage device reported a phase error"),
+            Self::CommandFailed(key, asc, ascq) => write!(
+                f,
+                "scsi command failed with sense key {:#04x}, asc {:#04x}, ascq {:#04x}",
+                key, asc, ascq
+            ),
+            Self::InvalidRecording(record) => write!(f, "invalid record {} in recording", record),
+            Self::DfuFailed(state, status) => write!(
+                f,
+                "dfu device is in state {} with status {:#04x}",
+                state, status
+            ),
         }
     }
 }
 
 impl std::error::Error for UsbIpError {}
 
+impl From<std::io::Error> for UsbIpError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Self::Timeout,
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe => Self::ConnectionClosed,
+            kind => Self::Io(kind),
+        }
+    }
+}
+
+/// Limits on the resources, a host can make the [`UsbIpBus`] allocate.
+///
+/// A host exceeding these limits gets disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbIpLimits {
+    /// The maximum transfer buffer length of a single urb in bytes.
+    pub max_urb_size: usize,
+
+    /// The maximum number of in urbs, that may be pending at the same time.
+    pub max_pending_urbs: usize,
+
+    /// The maximum number of bytes, that are buffered for a host, which does not receive its responses.
+    pub max_send_buffer: usize,
+}
+
+impl Default for UsbIpLimits {
+    fn default() -> Self {
+        Self {
+            max_urb_size: 1 << 20,
+            max_pending_urbs: 1024,
+            max_send_buffer: 4 << 20,
+        }
+    }
+}
+
 const NUM_ENDPOINTS: usize = 8;
 
 #[derive(Debug, Clone)]
@@ -54,8 +220,11 @@ pub(crate) struct Pipe {
     pub data: VecDeque<Vec<u8>>,
     pub ty: EndpointType,
     pub max_packet_size: u16,
-    #[allow(dead_code)]
     pub interval: u8,
+    /// Whether the pipe is halted, in which case all urbs fail
+    pub stalled: bool,
+    /// The counters of the traffic through this pipe
+    pub stats: PipeStats,
 }
 
 impl Pipe {
@@ -76,31 +245,31 @@ impl Pipe {
             }
         }
     }
+
+    /// Returns the current state of the pipe.
+    fn snapshot(&self) -> PipeSnapshot {
+        PipeSnapshot {
+            ty: self.ty,
+            max_packet_size: self.max_packet_size,
+            stalled: self.stalled,
+            queued_packets: self.data.len(),
+            queued_bytes: self.data.iter().map(Vec::len).sum(),
+            stats: self.stats.clone(),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct Endpoint {
     pub(crate) pipe_in: Option<Pipe>,
     pub(crate) pipe_out: Option<Pipe>,
     pub(crate) pending_ins: VecDeque<(UsbIpHeader, UsbIpCmdSubmit, Vec<u8>)>,
-    pub(crate) stalled: bool,
+    /// A control out urb, that is acknowledged once the device completes the status stage
+    pub(crate) pending_control_out: Option<(UsbIpHeader, usize)>,
     pub(crate) setup_flag: bool,
     pub(crate) in_complete_flag: bool,
 }
 
-impl Default for Endpoint {
-    fn default() -> Self {
-        Self {
-            pipe_in: None,
-            pipe_out: None,
-            pending_ins: VecDeque::new(),
-            stalled: true,
-            setup_flag: false,
-            in_complete_flag: false,
-        }
-    }
-}
-
 impl Endpoint {
     /// Returns the input pipe of this endpoint
     fn get_in(&mut self) -> UsbResult<&mut Pipe> {
@@ -127,6 +296,13 @@ impl Endpoint {
     /// - `false` if it was not found
     // NOTE: This is super inefficient, use linked lists, as soon as linked_list_remove stabilizes
     fn unlink(&mut self, seqnum: u32) -> bool {
+        if let Some((ref header, _)) = self.pending_control_out {
+            if header.seqnum == seqnum {
+                self.pending_control_out = None;
+                return true;
+            }
+        }
+
         let old_len = self.pending_ins.len();
 
         self.pending_ins = self
@@ -147,25 +323,67 @@ pub(crate) struct UsbIpBusInner {
     pub endpoint: [Endpoint; NUM_ENDPOINTS],
     pub device_address: u8,
     pub reset: bool,
+    /// The host has reset the port, but the device has not seen the reset yet
+    pub port_reset: bool,
     pub suspended: bool,
+    pub limits: UsbIpLimits,
+    /// The captures, into which all urbs are written
+    pub captures: Vec<Box<dyn Capture>>,
+    /// The replay or emulation, that urbs are answered from instead of the device
+    pub responder: Option<Responder>,
+    /// The decoder of the logged urbs
+    pub decoder: ClassDecoder,
+    /// The submitted urbs, that have not been completed yet, by their sequence number
+    pub in_flight: HashMap<u32, SubmittedUrb>,
+    /// The channels, that events are sent to
+    pub subscribers: Vec<Sender<BusEvent>>,
+    /// The injector of faults into the completed urbs
+    pub faults: Option<FaultInjector>,
+    /// The emulation of the bus timing, that holds back completions
+    pub timing: Option<TimingState>,
+    /// The clock, that all times are read from
+    pub clock: Box<dyn Clock>,
 }
 
 impl UsbIpBusInner {
     /// Creates a new UsbIpBusInner
-    fn new() -> Self {
+    fn new(handler: SocketHandler) -> Self {
         Self {
-            handler: SocketHandler::new(),
+            handler,
             endpoint: <[Endpoint; NUM_ENDPOINTS]>::default(),
             device_address: 0,
             reset: true,
+            port_reset: false,
             suspended: false,
+            limits: UsbIpLimits::default(),
+            captures: Vec::new(),
+            responder: None,
+            decoder: ClassDecoder::new(),
+            in_flight: HashMap::new(),
+            subscribers: Vec::new(),
+            faults: None,
+            timing: None,
+            clock: Box::new(WallClock::new()),
         }
     }
 
-    /// Resets the handler to the state, in which it acts like it is new
-    fn reset(&mut self) {
-        self.endpoint = <[Endpoint; NUM_ENDPOINTS]>::default();
-        self.reset = true;
+    /// Resets the state of all endpoints as a USB reset does, but keeps them allocated.
+    ///
+    /// Urbs, that are still pending, fail with `-ESHUTDOWN`.
+    pub fn reset(&mut self) {
+        for i in 0..NUM_ENDPOINTS {
+            self.fail_pending(i, -ESHUTDOWN);
+
+            let ep = &mut self.endpoint[i];
+            for pipe in ep.pipe_in.iter_mut().chain(ep.pipe_out.iter_mut()) {
+                pipe.data.clear();
+                pipe.stalled = false;
+            }
+            ep.setup_flag = false;
+            ep.in_complete_flag = false;
+        }
+
+        self.device_address = 0;
         self.suspended = false;
     }
 
@@ -205,12 +423,43 @@ impl UsbIpBusInner {
         Ok(&mut self.endpoint[ep])
     }
 
+    /// Returns the pipe of an endpoint in the given direction, if it is allocated.
+    fn pipe_mut(&mut self, ep: usize, direction: Direction) -> Option<&mut Pipe> {
+        let ep = self.endpoint.get_mut(ep)?;
+        match direction {
+            Direction::IN => ep.pipe_in.as_mut(),
+            _ => ep.pipe_out.as_mut(),
+        }
+    }
+
+    /// Returns the counters of the pipe of an endpoint in the given direction,
+    /// if the pipe is allocated.
+    fn pipe_stats(&mut self, ep: usize, direction: Direction) -> Option<&mut PipeStats> {
+        self.pipe_mut(ep, direction).map(|pipe| &mut pipe.stats)
+    }
+
+    /// Returns the number of in urbs, that are pending on all endpoints.
+    fn num_pending_urbs(&self) -> usize {
+        self.endpoint.iter().map(|ep| ep.pending_ins.len()).sum()
+    }
+
     /// Processes an unlink and removes the pending packet.
     ///
     /// # Returns
     /// - `true` if pending urb was removed
     /// - `false` if it was not found
     fn unlink(&mut self, seqnum: u32) -> bool {
+        // A completion, that is held back, has not reached the host yet
+        if let Some(ref mut timing) = self.timing {
+            if timing.unlink(seqnum) {
+                return true;
+            }
+        }
+
+        if let Some(ref mut responder) = self.responder {
+            return responder.unlink(seqnum);
+        }
+
         for i in 0..NUM_ENDPOINTS {
             if self.endpoint[i].unlink(seqnum) {
                 return true;
@@ -236,10 +485,168 @@ impl UsbIpBus {
     /// # Panics
     /// If port 3240 is already in use.
     pub fn new() -> Self {
-        Self(Arc::new(Mutex::new(UsbIpBusInner::new())))
+        Self(Arc::new(Mutex::new(UsbIpBusInner::new(
+            SocketHandler::new(),
+        ))))
+    }
+
+    /// Create a new [`UsbIpBus`], that does not listen on port 3240, but is
+    /// driven by the returned in-process [`LoopbackHost`] instead.
+    ///
+    /// The device is imported by the host right away.
+    pub fn loopback() -> (Self, LoopbackHost) {
+        let mut inner = UsbIpBusInner::new(SocketHandler::loopback());
+        inner.reset = false;
+
+        let bus = Self(Arc::new(Mutex::new(inner)));
+        let host = LoopbackHost::new(bus.clone());
+        (bus, host)
+    }
+
+    /// Sets the limits, that protect the bus from misbehaving hosts.
+    pub fn set_limits(&self, limits: UsbIpLimits) {
+        self.lock().limits = limits;
+    }
+
+    /// Sets the injector, that fails urbs completed by the device, or removes it with `None`.
+    pub fn set_fault_injector(&self, faults: Option<FaultInjector>) {
+        self.lock().faults = faults;
+    }
+
+    /// Sets the emulation of the timing of a real bus or removes it with `None`.
+    ///
+    /// Completions, that are held back by a previous timing, are sent right away.
+    pub fn set_timing(&self, timing: Option<BusTiming>) {
+        let mut inner = self.lock();
+        if let Some(mut old) = inner.timing.take() {
+            for response in old.drain() {
+                inner.transmit_response(response);
+            }
+        }
+        let now = inner.clock.now();
+        inner.timing = timing.map(|timing| TimingState::new(timing, now));
+    }
+
+    /// Sets the clock, that the bus reads all times from.
+    ///
+    /// The schedule of the timing emulation and the submission times of pending urbs
+    /// are moved over to the new clock, such that they keep their distance to the present.
+    pub fn set_clock<C: Clock + 'static>(&self, clock: C) {
+        let mut inner = self.lock();
+        let old_now = inner.clock.now();
+        let now = clock.now();
+
+        if let Some(ref mut timing) = inner.timing {
+            timing.rebase(old_now, now);
+        }
+        for urb in inner.in_flight.values_mut() {
+            urb.time = (urb.time + now).saturating_sub(old_now);
+        }
+        inner.clock = Box::new(clock);
+    }
+
+    /// Starts capturing all urbs of the bus into `capture`, in addition to existing captures.
+    pub fn add_capture<C: Capture + 'static>(&self, capture: C) {
+        self.lock().captures.push(Box::new(capture));
+    }
+
+    /// Stops all captures.
+    pub fn clear_captures(&self) {
+        self.lock().captures.clear();
+    }
+
+    /// Starts capturing all urbs of the bus into `capture` or stops capturing for `None`.
+    ///
+    /// Replaces all captures, that were added before.
+    pub fn set_capture(&self, capture: Option<PcapWriter>) {
+        let mut inner = self.lock();
+        inner.captures.clear();
+        if let Some(capture) = capture {
+            inner.captures.push(Box::new(capture));
+        }
+    }
+
+    /// Returns a receiver, that gets all events happening on the bus from now on.
+    pub fn subscribe(&self) -> Receiver<BusEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock().subscribers.push(sender);
+        receiver
+    }
+
+    /// Handles the socket until a host has imported the device.
+    ///
+    /// This is meant to be called before the device is polled, such that the
+    /// device only starts running once it is attached.
+    ///
+    /// The timeout is measured with the clock of the bus, a [`VirtualClock`](clock::VirtualClock)
+    /// has to be advanced by another thread for it to expire.
+    ///
+    /// # Returns
+    /// - `Err(UsbIpError::Timeout)` if no host imported the device within `timeout`
+    pub fn wait_for_attach(&self, timeout: Duration) -> Result<(), UsbIpError> {
+        let deadline = self.lock().clock.now() + timeout;
+        loop {
+            let mut inner = self.lock();
+            if !inner.reset {
+                return Ok(());
+            }
+            if inner.clock.now() >= deadline {
+                return Err(UsbIpError::Timeout);
+            }
+
+            inner.handle_socket();
+            drop(inner);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Returns the state of the connection and of all allocated endpoints.
+    pub fn snapshot(&self) -> BusSnapshot {
+        let inner = self.lock();
+        let now = inner.clock.now();
+
+        let endpoints = inner
+            .endpoint
+            .iter()
+            .enumerate()
+            .filter(|(_, ep)| ep.pipe_in.is_some() || ep.pipe_out.is_some())
+            .map(|(number, ep)| EndpointSnapshot {
+                number: number as u8,
+                pipe_in: ep.pipe_in.as_ref().map(Pipe::snapshot),
+                pipe_out: ep.pipe_out.as_ref().map(Pipe::snapshot),
+                pending_ins: ep.pending_ins.len(),
+                pending_control_out: ep.pending_control_out.is_some(),
+                oldest_pending: inner
+                    .in_flight
+                    .values()
+                    .filter(|urb| urb.ep == number)
+                    .map(|urb| now.saturating_sub(urb.time))
+                    .max(),
+            })
+            .collect();
+
+        BusSnapshot {
+            connection: inner.handler.connection_state(),
+            peer_addr: inner.handler.peer_addr(),
+            version: inner.handler.version(),
+            reset: inner.reset,
+            suspended: inner.suspended,
+            device_address: inner.device_address,
+            endpoints,
+        }
+    }
+
+    /// Sets the counters of all endpoints back to zero.
+    pub fn reset_stats(&self) {
+        let mut inner = self.lock();
+        for ep in inner.endpoint.iter_mut() {
+            for pipe in ep.pipe_in.iter_mut().chain(ep.pipe_out.iter_mut()) {
+                pipe.stats = PipeStats::default();
+            }
+        }
     }
 
-    fn lock(&self) -> MutexGuard<UsbIpBusInner> {
+    pub(crate) fn lock(&self) -> MutexGuard<'_, UsbIpBusInner> {
         self.0.lock().unwrap()
     }
 }
@@ -275,7 +682,7 @@ impl UsbBus for UsbIpBus {
                 .ok_or(UsbError::EndpointMemoryOverflow)?,
         };
 
-        let endpoint = &mut inner.endpoint[endpoint_index as usize];
+        let endpoint = &mut inner.endpoint[endpoint_index];
 
         // check endpoint allocation here
         let maybe_pipe = match ep_dir {
@@ -297,6 +704,8 @@ impl UsbBus for UsbIpBus {
             ty: ep_type,
             max_packet_size,
             interval,
+            stalled: false,
+            stats: PipeStats::default(),
         };
         match ep_dir {
             UsbDirection::In => endpoint.pipe_in = Some(pipe),
@@ -309,7 +718,7 @@ impl UsbBus for UsbIpBus {
             endpoint_index
         );
 
-        Ok(EndpointAddress::from_parts(endpoint_index as usize, ep_dir))
+        Ok(EndpointAddress::from_parts(endpoint_index, ep_dir))
     }
 
     fn enable(&mut self) {
@@ -319,13 +728,11 @@ impl UsbBus for UsbIpBus {
     fn reset(&self) {
         let mut inner = self.lock();
 
-        // Skip if we are already in reset state
-        if inner.reset {
-            return;
+        // The state of the bus has already been reset, when the host reset the port
+        if inner.port_reset {
+            inner.port_reset = false;
+            log::debug!("usb device is being reset");
         }
-
-        inner.reset();
-        log::debug!("usb device is being reset");
     }
 
     fn set_device_address(&self, addr: u8) {
@@ -351,6 +758,14 @@ impl UsbBus for UsbIpBus {
         // while the rest of the packets use packet logic
         if ep_addr.index() == 0 {
             ep.in_complete_flag = true;
+
+            // The status stage of a control out transfer completes the pending urb
+            if buf.is_empty() {
+                if let Some((header, len)) = ep.pending_control_out.take() {
+                    inner.ack_cmd_out(&header, len);
+                    return Ok(0);
+                }
+            }
         }
 
         let pipe = ep.get_in()?;
@@ -364,6 +779,8 @@ impl UsbBus for UsbIpBus {
         }
 
         pipe.data.push_back(buf.to_vec());
+        pipe.stats.packets += 1;
+        pipe.stats.bytes += buf.len() as u64;
 
         // we attempt to service in packets, if we have them available
         if pipe.is_rts() {
@@ -387,6 +804,8 @@ impl UsbBus for UsbIpBus {
             }
             Some(data) => data,
         };
+        pipe.stats.packets += 1;
+        pipe.stats.bytes += data.len() as u64;
 
         if buf.len() < data.len() {
             buf.copy_from_slice(&data[..buf.len()]);
@@ -399,19 +818,36 @@ impl UsbBus for UsbIpBus {
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
         let mut inner = self.lock();
 
-        let endpoint = match inner.get_endpoint(ep_addr.index()) {
-            Ok(endpoint) => endpoint,
+        let pipe = match inner.get_endpoint(ep_addr.index()) {
+            Ok(endpoint) => match ep_addr.direction() {
+                UsbDirection::In => endpoint.get_in(),
+                UsbDirection::Out => endpoint.get_out(),
+            },
+            Err(err) => Err(err),
+        };
+        let pipe = match pipe {
+            Ok(pipe) => pipe,
             _ => return,
         };
 
-        if endpoint.stalled != stalled {
+        if pipe.stalled != stalled {
             log::debug!(
                 "setting endpoint {:?} to stalled state {}",
                 ep_addr,
                 stalled
             );
+            pipe.stalled = stalled;
+            inner.emit(match stalled {
+                true => BusEvent::EndpointStalled(ep_addr),
+                false => BusEvent::EndpointCleared(ep_addr),
+            });
+        }
+
+        // The urbs waiting on a halted endpoint can no longer complete.
+        // On the control endpoint, this means the device rejected the current transfer.
+        if stalled && (ep_addr.index() == 0 || ep_addr.direction() == UsbDirection::In) {
+            inner.fail_pending(ep_addr.index(), -EPIPE);
         }
-        endpoint.stalled = stalled;
     }
 
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
@@ -422,7 +858,14 @@ impl UsbBus for UsbIpBus {
             _ => return false,
         };
 
-        endpoint.stalled
+        let pipe = match ep_addr.direction() {
+            UsbDirection::In => endpoint.get_in(),
+            UsbDirection::Out => endpoint.get_out(),
+        };
+        match pipe {
+            Ok(pipe) => pipe.stalled,
+            Err(_) => false,
+        }
     }
 
     fn suspend(&self) {
@@ -434,6 +877,7 @@ impl UsbBus for UsbIpBus {
         }
 
         inner.suspended = true;
+        inner.emit(BusEvent::Suspended);
     }
 
     fn resume(&self) {
@@ -445,6 +889,7 @@ impl UsbBus for UsbIpBus {
         }
 
         inner.suspended = false;
+        inner.emit(BusEvent::Resumed);
     }
 
     fn poll(&self) -> PollResult {
@@ -458,6 +903,11 @@ impl UsbBus for UsbIpBus {
             return PollResult::Reset;
         }
 
+        if inner.port_reset {
+            log::trace!("device is being reset by the host");
+            return PollResult::Reset;
+        }
+
         if inner.suspended {
             log::trace!("device is suspended");
             return PollResult::Suspend;
@@ -467,7 +917,7 @@ impl UsbBus for UsbIpBus {
         let mut ep_out: u16 = 0;
         let mut ep_setup: u16 = 0;
 
-        for i in (0..NUM_ENDPOINTS).into_iter().rev() {
+        for i in (0..NUM_ENDPOINTS).rev() {
             ep_in <<= 1;
             ep_out <<= 1;
             ep_setup <<= 1;
@@ -0,0 +1,275 @@
+//! An in-process host, that drives a [`UsbIpBus`] without any TCP socket.
+//!
+//! The [`LoopbackHost`] submits urbs straight into the bus and takes the responses
+//! from an in-memory queue. Since nothing runs concurrently, the device needs to be
+//! polled while the host waits for a completion. A [`LoopbackSession`] combines the
+//! host with a closure, that polls the device, and implements [`UsbHost`].
+//! This allows testing usb-device classes in a plain `#[test]`, in a single thread
+//! and fully deterministically.
+//!
+//! ```
+//! use usb_device::{bus::UsbBusAllocator, prelude::*};
+//! use usbd_serial::{SerialPort, USB_CLASS_CDC};
+//! use usbip_device::{
+//!     client::{SetupPacket, UsbHost},
+//!     UsbIpBus,
+//! };
+//!
+//! let (bus, mut host) = UsbIpBus::loopback();
+//! let bus_allocator = UsbBusAllocator::new(bus);
+//! let mut usb_serial = SerialPort::new(&bus_allocator);
+//! let mut usb_bus = UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(0x16c0, 0x27dd))
+//!     .device_class(USB_CLASS_CDC)
+//!     .build();
+//!
+//! let mut session = host.session(|| {
+//!     usb_bus.poll(&mut [&mut usb_serial]);
+//! });
+//!
+//! // GET_DESCRIPTOR(DEVICE)
+//! let descriptor = session
+//!     .control_transfer(SetupPacket::new(0x80, 0x06, 0x0100, 0, 18), &[])
+//!     .unwrap();
+//! assert_eq!(&descriptor[8..12], &[0xc0, 0x16, 0xdd, 0x27]);
+//! ```
+
+use crate::{
+    client::{SetupPacket, UrbCompletion, UsbHost},
+    cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
+    event::BusEvent,
+    request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd},
+    response::{UsbIpResponse, UsbIpResponseCmd, ECONNRESET},
+    UsbIpBus, UsbIpError,
+};
+use std::collections::{HashMap, HashSet};
+
+/// The device id, under which the loopback host addresses the device
+const LOOPBACK_DEVID: u32 = 1 << 16 | 2;
+
+/// A host, that has imported the device of a [`UsbIpBus`] in-process.
+///
+/// Created by [`UsbIpBus::loopback`].
+#[derive(Debug)]
+pub struct LoopbackHost {
+    bus: UsbIpBus,
+    next_seqnum: u32,
+    /// The sequence numbers of all submitted urbs, that have not been completed yet
+    pending: HashSet<u32>,
+    /// Responses, that were received while waiting for a different urb
+    completed: HashMap<u32, UsbIpResponse>,
+    max_polls: usize,
+}
+
+impl LoopbackHost {
+    pub(crate) fn new(bus: UsbIpBus) -> Self {
+        Self {
+            bus,
+            next_seqnum: 1,
+            pending: HashSet::new(),
+            completed: HashMap::new(),
+            max_polls: 100,
+        }
+    }
+
+    /// Sets how often the device is polled, while waiting for an urb to complete.
+    ///
+    /// The synchronous transfer functions unlink urbs, that do not complete in time.
+    /// A timed out [`wait`](UsbHost::wait) leaves the urb pending.
+    pub fn set_max_polls(&mut self, max_polls: usize) {
+        self.max_polls = max_polls;
+    }
+
+    /// Imports the device again, after it has been detached.
+    pub fn attach(&mut self) {
+        let mut inner = self.bus.lock();
+        if !inner.handler.is_connected() {
+            log::info!("attaching loopback host");
+            inner.handler.attach_loopback();
+            inner.reset = false;
+            inner.emit(BusEvent::Imported(None));
+        }
+    }
+
+    /// Detaches the device, which puts it into reset state.
+    pub fn detach(&mut self) {
+        log::info!("detaching loopback host");
+        self.bus.lock().disconnect();
+        self.pending.clear();
+        self.completed.clear();
+    }
+
+    /// Submits a raw request to the device.
+    ///
+    /// A request, that the device refuses to process, detaches the device
+    /// just as it would drop a TCP connection.
+    pub fn submit_request(&mut self, request: UsbIpRequest) -> Result<(), UsbIpError> {
+        log::debug!("{:?}", request);
+
+        let mut inner = self.bus.lock();
+        if !inner.handler.is_connected() {
+            return Err(UsbIpError::ConnectionClosed);
+        }
+
+        if let Err(err) = inner.handle_usbip_pkg(request) {
+            log::warn!("detaching after invalid urb: {}", err);
+            inner.disconnect();
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Takes the next response of the device without polling it.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if there is no response waiting
+    pub fn receive(&mut self) -> Result<Option<UsbIpResponse>, UsbIpError> {
+        let response = self.bus.lock().handler.pop_loopback_response()?;
+        if let Some(ref response) = response {
+            log::debug!("{:?}", response);
+        }
+
+        Ok(response)
+    }
+
+    /// Starts a session, in which `poll` is called to drive the device,
+    /// while waiting for urbs to complete.
+    pub fn session<F: FnMut()>(&mut self, poll: F) -> LoopbackSession<'_, F> {
+        LoopbackSession { host: self, poll }
+    }
+
+    /// Receives responses and polls the device, until the response with `seqnum` arrives.
+    fn wait_response(
+        &mut self,
+        seqnum: u32,
+        poll: &mut dyn FnMut(),
+    ) -> Result<UsbIpResponse, UsbIpError> {
+        for _ in 0..=self.max_polls {
+            while let Some(response) = self.receive()? {
+                // Nobody waits for duplicated responses
+                if self.pending.remove(&response.header.seqnum) {
+                    self.completed.insert(response.header.seqnum, response);
+                } else {
+                    log::warn!(
+                        "dropping response for unknown urb {}",
+                        response.header.seqnum
+                    );
+                }
+            }
+
+            if let Some(response) = self.completed.remove(&seqnum) {
+                return Ok(response);
+            }
+
+            if !self.pending.contains(&seqnum) {
+                return Err(UsbIpError::UnknownSeqnum(seqnum));
+            }
+
+            poll();
+        }
+
+        Err(UsbIpError::Timeout)
+    }
+
+    fn header(&self, command: UsbCmd, seqnum: u32, direction: Direction, ep: u8) -> UsbIpHeader {
+        UsbIpHeader {
+            command,
+            seqnum,
+            devid: LOOPBACK_DEVID,
+            direction,
+            ep: ep as u32,
+        }
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        let seqnum = self.next_seqnum;
+        self.next_seqnum = self.next_seqnum.wrapping_add(1).max(1);
+        seqnum
+    }
+}
+
+/// A [`LoopbackHost`] together with a closure, that polls the device.
+pub struct LoopbackSession<'a, F> {
+    host: &'a mut LoopbackHost,
+    poll: F,
+}
+
+impl<'a, F: FnMut()> UsbHost for LoopbackSession<'a, F> {
+    fn submit(
+        &mut self,
+        ep: u8,
+        direction: Direction,
+        setup: Option<SetupPacket>,
+        data: &[u8],
+        length: usize,
+    ) -> Result<u32, UsbIpError> {
+        let length = if direction == Direction::OUT {
+            data.len()
+        } else {
+            length
+        };
+
+        let seqnum = self.host.next_seqnum();
+        let request = UsbIpRequest {
+            header: self.host.header(UsbCmd::Request, seqnum, direction, ep),
+            cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+                transfer_flags: TransferFlags::empty(),
+                transfer_buffer_length: length as i32,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup: setup.map(|setup| setup.to_array()).unwrap_or_default(),
+            }),
+            data: if direction == Direction::OUT {
+                data.to_vec()
+            } else {
+                vec![]
+            },
+        };
+
+        self.host.pending.insert(seqnum);
+        self.host.submit_request(request)?;
+
+        Ok(seqnum)
+    }
+
+    fn wait(&mut self, seqnum: u32) -> Result<UrbCompletion, UsbIpError> {
+        let response = self.host.wait_response(seqnum, &mut self.poll)?;
+        match response.cmd {
+            UsbIpResponseCmd::Cmd(ret) => Ok(UrbCompletion {
+                status: ret.status,
+                actual_length: ret.actual_length.max(0) as usize,
+                data: response.data,
+            }),
+            UsbIpResponseCmd::Unlink(_) => Err(UsbIpError::InvalidUrbCommand(
+                UsbCmd::UnlinkResponse.to_u32(),
+            )),
+        }
+    }
+
+    fn unlink(&mut self, seqnum: u32) -> Result<bool, UsbIpError> {
+        let unlink_seqnum = self.host.next_seqnum();
+        let request = UsbIpRequest {
+            header: self
+                .host
+                .header(UsbCmd::UnlinkRequest, unlink_seqnum, Direction::OUT, 0),
+            cmd: UsbIpRequestCmd::Unlink(UsbIpCmdUnlink { seqnum }),
+            data: vec![],
+        };
+
+        self.host.pending.insert(unlink_seqnum);
+        self.host.submit_request(request)?;
+
+        let response = self.host.wait_response(unlink_seqnum, &mut self.poll)?;
+        match response.cmd {
+            UsbIpResponseCmd::Unlink(ret) if ret.status == -ECONNRESET => {
+                self.host.pending.remove(&seqnum);
+                Ok(true)
+            }
+            UsbIpResponseCmd::Unlink(_) => Ok(false),
+            UsbIpResponseCmd::Cmd(_) => {
+                Err(UsbIpError::InvalidUrbCommand(UsbCmd::Response.to_u32()))
+            }
+        }
+    }
+}
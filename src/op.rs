@@ -1,12 +1,38 @@
 use crate::UsbIpError;
-use std::{
-    convert::TryInto,
-    io::{Error, ErrorKind, Read},
-    net::TcpStream,
-};
-
+use std::convert::TryInto;
+
+/// The version of the USBIP protocol, that is implemented by this crate
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// Op code of a request to list the exported devices
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+/// Op code of the reply to [`OP_REQ_DEVLIST`]
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+/// Op code of a request to import a device
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+/// Op code of the reply to [`OP_REQ_IMPORT`]
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+/// Request was successful
+pub const ST_OK: u32 = 0x00;
+/// Device is already imported by another client
+pub const ST_DEV_BUSY: u32 = 0x02;
+/// Requested device does not exist
+pub const ST_NODEV: u32 = 0x04;
+/// Unspecified error, e.g. unsupported protocol version
+pub const ST_ERROR: u32 = 0x05;
+
+/// Size of the [`OpHeader`] in bytes
+pub const OP_HEADER_SIZE: usize = 8;
+/// Size of an encoded [`OpExportedDevice`] without its interfaces in bytes
+pub const OP_DEVICE_SIZE: usize = 312;
+
+const PATH_SIZE: usize = 256;
+const BUS_ID_SIZE: usize = 32;
+
+/// The header, that precedes every op packet.
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpHeader {
     pub version: u16,
     pub command: u16,
@@ -14,7 +40,7 @@ pub struct OpHeader {
 }
 
 impl OpHeader {
-    fn to_array(&self) -> [u8; 8] {
+    pub fn to_array(&self) -> [u8; 8] {
         let mut result = [0; 8];
 
         result[0..2].copy_from_slice(&self.version.to_be_bytes());
@@ -24,160 +50,252 @@ impl OpHeader {
         result
     }
 
-    fn from_slice(data: &[u8]) -> Self {
-        Self {
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < OP_HEADER_SIZE {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
             version: u16::from_be_bytes(data[0..2].try_into().unwrap()),
             command: u16::from_be_bytes(data[2..4].try_into().unwrap()),
             status: u32::from_be_bytes(data[4..8].try_into().unwrap()),
-        }
+        })
     }
 }
 
+/// An op packet sent from the host to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OpRequest {
+    /// OP_REQ_DEVLIST
     ListDevices(OpHeader),
-    ConnectDevice(OpHeader),
+    /// OP_REQ_IMPORT, carrying the bus id of the device to import
+    ConnectDevice(OpHeader, String),
 }
 
 impl OpRequest {
-    pub fn read(reader: &mut TcpStream) -> Result<Self, Error> {
-        // Receive the header
-        reader.set_nonblocking(true)?;
-        let mut header_buf = [0; 8];
-        match reader.read(&mut header_buf) {
-            Ok(bytes_read) if bytes_read == 8 => (),
-            Ok(0) => {
-                return Err(Error::new(
-                    ErrorKind::NotConnected,
-                    Box::new(UsbIpError::ConnectionClosed),
-                ))
-            }
-            Ok(bytes_read) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    Box::new(UsbIpError::PkgTooShort(bytes_read)),
-                ))
-            }
-            Err(err) => return Err(err),
+    /// Returns the header of this request.
+    pub fn header(&self) -> &OpHeader {
+        match self {
+            Self::ListDevices(header) => header,
+            Self::ConnectDevice(header, _) => header,
+        }
+    }
+
+    /// Encodes the request into its wire format.
+    pub fn encode(&self) -> Result<Vec<u8>, UsbIpError> {
+        let mut result = self.header().to_array().to_vec();
+
+        if let Self::ConnectDevice(_, ref bus_id) = self {
+            result.extend_from_slice(&encode_str::<BUS_ID_SIZE>(bus_id)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Decodes a request from the start of `data`.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if `data` does not yet contain the complete request
+    /// - `Ok(Some((request, len)))` where `len` is the number of bytes consumed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, UsbIpError> {
+        if data.len() < OP_HEADER_SIZE {
+            return Ok(None);
         }
-        reader.set_nonblocking(false)?;
 
         // Parse the header
-        let header = OpHeader::from_slice(&header_buf);
+        let header = OpHeader::from_slice(data)?;
 
         // Check status
-        if header.status != 0 {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                Box::new(UsbIpError::StatusNotOk(header.status)),
-            ));
+        if header.status != ST_OK {
+            return Err(UsbIpError::StatusNotOk(header.status));
         }
 
-        log::debug!("request version is {}", header.version);
-
         // Dispatch on command
         match header.command {
-            0x8005 => {
-                log::info!("received request to list devices");
-                Ok(Self::ListDevices(header))
+            OP_REQ_DEVLIST => Ok(Some((Self::ListDevices(header), OP_HEADER_SIZE))),
+            OP_REQ_IMPORT => {
+                let len = OP_HEADER_SIZE + BUS_ID_SIZE;
+                if data.len() < len {
+                    return Ok(None);
+                }
+
+                let bus_id = decode_str(&data[OP_HEADER_SIZE..len])?;
+                Ok(Some((Self::ConnectDevice(header, bus_id), len)))
             }
-            0x8003 => {
-                let mut bus_id_buf = [0; 32];
-                reader.read_exact(&mut bus_id_buf)?;
-
-                let bus_id = match std::str::from_utf8(&bus_id_buf) {
-                    Ok(data) => data.trim_matches(char::from(0)).to_string(),
-                    Err(err) => {
-                        return Err(Error::new(ErrorKind::InvalidInput, err));
-                    }
-                };
-
-                log::info!("received request to connect device {}", bus_id);
-                Ok(Self::ConnectDevice(header))
-            }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                Box::new(UsbIpError::InvalidCommand(header.command)),
-            )),
+            _ => Err(UsbIpError::InvalidCommand(header.command)),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// An op packet sent from the server to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpResponse {
     pub version: u16,
-    pub path: String,
-    pub bus_id: String,
-    pub descriptor: OpDeviceDescriptor,
+    pub status: u32,
     pub cmd: OpResponseCommand,
 }
 
-#[derive(Debug, Clone)]
+/// The body of an [`OpResponse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OpResponseCommand {
-    ListDevices(OpInterfaceDescriptor),
-    ConnectDevice,
+    /// OP_REP_DEVLIST, listing all exported devices and their interfaces
+    ListDevices(Vec<OpExportedDevice>),
+    /// OP_REP_IMPORT, describing the imported device if the status is [`ST_OK`]
+    ConnectDevice(Option<OpExportedDevice>),
 }
 
 impl OpResponse {
-    pub fn to_vec(&self) -> Option<Vec<u8>> {
+    /// Encodes the response into its wire format.
+    pub fn encode(&self) -> Result<Vec<u8>, UsbIpError> {
         let mut result = vec![];
 
         // Build and serialize the header
         let reply: u16 = match self.cmd {
-            OpResponseCommand::ListDevices(_) => 0x0005,
-            OpResponseCommand::ConnectDevice => 0x0003,
+            OpResponseCommand::ListDevices(_) => OP_REP_DEVLIST,
+            OpResponseCommand::ConnectDevice(_) => OP_REP_IMPORT,
         };
 
         let header = OpHeader {
             version: self.version,
             command: reply,
-            status: 0,
+            status: self.status,
         };
 
         result.extend_from_slice(&header.to_array());
 
+        // Failed requests are only answered with the header
+        if self.status != ST_OK {
+            return Ok(result);
+        }
+
         match self.cmd {
-            // This implementation can only ever export one device,
-            // therefore number of exported devices is fixed
-            OpResponseCommand::ListDevices(_) => result.extend_from_slice(&[0, 0, 0, 1]),
-            OpResponseCommand::ConnectDevice => (),
+            OpResponseCommand::ListDevices(ref devices) => {
+                result.extend_from_slice(&(devices.len() as u32).to_be_bytes());
+                for device in devices {
+                    result.extend_from_slice(&device.encode()?);
+                    for interface in device.interfaces.iter() {
+                        result.extend_from_slice(&interface.to_array());
+                    }
+                }
+            }
+            OpResponseCommand::ConnectDevice(Some(ref device)) => {
+                result.extend_from_slice(&device.encode()?)
+            }
+            OpResponseCommand::ConnectDevice(None) => (),
         };
 
-        // Serialize path
-        let str_len = self.path.as_bytes().len();
-        if str_len > 256 {
-            log::warn!("path is longer than 256 bytes");
-            return None;
+        Ok(result)
+    }
+
+    /// Decodes a response from the start of `data`.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if `data` does not yet contain the complete response
+    /// - `Ok(Some((response, len)))` where `len` is the number of bytes consumed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, UsbIpError> {
+        if data.len() < OP_HEADER_SIZE {
+            return Ok(None);
         }
 
-        let mut path_buf = [0; 256];
-        path_buf[..str_len].copy_from_slice(self.path.as_bytes());
-        result.extend_from_slice(&path_buf);
+        let header = OpHeader::from_slice(data)?;
+        let mut pos = OP_HEADER_SIZE;
+
+        let cmd = match header.command {
+            OP_REP_DEVLIST if header.status != ST_OK => OpResponseCommand::ListDevices(vec![]),
+            OP_REP_DEVLIST => {
+                if data.len() < pos + 4 {
+                    return Ok(None);
+                }
+                let num_devices = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+                pos += 4;
+
+                // Do not trust the number of devices for the allocation
+                let mut devices = vec![];
+                for _ in 0..num_devices {
+                    if data.len() < pos + OP_DEVICE_SIZE {
+                        return Ok(None);
+                    }
+                    let mut device = OpExportedDevice::decode(&data[pos..pos + OP_DEVICE_SIZE])?;
+                    pos += OP_DEVICE_SIZE;
+
+                    for _ in 0..device.descriptor.num_interfaces {
+                        if data.len() < pos + 4 {
+                            return Ok(None);
+                        }
+                        device
+                            .interfaces
+                            .push(OpInterfaceDescriptor::from_slice(&data[pos..pos + 4])?);
+                        pos += 4;
+                    }
 
-        // Serialize bus_id
-        let str_len = self.bus_id.as_bytes().len();
-        if str_len > 32 {
-            log::warn!("bus_id is longr than 32 bytes");
-            return None;
-        }
+                    devices.push(device);
+                }
+
+                OpResponseCommand::ListDevices(devices)
+            }
+            OP_REP_IMPORT if header.status != ST_OK => OpResponseCommand::ConnectDevice(None),
+            OP_REP_IMPORT => {
+                if data.len() < pos + OP_DEVICE_SIZE {
+                    return Ok(None);
+                }
+                let device = OpExportedDevice::decode(&data[pos..pos + OP_DEVICE_SIZE])?;
+                pos += OP_DEVICE_SIZE;
+
+                OpResponseCommand::ConnectDevice(Some(device))
+            }
+            _ => return Err(UsbIpError::InvalidCommand(header.command)),
+        };
 
-        let mut bus_id_buf = [0; 32];
-        bus_id_buf[..str_len].copy_from_slice(self.bus_id.as_bytes());
-        result.extend_from_slice(&bus_id_buf);
+        Ok(Some((
+            Self {
+                version: header.version,
+                status: header.status,
+                cmd,
+            },
+            pos,
+        )))
+    }
+}
 
-        // Serialize the Op Desciptor
-        result.extend_from_slice(&self.descriptor.to_array());
+/// A device, as it is exported by a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpExportedDevice {
+    pub path: String,
+    pub bus_id: String,
+    pub descriptor: OpDeviceDescriptor,
+    /// The interfaces of the device, only transmitted in OP_REP_DEVLIST
+    pub interfaces: Vec<OpInterfaceDescriptor>,
+}
+
+impl OpExportedDevice {
+    /// Encodes the device without its interfaces
+    fn encode(&self) -> Result<[u8; OP_DEVICE_SIZE], UsbIpError> {
+        let mut result = [0; OP_DEVICE_SIZE];
+
+        result[0..256].copy_from_slice(&encode_str::<PATH_SIZE>(&self.path)?);
+        result[256..288].copy_from_slice(&encode_str::<BUS_ID_SIZE>(&self.bus_id)?);
+        result[288..312].copy_from_slice(&self.descriptor.to_array());
+
+        Ok(result)
+    }
 
-        // If exists, serialize the interface descriptor
-        if let OpResponseCommand::ListDevices(ref interface) = self.cmd {
-            result.extend_from_slice(&interface.to_array());
+    /// Decodes the device without its interfaces
+    fn decode(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < OP_DEVICE_SIZE {
+            return Err(UsbIpError::PkgTooShort(data.len()));
         }
 
-        Some(result)
+        Ok(Self {
+            path: decode_str(&data[0..256])?,
+            bus_id: decode_str(&data[256..288])?,
+            descriptor: OpDeviceDescriptor::from_slice(&data[288..312])?,
+            interfaces: vec![],
+        })
     }
 }
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpDeviceDescriptor {
     pub busnum: u32,
     pub devnum: u32,
@@ -194,7 +312,7 @@ pub struct OpDeviceDescriptor {
 }
 
 impl OpDeviceDescriptor {
-    fn to_array(&self) -> [u8; 24] {
+    pub fn to_array(&self) -> [u8; 24] {
         let mut result = [0; 24];
 
         result[0..4].copy_from_slice(&self.busnum.to_be_bytes());
@@ -216,10 +334,31 @@ impl OpDeviceDescriptor {
 
         result
     }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 24 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            busnum: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            devnum: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            speed: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            vendor: u16::from_be_bytes(data[12..14].try_into().unwrap()),
+            product: u16::from_be_bytes(data[14..16].try_into().unwrap()),
+            bcd_device: u16::from_be_bytes(data[16..18].try_into().unwrap()),
+            device_class: data[18],
+            device_subclass: data[19],
+            device_protocol: data[20],
+            configuration_value: data[21],
+            num_configurations: data[22],
+            num_interfaces: data[23],
+        })
+    }
 }
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OpInterfaceDescriptor {
     pub interface_class: u8,
     pub interface_subclass: u8,
@@ -228,7 +367,7 @@ pub struct OpInterfaceDescriptor {
 }
 
 impl OpInterfaceDescriptor {
-    fn to_array(&self) -> [u8; 4] {
+    pub fn to_array(&self) -> [u8; 4] {
         [
             self.interface_class,
             self.interface_subclass,
@@ -236,4 +375,39 @@ impl OpInterfaceDescriptor {
             self.padding,
         ]
     }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 4 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            interface_class: data[0],
+            interface_subclass: data[1],
+            interface_protocol: data[2],
+            padding: data[3],
+        })
+    }
+}
+
+/// Serializes a string into a zero padded buffer of fixed length.
+fn encode_str<const N: usize>(string: &str) -> Result<[u8; N], UsbIpError> {
+    // The string must leave room for the terminating zero
+    let str_len = string.len();
+    if str_len >= N {
+        return Err(UsbIpError::StringTooLong(str_len));
+    }
+
+    let mut buf = [0; N];
+    buf[..str_len].copy_from_slice(string.as_bytes());
+    Ok(buf)
+}
+
+/// Parses a zero padded string.
+fn decode_str(data: &[u8]) -> Result<String, UsbIpError> {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    match std::str::from_utf8(&data[..end]) {
+        Ok(string) => Ok(string.to_string()),
+        Err(_) => Err(UsbIpError::InvalidString),
+    }
 }
@@ -0,0 +1,33 @@
+//! A sans-IO implementation of the USBIP wire protocol.
+//!
+//! Every message can be encoded into and decoded from plain byte buffers,
+//! independent of the transport it is sent over.
+//! Decoding functions take a buffer, that may contain an incomplete message.
+//! They return `Ok(None)`, if more bytes are needed, and otherwise the decoded
+//! message together with the number of bytes it occupied.
+//!
+//! | Message            | Type                                     |
+//! |--------------------|------------------------------------------|
+//! | `OP_REQ_DEVLIST`   | [`OpRequest::ListDevices`]               |
+//! | `OP_REQ_IMPORT`    | [`OpRequest::ConnectDevice`]             |
+//! | `OP_REP_DEVLIST`   | [`OpResponseCommand::ListDevices`]       |
+//! | `OP_REP_IMPORT`    | [`OpResponseCommand::ConnectDevice`]     |
+//! | `USBIP_CMD_SUBMIT` | [`UsbIpRequestCmd::Cmd`]                 |
+//! | `USBIP_CMD_UNLINK` | [`UsbIpRequestCmd::Unlink`]              |
+//! | `USBIP_RET_SUBMIT` | [`UsbIpResponseCmd::Cmd`]                |
+//! | `USBIP_RET_UNLINK` | [`UsbIpResponseCmd::Unlink`]             |
+
+pub use crate::{
+    cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
+    op::{
+        OpDeviceDescriptor, OpExportedDevice, OpHeader, OpInterfaceDescriptor, OpRequest,
+        OpResponse, OpResponseCommand, OP_DEVICE_SIZE, OP_HEADER_SIZE, OP_REP_DEVLIST,
+        OP_REP_IMPORT, OP_REQ_DEVLIST, OP_REQ_IMPORT, ST_DEV_BUSY, ST_ERROR, ST_NODEV, ST_OK,
+        USBIP_VERSION,
+    },
+    request::{UsbIpCmdSubmit, UsbIpCmdUnlink, UsbIpRequest, UsbIpRequestCmd, URB_HEADER_SIZE},
+    response::{
+        UsbIpResponse, UsbIpResponseCmd, UsbIpRetSubmit, UsbIpRetUnlink, ECONNRESET, ENOENT,
+        EOVERFLOW, EPIPE, EPROTO, ESHUTDOWN, ETIME,
+    },
+};
@@ -0,0 +1,465 @@
+//! A man-in-the-middle proxy between a USBIP client and a USBIP server.
+//!
+//! The [`UsbIpProxy`] listens for clients, such as `usbip attach` or a [`UsbIpClient`](crate::client::UsbIpClient),
+//! and opens a connection to the upstream server for each of them.
+//! The upstream server may be a [`UsbIpBus`](crate::UsbIpBus) or the `usbipd` of a real device.
+//! All op packets and urbs are decoded, logged and forwarded in both directions.
+//! [`ProxyHooks`] may rewrite or drop individual urbs on the way.
+//!
+//! ```no_run
+//! use usbip_device::proxy::UsbIpProxy;
+//!
+//! let mut proxy = UsbIpProxy::new("127.0.0.1:3241", "127.0.0.1:3240", ()).unwrap();
+//! loop {
+//!     proxy.poll();
+//! #   break;
+//! }
+//! ```
+
+use crate::{
+    cmd::Direction,
+    debug::ClassDecoder,
+    handler::Connection,
+    op::{OpRequest, OpResponse, OpResponseCommand, ST_OK},
+    request::{UsbIpRequest, UsbIpRequestCmd, URB_HEADER_SIZE},
+    response::{UsbIpResponse, UsbIpResponseCmd},
+    UsbIpError, UsbIpLimits,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::{Error, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+};
+
+/// What the proxy does with an urb, after the hooks have seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyAction {
+    /// Forward the urb, including the changes made by the hook
+    Forward,
+    /// Do not forward the urb
+    Drop,
+}
+
+/// Callbacks, that inspect and rewrite the urbs passing the proxy.
+///
+/// Note that a dropped submit or completion leaves the urb pending at the client,
+/// until the client unlinks it.
+pub trait ProxyHooks: Send {
+    /// Called with every urb sent from the client to the server.
+    fn request(&mut self, request: &mut UsbIpRequest) -> ProxyAction {
+        let _ = request;
+        ProxyAction::Forward
+    }
+
+    /// Called with every urb sent from the server to the client.
+    fn response(&mut self, response: &mut UsbIpResponse) -> ProxyAction {
+        let _ = response;
+        ProxyAction::Forward
+    }
+}
+
+/// Hooks, that forward all urbs unchanged.
+impl ProxyHooks for () {}
+
+/// A proxy, that forwards the traffic of its clients to an upstream server.
+pub struct UsbIpProxy {
+    listener: TcpListener,
+    upstream: Vec<SocketAddr>,
+    sessions: Vec<ProxySession>,
+    hooks: Box<dyn ProxyHooks>,
+    limits: UsbIpLimits,
+}
+
+impl Debug for UsbIpProxy {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("UsbIpProxy")
+            .field("listener", &self.listener)
+            .field("upstream", &self.upstream)
+            .field("sessions", &self.sessions)
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+/// A client together with its connection to the upstream server.
+#[derive(Debug)]
+struct ProxySession {
+    client: Connection,
+    server: Connection,
+    /// `true` once the client has imported a device and sends urbs
+    imported: bool,
+    /// The directions of the submitted urbs, that have not been completed yet
+    pending: HashMap<u32, Direction>,
+    /// The urbs, that are being unlinked, by the sequence number of the unlink
+    unlinks: HashMap<u32, u32>,
+    /// The decoder of the logged urbs
+    decoder: ClassDecoder,
+}
+
+impl UsbIpProxy {
+    /// Creates a proxy, that listens on `addr` and forwards to the server at `upstream`.
+    pub fn new<H: ProxyHooks + 'static>(
+        addr: impl ToSocketAddrs,
+        upstream: impl ToSocketAddrs,
+        hooks: H,
+    ) -> Result<Self, UsbIpError> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            upstream: upstream.to_socket_addrs()?.collect(),
+            sessions: vec![],
+            hooks: Box::new(hooks),
+            limits: UsbIpLimits::default(),
+        })
+    }
+
+    /// Returns the address, that the proxy listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr, UsbIpError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Sets the limits, that protect the proxy from misbehaving clients and servers.
+    pub fn set_limits(&mut self, limits: UsbIpLimits) {
+        self.limits = limits;
+    }
+
+    /// Returns the number of clients, that are currently connected.
+    pub fn num_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Accepts new clients and forwards all packets, that were received in the meantime.
+    pub fn poll(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    log::info!("proxy: new connection from {}", addr);
+                    match self.connect(stream) {
+                        Ok(session) => self.sessions.push(session),
+                        Err(err) => log::error!("proxy: failed to connect upstream: {}", err),
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("proxy: failed to accept connection: {}", err);
+                    break;
+                }
+            }
+        }
+
+        let hooks = &mut self.hooks;
+        let limits = &self.limits;
+        self.sessions
+            .retain_mut(|session| match session.forward(hooks.as_mut(), limits) {
+                Ok(()) => true,
+                Err(err) if err.kind() == ErrorKind::NotConnected => {
+                    log::info!("proxy: connection closed");
+                    false
+                }
+                Err(err) => {
+                    log::warn!("proxy: closing connection: {}", err);
+                    false
+                }
+            });
+    }
+
+    fn connect(&self, stream: TcpStream) -> Result<ProxySession, Error> {
+        let upstream = TcpStream::connect(&self.upstream[..])?;
+        stream.set_nodelay(true)?;
+        upstream.set_nodelay(true)?;
+
+        Ok(ProxySession {
+            client: Connection::new(stream)?,
+            server: Connection::new(upstream)?,
+            imported: false,
+            pending: HashMap::new(),
+            unlinks: HashMap::new(),
+            decoder: ClassDecoder::new(),
+        })
+    }
+}
+
+impl ProxySession {
+    /// Forwards the received packets in both directions.
+    ///
+    /// # Returns
+    /// - `Err(err)` with kind [`ErrorKind::NotConnected`] if either side closed the connection
+    fn forward(&mut self, hooks: &mut dyn ProxyHooks, limits: &UsbIpLimits) -> Result<(), Error> {
+        let capacity = URB_HEADER_SIZE + limits.max_urb_size;
+        let send_capacity = limits.max_send_buffer;
+
+        // Send what the sockets did not accept during the last poll
+        self.client.flush()?;
+        self.server.flush()?;
+
+        while !self.imported {
+            if let Some(request) = self.client.receive(capacity, OpRequest::decode)? {
+                log::info!("proxy: client -> server: {:?}", request);
+                self.server
+                    .send(&encode(request.encode())?, send_capacity)?;
+                continue;
+            }
+
+            match self.server.receive(capacity, OpResponse::decode)? {
+                Some(response) => {
+                    log::info!("proxy: server -> client: {:?}", response);
+                    if let OpResponse {
+                        status: ST_OK,
+                        cmd: OpResponseCommand::ConnectDevice(Some(_)),
+                        ..
+                    } = response
+                    {
+                        self.imported = true;
+                    }
+                    self.client
+                        .send(&encode(response.encode())?, send_capacity)?;
+                }
+                None => return Ok(()),
+            }
+        }
+
+        while let Some(mut request) = self
+            .client
+            .receive(capacity, |buf| UsbIpRequest::decode(buf, limits))?
+        {
+            log::debug!(
+                "proxy: client -> server: {:?}",
+                self.decoder.request(&request)
+            );
+            match request.cmd {
+                UsbIpRequestCmd::Cmd(_) => {
+                    let direction = request.header.direction;
+                    self.pending.insert(request.header.seqnum, direction);
+                }
+                UsbIpRequestCmd::Unlink(ref unlink) => {
+                    self.unlinks.insert(request.header.seqnum, unlink.seqnum);
+                }
+            }
+
+            match hooks.request(&mut request) {
+                ProxyAction::Forward => self.server.send(&request.encode(), send_capacity)?,
+                ProxyAction::Drop => log::info!("proxy: dropped {:?}", request.header),
+            }
+        }
+
+        loop {
+            let pending = &self.pending;
+            let mut response = match self.server.receive(capacity, |buf| {
+                // The direction of an unknown urb can only be taken from the header
+                UsbIpResponse::decode(buf, limits, |header| {
+                    pending
+                        .get(&header.seqnum)
+                        .copied()
+                        .unwrap_or(header.direction)
+                })
+            })? {
+                Some(response) => response,
+                None => return Ok(()),
+            };
+            log::debug!(
+                "proxy: server -> client: {:?}",
+                self.decoder.response(&response)
+            );
+
+            match response.cmd {
+                UsbIpResponseCmd::Cmd(_) => {
+                    self.pending.remove(&response.header.seqnum);
+                }
+                UsbIpResponseCmd::Unlink(_) => {
+                    if let Some(seqnum) = self.unlinks.remove(&response.header.seqnum) {
+                        self.pending.remove(&seqnum);
+                    }
+                }
+            }
+
+            match hooks.response(&mut response) {
+                ProxyAction::Forward => self.client.send(&response.encode(), send_capacity)?,
+                ProxyAction::Drop => log::info!("proxy: dropped {:?}", response.header),
+            }
+        }
+    }
+}
+
+/// Converts a failure to encode an op packet into an i/o error.
+fn encode(encoded: Result<Vec<u8>, UsbIpError>) -> Result<Vec<u8>, Error> {
+    encoded.map_err(|err| Error::new(ErrorKind::InvalidData, Box::new(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{TransferFlags, UsbCmd, UsbIpHeader},
+        op::{OpDeviceDescriptor, OpExportedDevice, OpHeader, OP_REQ_IMPORT, USBIP_VERSION},
+        request::UsbIpCmdSubmit,
+    };
+    use std::{
+        io::{Read, Write},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// Shouts all data and drops the urbs to endpoint 2.
+    struct Shout;
+
+    impl ProxyHooks for Shout {
+        fn request(&mut self, request: &mut UsbIpRequest) -> ProxyAction {
+            request.data.make_ascii_uppercase();
+            match request.header.ep {
+                2 => ProxyAction::Drop,
+                _ => ProxyAction::Forward,
+            }
+        }
+
+        fn response(&mut self, response: &mut UsbIpResponse) -> ProxyAction {
+            response.data.make_ascii_uppercase();
+            ProxyAction::Forward
+        }
+    }
+
+    fn submit(seqnum: u32, ep: u32, direction: Direction, data: &[u8]) -> UsbIpRequest {
+        let length = match direction {
+            Direction::IN => 64,
+            _ => data.len() as i32,
+        };
+        UsbIpRequest {
+            header: UsbIpHeader {
+                command: UsbCmd::Request,
+                seqnum,
+                devid: 1 << 16 | 2,
+                direction,
+                ep,
+            },
+            cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+                transfer_flags: TransferFlags::empty(),
+                transfer_buffer_length: length,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup: [0; 8],
+            }),
+            data: data.to_vec(),
+        }
+    }
+
+    /// Polls the proxy, until a complete packet arrives at `stream`.
+    fn receive<T>(
+        proxy: &mut UsbIpProxy,
+        stream: &mut TcpStream,
+        decode: impl Fn(&[u8]) -> Result<Option<(T, usize)>, UsbIpError>,
+    ) -> T {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = vec![];
+        loop {
+            proxy.poll();
+
+            let mut chunk = [0; 1024];
+            match stream.read(&mut chunk) {
+                Ok(0) => panic!("connection closed"),
+                Ok(len) => buf.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(err) => panic!("{}", err),
+            }
+
+            if let Some((packet, len)) = decode(&buf).unwrap() {
+                assert_eq!(len, buf.len(), "more than one packet received");
+                return packet;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+        }
+    }
+
+    #[test]
+    fn forward_and_rewrite() {
+        let limits = UsbIpLimits::default();
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut proxy =
+            UsbIpProxy::new("127.0.0.1:0", upstream.local_addr().unwrap(), Shout).unwrap();
+
+        let mut client = TcpStream::connect(proxy.local_addr().unwrap()).unwrap();
+        client.set_nonblocking(true).unwrap();
+        while proxy.num_sessions() == 0 {
+            proxy.poll();
+        }
+        let (mut server, _) = upstream.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        // The import is forwarded unchanged
+        let import = OpRequest::ConnectDevice(
+            OpHeader {
+                version: USBIP_VERSION,
+                command: OP_REQ_IMPORT,
+                status: ST_OK,
+            },
+            "1-1".to_string(),
+        );
+        client.write_all(&import.encode().unwrap()).unwrap();
+        assert_eq!(receive(&mut proxy, &mut server, OpRequest::decode), import);
+
+        let device = OpExportedDevice {
+            path: "/sys/devices/usbip/1-1".to_string(),
+            bus_id: "1-1".to_string(),
+            descriptor: OpDeviceDescriptor {
+                busnum: 1,
+                devnum: 2,
+                speed: 2,
+                vendor: 0x1209,
+                product: 0x0001,
+                bcd_device: 0x0010,
+                device_class: 0,
+                device_subclass: 0,
+                device_protocol: 0,
+                configuration_value: 1,
+                num_configurations: 1,
+                num_interfaces: 1,
+            },
+            interfaces: vec![],
+        };
+        let imported = OpResponse {
+            version: USBIP_VERSION,
+            status: ST_OK,
+            cmd: OpResponseCommand::ConnectDevice(Some(device)),
+        };
+        server.write_all(&imported.encode().unwrap()).unwrap();
+        assert_eq!(
+            receive(&mut proxy, &mut client, OpResponse::decode),
+            imported
+        );
+
+        // Requests are rewritten or dropped by the hooks
+        client
+            .write_all(&submit(1, 2, Direction::OUT, b"dropped").encode())
+            .unwrap();
+        client
+            .write_all(&submit(2, 1, Direction::OUT, b"hello").encode())
+            .unwrap();
+        let decode_request = |buf: &[u8]| UsbIpRequest::decode(buf, &limits);
+        let forwarded = receive(&mut proxy, &mut server, decode_request);
+        assert_eq!(forwarded, submit(2, 1, Direction::OUT, b"HELLO"));
+
+        // Responses are framed by the direction of the urb, that they complete
+        client
+            .write_all(&submit(3, 1, Direction::IN, &[]).encode())
+            .unwrap();
+        let forwarded = receive(&mut proxy, &mut server, decode_request);
+        let response = UsbIpResponse::ret_submit(&forwarded.header, 0, 5, b"world".to_vec());
+        server.write_all(&response.encode()).unwrap();
+        let decode_response = |buf: &[u8]| UsbIpResponse::decode(buf, &limits, |_| Direction::IN);
+        let completion = receive(&mut proxy, &mut client, decode_response);
+        assert_eq!(completion.header.seqnum, 3);
+        assert_eq!(completion.data, b"WORLD");
+
+        // The session ends with either side
+        drop(client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while proxy.num_sessions() > 0 {
+            assert!(Instant::now() < deadline, "session not closed");
+            proxy.poll();
+        }
+    }
+}
@@ -0,0 +1,841 @@
+//! Impersonation of a device by replaying a recorded session.
+//!
+//! A [`Recording`] is loaded from a usbmon pcap file or from the log of a
+//! [`JsonLinesWriter`](crate::capture::JsonLinesWriter). The [`ReplayServer`] serves it
+//! like a [`UsbIpBus`]: It answers op packets and matches every submitted urb with
+//! the next recorded urb to the same endpoint, control transfers by their setup packet.
+//! The host gets the recorded completion, urbs, that were never completed in the
+//! recording, stay pending until they are unlinked. Urbs, that do not match the
+//! recording, are reported as [`Divergence`].
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use usbip_device::replay::{Recording, ReplayServer};
+//!
+//! let recording = Recording::from_pcap(File::open("usb.pcap").unwrap()).unwrap();
+//! let server = ReplayServer::new(recording);
+//! loop {
+//!     server.poll();
+//!     for divergence in server.take_divergences() {
+//!         println!("{}", divergence);
+//!     }
+//! #   break;
+//! }
+//! ```
+
+use crate::{
+    capture::{PayloadEncoding, LINKTYPE_USB_LINUX_MMAPPED},
+    cmd::{Direction, UsbIpHeader},
+    handler::{Responder, SocketHandler},
+    loopback::LoopbackHost,
+    request::UsbIpCmdSubmit,
+    response::{UsbIpResponse, ECONNRESET, ENOENT, EPIPE},
+    UsbIpBus, UsbIpBusInner, UsbIpError,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{BufRead, Read},
+    sync::{Arc, Mutex},
+};
+
+/// The pcap link type of usbmon captures with the 48 byte header
+const LINKTYPE_USB_LINUX: u32 = 189;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+/// The completion of a recorded urb.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedCompletion {
+    pub status: i32,
+    pub actual_length: usize,
+    /// The data received by an in urb
+    pub data: Vec<u8>,
+}
+
+/// A single recorded urb.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedUrb {
+    /// The device, as `busnum << 16 | devnum`
+    pub devid: u32,
+    pub ep: u8,
+    pub direction: Direction,
+    /// The setup packet of a control transfer
+    pub setup: Option<[u8; 8]>,
+    pub transfer_buffer_length: usize,
+    /// The data sent by an out urb
+    pub data: Vec<u8>,
+    /// `None` if the urb was unlinked or did not complete before the recording ended
+    pub completion: Option<RecordedCompletion>,
+}
+
+/// A recorded session of urbs, in the order they were submitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub urbs: Vec<RecordedUrb>,
+}
+
+impl Recording {
+    /// Loads a pcap file with the usbmon link type, as written by
+    /// [`PcapWriter`](crate::capture::PcapWriter) or captured on Linux.
+    ///
+    /// Isochronous urbs are skipped.
+    pub fn from_pcap<R: Read>(mut reader: R) -> Result<Self, UsbIpError> {
+        let mut file = vec![];
+        reader.read_to_end(&mut file)?;
+
+        if file.len() < 24 {
+            return Err(UsbIpError::InvalidRecording(0));
+        }
+
+        // The byte order of the file is the one of the capturing host
+        let little_endian = match u32::from_le_bytes([file[0], file[1], file[2], file[3]]) {
+            PCAP_MAGIC | PCAP_MAGIC_NANOS => true,
+            magic if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NANOS => {
+                false
+            }
+            _ => return Err(UsbIpError::InvalidRecording(0)),
+        };
+        let u16_at = |data: &[u8], i: usize| {
+            let bytes = [data[i], data[i + 1]];
+            match little_endian {
+                true => u16::from_le_bytes(bytes),
+                false => u16::from_be_bytes(bytes),
+            }
+        };
+        let u32_at = |data: &[u8], i: usize| {
+            let bytes = [data[i], data[i + 1], data[i + 2], data[i + 3]];
+            match little_endian {
+                true => u32::from_le_bytes(bytes),
+                false => u32::from_be_bytes(bytes),
+            }
+        };
+
+        let header_size = match u32_at(&file, 20) {
+            LINKTYPE_USB_LINUX_MMAPPED => 64,
+            LINKTYPE_USB_LINUX => 48,
+            _ => return Err(UsbIpError::InvalidRecording(0)),
+        };
+
+        let mut recording = Self::default();
+        // Submitted urbs by their usbmon id, until they complete
+        let mut submitted: HashMap<(u32, u32), usize> = HashMap::new();
+
+        let mut offset = 24;
+        let mut record_number = 0;
+        while offset < file.len() {
+            record_number += 1;
+            if offset + 16 > file.len() {
+                return Err(UsbIpError::InvalidRecording(record_number));
+            }
+
+            let incl_len = u32_at(&file, offset + 8) as usize;
+            let record = match file.get(offset + 16..offset + 16 + incl_len) {
+                Some(record) if record.len() >= header_size => record,
+                _ => return Err(UsbIpError::InvalidRecording(record_number)),
+            };
+            offset += 16 + incl_len;
+
+            let id = (u32_at(record, 0), u32_at(record, 4));
+            let event = record[8];
+            let xfer_type = record[9];
+            let epnum = record[10];
+            let devid = (u16_at(record, 12) as u32) << 16 | record[11] as u32;
+            let status = u32_at(record, 28) as i32;
+            let length = u32_at(record, 32) as usize;
+            let data = &record[header_size..];
+
+            // Isochronous transfers are not supported
+            if xfer_type == 0 {
+                continue;
+            }
+
+            let direction = match epnum & 0x80 {
+                0 => Direction::OUT,
+                _ => Direction::IN,
+            };
+
+            match event {
+                b'S' => {
+                    let setup = match record[14] {
+                        0 => Some(record[40..48].try_into().unwrap()),
+                        _ => None,
+                    };
+
+                    submitted.insert(id, recording.urbs.len());
+                    recording.urbs.push(RecordedUrb {
+                        devid,
+                        ep: epnum & 0x7f,
+                        direction,
+                        setup,
+                        transfer_buffer_length: length,
+                        data: match direction {
+                            Direction::OUT => data.to_vec(),
+                            _ => vec![],
+                        },
+                        completion: None,
+                    });
+                }
+                b'C' | b'E' => {
+                    let index = match submitted.remove(&id) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+
+                    // Unlinked urbs stay pending in the replay
+                    if event == b'C' && (status == -ECONNRESET || status == -ENOENT) {
+                        continue;
+                    }
+
+                    recording.urbs[index].completion = Some(RecordedCompletion {
+                        status,
+                        actual_length: length,
+                        data: match direction {
+                            Direction::IN => data.to_vec(),
+                            _ => vec![],
+                        },
+                    });
+                }
+                _ => return Err(UsbIpError::InvalidRecording(record_number)),
+            }
+        }
+
+        Ok(recording)
+    }
+
+    /// Loads the log of a [`JsonLinesWriter`](crate::capture::JsonLinesWriter),
+    /// that encoded its payloads with `encoding`.
+    pub fn from_json_lines<R: BufRead>(
+        reader: R,
+        encoding: PayloadEncoding,
+    ) -> Result<Self, UsbIpError> {
+        let mut recording = Self::default();
+        let mut submitted: HashMap<u32, usize> = HashMap::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line_number = i + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid = || UsbIpError::InvalidRecording(line_number);
+            let object = parse_object(&line).ok_or_else(invalid)?;
+            let number = |key: &str| {
+                object
+                    .get(key)
+                    .and_then(|value| value.parse::<i64>().ok())
+                    .ok_or_else(invalid)
+            };
+            let payload = |key: &str| match object.get(key) {
+                Some(value) => decode_payload(value, encoding).ok_or_else(invalid),
+                None => Err(invalid()),
+            };
+
+            match object.get("event").copied() {
+                Some("submit") => {
+                    let setup = match object.get("setup").copied() {
+                        Some("null") | None => None,
+                        Some(setup) => Some(
+                            decode_payload(setup, encoding)
+                                .and_then(|setup| setup.try_into().ok())
+                                .ok_or_else(invalid)?,
+                        ),
+                    };
+
+                    submitted.insert(number("seqnum")? as u32, recording.urbs.len());
+                    recording.urbs.push(RecordedUrb {
+                        devid: number("devid")? as u32,
+                        ep: number("ep")? as u8,
+                        direction: match object.get("direction").copied() {
+                            Some("in") => Direction::IN,
+                            _ => Direction::OUT,
+                        },
+                        setup,
+                        transfer_buffer_length: number("transfer_buffer_length")?.max(0) as usize,
+                        data: payload("data")?,
+                        completion: None,
+                    });
+                }
+                Some("complete") => {
+                    if let Some(index) = submitted.remove(&(number("seqnum")? as u32)) {
+                        recording.urbs[index].completion = Some(RecordedCompletion {
+                            status: number("status")? as i32,
+                            actual_length: number("actual_length")?.max(0) as usize,
+                            data: payload("data")?,
+                        });
+                    }
+                }
+                Some("unlink_complete") => {
+                    // Unlinked urbs stay pending in the replay
+                    if number("status")? == -ECONNRESET as i64 {
+                        submitted.remove(&(number("unlink_seqnum")? as u32));
+                    }
+                }
+                Some("unlink") => (),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(recording)
+    }
+
+    /// Removes all urbs, that were not sent to the device `devid` (`busnum << 16 | devnum`).
+    ///
+    /// Captures on Linux usually contain the traffic of all devices on a bus.
+    pub fn retain_device(&mut self, devid: u32) {
+        self.urbs.retain(|urb| urb.devid == devid);
+    }
+}
+
+/// An urb, that did not match the recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The host submitted an urb, for which there is no matching urb in the recording
+    Unexpected {
+        seqnum: u32,
+        ep: u8,
+        direction: Direction,
+        setup: Option<[u8; 8]>,
+    },
+    /// The host sent different data in an out urb than recorded
+    DataMismatch {
+        seqnum: u32,
+        ep: u8,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Unexpected {
+                seqnum,
+                ep,
+                direction,
+                setup: Some(setup),
+            } => write!(
+                f,
+                "urb {} to endpoint {} {:?} has an unrecorded setup packet {:02x?}",
+                seqnum, ep, direction, setup
+            ),
+            Self::Unexpected {
+                seqnum,
+                ep,
+                direction,
+                setup: None,
+            } => write!(
+                f,
+                "urb {} to endpoint {} {:?} exceeds the recording",
+                seqnum, ep, direction
+            ),
+            Self::DataMismatch {
+                seqnum,
+                ep,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "urb {} to endpoint {} sent {:02x?} instead of {:02x?}",
+                seqnum, ep, actual, expected
+            ),
+        }
+    }
+}
+
+/// The state of a replay, that the bus answers urbs from.
+#[derive(Debug)]
+pub(crate) struct ReplayState {
+    /// The recorded urbs, that have not been replayed yet, by endpoint and direction
+    queues: HashMap<(u8, bool), VecDeque<RecordedUrb>>,
+    /// Urbs, that were not completed in the recording
+    pending: HashSet<u32>,
+    divergences: Vec<Divergence>,
+}
+
+impl ReplayState {
+    fn new(recording: Recording) -> Self {
+        let mut queues: HashMap<(u8, bool), VecDeque<RecordedUrb>> = HashMap::new();
+        for urb in recording.urbs {
+            let key = (urb.ep, urb.direction == Direction::IN);
+            queues.entry(key).or_default().push_back(urb);
+        }
+
+        Self {
+            queues,
+            pending: HashSet::new(),
+            divergences: vec![],
+        }
+    }
+
+    /// Answers a submitted urb from the recording.
+    ///
+    /// # Returns
+    /// - `None` if the urb stays pending
+    pub fn submit(
+        &mut self,
+        header: &UsbIpHeader,
+        cmd: &UsbIpCmdSubmit,
+        data: &[u8],
+    ) -> Option<UsbIpResponse> {
+        let ep = header.ep as u8;
+        let is_control = ep == 0;
+        let queue = self
+            .queues
+            .entry((ep, header.direction == Direction::IN))
+            .or_default();
+
+        // Control transfers are matched by their setup packet, since hosts
+        // do not always request descriptors in the same order
+        let index = match is_control {
+            true => queue.iter().position(|urb| urb.setup == Some(cmd.setup)),
+            false if queue.is_empty() => None,
+            false => Some(0),
+        };
+
+        let urb = match index.and_then(|index| queue.remove(index)) {
+            Some(urb) => urb,
+            None => {
+                self.diverge(Divergence::Unexpected {
+                    seqnum: header.seqnum,
+                    ep,
+                    direction: header.direction,
+                    setup: if is_control { Some(cmd.setup) } else { None },
+                });
+
+                return match (is_control, header.direction) {
+                    (true, _) => Some(UsbIpResponse::ret_submit(header, -EPIPE, 0, vec![])),
+                    (false, Direction::OUT) => {
+                        Some(UsbIpResponse::ret_submit(header, 0, data.len(), vec![]))
+                    }
+                    _ => {
+                        self.pending.insert(header.seqnum);
+                        None
+                    }
+                };
+            }
+        };
+
+        if header.direction == Direction::OUT && urb.data != data {
+            self.diverge(Divergence::DataMismatch {
+                seqnum: header.seqnum,
+                ep,
+                expected: urb.data,
+                actual: data.to_vec(),
+            });
+        }
+
+        match urb.completion {
+            Some(mut completion) => {
+                let requested = cmd.transfer_buffer_length.max(0) as usize;
+                completion.data.truncate(requested);
+                let actual_length = match header.direction {
+                    Direction::IN => completion.data.len(),
+                    _ => completion.actual_length,
+                };
+
+                Some(UsbIpResponse::ret_submit(
+                    header,
+                    completion.status,
+                    actual_length,
+                    completion.data,
+                ))
+            }
+            None => {
+                self.pending.insert(header.seqnum);
+                None
+            }
+        }
+    }
+
+    /// Removes a pending urb.
+    ///
+    /// # Returns
+    /// - `true` if the urb was pending
+    pub fn unlink(&mut self, seqnum: u32) -> bool {
+        self.pending.remove(&seqnum)
+    }
+
+    fn diverge(&mut self, divergence: Divergence) {
+        log::warn!("replay diverged: {}", divergence);
+        self.divergences.push(divergence);
+    }
+}
+
+/// A server, that impersonates a device by replaying a [`Recording`].
+#[derive(Debug, Clone)]
+pub struct ReplayServer {
+    bus: UsbIpBus,
+}
+
+impl ReplayServer {
+    /// Creates a server, that exports the replayed device on port 3240.
+    ///
+    /// # Panics
+    /// If port 3240 is already in use.
+    pub fn new(recording: Recording) -> Self {
+        Self::with_handler(SocketHandler::new(), recording)
+    }
+
+    /// Creates a server, that is driven by the returned in-process [`LoopbackHost`].
+    ///
+    /// Urbs are answered right when they are submitted, such that the host
+    /// does not need to poll the server.
+    pub fn loopback(recording: Recording) -> (Self, LoopbackHost) {
+        let server = Self::with_handler(SocketHandler::loopback(), recording);
+        server.bus.lock().reset = false;
+
+        let host = LoopbackHost::new(server.bus.clone());
+        (server, host)
+    }
+
+    fn with_handler(handler: SocketHandler, recording: Recording) -> Self {
+        let mut inner = UsbIpBusInner::new(handler);
+        inner.responder = Some(Responder::Replay(ReplayState::new(recording)));
+
+        Self {
+            bus: UsbIpBus(Arc::new(Mutex::new(inner))),
+        }
+    }
+
+    /// Accepts connections and answers the received packets.
+    pub fn poll(&self) {
+        self.bus.lock().handle_socket();
+    }
+
+    /// Returns the divergences, that occurred since the last call.
+    pub fn take_divergences(&self) -> Vec<Divergence> {
+        match self.bus.lock().responder {
+            Some(Responder::Replay(ref mut replay)) => std::mem::take(&mut replay.divergences),
+            _ => vec![],
+        }
+    }
+
+    /// Returns the number of recorded urbs, that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        match self.bus.lock().responder {
+            Some(Responder::Replay(ref replay)) => replay.queues.values().map(VecDeque::len).sum(),
+            _ => 0,
+        }
+    }
+}
+
+/// Parses a flat JSON object with string, number and null values, as written by the
+/// [`JsonLinesWriter`](crate::capture::JsonLinesWriter).
+///
+/// Values are returned as their raw text, strings without the quotes.
+fn parse_object(line: &str) -> Option<HashMap<&str, &str>> {
+    let mut object = HashMap::new();
+    let mut rest = line.trim().strip_prefix('{')?.strip_suffix('}')?.trim();
+
+    while !rest.is_empty() {
+        let (key, tail) = parse_string(rest)?;
+        rest = tail.trim_start().strip_prefix(':')?.trim_start();
+
+        let (value, tail) = match rest.starts_with('"') {
+            true => parse_string(rest)?,
+            false => {
+                let end = rest.find(',').unwrap_or(rest.len());
+                (rest[..end].trim(), &rest[end..])
+            }
+        };
+        object.insert(key, value);
+
+        rest = tail.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+
+    Some(object)
+}
+
+/// Splits a quoted string without escapes from the start of `text`.
+fn parse_string(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix('"')?;
+    let end = text.find('"')?;
+    Some((&text[..end], &text[end + 1..]))
+}
+
+fn decode_payload(text: &str, encoding: PayloadEncoding) -> Option<Vec<u8>> {
+    match encoding {
+        PayloadEncoding::Hex => {
+            if text.len() & 1 != 0 {
+                return None;
+            }
+
+            (0..text.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+                .collect()
+        }
+        PayloadEncoding::Base64 => decode_base64(text),
+    }
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut group: u32 = 0;
+    let mut bits = 0;
+
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        group = group << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((group >> bits) as u8);
+            group &= (1 << bits) - 1;
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{TransferFlags, UsbCmd},
+        response::UsbIpResponseCmd,
+    };
+
+    const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0, 0, 18, 0];
+    const GET_CONFIGURATION_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x02, 0, 0, 9, 0];
+
+    fn recorded(ep: u8, direction: Direction, setup: Option<[u8; 8]>, data: &[u8]) -> RecordedUrb {
+        let (out_data, in_data) = match direction {
+            Direction::IN => (vec![], data.to_vec()),
+            _ => (data.to_vec(), vec![]),
+        };
+        RecordedUrb {
+            devid: 1 << 16 | 2,
+            ep,
+            direction,
+            setup,
+            transfer_buffer_length: data.len(),
+            completion: Some(RecordedCompletion {
+                status: 0,
+                actual_length: data.len(),
+                data: in_data,
+            }),
+            data: out_data,
+        }
+    }
+
+    /// Submits an urb to the replay and returns the status and data of its completion.
+    fn submit(
+        replay: &mut ReplayState,
+        seqnum: u32,
+        ep: u8,
+        direction: Direction,
+        setup: [u8; 8],
+        data: &[u8],
+    ) -> Option<(i32, Vec<u8>)> {
+        let header = UsbIpHeader {
+            command: UsbCmd::Request,
+            seqnum,
+            devid: 1 << 16 | 2,
+            direction,
+            ep: ep as u32,
+        };
+        let cmd = UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: 64,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+        };
+
+        let response = replay.submit(&header, &cmd, data)?;
+        match response.cmd {
+            UsbIpResponseCmd::Cmd(ret) => Some((ret.status, response.data)),
+            UsbIpResponseCmd::Unlink(_) => panic!("submit answered with an unlink"),
+        }
+    }
+
+    #[test]
+    fn reordered_control_transfers_are_matched_by_setup() {
+        let mut replay = ReplayState::new(Recording {
+            urbs: vec![
+                recorded(0, Direction::IN, Some(GET_DEVICE_DESCRIPTOR), &[18, 1]),
+                recorded(
+                    0,
+                    Direction::IN,
+                    Some(GET_CONFIGURATION_DESCRIPTOR),
+                    &[9, 2],
+                ),
+            ],
+        });
+
+        let config = submit(
+            &mut replay,
+            1,
+            0,
+            Direction::IN,
+            GET_CONFIGURATION_DESCRIPTOR,
+            &[],
+        );
+        let device = submit(&mut replay, 2, 0, Direction::IN, GET_DEVICE_DESCRIPTOR, &[]);
+        assert_eq!(config, Some((0, vec![9, 2])));
+        assert_eq!(device, Some((0, vec![18, 1])));
+        assert!(replay.divergences.is_empty());
+    }
+
+    #[test]
+    fn reordered_bulk_urbs_diverge() {
+        let mut replay = ReplayState::new(Recording {
+            urbs: vec![
+                recorded(1, Direction::OUT, None, b"first"),
+                recorded(2, Direction::IN, None, b"answer"),
+                recorded(1, Direction::OUT, None, b"second"),
+            ],
+        });
+
+        // Endpoints are replayed independently of each other
+        let answer = submit(&mut replay, 1, 2, Direction::IN, [0; 8], &[]);
+        assert_eq!(answer, Some((0, b"answer".to_vec())));
+        assert!(replay.divergences.is_empty());
+
+        // But the urbs of one endpoint are replayed in order
+        assert!(submit(&mut replay, 2, 1, Direction::OUT, [0; 8], b"second").is_some());
+        assert!(submit(&mut replay, 3, 1, Direction::OUT, [0; 8], b"first").is_some());
+        assert_eq!(
+            replay.divergences,
+            vec![
+                Divergence::DataMismatch {
+                    seqnum: 2,
+                    ep: 1,
+                    expected: b"first".to_vec(),
+                    actual: b"second".to_vec(),
+                },
+                Divergence::DataMismatch {
+                    seqnum: 3,
+                    ep: 1,
+                    expected: b"second".to_vec(),
+                    actual: b"first".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatched_urbs_diverge() {
+        let mut replay = ReplayState::new(Recording {
+            urbs: vec![recorded(
+                0,
+                Direction::IN,
+                Some(GET_DEVICE_DESCRIPTOR),
+                &[18, 1],
+            )],
+        });
+
+        // An unrecorded control transfer stalls
+        let string = [0x80, 0x06, 0x01, 0x03, 0x09, 0x04, 0xff, 0];
+        assert_eq!(
+            submit(&mut replay, 1, 0, Direction::IN, string, &[]),
+            Some((-EPIPE, vec![]))
+        );
+        // Unrecorded out urbs complete, unrecorded in urbs stay pending
+        assert_eq!(
+            submit(&mut replay, 2, 1, Direction::OUT, [0; 8], b"data"),
+            Some((0, vec![]))
+        );
+        assert_eq!(submit(&mut replay, 3, 1, Direction::IN, [0; 8], &[]), None);
+        assert!(replay.unlink(3));
+        assert!(!replay.unlink(3));
+
+        assert_eq!(
+            replay.divergences,
+            vec![
+                Divergence::Unexpected {
+                    seqnum: 1,
+                    ep: 0,
+                    direction: Direction::IN,
+                    setup: Some(string),
+                },
+                Divergence::Unexpected {
+                    seqnum: 2,
+                    ep: 1,
+                    direction: Direction::OUT,
+                    setup: None,
+                },
+                Divergence::Unexpected {
+                    seqnum: 3,
+                    ep: 1,
+                    direction: Direction::IN,
+                    setup: None,
+                },
+            ]
+        );
+
+        // The recorded urb is still there
+        let device = submit(&mut replay, 4, 0, Direction::IN, GET_DEVICE_DESCRIPTOR, &[]);
+        assert_eq!(device, Some((0, vec![18, 1])));
+    }
+
+    #[test]
+    fn json_lines() {
+        let log = r#"{"time":0.000001,"event":"submit","seqnum":1,"devid":65538,"ep":1,"direction":"in","type":"bulk","transfer_flags":0,"transfer_buffer_length":64,"setup":null,"data":""}
+{"time":0.000002,"event":"submit","seqnum":2,"devid":65538,"ep":1,"direction":"out","type":"bulk","transfer_flags":0,"transfer_buffer_length":2,"setup":null,"data":"abcd"}
+{"time":0.000003,"event":"complete","seqnum":2,"devid":65538,"ep":1,"direction":"out","type":"bulk","status":0,"actual_length":2,"data":""}
+{"time":0.000004,"event":"unlink","seqnum":3,"devid":65538,"unlink_seqnum":1}
+{"time":0.000005,"event":"unlink_complete","seqnum":3,"unlink_seqnum":1,"status":-104}
+"#;
+        let recording = Recording::from_json_lines(log.as_bytes(), PayloadEncoding::Hex).unwrap();
+
+        assert_eq!(recording.urbs.len(), 2);
+        assert_eq!(recording.urbs[0].completion, None);
+        assert_eq!(recording.urbs[1].data, vec![0xab, 0xcd]);
+        assert_eq!(
+            recording.urbs[1].completion,
+            Some(RecordedCompletion {
+                status: 0,
+                actual_length: 2,
+                data: vec![],
+            })
+        );
+
+        let truncated = &log[..log.find('\n').unwrap() / 2];
+        assert!(matches!(
+            Recording::from_json_lines(truncated.as_bytes(), PayloadEncoding::Hex),
+            Err(UsbIpError::InvalidRecording(1))
+        ));
+    }
+
+    #[test]
+    fn truncated_pcap() {
+        // A pcap header with the usbmon link type and a record header without its record
+        let mut file = vec![];
+        file.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        file.extend_from_slice(&[2, 0, 4, 0]);
+        file.extend_from_slice(&[0; 12]);
+        file.extend_from_slice(&LINKTYPE_USB_LINUX.to_le_bytes());
+        assert_eq!(
+            Recording::from_pcap(&file[..]).unwrap(),
+            Recording::default()
+        );
+
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&48u32.to_le_bytes());
+        file.extend_from_slice(&48u32.to_le_bytes());
+        assert!(matches!(
+            Recording::from_pcap(&file[..]),
+            Err(UsbIpError::InvalidRecording(1))
+        ));
+        assert!(matches!(
+            Recording::from_pcap(&file[..20]),
+            Err(UsbIpError::InvalidRecording(0))
+        ));
+    }
+}
@@ -1,19 +1,22 @@
 use crate::{
     cmd::{Direction, TransferFlags, UsbCmd, UsbIpHeader},
-    debug::{DbgBuf, DbgEmpty},
-    UsbIpError,
+    debug::{DbgBuf, DbgEmpty, DbgSetup},
+    UsbIpError, UsbIpLimits,
 };
 use std::{
-    convert::TryInto,
+    convert::{TryFrom, TryInto},
     fmt::{Debug, Formatter, Result as FmtResult},
-    io::{Error, ErrorKind, Read},
-    net::TcpStream,
 };
 
-#[derive(Clone)]
+/// Size of the header of every urb packet in bytes
+pub const URB_HEADER_SIZE: usize = 48;
+
+/// An urb packet sent from the host to the server.
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpRequest {
     pub header: UsbIpHeader,
     pub cmd: UsbIpRequestCmd,
+    /// The transfer buffer, only transmitted for out urbs
     pub data: Vec<u8>,
 }
 
@@ -27,79 +30,106 @@ impl Debug for UsbIpRequest {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The body of an [`UsbIpRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UsbIpRequestCmd {
+    /// USBIP_CMD_SUBMIT
     Cmd(UsbIpCmdSubmit),
+    /// USBIP_CMD_UNLINK
     Unlink(UsbIpCmdUnlink),
 }
 
 impl UsbIpRequest {
-    pub fn read(reader: &mut TcpStream) -> Result<Self, Error> {
-        // Read an parse header
-        reader.set_nonblocking(true)?;
-        let mut buf = [0; 48];
-        match reader.read(&mut buf) {
-            Ok(bytes_read) if bytes_read == 48 => (),
-            Ok(0) => {
-                return Err(Error::new(
-                    ErrorKind::NotConnected,
-                    Box::new(UsbIpError::ConnectionClosed),
-                ))
-            }
-            Ok(bytes_read) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    Box::new(UsbIpError::PkgTooShort(bytes_read)),
-                ))
+    /// Encodes the request into its wire format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(URB_HEADER_SIZE + self.data.len());
+
+        result.extend_from_slice(&self.header.to_array());
+        match self.cmd {
+            UsbIpRequestCmd::Cmd(ref cmd) => {
+                result.extend_from_slice(&cmd.to_array());
+
+                // Only out urbs carry the transfer buffer
+                if self.header.direction == Direction::OUT {
+                    result.extend_from_slice(&self.data);
+                }
             }
-            Err(err) => return Err(err),
+            UsbIpRequestCmd::Unlink(ref unlink) => result.extend_from_slice(&unlink.to_array()),
+        }
+
+        result
+    }
+
+    /// Decodes a request from the start of `data`.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if `data` does not yet contain the complete request
+    /// - `Ok(Some((request, len)))` where `len` is the number of bytes consumed
+    pub fn decode(data: &[u8], limits: &UsbIpLimits) -> Result<Option<(Self, usize)>, UsbIpError> {
+        if data.len() < URB_HEADER_SIZE {
+            return Ok(None);
         }
-        reader.set_nonblocking(false)?;
 
-        let header = UsbIpHeader::from_slice(&buf[0..20]);
+        let header = UsbIpHeader::from_slice(&data[0..20])?;
         match header.command {
             UsbCmd::Request => {
-                let cmd = UsbIpCmdSubmit::from_slice(&buf[20..48]);
+                let cmd = UsbIpCmdSubmit::from_slice(&data[20..48])?;
+
+                // Isochronous transfers would be followed by packet descriptors
+                if cmd.number_of_packets > 0 {
+                    return Err(UsbIpError::IsochronousUnsupported);
+                }
+
+                // Refuse to allocate buffers for nonsensical or overly large urbs
+                let len = match usize::try_from(cmd.transfer_buffer_length) {
+                    Ok(len) if len <= limits.max_urb_size => len,
+                    Ok(len) => return Err(UsbIpError::UrbTooLarge(len)),
+                    Err(_) => {
+                        return Err(UsbIpError::InvalidTransferLength(
+                            cmd.transfer_buffer_length,
+                        ))
+                    }
+                };
 
                 // Receive the URB if this is a OUT packet
-                let data = if header.direction == Direction::OUT && cmd.transfer_buffer_length != 0
-                {
-                    // NOTE: Reading 0 bytes would still block the reader
-                    let mut data = vec![0; cmd.transfer_buffer_length as usize];
-                    reader.read_exact(&mut data)?;
-
-                    data
+                let len = if header.direction == Direction::OUT {
+                    len
                 } else {
-                    vec![]
+                    0
                 };
-
-                //Ok(Self::Cmd(header, cmd, data))
-                Ok(Self {
-                    header,
-                    cmd: UsbIpRequestCmd::Cmd(cmd),
-                    data,
-                })
+                if data.len() < URB_HEADER_SIZE + len {
+                    return Ok(None);
+                }
+
+                Ok(Some((
+                    Self {
+                        header,
+                        cmd: UsbIpRequestCmd::Cmd(cmd),
+                        data: data[URB_HEADER_SIZE..URB_HEADER_SIZE + len].to_vec(),
+                    },
+                    URB_HEADER_SIZE + len,
+                )))
             }
             UsbCmd::UnlinkRequest => {
-                let unlink = UsbIpCmdUnlink::from_slice(&buf[20..24]);
+                let unlink = UsbIpCmdUnlink::from_slice(&data[20..48])?;
 
                 // NOTE: We do not expect to see urb data behind an unlink
 
-                Ok(Self {
-                    header,
-                    cmd: UsbIpRequestCmd::Unlink(unlink),
-                    data: vec![],
-                })
+                Ok(Some((
+                    Self {
+                        header,
+                        cmd: UsbIpRequestCmd::Unlink(unlink),
+                        data: vec![],
+                    },
+                    URB_HEADER_SIZE,
+                )))
             }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                Box::new(UsbIpError::InvalidCommand(header.command as u16)),
-            )),
+            _ => Err(UsbIpError::InvalidUrbCommand(header.command.to_u32())),
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpCmdSubmit {
     pub transfer_flags: TransferFlags,
     pub transfer_buffer_length: i32,
@@ -114,7 +144,7 @@ impl Debug for UsbIpCmdSubmit {
     /// they are not being printed
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         // Only output setup bytes, if they are relevant
-        let setup_dbg = DbgBuf(&self.setup);
+        let setup_dbg = DbgSetup(&self.setup);
         let setup: &dyn Debug = if self.setup != [0, 0, 0, 0, 0, 0, 0, 0] {
             &setup_dbg
         } else {
@@ -130,8 +160,25 @@ impl Debug for UsbIpCmdSubmit {
 }
 
 impl UsbIpCmdSubmit {
-    fn from_slice(data: &[u8]) -> Self {
-        Self {
+    pub fn to_array(&self) -> [u8; 28] {
+        let mut result = [0; 28];
+
+        result[0..4].copy_from_slice(&self.transfer_flags.bits().to_be_bytes());
+        result[4..8].copy_from_slice(&self.transfer_buffer_length.to_be_bytes());
+        result[8..12].copy_from_slice(&self.start_frame.to_be_bytes());
+        result[12..16].copy_from_slice(&self.number_of_packets.to_be_bytes());
+        result[16..20].copy_from_slice(&self.interval.to_be_bytes());
+        result[20..28].copy_from_slice(&self.setup);
+
+        result
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 28 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
             transfer_flags: TransferFlags::from_bits_truncate(u32::from_be_bytes(
                 data[0..4].try_into().unwrap(),
             )),
@@ -140,19 +187,29 @@ impl UsbIpCmdSubmit {
             number_of_packets: i32::from_be_bytes(data[12..16].try_into().unwrap()),
             interval: i32::from_be_bytes(data[16..20].try_into().unwrap()),
             setup: data[20..28].try_into().unwrap(),
-        }
+        })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UsbIpCmdUnlink {
     pub seqnum: u32,
 }
 
 impl UsbIpCmdUnlink {
-    fn from_slice(data: &[u8]) -> Self {
-        Self {
-            seqnum: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+    pub fn to_array(&self) -> [u8; 28] {
+        let mut result = [0; 28];
+        result[0..4].copy_from_slice(&self.seqnum.to_be_bytes());
+        result
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 4 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
         }
+
+        Ok(Self {
+            seqnum: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+        })
     }
 }
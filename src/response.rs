@@ -1,32 +1,96 @@
-use crate::{cmd::UsbIpHeader, debug::DbgBuf};
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use crate::{
+    cmd::{Direction, UsbCmd, UsbIpHeader},
+    debug::{DbgBuf, DbgControlData},
+    request::URB_HEADER_SIZE,
+    UsbIpError, UsbIpLimits,
+};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt::{Debug, Formatter, Result as FmtResult},
+};
 
-#[derive(Clone)]
+/// Error number signaling, that the endpoint does not exist
+pub const ENOENT: i32 = 2;
+/// Error number signaling, that the endpoint is stalled or can not process the urb
+pub const EPIPE: i32 = 32;
+/// Error number signaling, that the urb timed out on the bus
+pub const ETIME: i32 = 62;
+/// Error number signaling a protocol error on the bus, such as a bad crc
+pub const EPROTO: i32 = 71;
+/// Error number signaling, that the device sent more data than requested
+pub const EOVERFLOW: i32 = 75;
+/// Error number signaling, that the urb has been unlinked
+pub const ECONNRESET: i32 = 104;
+/// Error number signaling, that the urb was cancelled by a reset of the device
+pub const ESHUTDOWN: i32 = 108;
+
+/// An urb packet sent from the server to the host.
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpResponse {
     pub header: UsbIpHeader,
     pub cmd: UsbIpResponseCmd,
+    /// The transfer buffer, only transmitted for in urbs
     pub data: Vec<u8>,
 }
 
 impl Debug for UsbIpResponse {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        // Control transfers return descriptors during the enumeration
+        let data_dbg = DbgBuf(&self.data);
+        let control_dbg = DbgControlData(&self.data);
+        let data: &dyn Debug = if self.header.ep == 0 {
+            &control_dbg
+        } else {
+            &data_dbg
+        };
+
         f.debug_struct("UsbIpResponse")
             .field("header", &self.header)
             .field("cmd", &self.cmd)
-            .field("data", &DbgBuf(&self.data))
+            .field("data", data)
             .finish()
     }
 }
 
-#[derive(Debug, Clone)]
+/// The body of an [`UsbIpResponse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UsbIpResponseCmd {
+    /// USBIP_RET_SUBMIT
     Cmd(UsbIpRetSubmit),
+    /// USBIP_RET_UNLINK
     Unlink(UsbIpRetUnlink),
 }
 
 impl UsbIpResponse {
-    pub fn to_vec(&self) -> Option<Vec<u8>> {
-        let mut result = vec![];
+    /// Creates the USBIP_RET_SUBMIT, that completes the urb of `header`.
+    pub(crate) fn ret_submit(
+        header: &UsbIpHeader,
+        status: i32,
+        actual_length: usize,
+        data: Vec<u8>,
+    ) -> Self {
+        Self {
+            header: UsbIpHeader {
+                command: UsbCmd::Response,
+                seqnum: header.seqnum,
+                devid: header.devid,
+                direction: header.direction,
+                ep: header.ep,
+            },
+            cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
+                status,
+                actual_length: actual_length as i32,
+                start_frame: 0,
+                number_of_packets: 0,
+                error_count: 0,
+            }),
+            data,
+        }
+    }
+
+    /// Encodes the response into its wire format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(URB_HEADER_SIZE + self.data.len());
 
         // Parse the header
         result.extend_from_slice(&self.header.to_array());
@@ -44,11 +108,74 @@ impl UsbIpResponse {
         // parse the data
         result.extend_from_slice(&self.data[..]);
 
-        Some(result)
+        result
+    }
+
+    /// Decodes a response from the start of `data`.
+    ///
+    /// A USBIP_RET_SUBMIT does not necessarily carry the direction of the
+    /// urb it completes. Therefore, `direction` is called with the header
+    /// of the response to look up the direction of the submitted urb.
+    ///
+    /// # Returns
+    /// - `Ok(None)` if `data` does not yet contain the complete response
+    /// - `Ok(Some((response, len)))` where `len` is the number of bytes consumed
+    pub fn decode(
+        data: &[u8],
+        limits: &UsbIpLimits,
+        direction: impl FnOnce(&UsbIpHeader) -> Direction,
+    ) -> Result<Option<(Self, usize)>, UsbIpError> {
+        if data.len() < URB_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let header = UsbIpHeader::from_slice(&data[0..20])?;
+        match header.command {
+            UsbCmd::Response => {
+                let cmd = UsbIpRetSubmit::from_slice(&data[20..48])?;
+
+                // Isochronous transfers would be followed by packet descriptors
+                if cmd.number_of_packets > 0 {
+                    return Err(UsbIpError::IsochronousUnsupported);
+                }
+
+                // Only in urbs carry the transfer buffer
+                let len = if direction(&header) == Direction::IN {
+                    match usize::try_from(cmd.actual_length) {
+                        Ok(len) if len <= limits.max_urb_size => len,
+                        Ok(len) => return Err(UsbIpError::UrbTooLarge(len)),
+                        Err(_) => return Err(UsbIpError::InvalidTransferLength(cmd.actual_length)),
+                    }
+                } else {
+                    0
+                };
+                if data.len() < URB_HEADER_SIZE + len {
+                    return Ok(None);
+                }
+
+                Ok(Some((
+                    Self {
+                        header,
+                        cmd: UsbIpResponseCmd::Cmd(cmd),
+                        data: data[URB_HEADER_SIZE..URB_HEADER_SIZE + len].to_vec(),
+                    },
+                    URB_HEADER_SIZE + len,
+                )))
+            }
+            UsbCmd::UnlinkResponse => Ok(Some((
+                Self {
+                    header,
+                    cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink::from_slice(&data[20..48])?),
+                    data: vec![],
+                },
+                URB_HEADER_SIZE,
+            ))),
+            _ => Err(UsbIpError::InvalidUrbCommand(header.command.to_u32())),
+        }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UsbIpRetSubmit {
     pub status: i32,
     pub actual_length: i32,
@@ -69,7 +196,7 @@ impl Debug for UsbIpRetSubmit {
 }
 
 impl UsbIpRetSubmit {
-    fn to_array(&self) -> [u8; 28] {
+    pub fn to_array(&self) -> [u8; 28] {
         let mut result = [0; 28];
 
         result[0..4].copy_from_slice(&self.status.to_be_bytes());
@@ -80,17 +207,41 @@ impl UsbIpRetSubmit {
 
         result
     }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 20 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            status: i32::from_be_bytes(data[0..4].try_into().unwrap()),
+            actual_length: i32::from_be_bytes(data[4..8].try_into().unwrap()),
+            start_frame: i32::from_be_bytes(data[8..12].try_into().unwrap()),
+            number_of_packets: i32::from_be_bytes(data[12..16].try_into().unwrap()),
+            error_count: i32::from_be_bytes(data[16..20].try_into().unwrap()),
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UsbIpRetUnlink {
-    pub status: u32,
+    pub status: i32,
 }
 
 impl UsbIpRetUnlink {
-    fn to_array(&self) -> [u8; 28] {
+    pub fn to_array(&self) -> [u8; 28] {
         let mut result = [0; 28];
         result[0..4].copy_from_slice(&self.status.to_be_bytes());
         result
     }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, UsbIpError> {
+        if data.len() < 4 {
+            return Err(UsbIpError::PkgTooShort(data.len()));
+        }
+
+        Ok(Self {
+            status: i32::from_be_bytes(data[0..4].try_into().unwrap()),
+        })
+    }
 }
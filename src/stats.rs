@@ -0,0 +1,267 @@
+//! Statistics and introspection of a running [`UsbIpBus`](crate::UsbIpBus).
+//!
+//! [`UsbIpBus::snapshot`](crate::UsbIpBus::snapshot) returns the state of the connection
+//! and of all allocated endpoints, together with the counters of their traffic.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usbip_device::UsbIpBus;
+//!
+//! let bus = UsbIpBus::new();
+//! // ... run the device ...
+//!
+//! for endpoint in bus.snapshot().endpoints {
+//!     if endpoint.oldest_pending > Some(Duration::from_secs(1)) {
+//!         println!("endpoint {} is stuck", endpoint.number);
+//!     }
+//! }
+//! ```
+
+use crate::cmd::Direction;
+use std::{net::SocketAddr, time::Duration};
+use usb_device::endpoint::EndpointType;
+
+/// The upper bounds of the buckets of a [`LatencyHistogram`]
+const LATENCY_BOUNDS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// An urb, that has been submitted but not completed yet.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SubmittedUrb {
+    pub ep: usize,
+    pub direction: Direction,
+    /// The time of the submission, as read from the clock of the bus
+    pub time: Duration,
+}
+
+/// A histogram of the time between the submission and the completion of urbs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// The counts of the buckets, the last one counts everything above the last bound
+    counts: [u64; LATENCY_BOUNDS.len() + 1],
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|bound| latency < *bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+
+        self.counts[bucket] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Returns the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the mean latency or `None`, if nothing was recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(self.total / count as u32),
+        }
+    }
+
+    /// Returns the highest recorded latency.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the buckets as their exclusive upper bound and count.
+    ///
+    /// The last bucket has no upper bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        LATENCY_BOUNDS
+            .iter()
+            .map(|bound| Some(*bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+}
+
+/// The counters of the traffic of one direction of an endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipeStats {
+    /// The number of packets written or read by the device
+    pub packets: u64,
+    /// The number of bytes written or read by the device
+    pub bytes: u64,
+    /// The number of urbs, that completed successfully
+    pub urbs_completed: u64,
+    /// The number of urbs, that failed with `-EPIPE`
+    pub urbs_stalled: u64,
+    /// The number of urbs, that the host unlinked before they completed
+    pub urbs_unlinked: u64,
+    /// The number of urbs, that failed with any other error
+    pub urbs_failed: u64,
+    /// The latencies of the completed urbs
+    pub latency: LatencyHistogram,
+}
+
+/// The state of one direction of an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipeSnapshot {
+    pub ty: EndpointType,
+    pub max_packet_size: u16,
+    pub stalled: bool,
+    /// The number of packets, that are buffered in the pipe
+    pub queued_packets: usize,
+    /// The number of bytes, that are buffered in the pipe
+    pub queued_bytes: usize,
+    pub stats: PipeStats,
+}
+
+/// The state of an endpoint, that has at least one direction allocated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointSnapshot {
+    /// The endpoint number without the direction bit
+    pub number: u8,
+    pub pipe_in: Option<PipeSnapshot>,
+    pub pipe_out: Option<PipeSnapshot>,
+    /// The number of in urbs, that wait for data from the device
+    pub pending_ins: usize,
+    /// Whether a control out urb waits for the status stage
+    pub pending_control_out: bool,
+    /// The age of the oldest urb, that has not completed yet
+    pub oldest_pending: Option<Duration>,
+}
+
+/// How the device is connected to a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No host has imported the device
+    Detached,
+    /// A remote host has imported the device over TCP
+    Imported,
+    /// The in-process [`LoopbackHost`](crate::loopback::LoopbackHost) has imported the device
+    Loopback,
+}
+
+/// The state of a [`UsbIpBus`](crate::UsbIpBus) at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusSnapshot {
+    pub connection: ConnectionState,
+    /// The address of the remote host, if the device is imported over TCP
+    pub peer_addr: Option<SocketAddr>,
+    /// The protocol version, that the device was imported with
+    pub version: Option<u16>,
+    /// Whether the device is held in reset, because it is not imported
+    pub reset: bool,
+    pub suspended: bool,
+    pub device_address: u8,
+    pub endpoints: Vec<EndpointSnapshot>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{SetupPacket, UsbHost},
+        UsbIpBus,
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+    use usbd_serial::SerialPort;
+
+    #[test]
+    fn latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.mean(), None);
+
+        histogram.record(Duration::from_micros(5));
+        histogram.record(Duration::from_micros(10));
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_secs(2));
+
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.max(), Duration::from_secs(2));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(501_253_750)));
+
+        // The bounds are exclusive, everything above the last one lands in the last bucket
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(
+            buckets,
+            vec![
+                (Some(Duration::from_micros(10)), 1),
+                (Some(Duration::from_micros(100)), 1),
+                (Some(Duration::from_millis(1)), 0),
+                (Some(Duration::from_millis(10)), 1),
+                (Some(Duration::from_millis(100)), 0),
+                (Some(Duration::from_secs(1)), 0),
+                (None, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_counts_traffic() {
+        let (bus, mut host) = UsbIpBus::loopback();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut serial = SerialPort::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut serial]);
+            while serial.read(&mut [0; 64]).is_ok() {}
+        });
+        session.enumerate().unwrap();
+        bus.reset_stats();
+
+        assert_eq!(session.bulk_out(0x01, &[0xaa; 100]).unwrap(), 100);
+        // The serial port has no data, so the in urb stays pending
+        let seqnum = session.submit(2, Direction::IN, None, &[], 64).unwrap();
+
+        let snapshot = bus.snapshot();
+        assert_eq!(snapshot.connection, ConnectionState::Loopback);
+        assert!(!snapshot.reset);
+        let numbers: Vec<_> = snapshot.endpoints.iter().map(|ep| ep.number).collect();
+        assert_eq!(numbers, vec![0, 1, 2]);
+
+        let ep1 = &snapshot.endpoints[1];
+        let out = ep1.pipe_out.as_ref().unwrap();
+        assert_eq!(out.ty, EndpointType::Bulk);
+        assert_eq!(out.max_packet_size, 64);
+        assert_eq!(out.stats.urbs_completed, 1);
+        assert_eq!(out.stats.latency.count(), 1);
+        assert_eq!(ep1.oldest_pending, None);
+        // The packets are only counted, once the device has read them
+        assert_eq!(out.queued_packets, 2);
+        assert_eq!(out.queued_bytes, 100);
+        assert_eq!(out.stats.packets, 0);
+
+        let ep2 = &snapshot.endpoints[2];
+        assert_eq!(ep2.pending_ins, 1);
+        assert!(ep2.oldest_pending.is_some());
+
+        assert!(session.unlink(seqnum).unwrap());
+        // Polls the device, until it has answered GET_STATUS
+        let get_status = SetupPacket::new(0x80, 0x00, 0, 0, 2);
+        session.control_transfer(get_status, &[]).unwrap();
+        let snapshot = bus.snapshot();
+        let out = snapshot.endpoints[1].pipe_out.as_ref().unwrap();
+        assert_eq!(out.queued_packets, 0);
+        assert_eq!(out.stats.packets, 2);
+        assert_eq!(out.stats.bytes, 100);
+        let ep2 = &snapshot.endpoints[2];
+        assert_eq!(ep2.pending_ins, 0);
+        assert_eq!(ep2.oldest_pending, None);
+        assert_eq!(ep2.pipe_in.as_ref().unwrap().stats.urbs_unlinked, 1);
+
+        bus.reset_stats();
+        let snapshot = bus.snapshot();
+        let out = snapshot.endpoints[1].pipe_out.as_ref().unwrap();
+        assert_eq!(out.stats, PipeStats::default());
+    }
+}
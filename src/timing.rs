@@ -0,0 +1,226 @@
+//! Emulation of the timing of a real USB bus.
+//!
+//! Without emulation, urbs complete as fast as the host and the device can exchange them,
+//! which is orders of magnitude faster than a full speed bus.
+//! With a [`BusTiming`] installed by [`UsbIpBus::set_timing`](crate::UsbIpBus::set_timing),
+//! the completions are held back and sent at the end of the (micro)frame, in which the
+//! transfer would have finished on the real bus. Each frame carries at most
+//! [`BusSpeed::frame_budget`] bytes, the rest of a transfer spills into the following frames.
+//!
+//! The completions are sent while the bus is polled, the poll loop is never blocked.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usbip_device::{
+//!     timing::{BusSpeed, BusTiming},
+//!     UsbIpBus,
+//! };
+//!
+//! let bus = UsbIpBus::new();
+//! bus.set_timing(Some(
+//!     BusTiming::new(BusSpeed::Full).jitter(Duration::from_micros(200), 42),
+//! ));
+//! ```
+
+use crate::{cmd::Direction, prng::Prng, response::UsbIpResponse};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+/// The speed of the emulated bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusSpeed {
+    /// 1.5 Mbit/s in 1 ms frames
+    Low,
+    /// 12 Mbit/s in 1 ms frames
+    Full,
+    /// 480 Mbit/s in 125 µs microframes
+    High,
+}
+
+impl BusSpeed {
+    /// Returns the raw bit rate of the bus.
+    pub fn bits_per_second(self) -> u64 {
+        match self {
+            Self::Low => 1_500_000,
+            Self::Full => 12_000_000,
+            Self::High => 480_000_000,
+        }
+    }
+
+    /// Returns the length of a frame or, at high speed, of a microframe.
+    pub fn frame_duration(self) -> Duration {
+        match self {
+            Self::Low | Self::Full => Duration::from_millis(1),
+            Self::High => Duration::from_micros(125),
+        }
+    }
+
+    /// Returns the number of frames between two transactions of an interrupt endpoint
+    /// with the given `bInterval`.
+    pub fn interval_frames(self, interval: u8) -> u64 {
+        match self {
+            Self::Low | Self::Full => interval.max(1) as u64,
+            Self::High => 1 << (interval.clamp(1, 16) - 1),
+        }
+    }
+
+    /// Returns the number of bytes, that fit into a frame at the raw bit rate.
+    pub fn frame_budget(self) -> usize {
+        (self.bits_per_second() * self.frame_duration().as_nanos() as u64 / 8_000_000_000) as usize
+    }
+}
+
+/// The configuration of the timing emulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusTiming {
+    pub speed: BusSpeed,
+    /// The upper bound of the random delay, that is added to every completion
+    pub jitter: Duration,
+    /// The seed of the PRNG, that draws the jitter
+    pub seed: u64,
+}
+
+impl BusTiming {
+    /// Creates a timing of the given speed without jitter.
+    pub fn new(speed: BusSpeed) -> Self {
+        Self {
+            speed,
+            jitter: Duration::ZERO,
+            seed: 0,
+        }
+    }
+
+    /// Adds a random delay of up to `jitter` to every completion,
+    /// drawn from a PRNG seeded with `seed`.
+    pub fn jitter(mut self, jitter: Duration, seed: u64) -> Self {
+        self.jitter = jitter;
+        self.seed = seed;
+        self
+    }
+}
+
+/// The schedule of the bus and the completions, that are held back.
+#[derive(Debug)]
+pub(crate) struct TimingState {
+    timing: BusTiming,
+    rng: Prng,
+    /// The start of frame 0
+    epoch: Duration,
+    /// The last frame, that carries data
+    frame: u64,
+    /// The number of bytes already scheduled into `frame`
+    used: usize,
+    /// The frames of the last transactions of the interrupt endpoints, by endpoint address
+    periodic: HashMap<u8, u64>,
+    /// The completions by the time they are due, which never decreases
+    delayed: VecDeque<(Duration, UsbIpResponse)>,
+}
+
+impl TimingState {
+    pub fn new(timing: BusTiming, now: Duration) -> Self {
+        Self {
+            rng: Prng::new(timing.seed),
+            timing,
+            epoch: now,
+            frame: 0,
+            used: 0,
+            periodic: HashMap::new(),
+            delayed: VecDeque::new(),
+        }
+    }
+
+    /// Holds back a completion, that transferred `len` bytes over the bus,
+    /// until the end of the frame in which the transfer finishes.
+    ///
+    /// Completions on interrupt endpoints are additionally spaced by their `interval`.
+    pub fn delay(
+        &mut self,
+        response: UsbIpResponse,
+        len: usize,
+        interval: Option<u8>,
+        now: Duration,
+    ) {
+        let frame_duration = self.timing.speed.frame_duration();
+        let budget = self.timing.speed.frame_budget();
+
+        // Idle frames are not used by anyone anymore
+        let now_frame =
+            (now.saturating_sub(self.epoch).as_nanos() / frame_duration.as_nanos()) as u64;
+        if now_frame > self.frame {
+            self.frame = now_frame;
+            self.used = 0;
+        }
+
+        let mut left = len;
+        while left > budget - self.used {
+            left -= budget - self.used;
+            self.frame += 1;
+            self.used = 0;
+        }
+        self.used += left;
+
+        // An interrupt endpoint is only serviced once per interval
+        let mut frame = self.frame;
+        if let Some(interval) = interval {
+            let header = &response.header;
+            let address = match header.direction {
+                Direction::IN => header.ep as u8 | 0x80,
+                _ => header.ep as u8,
+            };
+            if let Some(last) = self.periodic.get(&address) {
+                frame = frame.max(last + self.timing.speed.interval_frames(interval));
+            }
+            self.periodic.insert(address, frame);
+        }
+
+        let frame_end = Duration::from_nanos(frame_duration.as_nanos() as u64 * (frame + 1));
+        let mut due = self.epoch + frame_end + self.timing.jitter.mul_f64(self.rng.next_f64());
+
+        // Jitter must not reorder the completions
+        if let Some((last, _)) = self.delayed.back() {
+            due = due.max(*last);
+        }
+        self.delayed.push_back((due, response));
+    }
+
+    /// Takes the next completion, that is due at `now`.
+    pub fn pop_due(&mut self, now: Duration) -> Option<UsbIpResponse> {
+        match self.delayed.front() {
+            Some((due, _)) if *due <= now => self.delayed.pop_front().map(|(_, response)| response),
+            _ => None,
+        }
+    }
+
+    /// Removes a completion, that has not been sent yet.
+    ///
+    /// # Returns
+    /// - `true` if the completion was removed
+    /// - `false` if it was not found
+    pub fn unlink(&mut self, seqnum: u32) -> bool {
+        let old_len = self.delayed.len();
+        self.delayed
+            .retain(|(_, response)| response.header.seqnum != seqnum);
+        old_len != self.delayed.len()
+    }
+
+    /// Moves the schedule from a clock, that reads `old_now`, to one that reads `now`.
+    pub fn rebase(&mut self, old_now: Duration, now: Duration) {
+        let rebase = |time: Duration| (time + now).saturating_sub(old_now);
+        self.epoch = rebase(self.epoch);
+        for (due, _) in self.delayed.iter_mut() {
+            *due = rebase(*due);
+        }
+    }
+
+    /// Takes all completions, that have not been sent yet.
+    pub fn drain(&mut self) -> impl Iterator<Item = UsbIpResponse> + '_ {
+        self.delayed.drain(..).map(|(_, response)| response)
+    }
+
+    /// Drops all completions, that have not been sent yet.
+    pub fn clear(&mut self) {
+        self.delayed.clear();
+    }
+}
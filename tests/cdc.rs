@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::{ParityType, SerialPort, StopBits};
+use usbip_device::{
+    class::cdc::{CdcAcm, LineCoding},
+    client::UsbHost,
+    UsbIpBus,
+};
+
+/// The bulk in endpoint of the serial port
+const EP_IN: u8 = 0x82;
+
+#[test]
+fn line_coding_and_control_lines() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let line_coding = LineCoding {
+        data_rate: 9600,
+        stop_bits: 2,
+        parity: 2,
+        data_bits: 7,
+    };
+
+    {
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut serial]);
+        });
+        let cdc = CdcAcm::new(&session.enumerate().unwrap()).unwrap();
+
+        cdc.set_line_coding(&mut session, &line_coding).unwrap();
+        assert_eq!(cdc.get_line_coding(&mut session).unwrap(), line_coding);
+        assert_eq!(line_coding.to_string(), "9600 7E2");
+
+        cdc.set_control_line_state(&mut session, true, false)
+            .unwrap();
+    }
+
+    let device_coding = serial.line_coding();
+    assert_eq!(device_coding.data_rate(), 9600);
+    assert_eq!(device_coding.data_bits(), 7);
+    assert!(device_coding.parity_type() == ParityType::Even);
+    assert!(device_coding.stop_bits() == StopBits::Two);
+    assert!(serial.dtr());
+    assert!(!serial.rts());
+}
+
+#[test]
+fn echo() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let data: Vec<u8> = (0..200).collect();
+
+    // The device sends back everything it receives
+    let mut echo = VecDeque::new();
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+        let mut buf = [0; 64];
+        if let Ok(len) = serial.read(&mut buf) {
+            echo.extend(&buf[..len]);
+        }
+        if let Ok(len) = serial.write(echo.as_slices().0) {
+            echo.drain(..len);
+        }
+    });
+    let mut cdc = CdcAcm::new(&session.enumerate().unwrap()).unwrap();
+
+    assert_eq!(cdc.write(&mut session, &data).unwrap(), data.len());
+    assert_eq!(cdc.read_exact(&mut session, data.len()).unwrap(), data);
+
+    // A read into a small buffer keeps the rest for the next read
+    cdc.write(&mut session, b"hello, world").unwrap();
+    let mut buf = [0; 5];
+    assert_eq!(cdc.read(&mut session, &mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"hello");
+    assert_eq!(cdc.read_exact(&mut session, 7).unwrap(), b", world");
+}
+
+#[test]
+fn short_urb_leaves_rest_of_packet_queued() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let data: Vec<u8> = (0..64).collect();
+
+    let mut tx = data.clone();
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+        if let Ok(len) = serial.write(&tx) {
+            tx.drain(..len);
+        }
+    });
+    session.enumerate().unwrap();
+
+    // A full packet is queued, but the urb only takes 10 bytes of it
+    assert_eq!(session.bulk_in(EP_IN, 10).unwrap(), &data[..10]);
+    assert_eq!(session.bulk_in(EP_IN, 64).unwrap(), &data[10..]);
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::{ControlIn, ControlOut, UsbClass},
+    control::{Recipient, RequestType},
+    descriptor::DescriptorWriter,
+    prelude::*,
+};
+use usbip_device::{
+    class::dfu::{Dfu, DfuState},
+    client::UsbHost,
+    UsbIpBus, UsbIpError,
+};
+
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+const TRANSFER_SIZE: usize = 64;
+/// The status errWRITE
+const ERR_WRITE: u8 = 0x03;
+
+/// A device, that switches between runtime and DFU mode on resets and keeps
+/// its firmware in memory.
+struct FirmwareDevice {
+    iface: InterfaceNumber,
+    dfu_mode: bool,
+    state: DfuState,
+    firmware: Vec<u8>,
+    staged: Vec<u8>,
+    /// The block, whose download fails with errWRITE
+    failing_block: Option<u16>,
+    /// Stays busy after every download request
+    stuck: bool,
+    resets: usize,
+}
+
+impl FirmwareDevice {
+    fn new(iface: InterfaceNumber, firmware: &[u8]) -> Self {
+        Self {
+            iface,
+            dfu_mode: false,
+            state: DfuState::AppIdle,
+            firmware: firmware.to_vec(),
+            staged: vec![],
+            failing_block: None,
+            stuck: false,
+            resets: 0,
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for FirmwareDevice {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        let protocol = if self.dfu_mode { 0x02 } else { 0x01 };
+        writer.interface(self.iface, 0xfe, 0x01, protocol)?;
+
+        // Can download and upload, detach timeout 100 ms, DFU 1.1
+        let [size_lo, size_hi] = (TRANSFER_SIZE as u16).to_le_bytes();
+        writer.write(0x21, &[0x03, 100, 0, size_lo, size_hi, 0x10, 0x01])
+    }
+
+    fn reset(&mut self) {
+        self.resets += 1;
+        match self.state {
+            DfuState::AppDetach => {
+                self.dfu_mode = true;
+                self.state = DfuState::DfuIdle;
+            }
+            DfuState::ManifestWaitReset => {
+                self.firmware = std::mem::take(&mut self.staged);
+                self.dfu_mode = false;
+                self.state = DfuState::AppIdle;
+            }
+            _ => (),
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = *xfer.request();
+        if request.request_type != RequestType::Class || request.recipient != Recipient::Interface {
+            return;
+        }
+
+        match request.request {
+            DFU_GETSTATUS => {
+                self.state = match self.state {
+                    DfuState::DnloadSync if self.stuck => DfuState::DnBusy,
+                    DfuState::DnloadSync => DfuState::DnloadIdle,
+                    DfuState::ManifestSync => DfuState::Manifest,
+                    DfuState::Manifest => DfuState::ManifestWaitReset,
+                    state => state,
+                };
+                let status = match self.state {
+                    DfuState::Error => ERR_WRITE,
+                    _ => 0,
+                };
+                xfer.accept_with(&[status, 0, 0, 0, self.state.to_u8(), 0])
+                    .unwrap();
+            }
+            DFU_GETSTATE => xfer.accept_with(&[self.state.to_u8()]).unwrap(),
+            DFU_UPLOAD if self.dfu_mode => {
+                let start = (request.value as usize * TRANSFER_SIZE).min(self.firmware.len());
+                let end = (start + request.length as usize).min(self.firmware.len());
+                let block = self.firmware[start..end].to_vec();
+                self.state = match block.len() < request.length as usize {
+                    true => DfuState::DfuIdle,
+                    false => DfuState::UploadIdle,
+                };
+                xfer.accept_with(&block).unwrap();
+            }
+            _ => xfer.reject().unwrap(),
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = *xfer.request();
+        if request.request_type != RequestType::Class || request.recipient != Recipient::Interface {
+            return;
+        }
+
+        match request.request {
+            DFU_DETACH if !self.dfu_mode => self.state = DfuState::AppDetach,
+            DFU_DNLOAD if self.dfu_mode => {
+                if Some(request.value) == self.failing_block {
+                    self.state = DfuState::Error;
+                } else if xfer.data().is_empty() {
+                    self.state = DfuState::ManifestSync;
+                } else {
+                    self.staged.extend_from_slice(xfer.data());
+                    self.state = DfuState::DnloadSync;
+                }
+            }
+            DFU_CLRSTATUS | DFU_ABORT => self.state = DfuState::DfuIdle,
+            _ => {
+                xfer.reject().unwrap();
+                return;
+            }
+        }
+        xfer.accept().unwrap();
+    }
+}
+
+#[test]
+fn upgrade_firmware() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut device = FirmwareDevice::new(alloc.interface(), b"old firmware");
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let firmware: Vec<u8> = (0..1000u32).map(|i| (i * 13) as u8).collect();
+
+    {
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut device]);
+        });
+
+        let runtime = Dfu::new(&session.enumerate().unwrap()).unwrap();
+        assert!(!runtime.is_dfu_mode());
+        assert_eq!(runtime.get_state(&mut session).unwrap(), DfuState::AppIdle);
+
+        let dfu = Dfu::new(&runtime.enter_dfu_mode(&mut session).unwrap()).unwrap();
+        assert!(dfu.is_dfu_mode());
+        assert_eq!(dfu.upload_all(&mut session).unwrap(), b"old firmware");
+
+        // The device waits for a reset to activate the new firmware
+        assert!(dfu.download(&mut session, &firmware).unwrap());
+        session.reset_device().unwrap();
+        let runtime = Dfu::new(&session.enumerate().unwrap()).unwrap();
+        assert!(!runtime.is_dfu_mode());
+    }
+
+    assert_eq!(device.firmware, firmware);
+    assert_eq!(device.resets, 2);
+}
+
+#[test]
+fn failed_download_reports_status() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut device = FirmwareDevice::new(alloc.interface(), b"");
+    device.failing_block = Some(2);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut device]);
+    });
+    let runtime = Dfu::new(&session.enumerate().unwrap()).unwrap();
+    let dfu = Dfu::new(&runtime.enter_dfu_mode(&mut session).unwrap()).unwrap();
+
+    assert!(matches!(
+        dfu.download(&mut session, &[0x55; 4 * TRANSFER_SIZE]),
+        Err(UsbIpError::DfuFailed(10, ERR_WRITE))
+    ));
+    dfu.clear_status(&mut session).unwrap();
+    assert_eq!(dfu.get_state(&mut session).unwrap(), DfuState::DfuIdle);
+}
+
+#[test]
+fn busy_device_times_out() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut device = FirmwareDevice::new(alloc.interface(), b"");
+    device.stuck = true;
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut device]);
+    });
+    let runtime = Dfu::new(&session.enumerate().unwrap()).unwrap();
+    let mut dfu = Dfu::new(&runtime.enter_dfu_mode(&mut session).unwrap()).unwrap();
+    dfu.set_busy_timeout(Duration::from_millis(50));
+
+    assert!(matches!(
+        dfu.download(&mut session, &[0x55; TRANSFER_SIZE]),
+        Err(UsbIpError::Timeout)
+    ));
+}
@@ -0,0 +1,164 @@
+use usb_device::{bus::UsbBusAllocator, endpoint::EndpointType, prelude::*, UsbDirection};
+use usbd_serial::SerialPort;
+use usbip_device::{
+    client::{SetupPacket, UsbHost},
+    event::BusEvent,
+    fault::{Fault, FaultInjector, FaultRule},
+    protocol::{Direction, EPROTO, ESHUTDOWN},
+    UsbIpBus, UsbIpError,
+};
+
+/// The bulk out endpoint of the serial port
+const EP_OUT: u8 = 0x01;
+/// The bulk in endpoint of the serial port
+const EP_IN: u8 = 0x82;
+
+fn get_device_descriptor() -> SetupPacket {
+    SetupPacket::new(0x80, 0x06, 0x0100, 0, 18)
+}
+
+/// Enumerates a serial port and injects `faults` into the urbs, that follow.
+///
+/// # Returns
+/// The results of writing `count` packets to the serial port.
+fn write_packets(faults: FaultInjector, count: u8) -> Vec<Result<usize, UsbIpError>> {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+        let _ = serial.read(&mut [0; 64]);
+    });
+    session.enumerate().unwrap();
+
+    bus.set_fault_injector(Some(faults));
+    (0..count)
+        .map(|i| session.bulk_out(EP_OUT, &[i; 16]))
+        .collect()
+}
+
+#[test]
+fn status_fault_fails_nth_urb() {
+    let rule = FaultRule::new(Fault::Status(-EPROTO))
+        .transfer_type(EndpointType::Bulk)
+        .direction(UsbDirection::Out)
+        .nth(2);
+    let results = write_packets(FaultInjector::new(0).rule(rule), 3);
+
+    assert!(matches!(results[0], Ok(16)));
+    assert!(matches!(results[1], Err(UsbIpError::UrbFailed(status)) if status == -EPROTO));
+    assert!(matches!(results[2], Ok(16)));
+}
+
+#[test]
+fn duplicated_completion_is_ignored() {
+    let rule = FaultRule::new(Fault::Duplicate).endpoint(EP_OUT);
+    let results = write_packets(FaultInjector::new(0).rule(rule), 3);
+    assert!(results.iter().all(|result| matches!(result, Ok(16))));
+}
+
+#[test]
+fn dropped_urb_times_out() {
+    let rule = FaultRule::new(Fault::Drop).endpoint(EP_OUT).nth(1);
+    let results = write_packets(FaultInjector::new(0).rule(rule), 3);
+
+    assert!(matches!(results[0], Err(UsbIpError::Timeout)));
+    assert!(matches!(results[1], Ok(16)));
+    assert!(matches!(results[2], Ok(16)));
+}
+
+#[test]
+fn truncate_fault_cuts_data() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+    });
+    let full = session
+        .control_transfer(get_device_descriptor(), &[])
+        .unwrap();
+    assert_eq!(full.len(), 18);
+
+    bus.set_fault_injector(Some(
+        FaultInjector::new(0).rule(
+            FaultRule::new(Fault::Truncate(4))
+                .endpoint(0)
+                .direction(UsbDirection::In),
+        ),
+    ));
+    let truncated = session
+        .control_transfer(get_device_descriptor(), &[])
+        .unwrap();
+    assert_eq!(truncated, &full[..4]);
+}
+
+#[test]
+fn random_faults_are_reproducible() {
+    let failures = |seed| {
+        let rule = FaultRule::new(Fault::Status(-EPROTO))
+            .endpoint(EP_OUT)
+            .probability(0.5);
+        write_packets(FaultInjector::new(seed).rule(rule), 32)
+            .iter()
+            .map(Result::is_err)
+            .collect::<Vec<_>>()
+    };
+
+    let first = failures(42);
+    assert_eq!(first, failures(42));
+    assert_ne!(first, failures(43));
+    assert!(first.contains(&true) && first.contains(&false));
+}
+
+#[test]
+fn unplug_drops_queued_urbs() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let events = bus.subscribe();
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    {
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut serial]);
+            let _ = serial.read(&mut [0; 64]);
+        });
+        session.enumerate().unwrap();
+
+        // An in urb waits for data, while the device is unplugged
+        let queued = session
+            .submit(EP_IN & 0x0f, Direction::IN, None, &[], 64)
+            .unwrap();
+        bus.set_fault_injector(Some(
+            FaultInjector::new(0).rule(FaultRule::new(Fault::Unplug).endpoint(EP_OUT)),
+        ));
+        assert!(session.bulk_out(EP_OUT, &[0; 16]).is_err());
+
+        let completions: Vec<_> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                BusEvent::UrbCompleted { seqnum, status, .. } => Some((seqnum, status)),
+                _ => None,
+            })
+            .collect();
+        assert!(completions.contains(&(queued, -ESHUTDOWN)));
+    }
+
+    bus.set_fault_injector(None);
+    host.attach();
+
+    // Data written after the next import must not complete the urb of the lost connection
+    for _ in 0..10 {
+        usb_dev.poll(&mut [&mut serial]);
+        let _ = serial.write(b"stale");
+    }
+    assert!(host.receive().unwrap().is_none());
+    assert!(events
+        .try_iter()
+        .any(|event| matches!(event, BusEvent::Imported(None))));
+}
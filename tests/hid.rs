@@ -0,0 +1,135 @@
+use usbip_device::{
+    class::hid::{ReportDescriptor, ReportType},
+    UsbIpError,
+};
+
+/// The boot keyboard of the HID specification, appendix B.1
+const KEYBOARD: &[u8] = &[
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+];
+
+/// A mouse with three buttons and relative X and Y axes in report 1
+const MOUSE: &[u8] = &[
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01,
+    0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05,
+    0x81, 0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x02,
+    0x81, 0x06, 0xc0, 0xc0,
+];
+
+#[test]
+fn keyboard_layout() {
+    let descriptor = ReportDescriptor::parse(KEYBOARD).unwrap();
+    assert!(!descriptor.uses_report_ids);
+
+    let layout: Vec<_> = descriptor
+        .fields
+        .iter()
+        .map(|field| {
+            (
+                field.report_type,
+                field.bit_offset,
+                field.report_size,
+                field.report_count,
+            )
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        vec![
+            (ReportType::Input, 0, 1, 8),
+            (ReportType::Input, 8, 8, 1),
+            (ReportType::Output, 0, 1, 5),
+            (ReportType::Output, 5, 3, 1),
+            (ReportType::Input, 16, 8, 6),
+        ]
+    );
+
+    let modifiers = &descriptor.fields[0];
+    assert!(modifiers.is_variable());
+    assert_eq!(modifiers.usages.first(), Some(&0x0007_00e0));
+    assert_eq!(modifiers.usages.last(), Some(&0x0007_00e7));
+    assert!(descriptor.fields[1].is_constant());
+
+    let leds = &descriptor.fields[2];
+    assert_eq!(
+        leds.usages,
+        vec![
+            0x0008_0001,
+            0x0008_0002,
+            0x0008_0003,
+            0x0008_0004,
+            0x0008_0005
+        ]
+    );
+}
+
+#[test]
+fn keyboard_report() {
+    let descriptor = ReportDescriptor::parse(KEYBOARD).unwrap();
+
+    // Left shift together with the keys a and b
+    let report = descriptor
+        .decode(ReportType::Input, &[0x02, 0x00, 0x04, 0x05, 0, 0, 0, 0])
+        .unwrap();
+    assert_eq!(report.report_id, 0);
+    assert_eq!(report.get(0x07, 0xe0), Some(0));
+    assert_eq!(report.get(0x07, 0xe1), Some(1));
+    assert_eq!(report.get(0x07, 0x04), Some(1));
+    assert_eq!(report.get(0x07, 0x05), Some(1));
+    assert_eq!(report.get(0x07, 0x06), None);
+}
+
+#[test]
+fn mouse_report_with_id() {
+    let descriptor = ReportDescriptor::parse(MOUSE).unwrap();
+    assert!(descriptor.uses_report_ids);
+    assert!(descriptor.fields.iter().all(|field| field.report_id == 1));
+
+    let axes = descriptor.fields.last().unwrap();
+    assert_eq!(axes.bit_offset, 8);
+    assert_eq!((axes.logical_minimum, axes.logical_maximum), (-127, 127));
+    assert!(axes.is_relative());
+
+    let report = descriptor
+        .decode(ReportType::Input, &[0x01, 0x05, 0xfd, 0x04])
+        .unwrap();
+    assert_eq!(report.report_id, 1);
+    assert_eq!(report.get(0x09, 0x01), Some(1));
+    assert_eq!(report.get(0x09, 0x02), Some(0));
+    assert_eq!(report.get(0x09, 0x03), Some(1));
+    assert_eq!(report.get(0x01, 0x30), Some(-3));
+    assert_eq!(report.get(0x01, 0x31), Some(4));
+}
+
+#[test]
+fn short_report_is_rejected() {
+    let descriptor = ReportDescriptor::parse(MOUSE).unwrap();
+    assert!(matches!(
+        descriptor.decode(ReportType::Input, &[0x01, 0x05, 0xfd]),
+        Err(UsbIpError::PkgTooShort(3))
+    ));
+}
+
+#[test]
+fn truncated_item_is_rejected() {
+    // Logical Maximum announces 2 bytes of data, but only 1 follows
+    assert!(matches!(
+        ReportDescriptor::parse(&[0x05, 0x01, 0x26, 0xff]),
+        Err(UsbIpError::InvalidDescriptor(0x22))
+    ));
+}
+
+#[test]
+fn overflowing_offset_is_rejected() {
+    // Two inputs of 0xffffffff elements of 0xffffffff bits each
+    let data = [
+        0x77, 0xff, 0xff, 0xff, 0xff, 0x97, 0xff, 0xff, 0xff, 0xff, 0x81, 0x02, 0x81, 0x02,
+    ];
+    assert!(matches!(
+        ReportDescriptor::parse(&data),
+        Err(UsbIpError::InvalidDescriptor(0x22))
+    ));
+}
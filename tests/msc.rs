@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::{ControlIn, ControlOut, UsbClass},
+    control::{Recipient, RequestType},
+    descriptor::DescriptorWriter,
+    endpoint::{EndpointIn, EndpointOut},
+    prelude::*,
+};
+use usbip_device::{
+    class::msc::{CommandStatus, DataPhase, MassStorage, Sense},
+    client::UsbHost,
+    UsbIpBus, UsbIpError,
+};
+
+const BLOCK_SIZE: usize = 512;
+const NUM_BLOCKS: usize = 64;
+const PACKET_SIZE: usize = 64;
+
+const REQ_BULK_ONLY_RESET: u8 = 0xff;
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+/// A vendor command, that the disk answers with a phase error
+const PHASE_ERROR: u8 = 0xf0;
+
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+const CSW_PHASE_ERROR: u8 = 2;
+
+/// A RAM disk behind the Bulk-Only Transport.
+struct RamDisk<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    blocks: Vec<u8>,
+    /// The range and tag of the write command, whose data is being received
+    write: Option<(usize, usize, [u8; 4])>,
+    /// Sense key, additional sense code and qualifier of the last failed command
+    sense: (u8, u8, u8),
+    /// Packets, that are waiting for the in endpoint to become free
+    tx: VecDeque<Vec<u8>>,
+    /// Answers the next command with a wrong tag
+    corrupt_tag: bool,
+    resets: usize,
+}
+
+impl<'a, B: UsbBus> RamDisk<'a, B> {
+    fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            ep_in: alloc.bulk(PACKET_SIZE as u16),
+            ep_out: alloc.bulk(PACKET_SIZE as u16),
+            blocks: vec![0; NUM_BLOCKS * BLOCK_SIZE],
+            write: None,
+            sense: (0, 0, 0),
+            tx: VecDeque::new(),
+            corrupt_tag: false,
+            resets: 0,
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        self.tx
+            .extend(data.chunks(PACKET_SIZE).map(|chunk| chunk.to_vec()));
+        self.flush();
+    }
+
+    fn send_csw(&mut self, tag: [u8; 4], residue: u32, status: u8) {
+        let tag = match std::mem::take(&mut self.corrupt_tag) {
+            true => [0xde, 0xad, 0xbe, 0xef],
+            false => tag,
+        };
+
+        let mut csw = b"USBS".to_vec();
+        csw.extend_from_slice(&tag);
+        csw.extend_from_slice(&residue.to_le_bytes());
+        csw.push(status);
+        self.send(&csw);
+    }
+
+    fn flush(&mut self) {
+        while let Some(packet) = self.tx.front() {
+            if self.ep_in.write(packet).is_err() {
+                break;
+            }
+            self.tx.pop_front();
+        }
+    }
+
+    fn execute(&mut self, tag: [u8; 4], length: u32, cb: &[u8]) {
+        let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as usize;
+        let range =
+            lba * BLOCK_SIZE..(lba + u16::from_be_bytes([cb[7], cb[8]]) as usize) * BLOCK_SIZE;
+
+        match cb[0] {
+            TEST_UNIT_READY => (),
+            REQUEST_SENSE => {
+                let mut sense = [0; 18];
+                sense[0] = 0x70;
+                sense[2] = self.sense.0;
+                sense[12] = self.sense.1;
+                sense[13] = self.sense.2;
+                self.sense = (0, 0, 0);
+                self.send(&sense);
+            }
+            INQUIRY => {
+                let mut inquiry = [0; 36];
+                inquiry[1] = 0x80;
+                inquiry[8..16].copy_from_slice(b"usbip   ");
+                inquiry[16..32].copy_from_slice(b"ramdisk         ");
+                inquiry[32..36].copy_from_slice(b"0.1 ");
+                self.send(&inquiry);
+            }
+            READ_CAPACITY_10 => {
+                let mut capacity = ((NUM_BLOCKS - 1) as u32).to_be_bytes().to_vec();
+                capacity.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.send(&capacity);
+            }
+            READ_10 => {
+                let data = self.blocks[range].to_vec();
+                self.send(&data);
+            }
+            WRITE_10 => {
+                self.write = Some((range.start, range.end, tag));
+                return;
+            }
+            PHASE_ERROR => return self.send_csw(tag, 0, CSW_PHASE_ERROR),
+            _ => {
+                // Invalid command operation code
+                self.sense = (0x05, 0x20, 0x00);
+                if length > 0 {
+                    self.ep_in.stall();
+                }
+                return self.send_csw(tag, length, CSW_FAILED);
+            }
+        }
+
+        self.send_csw(tag, 0, CSW_PASSED);
+    }
+}
+
+impl<'a, B: UsbBus> UsbClass<B> for RamDisk<'a, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.iface, 0x08, 0x06, 0x50)?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = *xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.request == REQ_GET_MAX_LUN
+        {
+            xfer.accept_with(&[0]).unwrap();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = *xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.request == REQ_BULK_ONLY_RESET
+        {
+            self.resets += 1;
+            self.write = None;
+            self.tx.clear();
+            xfer.accept().unwrap();
+        }
+    }
+
+    fn poll(&mut self) {
+        self.flush();
+
+        let mut packet = [0; PACKET_SIZE];
+        let len = match self.ep_out.read(&mut packet) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+
+        if let Some((pos, end, tag)) = self.write.take() {
+            self.blocks[pos..pos + len].copy_from_slice(&packet[..len]);
+            match pos + len < end {
+                true => self.write = Some((pos + len, end, tag)),
+                false => self.send_csw(tag, 0, CSW_PASSED),
+            }
+            return;
+        }
+
+        assert_eq!(len, 31, "expected a command block wrapper");
+        assert_eq!(&packet[..4], b"USBC");
+        let tag = [packet[4], packet[5], packet[6], packet[7]];
+        let length = u32::from_le_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        self.execute(tag, length, &packet[15..31]);
+    }
+}
+
+#[test]
+fn read_and_write_blocks() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut disk = RamDisk::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+
+    {
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut disk]);
+        });
+        let mut msc = MassStorage::new(&session.enumerate().unwrap()).unwrap();
+
+        assert_eq!(msc.get_max_lun(&mut session).unwrap(), 0);
+        msc.test_unit_ready(&mut session, 0).unwrap();
+        assert_eq!(msc.inquiry(&mut session, 0).unwrap().product, "ramdisk");
+
+        let capacity = msc.read_capacity(&mut session, 0).unwrap();
+        assert_eq!(capacity.block_count(), NUM_BLOCKS as u64);
+        assert_eq!(capacity.block_size, BLOCK_SIZE as u32);
+
+        msc.write_10(&mut session, 0, 3, BLOCK_SIZE as u32, &data)
+            .unwrap();
+        assert_eq!(
+            msc.read_10(&mut session, 0, 3, 2, BLOCK_SIZE as u32)
+                .unwrap(),
+            data
+        );
+        assert_eq!(
+            msc.read_10(&mut session, 0, 2, 1, BLOCK_SIZE as u32)
+                .unwrap(),
+            vec![0; BLOCK_SIZE]
+        );
+    }
+
+    assert_eq!(&disk.blocks[3 * BLOCK_SIZE..5 * BLOCK_SIZE], &data[..]);
+}
+
+#[test]
+fn failed_command_clears_halt() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut disk = RamDisk::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut disk]);
+    });
+    let mut msc = MassStorage::new(&session.enumerate().unwrap()).unwrap();
+
+    // The disk stalls the data stage of an unknown command
+    let status = msc
+        .command(
+            &mut session,
+            0,
+            &[0x5a, 0, 0, 0, 0, 0, 0, 0, 64, 0],
+            DataPhase::In(64),
+        )
+        .unwrap();
+    assert!(!status.passed);
+    assert_eq!(status.residue, 64);
+    assert_eq!(
+        msc.request_sense(&mut session, 0).unwrap(),
+        Sense {
+            key: 0x05,
+            asc: 0x20,
+            ascq: 0x00
+        }
+    );
+
+    // The in endpoint works again
+    assert!(matches!(
+        msc.command(&mut session, 0, &[0x5b], DataPhase::None),
+        Ok(CommandStatus { passed: false, .. })
+    ));
+    assert!(msc.inquiry(&mut session, 0).is_ok());
+}
+
+#[test]
+fn reset_recovery_after_phase_error() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut disk = RamDisk::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    {
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut disk]);
+        });
+        let mut msc = MassStorage::new(&session.enumerate().unwrap()).unwrap();
+
+        assert!(matches!(
+            msc.command(&mut session, 0, &[PHASE_ERROR], DataPhase::None),
+            Err(UsbIpError::PhaseError)
+        ));
+        msc.test_unit_ready(&mut session, 0).unwrap();
+    }
+    assert_eq!(disk.resets, 1);
+
+    disk.corrupt_tag = true;
+    {
+        let mut session = host.session(|| {
+            usb_dev.poll(&mut [&mut disk]);
+        });
+        let mut msc = MassStorage::new(&session.enumerate().unwrap()).unwrap();
+
+        assert!(matches!(
+            msc.test_unit_ready(&mut session, 0),
+            Err(UsbIpError::InvalidCsw)
+        ));
+        msc.test_unit_ready(&mut session, 0).unwrap();
+    }
+    assert_eq!(disk.resets, 2);
+}
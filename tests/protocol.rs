@@ -0,0 +1,652 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::SerialPort;
+use usbip_device::{
+    client::{SetupPacket, UsbHost, UsbIpClient},
+    event::BusEvent,
+    protocol::*,
+    UsbIpBus, UsbIpError, UsbIpLimits,
+};
+
+/// The address, that [`UsbIpBus::new`] listens on
+const SERVER_ADDR: &str = "127.0.0.1:3240";
+
+/// Serializes the tests, that run a server on the fixed port
+static SERVER: Mutex<()> = Mutex::new(());
+
+/// Stops the device thread, even if the test fails.
+struct StopOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Exports a serial port over TCP, while `test` runs.
+fn with_server(test: impl FnOnce()) {
+    let _guard = SERVER.lock().unwrap_or_else(|err| err.into_inner());
+    let alloc = UsbBusAllocator::new(UsbIpBus::new());
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                usb_dev.poll(&mut [&mut serial]);
+                thread::sleep(Duration::from_micros(100));
+            }
+        });
+
+        let _stop = StopOnDrop(&stop);
+        test();
+    });
+}
+
+fn header(command: UsbCmd, direction: Direction) -> UsbIpHeader {
+    UsbIpHeader {
+        command,
+        seqnum: 7,
+        devid: 0x0001_0002,
+        direction,
+        ep: 1,
+    }
+}
+
+fn exported_device(num_interfaces: usize) -> OpExportedDevice {
+    OpExportedDevice {
+        path: "/sys/devices/usbip/1-1".into(),
+        bus_id: "1-1".into(),
+        descriptor: OpDeviceDescriptor {
+            busnum: 1,
+            devnum: 2,
+            speed: 2,
+            vendor: 0x1209,
+            product: 0x0001,
+            bcd_device: 0x0100,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: num_interfaces as u8,
+        },
+        interfaces: vec![
+            OpInterfaceDescriptor {
+                interface_class: 3,
+                interface_subclass: 1,
+                interface_protocol: 2,
+                padding: 0,
+            };
+            num_interfaces
+        ],
+    }
+}
+
+#[test]
+fn submit_request_roundtrip() {
+    let limits = UsbIpLimits::default();
+    let request = UsbIpRequest {
+        header: header(UsbCmd::Request, Direction::OUT),
+        cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::ZERO_PACKET,
+            transfer_buffer_length: 3,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x21, 0x09, 0x00, 0x02, 0x00, 0x00, 0x03, 0x00],
+        }),
+        data: vec![1, 2, 3],
+    };
+
+    let data = request.encode();
+    assert_eq!(data.len(), URB_HEADER_SIZE + 3);
+    assert_eq!(
+        UsbIpRequest::decode(&data, &limits).unwrap(),
+        Some((request, data.len()))
+    );
+}
+
+#[test]
+fn unlink_request_roundtrip() {
+    let limits = UsbIpLimits::default();
+    let request = UsbIpRequest {
+        header: header(UsbCmd::UnlinkRequest, Direction::OUT),
+        cmd: UsbIpRequestCmd::Unlink(UsbIpCmdUnlink { seqnum: 3 }),
+        data: vec![],
+    };
+
+    let data = request.encode();
+    assert_eq!(
+        UsbIpRequest::decode(&data, &limits).unwrap(),
+        Some((request, URB_HEADER_SIZE))
+    );
+}
+
+#[test]
+fn partial_request_is_incomplete() {
+    let limits = UsbIpLimits::default();
+    let request = UsbIpRequest {
+        header: header(UsbCmd::Request, Direction::OUT),
+        cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: 4,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+        }),
+        data: vec![1, 2, 3, 4],
+    };
+
+    let data = request.encode();
+    for len in [0, 20, URB_HEADER_SIZE, data.len() - 1] {
+        assert_eq!(UsbIpRequest::decode(&data[..len], &limits).unwrap(), None);
+    }
+}
+
+#[test]
+fn oversized_request_is_rejected() {
+    let limits = UsbIpLimits {
+        max_urb_size: 2,
+        ..UsbIpLimits::default()
+    };
+    let request = UsbIpRequest {
+        header: header(UsbCmd::Request, Direction::OUT),
+        cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: 3,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+        }),
+        data: vec![1, 2, 3],
+    };
+
+    assert!(matches!(
+        UsbIpRequest::decode(&request.encode(), &limits),
+        Err(UsbIpError::UrbTooLarge(3))
+    ));
+}
+
+#[test]
+fn invalid_direction_is_rejected() {
+    let limits = UsbIpLimits::default();
+    let request = UsbIpRequest {
+        header: header(UsbCmd::Request, Direction::IN),
+        cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: 8,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+        }),
+        data: vec![],
+    };
+
+    let mut data = request.encode();
+    data[12..16].copy_from_slice(&2u32.to_be_bytes());
+    assert!(matches!(
+        UsbIpRequest::decode(&data, &limits),
+        Err(UsbIpError::InvalidDirection(2))
+    ));
+}
+
+#[test]
+fn unknown_command_is_rejected() {
+    let limits = UsbIpLimits::default();
+    let mut data = UsbIpRequest {
+        header: header(UsbCmd::UnlinkRequest, Direction::OUT),
+        cmd: UsbIpRequestCmd::Unlink(UsbIpCmdUnlink { seqnum: 3 }),
+        data: vec![],
+    }
+    .encode();
+
+    data[0..4].copy_from_slice(&9u32.to_be_bytes());
+    assert!(matches!(
+        UsbIpRequest::decode(&data, &limits),
+        Err(UsbIpError::InvalidUrbCommand(9))
+    ));
+
+    // A response is not a valid request
+    data[0..4].copy_from_slice(&UsbCmd::Response.to_u32().to_be_bytes());
+    assert!(matches!(
+        UsbIpRequest::decode(&data, &limits),
+        Err(UsbIpError::InvalidUrbCommand(3))
+    ));
+}
+
+#[test]
+fn negative_transfer_length_is_rejected() {
+    let limits = UsbIpLimits::default();
+    let request = UsbIpRequest {
+        header: header(UsbCmd::Request, Direction::IN),
+        cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: -1,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+        }),
+        data: vec![],
+    };
+
+    assert!(matches!(
+        UsbIpRequest::decode(&request.encode(), &limits),
+        Err(UsbIpError::InvalidTransferLength(-1))
+    ));
+}
+
+#[test]
+fn isochronous_request_is_rejected() {
+    let limits = UsbIpLimits::default();
+    let request = UsbIpRequest {
+        header: header(UsbCmd::Request, Direction::IN),
+        cmd: UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: 192,
+            start_frame: 0,
+            number_of_packets: 3,
+            interval: 1,
+            setup: [0; 8],
+        }),
+        data: vec![],
+    };
+
+    assert!(matches!(
+        UsbIpRequest::decode(&request.encode(), &limits),
+        Err(UsbIpError::IsochronousUnsupported)
+    ));
+}
+
+#[test]
+fn too_many_pending_urbs_detach() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    bus.set_limits(UsbIpLimits {
+        max_pending_urbs: 2,
+        ..UsbIpLimits::default()
+    });
+    let events = bus.subscribe();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+    });
+    session.enumerate().unwrap();
+
+    // The serial port has no data, so the in urbs stay pending
+    for _ in 0..2 {
+        session.submit(2, Direction::IN, None, &[], 64).unwrap();
+    }
+    assert!(matches!(
+        session.submit(2, Direction::IN, None, &[], 64),
+        Err(UsbIpError::TooManyPendingUrbs(3))
+    ));
+    assert!(matches!(
+        session.submit(2, Direction::IN, None, &[], 64),
+        Err(UsbIpError::ConnectionClosed)
+    ));
+    assert!(events
+        .try_iter()
+        .any(|event| matches!(event, BusEvent::Detached)));
+}
+
+#[test]
+fn timed_out_transfers_are_unlinked() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    let mut poll = || {
+        usb_dev.poll(&mut [&mut serial]);
+    };
+
+    host.session(&mut poll).enumerate().unwrap();
+    host.set_max_polls(5);
+    let mut session = host.session(&mut poll);
+    let pending_ins = || bus.snapshot().endpoints[2].pending_ins;
+
+    // A timed out wait leaves the urb pending
+    let seqnum = session.submit(2, Direction::IN, None, &[], 64).unwrap();
+    assert!(matches!(session.wait(seqnum), Err(UsbIpError::Timeout)));
+    assert_eq!(pending_ins(), 1);
+    assert!(session.unlink(seqnum).unwrap());
+    assert_eq!(pending_ins(), 0);
+
+    // A timed out transfer unlinks the urb
+    assert!(matches!(
+        session.bulk_in(0x82, 64),
+        Err(UsbIpError::Timeout)
+    ));
+    assert_eq!(pending_ins(), 0);
+}
+
+#[test]
+fn responses_carry_the_devid_of_the_request() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+    host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+    })
+    .enumerate()
+    .unwrap();
+
+    let devid = 3 << 16 | 5;
+    let request = |seqnum, direction, ep, cmd, data: &[u8]| UsbIpRequest {
+        header: UsbIpHeader {
+            command: match cmd {
+                UsbIpRequestCmd::Cmd(_) => UsbCmd::Request,
+                UsbIpRequestCmd::Unlink(_) => UsbCmd::UnlinkRequest,
+            },
+            seqnum,
+            devid,
+            direction,
+            ep,
+        },
+        cmd,
+        data: data.to_vec(),
+    };
+    let submit = |length, setup: SetupPacket| {
+        UsbIpRequestCmd::Cmd(UsbIpCmdSubmit {
+            transfer_flags: TransferFlags::empty(),
+            transfer_buffer_length: length,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: setup.to_array(),
+        })
+    };
+    let no_setup = SetupPacket::from_array(&[0; 8]);
+
+    let requests = vec![
+        // GET_DESCRIPTOR(DEVICE)
+        request(
+            1,
+            Direction::IN,
+            0,
+            submit(18, SetupPacket::new(0x80, 0x06, 0x0100, 0, 18)),
+            &[],
+        ),
+        request(2, Direction::OUT, 1, submit(3, no_setup), b"abc"),
+        // The serial port has no data, so the in urb stays pending until it is unlinked
+        request(3, Direction::IN, 2, submit(64, no_setup), &[]),
+        request(
+            4,
+            Direction::OUT,
+            0,
+            UsbIpRequestCmd::Unlink(UsbIpCmdUnlink { seqnum: 3 }),
+            &[],
+        ),
+        // The endpoint does not exist
+        request(5, Direction::IN, 5, submit(64, no_setup), &[]),
+    ];
+
+    let mut responses = vec![];
+    for request in requests {
+        host.submit_request(request).unwrap();
+        for _ in 0..100 {
+            usb_dev.poll(&mut [&mut serial]);
+            while let Some(response) = host.receive().unwrap() {
+                responses.push(response);
+            }
+        }
+    }
+
+    let seqnums: Vec<_> = responses
+        .iter()
+        .map(|response| response.header.seqnum)
+        .collect();
+    assert_eq!(seqnums, vec![1, 2, 4, 5]);
+    assert!(responses
+        .iter()
+        .all(|response| response.header.devid == devid));
+}
+
+#[test]
+fn submit_response_roundtrip() {
+    let limits = UsbIpLimits::default();
+    let response = UsbIpResponse {
+        header: header(UsbCmd::Response, Direction::IN),
+        cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
+            status: 0,
+            actual_length: 2,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+        }),
+        data: vec![0xaa, 0x55],
+    };
+
+    let data = response.encode();
+    assert_eq!(
+        UsbIpResponse::decode(&data, &limits, |_| Direction::IN).unwrap(),
+        Some((response, URB_HEADER_SIZE + 2))
+    );
+    assert_eq!(
+        UsbIpResponse::decode(&data[..data.len() - 1], &limits, |_| Direction::IN).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn out_response_carries_no_data() {
+    let limits = UsbIpLimits::default();
+    let response = UsbIpResponse {
+        header: header(UsbCmd::Response, Direction::OUT),
+        cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
+            status: 0,
+            actual_length: 64,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+        }),
+        data: vec![],
+    };
+
+    let data = response.encode();
+    let (decoded, len) = UsbIpResponse::decode(&data, &limits, |header| header.direction)
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded, response);
+    assert_eq!(len, URB_HEADER_SIZE);
+}
+
+#[test]
+fn unlink_response_roundtrip() {
+    let limits = UsbIpLimits::default();
+    let response = UsbIpResponse {
+        header: header(UsbCmd::UnlinkResponse, Direction::OUT),
+        cmd: UsbIpResponseCmd::Unlink(UsbIpRetUnlink {
+            status: -ECONNRESET,
+        }),
+        data: vec![],
+    };
+
+    let data = response.encode();
+    assert_eq!(
+        UsbIpResponse::decode(&data, &limits, |_| Direction::OUT).unwrap(),
+        Some((response, URB_HEADER_SIZE))
+    );
+}
+
+#[test]
+fn op_request_roundtrip() {
+    let header = OpHeader {
+        version: USBIP_VERSION,
+        command: OP_REQ_IMPORT,
+        status: 0,
+    };
+    let request = OpRequest::ConnectDevice(header, "1-1".into());
+
+    let data = request.encode().unwrap();
+    assert_eq!(
+        OpRequest::decode(&data).unwrap(),
+        Some((request, data.len()))
+    );
+    assert_eq!(OpRequest::decode(&data[..data.len() - 1]).unwrap(), None);
+}
+
+#[test]
+fn op_response_roundtrip() {
+    let responses = vec![
+        OpResponse {
+            version: USBIP_VERSION,
+            status: ST_OK,
+            cmd: OpResponseCommand::ListDevices(vec![exported_device(2), exported_device(1)]),
+        },
+        OpResponse {
+            version: USBIP_VERSION,
+            status: ST_OK,
+            cmd: OpResponseCommand::ListDevices(vec![]),
+        },
+        OpResponse {
+            version: USBIP_VERSION,
+            status: ST_OK,
+            cmd: OpResponseCommand::ConnectDevice(Some(exported_device(0))),
+        },
+        OpResponse {
+            version: USBIP_VERSION,
+            status: ST_DEV_BUSY,
+            cmd: OpResponseCommand::ConnectDevice(None),
+        },
+    ];
+
+    for response in responses {
+        let data = response.encode().unwrap();
+        assert_eq!(
+            OpResponse::decode(&data).unwrap(),
+            Some((response, data.len()))
+        );
+    }
+}
+
+#[test]
+fn devlist_and_import_while_imported() {
+    with_server(|| {
+        let mut client = UsbIpClient::import(SERVER_ADDR, "1-1").unwrap();
+
+        let devices = UsbIpClient::list_devices(SERVER_ADDR).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus_id, "1-1");
+        assert!(matches!(
+            UsbIpClient::import(SERVER_ADDR, "1-1"),
+            Err(UsbIpError::StatusNotOk(ST_DEV_BUSY))
+        ));
+
+        // The refused import does not disturb the imported device
+        let setup = SetupPacket::new(0x80, 0x06, 0x0100, 0, 18);
+        assert_eq!(client.control_transfer(setup, &[]).unwrap().len(), 18);
+    });
+}
+
+#[test]
+fn unknown_bus_id_is_refused() {
+    with_server(|| {
+        assert!(matches!(
+            UsbIpClient::import(SERVER_ADDR, "2-1"),
+            Err(UsbIpError::StatusNotOk(ST_NODEV))
+        ));
+        assert!(UsbIpClient::import(SERVER_ADDR, "1-1").is_ok());
+    });
+}
+
+#[test]
+fn unsupported_version_is_refused() {
+    with_server(|| {
+        let mut stream = TcpStream::connect(SERVER_ADDR).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = OpRequest::ListDevices(OpHeader {
+            version: 0x0100,
+            command: OP_REQ_DEVLIST,
+            status: ST_OK,
+        });
+        stream.write_all(&request.encode().unwrap()).unwrap();
+
+        // The server answers with an error and then closes the connection
+        let mut data = vec![];
+        stream.read_to_end(&mut data).unwrap();
+        let (response, len) = OpResponse::decode(&data).unwrap().unwrap();
+        assert_eq!(response.status, ST_ERROR);
+        assert_eq!(len, data.len());
+    });
+}
+
+#[test]
+fn op_request_times_out() {
+    // The connection is accepted by the backlog, but never answered
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let start = Instant::now();
+    assert!(matches!(
+        UsbIpClient::list_devices(addr),
+        Err(UsbIpError::Timeout)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(matches!(
+        UsbIpClient::import(addr, "1-1"),
+        Err(UsbIpError::Timeout)
+    ));
+}
+
+#[test]
+fn bytes_after_import_reply_are_kept() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; OP_HEADER_SIZE + 32];
+        stream.read_exact(&mut request).unwrap();
+
+        // The completion of the first urb arrives together with the import reply
+        let reply = OpResponse {
+            version: USBIP_VERSION,
+            status: ST_OK,
+            cmd: OpResponseCommand::ConnectDevice(Some(exported_device(0))),
+        };
+        let mut completion = header(UsbCmd::Response, Direction::IN);
+        completion.seqnum = 1;
+        let completion = UsbIpResponse {
+            header: completion,
+            cmd: UsbIpResponseCmd::Cmd(UsbIpRetSubmit {
+                status: 0,
+                actual_length: 3,
+                start_frame: 0,
+                number_of_packets: 0,
+                error_count: 0,
+            }),
+            data: vec![1, 2, 3],
+        };
+        let mut data = reply.encode().unwrap();
+        data.extend_from_slice(&completion.encode());
+        stream.write_all(&data).unwrap();
+
+        // Wait for the client to disconnect
+        let _ = stream.read_to_end(&mut vec![]);
+    });
+
+    let mut client = UsbIpClient::import(addr, "1-1").unwrap();
+    assert_eq!(client.bulk_in(1, 64).unwrap(), vec![1, 2, 3]);
+    drop(client);
+    server.join().unwrap();
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_hid::{
+    descriptor::{generator_prelude::*, MouseReport},
+    hid_class::HIDClass,
+};
+use usbd_serial::SerialPort;
+use usbip_device::{
+    class::hid::Hid,
+    client::UsbHost,
+    clock::{Clock, VirtualClock},
+    timing::{BusSpeed, BusTiming},
+    UsbIpBus, UsbIpError,
+};
+
+/// The bulk out endpoint of the serial port
+const EP_OUT: u8 = 0x01;
+/// The polling interval of the mouse in frames
+const MOUSE_INTERVAL: u8 = 5;
+
+const FRAME: Duration = Duration::from_millis(1);
+
+#[test]
+fn bus_speeds() {
+    assert_eq!(BusSpeed::Low.frame_budget(), 187);
+    assert_eq!(BusSpeed::Full.frame_budget(), 1500);
+    assert_eq!(BusSpeed::High.frame_budget(), 7500);
+
+    assert_eq!(BusSpeed::Full.interval_frames(0), 1);
+    assert_eq!(BusSpeed::Full.interval_frames(10), 10);
+    assert_eq!(BusSpeed::High.interval_frames(4), 8);
+    assert_eq!(BusSpeed::High.interval_frames(255), 1 << 15);
+}
+
+#[test]
+fn completion_waits_for_frozen_clock() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let clock = VirtualClock::new();
+    bus.set_clock(clock.clone());
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+        let _ = serial.read(&mut [0; 64]);
+    });
+    session.enumerate().unwrap();
+
+    // Without time passing, the end of the frame is never reached
+    bus.set_timing(Some(BusTiming::new(BusSpeed::Full)));
+    assert!(matches!(
+        session.bulk_out(EP_OUT, &[0; 16]),
+        Err(UsbIpError::Timeout)
+    ));
+
+    bus.set_timing(None);
+    assert!(matches!(session.bulk_out(EP_OUT, &[0; 16]), Ok(16)));
+}
+
+#[test]
+fn large_transfer_spans_frames() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let clock = VirtualClock::new();
+    bus.set_clock(clock.clone());
+    bus.set_timing(Some(BusTiming::new(BusSpeed::Full)));
+    host.set_max_polls(10_000);
+    let alloc = UsbBusAllocator::new(bus);
+    let mut serial = SerialPort::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let step = clock.clone();
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut serial]);
+        let _ = serial.read(&mut [0; 64]);
+        step.advance(Duration::from_micros(10));
+    });
+    session.enumerate().unwrap();
+
+    // Start in a frame, that is not used by enumeration anymore
+    let start = clock.now() + 2 * FRAME;
+    let start = start - Duration::from_nanos(start.as_nanos() as u64 % FRAME.as_nanos() as u64);
+    clock.set(start);
+
+    // 4000 bytes take three frames of 1500 bytes each
+    assert!(matches!(session.bulk_out(EP_OUT, &[0x55; 4000]), Ok(4000)));
+    let elapsed = clock.now() - start;
+    assert!(elapsed >= 3 * FRAME, "completed after {:?}", elapsed);
+    assert!(elapsed < 3 * FRAME + 10 * Duration::from_micros(10));
+}
+
+#[test]
+fn interrupt_reports_are_spaced_by_interval() {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let clock = VirtualClock::new();
+    bus.set_clock(clock.clone());
+    bus.set_timing(Some(BusTiming::new(BusSpeed::Full)));
+    host.set_max_polls(100_000);
+    let alloc = UsbBusAllocator::new(bus);
+    let mut hid = HIDClass::new(&alloc, MouseReport::desc(), MOUSE_INTERVAL);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let step = clock.clone();
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut hid]);
+        let _ = hid.push_input(&MouseReport {
+            x: 1,
+            y: 0,
+            buttons: 0,
+            wheel: 0,
+            pan: 0,
+        });
+        step.advance(Duration::from_micros(50));
+    });
+    let device = session.enumerate().unwrap();
+    let mouse = Hid::new(&mut session, &device).unwrap();
+
+    let mut times = vec![];
+    for _ in 0..4 {
+        assert!(mouse.poll_report(&mut session).unwrap().is_some());
+        times.push(clock.now());
+    }
+    for pair in times.windows(2) {
+        let gap = pair[1] - pair[0];
+        assert!(
+            gap >= MOUSE_INTERVAL as u32 * FRAME,
+            "reports {:?} apart",
+            gap
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    io::{Result as IoResult, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use usb_device::{bus::UsbBusAllocator, endpoint::EndpointType, prelude::*};
+use usbd_hid::{
+    descriptor::{generator_prelude::*, MouseReport},
+    hid_class::HIDClass,
+};
+use usbip_device::{
+    capture::{JsonLinesWriter, PcapWriter},
+    class::hid::Hid,
+    client::UsbHost,
+    clock::VirtualClock,
+    fault::{Fault, FaultInjector, FaultRule},
+    protocol::EPROTO,
+    timing::{BusSpeed, BusTiming},
+    UsbIpBus,
+};
+
+/// A capture target, that stays readable after the bus took ownership of the writer.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Reads mouse reports over a jittery bus with random faults.
+///
+/// # Returns
+/// The pcap and the JSON lines capture of the session.
+fn capture_session(seed: u64) -> (Vec<u8>, Vec<u8>) {
+    let (bus, mut host) = UsbIpBus::loopback();
+    let clock = VirtualClock::new();
+    bus.set_clock(clock.clone());
+    bus.set_timing(Some(
+        BusTiming::new(BusSpeed::Full).jitter(Duration::from_micros(300), seed),
+    ));
+    let pcap = SharedBuffer::default();
+    let json = SharedBuffer::default();
+    bus.add_capture(PcapWriter::new(pcap.clone()).unwrap());
+    bus.add_capture(JsonLinesWriter::new(json.clone()));
+    host.set_max_polls(100_000);
+
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut hid = HIDClass::new(&alloc, MouseReport::desc(), 5);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let step = clock.clone();
+    let mut x = 0;
+    let mut session = host.session(|| {
+        usb_dev.poll(&mut [&mut hid]);
+        let report = MouseReport {
+            x,
+            y: 0,
+            buttons: 0,
+            wheel: 0,
+            pan: 0,
+        };
+        if hid.push_input(&report).is_ok() {
+            x = x.wrapping_add(1);
+        }
+        step.advance(Duration::from_micros(50));
+    });
+    let device = session.enumerate().unwrap();
+    let mouse = Hid::new(&mut session, &device).unwrap();
+
+    bus.set_fault_injector(Some(
+        FaultInjector::new(seed).rule(
+            FaultRule::new(Fault::Status(-EPROTO))
+                .transfer_type(EndpointType::Interrupt)
+                .probability(0.3),
+        ),
+    ));
+    for _ in 0..16 {
+        let _ = mouse.poll_report(&mut session);
+    }
+    bus.clear_captures();
+
+    let pcap = pcap.0.lock().unwrap().clone();
+    let json = json.0.lock().unwrap().clone();
+    (pcap, json)
+}
+
+#[test]
+fn traces_are_identical_across_runs() {
+    let (pcap, json) = capture_session(7);
+    assert!(!pcap.is_empty());
+    assert!(!json.is_empty());
+
+    assert!(capture_session(7) == (pcap.clone(), json.clone()));
+    assert!(capture_session(8) != (pcap, json));
+}